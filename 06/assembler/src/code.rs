@@ -14,11 +14,7 @@ pub fn code(command: Command) -> [bool; 16] {
             let mut bit_array = [false; 16];
             let mut count = 15;
             for b in bit_string.chars().rev() {
-                if b == '1' {
-                    bit_array[count] = true
-                } else {
-                    bit_array[count] = false
-                }
+                bit_array[count] = b == '1';
                 count -= 1;
             }
 
@@ -27,8 +23,6 @@ pub fn code(command: Command) -> [bool; 16] {
         // C命令
         // 111a cccc ccdd djjj
         Command::CCommand(mnemonics) => {
-            println!("mnemonics: {:?}", mnemonics);
-
             let mut bits: Vec<bool> = vec![true, true, true];
             bits.append(&mut a_comp(mnemonics.1.as_str()).to_vec());
             bits.append(&mut dest(mnemonics.0.as_str()).to_vec());
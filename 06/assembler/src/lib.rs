@@ -0,0 +1,31 @@
+// 他のツール(VMトランスレータなど)からプロセス内でアセンブルするためのAPI
+use std::io::{BufRead, Seek};
+use crate::parser::Parser;
+
+pub mod code;
+pub mod parser;
+pub mod symbol_table;
+
+// アセンブリプログラム全体を機械語に変換する
+pub fn assemble<R: BufRead + Seek>(reader: R) -> Vec<[bool; 16]> {
+    let mut parser = Parser::new(reader);
+    parser.scan_labels();
+
+    let mut binary_code = vec![];
+    while let Some(command) = parser.advance() {
+        binary_code.push(code::code(command));
+    }
+    binary_code
+}
+
+// .hack ファイルの形式 (1行に1命令、'0'と'1'の16文字)
+pub fn to_hack(binary_code: &[[bool; 16]]) -> String {
+    let mut hack = String::new();
+    for line in binary_code.iter() {
+        for b in line.iter() {
+            hack.push(if *b { '1' } else { '0' });
+        }
+        hack.push('\n');
+    }
+    hack
+}
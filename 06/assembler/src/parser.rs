@@ -1,16 +1,18 @@
-use std::fs::File;
-use std::io::{BufReader, BufRead, Seek, SeekFrom};
+use std::io::{BufRead, Seek, SeekFrom};
 use crate::parser::Command::{ACommand, LCommand, CCommand};
 use crate::symbol_table::SymbolTable;
 
 // 6.3.1 Parserモジュール
 // 主な機能は各アセンブリコマンドをその基本要素に分解すること
-pub struct Parser {
-    reader: BufReader<File>,
+// ラベルを解決するために2回読むので、巻き戻せる入力(ファイル, Cursor)を受け取る
+pub struct Parser<R: BufRead + Seek> {
+    reader: R,
     symbol_table: SymbolTable,
     next_rom_address: u16,
 }
 
+// 本の A_COMMAND, C_COMMAND, L_COMMAND に合わせた名前
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Command {
     ACommand(String),
@@ -18,10 +20,10 @@ pub enum Command {
     LCommand(String),
 }
 
-impl Parser {
-    pub fn new(file: File) -> Self {
+impl<R: BufRead + Seek> Parser<R> {
+    pub fn new(reader: R) -> Self {
         Self {
-            reader: BufReader::new(file),
+            reader,
             symbol_table: SymbolTable::new(),
             next_rom_address: 0,
         }
@@ -56,7 +58,7 @@ impl Parser {
                 buf.replace_range(pos.., "");
             }
 
-            if buf.len() == 0 {
+            if buf.is_empty() {
                 buf.clear();
                 continue;
             }
@@ -86,22 +88,16 @@ impl Parser {
 
     // ラベルをシンボルテーブルに登録するために一度アセンブリプログラム全体をパースする
     pub fn scan_labels(&mut self) {
-        loop {
-            if let Some(command_string) = self.read_line() {
-                match self.parse_command(command_string.as_str()) {
-                    LCommand(label) => {
-                        // シンボルテーブルに登録する
-                        self.symbol_table.add(label.as_str(), self.next_rom_address);
-                        continue;
-                    },
-                    _ => {
-                        // A命令, C命令が読み込まれるROMアドレスを加算していく
-                        self.next_rom_address += 1;
-                        continue;
-                    }
+        while let Some(command_string) = self.read_line() {
+            match self.parse_command(command_string.as_str()) {
+                LCommand(label) => {
+                    // シンボルテーブルに登録する
+                    self.symbol_table.add(label.as_str(), self.next_rom_address);
+                },
+                _ => {
+                    // A命令, C命令が読み込まれるROMアドレスを加算していく
+                    self.next_rom_address += 1;
                 }
-            } else {
-                break;
             }
         }
 
@@ -114,7 +110,7 @@ impl Parser {
                 let mut command_string = String::new();
                 let mut chars = command.chars();
                 chars.next(); // @を除外する
-                while let Some(c) = chars.next() {
+                for c in chars {
                     command_string.push(c);
                 }
 
@@ -132,7 +128,7 @@ impl Parser {
                 let mut label_string = String::new();
                 let mut chars = command.chars();
                 chars.next(); // '(' を除外する
-                while let Some(c) = chars.next() {
+                for c in chars {
                     if c == ')' {
                         break;
                     }
@@ -142,8 +138,7 @@ impl Parser {
             }
             _ => {
                 let mut command_string = String::new();
-                let mut chars = command.chars();
-                while let Some(c) = chars.next() {
+                for c in command.chars() {
                     command_string.push(c);
                 }
 
@@ -53,10 +53,16 @@ impl SymbolTable {
         self.inner.insert(symbol.to_string(), address);
 
         self.next_ram_address += 1;
-        return address;
+        address
     }
 
     pub fn address(&self, symbol: &str) -> Option<&u16> {
         self.inner.get(symbol)
     }
 }
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -255,3 +255,146 @@ impl CodeWriter {
         a
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // 8章と同じHackコンピュータのエミュレータで変換したコードを実行する
+    use vm_translator::hack_cpu::Cpu;
+    use crate::parser::Parser;
+    use std::io::Cursor;
+
+    // テストスクリプトと同じように、セグメントのベースアドレスを設定してから実行する
+    const INITIAL_RAM: [(usize, i16); 5] = [(0, 256), (1, 300), (2, 400), (3, 3000), (4, 3010)];
+
+    fn run(commands: &[&str], ram: &[(usize, i16)]) -> Cpu {
+        let mut code_writer = CodeWriter::new("Test".into());
+        let mut codes = vec![];
+        for command in commands.iter() {
+            codes.append(&mut code_writer.code(Parser::parse(command)));
+        }
+
+        let binary_code = assembler::assemble(Cursor::new(codes.join("\n")));
+        let mut cpu = Cpu::new(Cpu::from_bits(&binary_code));
+        for (address, value) in INITIAL_RAM.iter().chain(ram.iter()) {
+            cpu.ram[*address] = *value;
+        }
+        cpu.run(10_000);
+        cpu
+    }
+
+    // スタックの先頭の値
+    fn top(cpu: &Cpu) -> i16 {
+        cpu.ram[cpu.ram[0] as usize - 1]
+    }
+
+    #[test]
+    fn push_constant() {
+        let cpu = run(&["push constant 7", "push constant 32767"], &[]);
+        assert_eq!(cpu.ram[0], 258);
+        assert_eq!(&cpu.ram[256..258], &[7, 32767]);
+    }
+
+    #[test]
+    fn push_segments() {
+        let cpu = run(
+            &[
+                "push local 2",
+                "push argument 1",
+                "push this 3",
+                "push that 0",
+                "push pointer 0",
+                "push pointer 1",
+                "push temp 7",
+            ],
+            &[(302, 11), (401, 22), (3003, 33), (3010, 44), (12, 55)],
+        );
+        assert_eq!(cpu.ram[0], 263);
+        assert_eq!(&cpu.ram[256..263], &[11, 22, 33, 44, 3000, 3010, 55]);
+    }
+
+    #[test]
+    fn pop_segments() {
+        let cpu = run(
+            &[
+                "push constant 1",
+                "pop local 2",
+                "push constant 2",
+                "pop argument 1",
+                "push constant 3",
+                "pop this 3",
+                "push constant 4",
+                "pop that 0",
+                "push constant 5",
+                "pop temp 7",
+                "push constant 4000",
+                "pop pointer 0",
+                "push constant 5000",
+                "pop pointer 1",
+            ],
+            &[],
+        );
+        assert_eq!(cpu.ram[0], 256);
+        assert_eq!(cpu.ram[302], 1);
+        assert_eq!(cpu.ram[401], 2);
+        assert_eq!(cpu.ram[3003], 3);
+        assert_eq!(cpu.ram[3010], 4);
+        assert_eq!(cpu.ram[12], 5);
+        assert_eq!(cpu.ram[3], 4000);
+        assert_eq!(cpu.ram[4], 5000);
+    }
+
+    #[test]
+    fn push_and_pop_static() {
+        let cpu = run(&["push constant 5", "pop static 3", "push constant 6", "pop static 0", "push static 3"], &[]);
+        assert_eq!(cpu.ram[0], 257);
+        assert_eq!(cpu.ram[256], 5);
+        // 変数は最初に現れた順に RAM[16] から割り当てられる
+        assert_eq!(cpu.ram[16], 5);
+        assert_eq!(cpu.ram[17], 6);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let cases: [(&[&str], i16); 6] = [
+            (&["push constant 7", "push constant 8", "add"], 15),
+            (&["push constant 7", "push constant 8", "sub"], -1),
+            (&["push constant 7", "neg"], -7),
+            (&["push constant 12", "push constant 10", "and"], 8),
+            (&["push constant 12", "push constant 10", "or"], 14),
+            (&["push constant 12", "not"], -13),
+        ];
+        for (commands, expected) in cases.iter() {
+            let cpu = run(commands, &[]);
+            assert_eq!(cpu.ram[0], 257, "{:?}", commands);
+            assert_eq!(top(&cpu), *expected, "{:?}", commands);
+        }
+    }
+
+    #[test]
+    fn comparison() {
+        // true は -1, false は 0
+        let cases: [(&str, i16, i16, i16); 9] = [
+            ("eq", 3, 3, -1),
+            ("eq", 3, 4, 0),
+            ("gt", 4, 3, -1),
+            ("gt", 3, 3, 0),
+            ("gt", 3, 4, 0),
+            ("lt", 3, 4, -1),
+            ("lt", 3, 3, 0),
+            ("lt", 4, 3, 0),
+            ("lt", 0, 1, -1),
+        ];
+        for (operator, x, y, expected) in cases.iter() {
+            let x = format!("push constant {}", x);
+            let y = format!("push constant {}", y);
+            let cpu = run(&[&x, &y, operator], &[]);
+            assert_eq!(cpu.ram[0], 257, "{} {} {}", x, y, operator);
+            assert_eq!(top(&cpu), *expected, "{} {} {}", x, y, operator);
+        }
+
+        // ラベルは比較ごとに異なる
+        let cpu = run(&["push constant 1", "push constant 1", "eq", "push constant 1", "push constant 2", "eq"], &[]);
+        assert_eq!(&cpu.ram[256..258], &[-1, 0]);
+    }
+}
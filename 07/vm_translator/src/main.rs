@@ -25,13 +25,9 @@ fn main() {
     let mut assembly_codes = vec![];
     let mut code_writer = CodeWriter::new(String::from(path.file_stem().unwrap().to_str().unwrap()));
 
-    loop {
-        if let Some(command) = parser.advance() {
-            println!("command: {:?}", command);
-            assembly_codes.append(&mut code_writer.code(command));
-        } else {
-            break;
-        }
+    while let Some(command) = parser.advance() {
+        println!("command: {:?}", command);
+        assembly_codes.append(&mut code_writer.code(command));
     }
 
     println!("assembly_codes: {:?}", assembly_codes);
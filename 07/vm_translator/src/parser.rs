@@ -85,15 +85,11 @@ impl MemoryAccess {
     fn from(segment: &str, index: u16) -> Self {
         let segment = MemorySegment::from(segment);
         match segment {
-            MemorySegment::Pointer => {
-                if index > 3 {
-                    panic!(format!("index {} is not supported", index));
-                }
+            MemorySegment::Pointer if index > 3 => {
+                panic!("index {} is not supported", index);
             }
-            MemorySegment::Temp => {
-                if index > 7 {
-                    panic!(format!("index {} is not supported", index));
-                }
+            MemorySegment::Temp if index > 7 => {
+                panic!("index {} is not supported", index);
             }
             _ => {}
         }
@@ -137,7 +133,7 @@ impl Parser {
                     buf.replace_range(pos.., "");
                 }
 
-                if buf.len() == 0 {
+                if buf.is_empty() {
                     continue;
                 }
 
@@ -148,7 +144,7 @@ impl Parser {
         }
     }
 
-    fn parse(s: &str) -> Command {
+    pub fn parse(s: &str) -> Command {
         let elems: Vec<&str> = s.split(" ").collect();
 
         match elems[0] {
@@ -166,7 +162,7 @@ impl Parser {
                     return Command::Arithmetic(arithmetic_operator);
                 }
 
-                panic!(format!("the operator is not supported: {}", other));
+                panic!("the operator is not supported: {}", other);
             }
         }
     }
@@ -0,0 +1,105 @@
+// 本の7章のテストプログラムを変換してHackコンピュータのエミュレータで実行し、
+// .cmp と同じRAMの値になることを確かめる
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// 8章と同じHackコンピュータのエミュレータを使う
+use vm_translator::hack_cpu::Cpu;
+
+const MAX_CYCLES: u64 = 100_000;
+
+struct TestProgram {
+    name: &'static str,
+    ram: &'static [(usize, i16)],
+    expected: &'static [(usize, i16)],
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name).join(format!("{}.vm", name))
+}
+
+// 変換結果は .vm の隣に書き出されるので、一時ディレクトリにコピーしてから変換する
+fn translate(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("vm_translator_07_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let vm_file = dir.join(format!("{}.vm", name));
+    std::fs::copy(fixture(name), &vm_file).expect("failed to copy the fixture");
+
+    let result = Command::new(env!("CARGO_BIN_EXE_vm_translator")).arg(&vm_file).output().expect("failed to run vm_translator");
+    assert!(
+        result.status.success(),
+        "{}: vm_translator failed: {}",
+        name,
+        String::from_utf8_lossy(&result.stderr),
+    );
+
+    let asm = std::fs::read_to_string(vm_file.with_extension("asm")).expect("failed to read the output");
+    std::fs::remove_dir_all(&dir).unwrap();
+    asm
+}
+
+fn run(program: TestProgram) {
+    let binary_code = assembler::assemble(Cursor::new(translate(program.name)));
+    let mut cpu = Cpu::new(Cpu::from_bits(&binary_code));
+    for (address, value) in program.ram.iter() {
+        cpu.ram[*address] = *value;
+    }
+    cpu.run(MAX_CYCLES);
+
+    for (address, value) in program.expected.iter() {
+        assert_eq!(cpu.ram[*address], *value, "{}: RAM[{}]", program.name, address);
+    }
+}
+
+#[test]
+fn simple_add() {
+    run(TestProgram {
+        name: "SimpleAdd",
+        ram: &[(0, 256)],
+        expected: &[(0, 257), (256, 15)],
+    });
+}
+
+#[test]
+fn stack_test() {
+    run(TestProgram {
+        name: "StackTest",
+        ram: &[(0, 256)],
+        expected: &[
+            (0, 266),
+            (256, -1), (257, 0), (258, 0), (259, 0), (260, -1),
+            (261, 0), (262, -1), (263, 0), (264, 0), (265, -91),
+        ],
+    });
+}
+
+#[test]
+fn basic_test() {
+    run(TestProgram {
+        name: "BasicTest",
+        ram: &[(0, 256), (1, 300), (2, 400), (3, 3000), (4, 3010)],
+        expected: &[
+            (256, 472), (300, 10), (401, 21), (402, 22),
+            (3006, 36), (3012, 42), (3015, 45), (11, 510),
+        ],
+    });
+}
+
+#[test]
+fn pointer_test() {
+    run(TestProgram {
+        name: "PointerTest",
+        ram: &[(0, 256)],
+        expected: &[(256, 6084), (3, 3030), (4, 3040), (3032, 32), (3046, 46)],
+    });
+}
+
+#[test]
+fn static_test() {
+    run(TestProgram {
+        name: "StaticTest",
+        ram: &[(0, 256)],
+        expected: &[(256, 1110)],
+    });
+}
@@ -0,0 +1,4 @@
+// .vm を直接実行するバーチャルマシンと組み込みの Jack OS
+// コンパイラのテストからも実行できるようにライブラリとして公開する
+pub mod os;
+pub mod vm;
@@ -0,0 +1,96 @@
+use std::io::BufReader;
+use std::path::Path;
+use vm_translator::parser::{vm_files, VmFile};
+use vm_emulator::os::Os;
+use vm_emulator::vm::{Vm, RAM_SIZE};
+
+#[derive(Debug)]
+struct Options {
+    path: String,
+    // 実行するコマンド数の上限
+    max_steps: Option<u64>,
+    // 停止後に出力するRAMの範囲
+    dump: Option<(usize, usize)>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut path = None;
+        let mut max_steps = None;
+        let mut dump = None;
+
+        for arg in args.iter() {
+            match arg.as_str() {
+                other if other.starts_with("--steps=") => {
+                    let steps = other.trim_start_matches("--steps=");
+                    max_steps = Some(steps.parse::<u64>().map_err(|_| format!("invalid --steps: {}", steps))?);
+                }
+                other if other.starts_with("--dump=") => {
+                    // `--dump=256..260`
+                    let range = other.trim_start_matches("--dump=");
+                    let bounds: Vec<Option<usize>> = range.split("..").map(|n| n.parse::<usize>().ok()).collect();
+                    match bounds.as_slice() {
+                        [Some(start), Some(end)] if start < end && *end <= RAM_SIZE => dump = Some((*start, *end)),
+                        _ => return Err(format!("invalid --dump: {}", range)),
+                    }
+                }
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown option: {}", other));
+                }
+                other => {
+                    if path.is_some() {
+                        return Err(format!("unexpected argument: {}", other));
+                    }
+                    path = Some(String::from(other));
+                }
+            }
+        }
+
+        Ok(Self {
+            path: path.ok_or("A path to .vm file or directory contains .vm file is required")?,
+            max_steps,
+            dump,
+        })
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let options = Options::parse(&args[1..]).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        eprintln!("usage: vm_emulator [--steps=N] [--dump=START..END] <path>");
+        std::process::exit(2);
+    });
+
+    let path = Path::new(&options.path);
+    let vm_files = vm_files(path).expect("failed to open files");
+    if vm_files.is_empty() {
+        panic!("{}: should have vm files", &options.path)
+    }
+
+    let files = vm_files.iter()
+        .map(|path| VmFile::open(path).expect("failed to open the file"))
+        .collect();
+    let os = Os::new(
+        Box::new(BufReader::new(std::io::stdin())),
+        Box::new(std::io::stdout()),
+    );
+
+    let mut vm = Vm::load(files, os).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    });
+    if let Err(e) = vm.run(options.max_steps) {
+        eprintln!("error: {} (after {} steps)", e, vm.steps());
+        std::process::exit(1);
+    }
+
+    if let Some((start, end)) = options.dump {
+        println!();
+        for address in start..end {
+            println!("RAM[{}] = {}", address, vm.ram[address]);
+        }
+    }
+}
+
@@ -0,0 +1,13 @@
+use crate::vm::Vm;
+
+pub fn new(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    if args[0] <= 0 {
+        // Array size must be positive
+        return super::error(vm, 2);
+    }
+    super::memory::alloc(vm, args)
+}
+
+pub fn dispose(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    super::memory::de_alloc(vm, args)
+}
@@ -0,0 +1,73 @@
+// キーボードの入力は Os の input (標準入力など) から読み込む
+// 入力が尽きたらそれ以上キーが押されることはないので、VMを停止する
+use std::io::Read;
+use crate::vm::{State, Vm, KBD};
+
+const NEW_LINE: i16 = 128;
+const BACK_SPACE: i16 = 129;
+
+// 1文字読み込む (EOFの場合はNone)
+fn next_char(vm: &mut Vm) -> Result<Option<i16>, String> {
+    let mut buf = [0u8; 1];
+    match vm.os.input.read(&mut buf) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(match buf[0] {
+            b'\n' => NEW_LINE,
+            0x08 | 0x7f => BACK_SPACE,
+            c => c as i16,
+        })),
+        Err(e) => Err(format!("failed to read the input: {}", e)),
+    }
+}
+
+pub fn init(_vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    Ok(0)
+}
+
+pub fn key_pressed(vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    Ok(vm.ram[KBD])
+}
+
+pub fn read_char(vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    match next_char(vm)? {
+        Some(c) => Ok(c),
+        None => {
+            vm.halt();
+            Ok(0)
+        }
+    }
+}
+
+pub fn read_line(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    super::output::print_string(vm, args)?;
+
+    let mut line = vec![];
+    loop {
+        match next_char(vm)? {
+            Some(NEW_LINE) => break,
+            Some(BACK_SPACE) => {
+                line.pop();
+            }
+            Some(c) => line.push((c as u8) as char),
+            None => {
+                if line.is_empty() {
+                    vm.halt();
+                    return Ok(0);
+                }
+                break;
+            }
+        }
+    }
+
+    super::string::create(vm, &line)
+}
+
+pub fn read_int(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let line = read_line(vm, args)?;
+    if *vm.state() == State::Halted {
+        return Ok(0);
+    }
+    let value = super::string::int_value(vm, &[line])?;
+    super::string::dispose(vm, &[line])?;
+    Ok(value)
+}
@@ -0,0 +1,51 @@
+use crate::vm::Vm;
+
+pub fn init(_vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    Ok(0)
+}
+
+pub fn abs(_vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    Ok(args[0].wrapping_abs())
+}
+
+// 16bitの範囲で桁あふれさせる (Hackの算術と同じ)
+pub fn multiply(_vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    Ok(args[0].wrapping_mul(args[1]))
+}
+
+pub fn divide(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    if args[1] == 0 {
+        // Division by zero
+        return super::error(vm, 3);
+    }
+    Ok(args[0].wrapping_div(args[1]))
+}
+
+pub fn min(_vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    Ok(args[0].min(args[1]))
+}
+
+pub fn max(_vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    Ok(args[0].max(args[1]))
+}
+
+pub fn sqrt(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let x = args[0];
+    if x < 0 {
+        // Cannot compute square root of a negative number
+        return super::error(vm, 4);
+    }
+
+    // 二分探索で y*y <= x を満たす最大の y を求める
+    let x = x as i32;
+    let (mut low, mut high) = (0, 182);
+    while low < high {
+        let mid = (low + high + 1) / 2;
+        if mid * mid <= x {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(low as i16)
+}
@@ -0,0 +1,107 @@
+use crate::vm::{Vm, HEAP_BASE, HEAP_END, RAM_SIZE};
+
+// ヒープの空き領域リスト
+// 確保したブロックの直前のワードにブロックのサイズを書き込んでおき、解放時に参照する
+pub struct Heap {
+    // (先頭アドレス, ワード数)
+    free_list: Vec<(usize, usize)>,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            free_list: vec![(HEAP_BASE, HEAP_END - HEAP_BASE + 1)],
+        }
+    }
+
+    // first-fit で確保する
+    pub fn alloc(&mut self, ram: &mut [i16], size: usize) -> Option<usize> {
+        let block_size = size + 1;
+        let position = self.free_list.iter().position(|(_, free)| *free >= block_size)?;
+
+        let (base, free) = self.free_list[position];
+        if free == block_size {
+            self.free_list.remove(position);
+        } else {
+            self.free_list[position] = (base + block_size, free - block_size);
+        }
+
+        ram[base] = size as i16;
+        Some(base + 1)
+    }
+
+    // ブロックの直前のワードが壊れていたら解放しない
+    pub fn de_alloc(&mut self, ram: &mut [i16], address: usize) -> Result<(), String> {
+        let base = address - 1;
+        let size = ram[base];
+        if size <= 0 || size as usize > HEAP_END - base {
+            return Err(format!("Memory.deAlloc: invalid block size {} at {}", size, base));
+        }
+        let block = (base, size as usize + 1);
+
+        let position = self.free_list.iter()
+            .position(|(free_base, _)| *free_base > base)
+            .unwrap_or(self.free_list.len());
+        self.free_list.insert(position, block);
+
+        // 隣接する空き領域を結合する
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (base, size) in self.free_list.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.0 + last.1 == base => last.1 += size,
+                _ => merged.push((base, size)),
+            }
+        }
+        self.free_list = merged;
+        Ok(())
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn init(vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    vm.os.heap = Heap::new();
+    Ok(0)
+}
+
+pub fn peek(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    Ok(vm.ram[address(args[0])?])
+}
+
+pub fn poke(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    vm.ram[address(args[0])?] = args[1];
+    Ok(0)
+}
+
+pub fn alloc(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    if args[0] <= 0 {
+        // Allocated memory size must be positive
+        return super::error(vm, 5);
+    }
+    match vm.os.heap.alloc(&mut vm.ram, args[0] as usize) {
+        Some(address) => Ok(address as i16),
+        // Heap overflow
+        None => super::error(vm, 6),
+    }
+}
+
+pub fn de_alloc(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let address = args[0] as u16 as usize;
+    if address <= HEAP_BASE || address > HEAP_END {
+        return Err(format!("Memory.deAlloc: {} is not a heap address", address));
+    }
+    vm.os.heap.de_alloc(&mut vm.ram, address)?;
+    Ok(0)
+}
+
+fn address(address: i16) -> Result<usize, String> {
+    let address = address as u16 as usize;
+    if address >= RAM_SIZE {
+        return Err(format!("invalid memory address: {}", address));
+    }
+    Ok(address)
+}
@@ -0,0 +1,108 @@
+// Jack OS の組み込み実装
+// .vm で同名の関数が定義されていない場合に、Rustで実装した関数を呼び出す
+use std::io::{BufRead, Write};
+use crate::vm::Vm;
+
+mod array;
+mod keyboard;
+mod math;
+mod memory;
+mod output;
+mod screen;
+mod string;
+mod sys;
+
+pub use memory::Heap;
+pub use output::Console;
+
+// 引数を受け取り、戻り値を返す (void の関数は 0 を返す)
+pub type Builtin = fn(&mut Vm, &[i16]) -> Result<i16, String>;
+
+// OSの状態
+pub struct Os {
+    pub heap: Heap,
+    pub console: Console,
+    pub screen_color: bool,
+    pub input: Box<dyn BufRead>,
+}
+
+impl Os {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Self {
+            heap: Heap::new(),
+            console: Console::new(output),
+            screen_color: true,
+            input,
+        }
+    }
+}
+
+// 関数名から引数の数と実装を引く
+pub fn builtin(function_name: &str) -> Option<(u16, Builtin)> {
+    let builtin: (u16, Builtin) = match function_name {
+        "Math.init" => (0, math::init),
+        "Math.abs" => (1, math::abs),
+        "Math.multiply" => (2, math::multiply),
+        "Math.divide" => (2, math::divide),
+        "Math.min" => (2, math::min),
+        "Math.max" => (2, math::max),
+        "Math.sqrt" => (1, math::sqrt),
+
+        "Memory.init" => (0, memory::init),
+        "Memory.peek" => (1, memory::peek),
+        "Memory.poke" => (2, memory::poke),
+        "Memory.alloc" => (1, memory::alloc),
+        "Memory.deAlloc" => (1, memory::de_alloc),
+
+        "String.new" => (1, string::new),
+        "String.dispose" => (1, string::dispose),
+        "String.length" => (1, string::length),
+        "String.charAt" => (2, string::char_at),
+        "String.setCharAt" => (3, string::set_char_at),
+        "String.appendChar" => (2, string::append_char),
+        "String.eraseLastChar" => (1, string::erase_last_char),
+        "String.intValue" => (1, string::int_value),
+        "String.setInt" => (2, string::set_int),
+        "String.backSpace" => (0, string::back_space),
+        "String.doubleQuote" => (0, string::double_quote),
+        "String.newLine" => (0, string::new_line),
+
+        "Array.new" => (1, array::new),
+        "Array.dispose" => (1, array::dispose),
+
+        "Output.init" => (0, output::init),
+        "Output.moveCursor" => (2, output::move_cursor),
+        "Output.printChar" => (1, output::print_char),
+        "Output.printString" => (1, output::print_string),
+        "Output.printInt" => (1, output::print_int),
+        "Output.println" => (0, output::println),
+        "Output.backSpace" => (0, output::back_space),
+
+        "Screen.init" => (0, screen::init),
+        "Screen.clearScreen" => (0, screen::clear_screen),
+        "Screen.setColor" => (1, screen::set_color),
+        "Screen.drawPixel" => (2, screen::draw_pixel),
+        "Screen.drawLine" => (4, screen::draw_line),
+        "Screen.drawRectangle" => (4, screen::draw_rectangle),
+        "Screen.drawCircle" => (3, screen::draw_circle),
+
+        "Keyboard.init" => (0, keyboard::init),
+        "Keyboard.keyPressed" => (0, keyboard::key_pressed),
+        "Keyboard.readChar" => (0, keyboard::read_char),
+        "Keyboard.readLine" => (1, keyboard::read_line),
+        "Keyboard.readInt" => (1, keyboard::read_int),
+
+        "Sys.init" => (0, sys::init),
+        "Sys.halt" => (0, sys::halt),
+        "Sys.error" => (1, sys::error),
+        "Sys.wait" => (1, sys::wait),
+
+        _ => return None,
+    };
+    Some(builtin)
+}
+
+// OSのエラーコードを出力して停止する (Sys.error と同じ)
+pub(crate) fn error(vm: &mut Vm, code: i16) -> Result<i16, String> {
+    sys::error(vm, &[code])
+}
@@ -0,0 +1,119 @@
+// Output はスクリーンに文字を描画する代わりに、テキストとして書き出す
+// カーソルの位置は Hack のスクリーンと同じ 23行 x 64列 で管理する
+use std::io::Write;
+use crate::vm::Vm;
+
+const ROWS: i16 = 23;
+const COLUMNS: i16 = 64;
+const NEW_LINE: i16 = 128;
+const BACK_SPACE: i16 = 129;
+
+pub struct Console {
+    writer: Box<dyn Write>,
+    row: i16,
+    column: i16,
+}
+
+impl Console {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer,
+            row: 0,
+            column: 0,
+        }
+    }
+
+    pub fn print_char(&mut self, c: i16) -> Result<(), String> {
+        match c {
+            NEW_LINE => self.println(),
+            BACK_SPACE => self.back_space(),
+            c => {
+                self.write(&((c as u8) as char).to_string())?;
+                self.column += 1;
+                if self.column == COLUMNS {
+                    self.println()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn print_str(&mut self, s: &str) -> Result<(), String> {
+        for c in s.chars() {
+            self.print_char(c as i16)?;
+        }
+        Ok(())
+    }
+
+    pub fn println(&mut self) -> Result<(), String> {
+        self.write("\n")?;
+        self.column = 0;
+        self.row = (self.row + 1) % ROWS;
+        Ok(())
+    }
+
+    pub fn back_space(&mut self) -> Result<(), String> {
+        if self.column > 0 {
+            self.write("\u{8} \u{8}")?;
+            self.column -= 1;
+        }
+        Ok(())
+    }
+
+    pub fn move_cursor(&mut self, row: i16, column: i16) -> Result<(), String> {
+        if row != self.row {
+            self.write("\n")?;
+        }
+        self.row = row;
+        self.column = column;
+        Ok(())
+    }
+
+    fn write(&mut self, s: &str) -> Result<(), String> {
+        self.writer.write_all(s.as_bytes())
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| format!("failed to write the output: {}", e))
+    }
+}
+
+pub fn init(vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    vm.os.console.move_cursor(0, 0)?;
+    Ok(0)
+}
+
+pub fn move_cursor(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    if args[0] < 0 || args[0] >= ROWS || args[1] < 0 || args[1] >= COLUMNS {
+        // Illegal cursor location
+        return super::error(vm, 20);
+    }
+    vm.os.console.move_cursor(args[0], args[1])?;
+    Ok(0)
+}
+
+pub fn print_char(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    vm.os.console.print_char(args[0])?;
+    Ok(0)
+}
+
+pub fn print_string(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let chars = super::string::read(vm, args[0])?;
+    for c in chars {
+        vm.os.console.print_char(c as i16)?;
+    }
+    Ok(0)
+}
+
+pub fn print_int(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    vm.os.console.print_str(&args[0].to_string())?;
+    Ok(0)
+}
+
+pub fn println(vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    vm.os.console.println()?;
+    Ok(0)
+}
+
+pub fn back_space(vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    vm.os.console.back_space()?;
+    Ok(0)
+}
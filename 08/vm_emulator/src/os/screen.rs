@@ -0,0 +1,115 @@
+// スクリーンのメモリマップ (RAM[16384..24575]) に描画する
+// 512 x 256 ピクセル、1行は16bitのワード32個
+use crate::vm::{Vm, SCREEN};
+
+const WIDTH: i16 = 512;
+const HEIGHT: i16 = 256;
+const WORDS_PER_ROW: usize = 32;
+
+fn in_screen(x: i16, y: i16) -> bool {
+    (0..WIDTH).contains(&x) && (0..HEIGHT).contains(&y)
+}
+
+fn set_pixel(vm: &mut Vm, x: i16, y: i16) {
+    let address = SCREEN + y as usize * WORDS_PER_ROW + x as usize / 16;
+    let mask = 1i16.wrapping_shl(x as u32 % 16);
+    if vm.os.screen_color {
+        vm.ram[address] |= mask;
+    } else {
+        vm.ram[address] &= !mask;
+    }
+}
+
+pub fn init(vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    vm.os.screen_color = true;
+    Ok(0)
+}
+
+pub fn clear_screen(vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    for word in vm.ram[SCREEN..SCREEN + HEIGHT as usize * WORDS_PER_ROW].iter_mut() {
+        *word = 0;
+    }
+    Ok(0)
+}
+
+pub fn set_color(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    vm.os.screen_color = args[0] != 0;
+    Ok(0)
+}
+
+pub fn draw_pixel(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let (x, y) = (args[0], args[1]);
+    if !in_screen(x, y) {
+        // Illegal pixel coordinates
+        return super::error(vm, 7);
+    }
+    set_pixel(vm, x, y);
+    Ok(0)
+}
+
+pub fn draw_line(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let (x1, y1, x2, y2) = (args[0], args[1], args[2], args[3]);
+    if !in_screen(x1, y1) || !in_screen(x2, y2) {
+        // Illegal line coordinates
+        return super::error(vm, 8);
+    }
+
+    // ブレゼンハムのアルゴリズム
+    let (dx, dy) = ((x2 - x1).abs(), -(y2 - y1).abs());
+    let (sx, sy) = ((x2 - x1).signum(), (y2 - y1).signum());
+    let (mut x, mut y) = (x1, y1);
+    let mut error = dx + dy;
+    loop {
+        set_pixel(vm, x, y);
+        if x == x2 && y == y2 {
+            break;
+        }
+        let e2 = 2 * error;
+        if e2 >= dy {
+            error += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            error += dx;
+            y += sy;
+        }
+    }
+    Ok(0)
+}
+
+pub fn draw_rectangle(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let (x1, y1, x2, y2) = (args[0], args[1], args[2], args[3]);
+    if !in_screen(x1, y1) || !in_screen(x2, y2) || x1 > x2 || y1 > y2 {
+        // Illegal rectangle coordinates
+        return super::error(vm, 9);
+    }
+    for y in y1..=y2 {
+        for x in x1..=x2 {
+            set_pixel(vm, x, y);
+        }
+    }
+    Ok(0)
+}
+
+pub fn draw_circle(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let (cx, cy, r) = (args[0], args[1], args[2]);
+    if !in_screen(cx, cy) {
+        // Illegal center coordinates
+        return super::error(vm, 12);
+    }
+    if !(0..=181).contains(&r) || !in_screen(cx - r, cy - r) || !in_screen(cx + r, cy + r) {
+        // Illegal radius
+        return super::error(vm, 13);
+    }
+
+    // 中心からの距離がr以内の水平線を塗りつぶす
+    let r = r as i32;
+    for dy in -r..=r {
+        let half = ((r * r - dy * dy) as f64).sqrt() as i16;
+        let y = cy + dy as i16;
+        for x in cx - half..=cx + half {
+            set_pixel(vm, x, y);
+        }
+    }
+    Ok(0)
+}
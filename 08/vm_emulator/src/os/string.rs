@@ -0,0 +1,146 @@
+// Stringオブジェクトのレイアウト
+// this[0]: 最大長, this[1]: 現在の長さ, this[2..]: 文字
+use crate::vm::{State, Vm, RAM_SIZE};
+
+const MAX_LENGTH: usize = 0;
+const LENGTH: usize = 1;
+const CHARS: usize = 2;
+
+pub fn new(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    if args[0] < 0 {
+        // Maximum length must be non-negative
+        return super::error(vm, 14);
+    }
+    let max_length = args[0] as usize;
+    match vm.os.heap.alloc(&mut vm.ram, CHARS + max_length) {
+        Some(this) => {
+            vm.ram[this + MAX_LENGTH] = max_length as i16;
+            vm.ram[this + LENGTH] = 0;
+            Ok(this as i16)
+        }
+        // Heap overflow
+        None => super::error(vm, 6),
+    }
+}
+
+pub fn dispose(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    super::memory::de_alloc(vm, args)
+}
+
+pub fn length(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let this = this(args[0])?;
+    Ok(vm.ram[this + LENGTH])
+}
+
+pub fn char_at(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let this = this(args[0])?;
+    if args[1] < 0 || args[1] >= vm.ram[this + LENGTH] {
+        // String index out of bounds
+        return super::error(vm, 15);
+    }
+    Ok(vm.ram[this + CHARS + args[1] as usize])
+}
+
+pub fn set_char_at(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let this = this(args[0])?;
+    if args[1] < 0 || args[1] >= vm.ram[this + LENGTH] {
+        // String index out of bounds
+        return super::error(vm, 16);
+    }
+    vm.ram[this + CHARS + args[1] as usize] = args[2];
+    Ok(0)
+}
+
+pub fn append_char(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let this = this(args[0])?;
+    let length = vm.ram[this + LENGTH];
+    if length >= vm.ram[this + MAX_LENGTH] {
+        // String is full
+        return super::error(vm, 17);
+    }
+    vm.ram[this + CHARS + length as usize] = args[1];
+    vm.ram[this + LENGTH] = length + 1;
+    Ok(this as i16)
+}
+
+pub fn erase_last_char(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let this = this(args[0])?;
+    if vm.ram[this + LENGTH] == 0 {
+        // String is empty
+        return super::error(vm, 18);
+    }
+    vm.ram[this + LENGTH] -= 1;
+    Ok(0)
+}
+
+pub fn int_value(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let chars = read(vm, args[0])?;
+    let (negative, digits) = match chars.first() {
+        Some('-') => (true, &chars[1..]),
+        _ => (false, &chars[..]),
+    };
+
+    let mut value: i16 = 0;
+    for c in digits.iter().take_while(|c| c.is_ascii_digit()) {
+        value = value.wrapping_mul(10).wrapping_add(*c as i16 - '0' as i16);
+    }
+    Ok(if negative { value.wrapping_neg() } else { value })
+}
+
+pub fn set_int(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    let this = this(args[0])?;
+    let digits = args[1].to_string();
+    if digits.len() > vm.ram[this + MAX_LENGTH] as usize {
+        // Insufficient string capacity
+        return super::error(vm, 19);
+    }
+    for (i, c) in digits.chars().enumerate() {
+        vm.ram[this + CHARS + i] = c as i16;
+    }
+    vm.ram[this + LENGTH] = digits.len() as i16;
+    Ok(0)
+}
+
+pub fn back_space(_vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    Ok(129)
+}
+
+pub fn double_quote(_vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    Ok(34)
+}
+
+pub fn new_line(_vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    Ok(128)
+}
+
+// Stringオブジェクトの文字列を読み出す
+pub fn read(vm: &Vm, this: i16) -> Result<Vec<char>, String> {
+    let this = self::this(this)?;
+    let length = vm.ram[this + LENGTH].max(0) as usize;
+    if this + CHARS + length > RAM_SIZE {
+        return Err(format!("invalid String object: {}", this));
+    }
+    Ok(vm.ram[this + CHARS..this + CHARS + length].iter()
+        .map(|c| (*c as u8) as char)
+        .collect())
+}
+
+// Stringオブジェクトを新しく作る
+pub fn create(vm: &mut Vm, chars: &[char]) -> Result<i16, String> {
+    let this = new(vm, &[chars.len() as i16])?;
+    if *vm.state() == State::Halted {
+        return Ok(0);
+    }
+    for c in chars {
+        append_char(vm, &[this, *c as i16])?;
+    }
+    Ok(this)
+}
+
+fn this(this: i16) -> Result<usize, String> {
+    let this = this as u16 as usize;
+    if this == 0 || this + CHARS > RAM_SIZE {
+        return Err(format!("invalid String object: {}", this));
+    }
+    Ok(this)
+}
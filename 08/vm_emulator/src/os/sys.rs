@@ -0,0 +1,27 @@
+use crate::vm::Vm;
+
+// .vm に Sys.init が無い場合は Vm のブートストラップが Main.main を直接呼び出すので、
+// ここに来るのは Sys.init が明示的に呼ばれた場合のみ
+pub fn init(_vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    Err("the built-in Sys.init cannot be called from VM code".into())
+}
+
+pub fn halt(vm: &mut Vm, _args: &[i16]) -> Result<i16, String> {
+    vm.halt();
+    Ok(0)
+}
+
+// `ERR<code>` を出力して停止する
+pub fn error(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    vm.os.console.print_str(&format!("ERR{}", args[0]))?;
+    vm.halt();
+    Ok(0)
+}
+
+// 実時間の待ち合わせはエミュレーションしない
+pub fn wait(vm: &mut Vm, args: &[i16]) -> Result<i16, String> {
+    if args[0] < 0 {
+        return super::error(vm, 1);
+    }
+    Ok(0)
+}
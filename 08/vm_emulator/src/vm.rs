@@ -0,0 +1,632 @@
+use std::collections::HashMap;
+use vm_translator::extended_ops::{shift_left, shift_right};
+use vm_translator::parser::{Command, MemoryAccess, MemorySegment, Operator, VmFile};
+use crate::os::{self, Os};
+
+// Hackプラットフォームのメモリマップ
+pub const RAM_SIZE: usize = 32768;
+pub const SP: usize = 0;
+pub const LCL: usize = 1;
+pub const ARG: usize = 2;
+pub const THIS: usize = 3;
+pub const THAT: usize = 4;
+pub const STATIC_BASE: usize = 16;
+pub const STATIC_END: usize = 255;
+pub const STACK_BASE: usize = 256;
+pub const STACK_END: usize = 2047;
+pub const HEAP_BASE: usize = 2048;
+pub const HEAP_END: usize = 16383;
+pub const SCREEN: usize = 16384;
+pub const KBD: usize = 24576;
+
+struct Instruction {
+    command: Command,
+    // スタティックセグメントのベースアドレス
+    static_base: usize,
+    // goto, if-goto の飛び先
+    target: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum State {
+    Running,
+    Halted,
+}
+
+// .vm のコマンドを直接解釈して実行するバーチャルマシン
+pub struct Vm {
+    pub ram: Vec<i16>,
+    pub os: Os,
+    program: Vec<Instruction>,
+    functions: HashMap<String, usize>,
+    pc: usize,
+    state: State,
+    steps: u64,
+}
+
+impl Vm {
+    pub fn load(files: Vec<VmFile>, os: Os) -> Result<Self, String> {
+        let mut program = vec![];
+        let mut functions = HashMap::new();
+        // `functionName$label` -> プログラム上の位置
+        let mut labels = HashMap::new();
+        // 各命令が属するラベルのスコープ
+        let mut scopes = vec![];
+        let mut next_static_base = STATIC_BASE;
+
+        for file in files {
+            let static_base = next_static_base;
+            next_static_base += Self::count_statics(&file.commands);
+            if next_static_base > STATIC_END + 1 {
+                return Err(format!("{}: static segment overflows RAM[{}..{}]", file.name, STATIC_BASE, STATIC_END));
+            }
+
+            let mut scope = file.name.clone();
+            for command in file.commands {
+                match &command {
+                    Command::Function(function) => {
+                        if functions.insert(function.name.clone(), program.len()).is_some() {
+                            return Err(format!("{}: function `{}` is defined more than once", file.name, function.name));
+                        }
+                        scope = function.name.clone();
+                    }
+                    Command::Label(label) => {
+                        labels.insert(format!("{}${}", scope, label), program.len());
+                    }
+                    _ => {}
+                }
+                scopes.push(scope.clone());
+                program.push(Instruction { command, static_base, target: None });
+            }
+        }
+
+        // 戻りアドレスを i16 としてスタックに積むので、プログラムの長さに上限がある
+        if program.len() >= i16::MAX as usize {
+            return Err(format!("the program is too large: {} commands", program.len()));
+        }
+
+        // ラベルを解決する
+        for (instruction, scope) in program.iter_mut().zip(scopes.iter()) {
+            if let Command::Goto(label) | Command::IfGoto(label) = &instruction.command {
+                let target = labels.get(&format!("{}${}", scope, label))
+                    .ok_or(format!("goto target `{}` is not defined in `{}`", label, scope))?;
+                instruction.target = Some(*target);
+            }
+        }
+
+        let mut vm = Self {
+            ram: vec![0; RAM_SIZE],
+            os,
+            program,
+            functions,
+            pc: 0,
+            state: State::Running,
+            steps: 0,
+        };
+        vm.bootstrap()?;
+        Ok(vm)
+    }
+
+    fn count_statics(commands: &[Command]) -> usize {
+        commands.iter().filter_map(|command| {
+            match command {
+                Command::Push(access) | Command::Pop(access) => match access.segment {
+                    MemorySegment::Static => Some(access.index as usize + 1),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }).max().unwrap_or(0)
+    }
+
+    // ブートストラップ
+    // 関数を含まないプログラムは先頭から実行する
+    fn bootstrap(&mut self) -> Result<(), String> {
+        self.ram[SP] = STACK_BASE as i16;
+
+        if self.functions.is_empty() {
+            return Ok(());
+        }
+
+        // Sys.init が無ければ、組み込みOSの Sys.init と同様に Main.main を呼び出す
+        let entry = if self.functions.contains_key("Sys.init") {
+            "Sys.init"
+        } else if self.functions.contains_key("Main.main") {
+            "Main.main"
+        } else {
+            return Err("neither Sys.init nor Main.main is defined".into());
+        };
+
+        // エントリーポイントから戻ってきたら停止する
+        self.pc = self.program.len();
+        self.call(entry, 0)
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn halt(&mut self) {
+        self.state = State::Halted;
+    }
+
+    pub fn run(&mut self, max_steps: Option<u64>) -> Result<(), String> {
+        while self.state == State::Running {
+            if let Some(max_steps) = max_steps {
+                if self.steps >= max_steps {
+                    break;
+                }
+            }
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    pub fn step(&mut self) -> Result<(), String> {
+        if self.pc >= self.program.len() {
+            self.halt();
+            return Ok(());
+        }
+        self.steps += 1;
+
+        let pc = self.pc;
+        self.pc += 1;
+
+        match &self.program[pc].command {
+            Command::Arithmetic(operator) => {
+                let operator = *operator;
+                self.arithmetic(operator)?
+            }
+            Command::Push(access) => {
+                let value = match access.segment {
+                    MemorySegment::Constant => access.index as i16,
+                    _ => self.ram[self.address(access, pc)?],
+                };
+                self.push(value)?;
+            }
+            Command::Pop(access) => {
+                if let MemorySegment::Constant = access.segment {
+                    return Err("pop constant is not allowed".into());
+                }
+                let address = self.address(access, pc)?;
+                self.ram[address] = self.pop()?;
+            }
+            Command::Label(_) => {}
+            Command::Goto(_) => {
+                self.pc = self.program[pc].target.expect("labels should be resolved");
+            }
+            Command::IfGoto(_) => {
+                if self.pop()? != 0 {
+                    self.pc = self.program[pc].target.expect("labels should be resolved");
+                }
+            }
+            Command::Function(function) => {
+                let num_local_variables = function.num_local_variables;
+                for _ in 0..num_local_variables {
+                    self.push(0)?;
+                }
+            }
+            Command::Call(call) => {
+                let function_name = call.function_name.clone();
+                let num_arguments = call.num_arguments;
+                self.call(&function_name, num_arguments)?;
+            }
+            Command::Return => self.r#return()?,
+            Command::TailCall(call) => {
+                let function_name = call.function_name.clone();
+                let num_arguments = call.num_arguments;
+                self.tail_call(&function_name, num_arguments)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn arithmetic(&mut self, operator: Operator) -> Result<(), String> {
+        let value = match operator {
+            Operator::Neg => self.pop()?.wrapping_neg(),
+            Operator::Not => !self.pop()?,
+            _ => {
+                let y = self.pop()?;
+                let x = self.pop()?;
+                match operator {
+                    Operator::Add => x.wrapping_add(y),
+                    Operator::Sub => x.wrapping_sub(y),
+                    Operator::Eq => Self::boolean(x == y),
+                    Operator::Gt => Self::boolean(x > y),
+                    Operator::Lt => Self::boolean(x < y),
+                    Operator::And => x & y,
+                    Operator::Or => x | y,
+                    Operator::Mul => x.wrapping_mul(y),
+                    // 0除算は Math.divide と同じエラーにする
+                    Operator::Div if y == 0 => return os::error(self, 3).map(|_| ()),
+                    Operator::Div => x.wrapping_div(y),
+                    Operator::Mod if y == 0 => return os::error(self, 3).map(|_| ()),
+                    Operator::Mod => x.wrapping_rem(y),
+                    Operator::Shl => shift_left(x, y),
+                    Operator::Shr => shift_right(x, y),
+                    Operator::Lte => Self::boolean(x <= y),
+                    Operator::Gte => Self::boolean(x >= y),
+                    Operator::Neq => Self::boolean(x != y),
+                    Operator::Neg | Operator::Not => unreachable!(),
+                }
+            }
+        };
+        self.push(value)
+    }
+
+    fn boolean(b: bool) -> i16 {
+        if b { -1 } else { 0 }
+    }
+
+    fn address(&self, access: &MemoryAccess, pc: usize) -> Result<usize, String> {
+        let index = access.index as usize;
+        let address = match access.segment {
+            MemorySegment::Constant => unreachable!(),
+            MemorySegment::Local => self.ram[LCL] as u16 as usize + index,
+            MemorySegment::Argument => self.ram[ARG] as u16 as usize + index,
+            MemorySegment::This => self.ram[THIS] as u16 as usize + index,
+            MemorySegment::That => self.ram[THAT] as u16 as usize + index,
+            MemorySegment::Pointer | MemorySegment::Temp => access.get_static_address() as usize,
+            MemorySegment::Static => self.program[pc].static_base + index,
+        };
+
+        if address >= RAM_SIZE {
+            return Err(format!("{:?} {} refers to an invalid address: {}", access.segment, access.index, address));
+        }
+        Ok(address)
+    }
+
+    pub fn push(&mut self, value: i16) -> Result<(), String> {
+        let sp = self.ram[SP] as u16 as usize;
+        if sp > STACK_END {
+            return Err(format!("stack overflow: SP={}", sp));
+        }
+        self.ram[sp] = value;
+        self.ram[SP] += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<i16, String> {
+        let sp = self.ram[SP] as u16 as usize;
+        if sp <= STACK_BASE {
+            return Err(format!("stack underflow: SP={}", sp));
+        }
+        self.ram[SP] -= 1;
+        Ok(self.ram[sp - 1])
+    }
+
+    fn call(&mut self, function_name: &str, num_arguments: u16) -> Result<(), String> {
+        let address = match self.functions.get(function_name) {
+            Some(address) => *address,
+            None => return self.call_builtin(function_name, num_arguments),
+        };
+
+        // 戻りアドレス, LCL, ARG, THIS, THAT を退避する
+        self.push(self.pc as i16)?;
+        self.push(self.ram[LCL])?;
+        self.push(self.ram[ARG])?;
+        self.push(self.ram[THIS])?;
+        self.push(self.ram[THAT])?;
+        // ARG = SP-n-5
+        self.ram[ARG] = self.ram[SP] - num_arguments as i16 - 5;
+        // LCL = SP
+        self.ram[LCL] = self.ram[SP];
+
+        self.pc = address;
+        Ok(())
+    }
+
+    // .vm で定義されていない関数は、組み込みのOSの実装で実行する
+    fn call_builtin(&mut self, function_name: &str, num_arguments: u16) -> Result<(), String> {
+        let (arity, builtin) = os::builtin(function_name)
+            .ok_or(format!("function `{}` is not defined", function_name))?;
+        if arity != num_arguments {
+            return Err(format!("`{}` expects {} arguments, but {} were passed", function_name, arity, num_arguments));
+        }
+
+        let mut args = vec![0; num_arguments as usize];
+        for arg in args.iter_mut().rev() {
+            *arg = self.pop()?;
+        }
+        // void の関数も 0 を返す
+        let value = builtin(self, &args)?;
+        self.push(value)
+    }
+
+    // 呼び出し元のフレームを再利用して呼び出す (`call f n` の直後に `return` するのと同じ結果になる)
+    fn tail_call(&mut self, function_name: &str, num_arguments: u16) -> Result<(), String> {
+        let address = match self.functions.get(function_name) {
+            Some(address) => *address,
+            None => {
+                // 組み込みのOSの関数は実行してからそのまま戻る
+                self.call_builtin(function_name, num_arguments)?;
+                return self.r#return();
+            }
+        };
+
+        let frame = self.ram[LCL] as u16 as usize;
+        if frame < STACK_BASE + 5 {
+            return Err("tail-call outside of a function".into());
+        }
+        let sp = self.ram[SP] as u16 as usize;
+        let num_arguments = num_arguments as usize;
+        if sp < frame + num_arguments {
+            return Err(format!("stack underflow: SP={}", sp));
+        }
+        // 戻りアドレス, LCL, ARG, THIS, THAT は呼び出し元が保存したものを引き継ぐ
+        let saved: Vec<i16> = self.ram[frame - 5..frame].to_vec();
+
+        // 引数を呼び出し元の引数の位置に移す
+        let arg = self.ram[ARG] as u16 as usize;
+        self.ram.copy_within(sp - num_arguments..sp, arg);
+        self.ram[SP] = (arg + num_arguments) as i16;
+        for value in saved {
+            self.push(value)?;
+        }
+        self.ram[ARG] = arg as i16;
+        self.ram[LCL] = self.ram[SP];
+
+        self.pc = address;
+        Ok(())
+    }
+
+    fn r#return(&mut self) -> Result<(), String> {
+        // FRAME = LCL
+        let frame = self.ram[LCL] as u16 as usize;
+        if frame < STACK_BASE + 5 {
+            return Err("return outside of a function".into());
+        }
+        // RET = *(FRAME-5)
+        let return_address = self.ram[frame - 5] as u16 as usize;
+        // *ARG = pop()
+        let value = self.pop()?;
+        let arg = self.ram[ARG] as u16 as usize;
+        self.ram[arg] = value;
+        // SP = ARG+1
+        self.ram[SP] = arg as i16 + 1;
+        self.ram[THAT] = self.ram[frame - 1];
+        self.ram[THIS] = self.ram[frame - 2];
+        self.ram[ARG] = self.ram[frame - 3];
+        self.ram[LCL] = self.ram[frame - 4];
+
+        self.pc = return_address;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::{empty, sink, Write};
+    use std::rc::Rc;
+
+    // Output の出力を集める
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn files(sources: &[(&str, &str)]) -> Vec<VmFile> {
+        sources.iter()
+            .map(|(name, source)| VmFile::read(String::from(*name), source.as_bytes()))
+            .collect()
+    }
+
+    fn load(sources: &[(&str, &str)]) -> Vm {
+        Vm::load(files(sources), Os::new(Box::new(empty()), Box::new(sink()))).unwrap()
+    }
+
+    // 停止するまで実行して、Output に書き出した文字列を返す
+    fn run_with_output(sources: &[(&str, &str)]) -> (Vm, String) {
+        let output = Output::default();
+        let mut vm = Vm::load(files(sources), Os::new(Box::new(empty()), Box::new(output.clone()))).unwrap();
+        vm.run(Some(100_000)).unwrap();
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        (vm, text)
+    }
+
+    // 関数を含まないプログラムを実行して、スタックに残った値を返す
+    fn stack(source: &str) -> Vec<i16> {
+        let mut vm = load(&[("Test", source)]);
+        vm.run(None).unwrap();
+        vm.ram[STACK_BASE..vm.ram[SP] as usize].to_vec()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(stack("push constant 7\npush constant 9\nsub\nneg\npush constant 3\nadd"), vec![5]);
+        assert_eq!(stack("push constant 12\npush constant 10\nand\npush constant 1\nor\nnot"), vec![-10]);
+        assert_eq!(
+            stack("push constant 3\npush constant 4\nlt\npush constant 3\npush constant 3\ngt\npush constant 5\npush constant 5\neq"),
+            vec![-1, 0, -1],
+        );
+        // 拡張命令
+        assert_eq!(stack("push constant 7\npush constant 6\nmul\npush constant 5\ndiv"), vec![8]);
+        assert_eq!(stack("push constant 7\nneg\npush constant 3\nmod"), vec![-1]);
+        assert_eq!(stack("push constant 1\npush constant 4\nshl\npush constant 2\nshr"), vec![4]);
+        assert_eq!(
+            stack("push constant 3\npush constant 3\nlte\npush constant 2\npush constant 3\ngte\npush constant 2\npush constant 3\nneq"),
+            vec![-1, 0, -1],
+        );
+    }
+
+    #[test]
+    fn statics_are_separate_for_each_file() {
+        let mut vm = load(&[
+            ("A", "function A.set 0\npush argument 0\npop static 0\npush constant 0\nreturn"),
+            ("B", "function B.set 0\npush argument 0\npop static 0\npush constant 0\nreturn"),
+            ("Main", "function Main.main 0\npush constant 1\ncall A.set 1\npush constant 2\ncall B.set 1\nreturn"),
+        ]);
+        vm.run(None).unwrap();
+        assert_eq!(&vm.ram[STATIC_BASE..STATIC_BASE + 2], &[1, 2]);
+    }
+
+    #[test]
+    fn call_builtin_os() {
+        let (vm, output) = run_with_output(&[("Main", "
+            function Main.main 0
+            push constant 2
+            call String.new 1
+            push constant 72
+            call String.appendChar 2
+            push constant 105
+            call String.appendChar 2
+            call Output.printString 1
+            pop temp 0
+            push constant 6
+            push constant 7
+            call Math.multiply 2
+            call Output.printInt 1
+            pop temp 0
+            push constant 0
+            return
+        ")]);
+        assert_eq!(output, "Hi42");
+        assert_eq!(*vm.state(), State::Halted);
+    }
+
+    #[test]
+    fn divide_by_zero_halts_with_error_3() {
+        let (vm, output) = run_with_output(&[("Main", "
+            function Main.main 0
+            push constant 1
+            push constant 0
+            div
+            return
+        ")]);
+        assert_eq!(output, "ERR3");
+        assert_eq!(*vm.state(), State::Halted);
+    }
+
+    #[test]
+    fn start_from_sys_init_or_main_main() {
+        // Sys.init があればそちらから実行する
+        let mut vm = load(&[
+            ("Main", "function Main.main 0\npush constant 1\npop temp 0\npush constant 0\nreturn"),
+            ("Sys", "function Sys.init 0\npush constant 2\npop temp 0\npush constant 0\nreturn"),
+        ]);
+        vm.run(None).unwrap();
+        assert_eq!(vm.ram[5], 2);
+
+        let mut vm = load(&[("Main", "function Main.main 0\npush constant 1\npop temp 0\npush constant 0\nreturn")]);
+        vm.run(None).unwrap();
+        assert_eq!(vm.ram[5], 1);
+    }
+
+    #[test]
+    fn load_errors() {
+        let error = |sources: &[(&str, &str)]| {
+            Vm::load(files(sources), Os::new(Box::new(empty()), Box::new(sink()))).err().unwrap()
+        };
+        assert_eq!(
+            error(&[("Main", "function Main.main 0\nreturn\nfunction Main.main 0\nreturn")]),
+            "Main: function `Main.main` is defined more than once",
+        );
+        assert_eq!(
+            error(&[("Main", "function Main.main 0\ngoto END\nreturn")]),
+            "goto target `END` is not defined in `Main.main`",
+        );
+        assert_eq!(error(&[("Foo", "function Foo.bar 0\nreturn")]), "neither Sys.init nor Main.main is defined");
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let mut vm = load(&[("Main", "function Main.main 0\ncall Main.missing 0\nreturn")]);
+        assert_eq!(vm.run(None), Err("function `Main.missing` is not defined".into()));
+
+        let mut vm = load(&[("Main", "function Main.main 0\npush constant 1\ncall Math.abs 2\nreturn")]);
+        assert_eq!(vm.run(None), Err("`Math.abs` expects 1 arguments, but 2 were passed".into()));
+
+        // ブロックのサイズを書いたワードを負の値で上書きしてから解放する
+        let mut vm = load(&[("Main", "
+            function Main.main 1
+            push constant 3
+            call Memory.alloc 1
+            pop local 0
+            push local 0
+            push constant 1
+            sub
+            push constant 1
+            neg
+            call Memory.poke 2
+            pop temp 0
+            push local 0
+            call Memory.deAlloc 1
+            return
+        ")]);
+        assert_eq!(vm.run(None), Err("Memory.deAlloc: invalid block size -1 at 2048".into()));
+    }
+
+    #[test]
+    fn tail_call_reuses_the_frame() {
+        // Main.sum(n, acc) = n == 0 ? acc : Main.sum(n - 1, acc + n)
+        let mut vm = load(&[("Main", "
+            function Main.main 0
+            push constant 100
+            push constant 0
+            call Main.sum 2
+            pop temp 0
+            push constant 0
+            return
+            function Main.sum 0
+            push argument 0
+            push constant 0
+            eq
+            if-goto END
+            push argument 0
+            push constant 1
+            sub
+            push argument 1
+            push argument 0
+            add
+            tail-call Main.sum 2
+            label END
+            push argument 1
+            return
+        ")]);
+        let mut max_sp = 0;
+        while *vm.state() == State::Running {
+            vm.step().unwrap();
+            max_sp = max_sp.max(vm.ram[SP]);
+        }
+        assert_eq!(vm.ram[5], 5050);
+        // 呼び出しのたびにフレームを積まない
+        assert!(max_sp < 300, "SP reached {}", max_sp);
+    }
+
+    #[test]
+    fn tail_call_to_builtin() {
+        let mut vm = load(&[("Main", "
+            function Main.main 0
+            push constant 6
+            push constant 7
+            call Main.multiply 2
+            pop temp 0
+            push constant 0
+            return
+            function Main.multiply 0
+            push argument 0
+            push argument 1
+            tail-call Math.multiply 2
+        ")]);
+        vm.run(None).unwrap();
+        assert_eq!(vm.ram[5], 42);
+        // Main.main の戻り値だけが残る
+        assert_eq!(vm.ram[SP], 257);
+    }
+}
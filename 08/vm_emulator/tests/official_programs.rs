@@ -0,0 +1,142 @@
+// 本の7章, 8章のテストプログラムを直接実行し、.cmp と同じRAMの値になることを確かめる
+// (テストプログラムは vm_translator のものを使う)
+use std::io::{empty, sink};
+use std::path::Path;
+use vm_emulator::os::Os;
+use vm_emulator::vm::{State, Vm};
+use vm_translator::parser::VmFile;
+
+const MAX_STEPS: u64 = 100_000;
+
+struct TestProgram {
+    name: &'static str,
+    // 関数を含まないプログラムは、テストスクリプトと同じようにRAMを初期化する
+    ram: &'static [(usize, i16)],
+    expected: &'static [(usize, i16)],
+}
+
+fn load(name: &str) -> Vm {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../vm_translator/tests/fixtures").join(name);
+    let mut paths: Vec<_> = std::fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    paths.sort();
+    let files = paths.iter().map(|path| VmFile::open(path).unwrap()).collect();
+    Vm::load(files, Os::new(Box::new(empty()), Box::new(sink()))).unwrap()
+}
+
+fn run(program: TestProgram) {
+    let mut vm = load(program.name);
+    for (address, value) in program.ram.iter() {
+        vm.ram[*address] = *value;
+    }
+    vm.run(Some(MAX_STEPS)).unwrap();
+
+    for (address, value) in program.expected.iter() {
+        assert_eq!(vm.ram[*address], *value, "{}: RAM[{}]", program.name, address);
+    }
+}
+
+#[test]
+fn simple_add() {
+    run(TestProgram {
+        name: "SimpleAdd",
+        ram: &[],
+        expected: &[(0, 257), (256, 15)],
+    });
+}
+
+#[test]
+fn stack_test() {
+    run(TestProgram {
+        name: "StackTest",
+        ram: &[],
+        expected: &[
+            (0, 266),
+            (256, -1), (257, 0), (258, 0), (259, 0), (260, -1),
+            (261, 0), (262, -1), (263, 0), (264, 0), (265, -91),
+        ],
+    });
+}
+
+#[test]
+fn basic_test() {
+    run(TestProgram {
+        name: "BasicTest",
+        ram: &[(1, 300), (2, 400), (3, 3000), (4, 3010)],
+        expected: &[
+            (256, 472), (300, 10), (401, 21), (402, 22),
+            (3006, 36), (3012, 42), (3015, 45), (11, 510),
+        ],
+    });
+}
+
+#[test]
+fn pointer_test() {
+    run(TestProgram {
+        name: "PointerTest",
+        ram: &[],
+        expected: &[(256, 6084), (3, 3030), (4, 3040), (3032, 32), (3046, 46)],
+    });
+}
+
+#[test]
+fn static_test() {
+    run(TestProgram {
+        name: "StaticTest",
+        ram: &[],
+        expected: &[(256, 1110)],
+    });
+}
+
+#[test]
+fn basic_loop() {
+    run(TestProgram {
+        name: "BasicLoop",
+        ram: &[(1, 300), (2, 400), (400, 3)],
+        expected: &[(0, 257), (256, 6)],
+    });
+}
+
+#[test]
+fn fibonacci_series() {
+    run(TestProgram {
+        name: "FibonacciSeries",
+        ram: &[(1, 300), (2, 400), (400, 6), (401, 3000)],
+        expected: &[(3000, 0), (3001, 1), (3002, 1), (3003, 2), (3004, 3), (3005, 5)],
+    });
+}
+
+#[test]
+fn nested_call() {
+    run(TestProgram {
+        name: "NestedCall",
+        ram: &[],
+        expected: &[(0, 261), (1, 261), (2, 256), (3, 4000), (4, 5000), (5, 135), (6, 246)],
+    });
+}
+
+#[test]
+fn fibonacci_element() {
+    run(TestProgram {
+        name: "FibonacciElement",
+        ram: &[],
+        expected: &[(0, 262), (261, 3)],
+    });
+}
+
+#[test]
+fn statics_test() {
+    run(TestProgram {
+        name: "StaticsTest",
+        ram: &[],
+        expected: &[(0, 263), (261, -2), (262, 8)],
+    });
+}
+
+// Sys.init の無限ループでは止まらないので、上限まで実行し続ける
+#[test]
+fn run_stops_at_max_steps() {
+    let mut vm = load("NestedCall");
+    vm.run(Some(1000)).unwrap();
+    assert_eq!(vm.steps(), 1000);
+    assert_eq!(*vm.state(), State::Running);
+}
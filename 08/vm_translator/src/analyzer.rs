@@ -0,0 +1,567 @@
+// 関数の呼び出しグラフとスタックの深さの解析
+// 実機で動かす前に、未定義の関数の呼び出しや引数の数の食い違い(リンクエラー)を見つける
+use std::collections::{HashMap, HashSet};
+use crate::parser::{Command, MemorySegment, Operator, VmFile};
+
+// RAM[256..2047]
+pub const STACK_SIZE: usize = 2048 - 256;
+
+// call で保存するフレーム (return address, LCL, ARG, THIS, THAT)
+const SAVED_FRAME_SIZE: usize = 5;
+
+// Jack OS の関数と、VMでの引数の数 (メソッドは this を含む)
+// .vm ファイルで定義されていなくても、OSが提供するものとして扱う
+const OS_FUNCTIONS: [(&str, u16); 49] = [
+    ("Math.init", 0), ("Math.abs", 1), ("Math.multiply", 2), ("Math.divide", 2),
+    ("Math.min", 2), ("Math.max", 2), ("Math.sqrt", 1),
+    ("String.new", 1), ("String.dispose", 1), ("String.length", 1), ("String.charAt", 2),
+    ("String.setCharAt", 3), ("String.appendChar", 2), ("String.eraseLastChar", 1),
+    ("String.intValue", 1), ("String.setInt", 2), ("String.backSpace", 0),
+    ("String.doubleQuote", 0), ("String.newLine", 0),
+    ("Array.new", 1), ("Array.dispose", 1),
+    ("Output.init", 0), ("Output.moveCursor", 2), ("Output.printChar", 1),
+    ("Output.printString", 1), ("Output.printInt", 1), ("Output.println", 0), ("Output.backSpace", 0),
+    ("Screen.init", 0), ("Screen.clearScreen", 0), ("Screen.setColor", 1), ("Screen.drawPixel", 2),
+    ("Screen.drawLine", 4), ("Screen.drawRectangle", 4), ("Screen.drawCircle", 3),
+    ("Keyboard.init", 0), ("Keyboard.keyPressed", 0), ("Keyboard.readChar", 0),
+    ("Keyboard.readLine", 1), ("Keyboard.readInt", 1),
+    ("Memory.init", 0), ("Memory.peek", 1), ("Memory.poke", 2), ("Memory.alloc", 1), ("Memory.deAlloc", 1),
+    ("Sys.init", 0), ("Sys.halt", 0), ("Sys.error", 1), ("Sys.wait", 1),
+];
+
+fn os_function(name: &str) -> Option<u16> {
+    OS_FUNCTIONS.iter().find(|(os_name, _)| *os_name == name).map(|(_, num_arguments)| *num_arguments)
+}
+
+#[derive(Debug)]
+struct CallSite {
+    callee: String,
+    num_arguments: u16,
+    // `Main.vm:12`
+    location: String,
+    // call の直前の作業用スタックの深さ (引数を含む)
+    depth: usize,
+}
+
+#[derive(Debug)]
+struct FunctionInfo {
+    name: String,
+    location: String,
+    num_local_variables: u16,
+    // 関数の中で参照している argument の数 (最大のインデックス + 1)
+    num_arguments_used: u16,
+    // 作業用スタックの最大の深さ
+    max_depth: usize,
+    calls: Vec<CallSite>,
+}
+
+// 関数内で必要なスタックのワード数
+// 再帰していて上限が決まらない場合はNone
+type Depth = Option<(usize, Vec<String>)>;
+
+pub struct Analysis {
+    functions: Vec<FunctionInfo>,
+    indexes: HashMap<String, usize>,
+    entry: String,
+    // 同じ名前の関数の二重定義 (最初の定義を使う)
+    duplicates: Vec<String>,
+}
+
+impl Analysis {
+    pub fn new(files: &[VmFile], entry: &str) -> Self {
+        let mut functions = vec![];
+        for file in files.iter() {
+            functions.extend(Self::scan(file));
+        }
+        let mut indexes: HashMap<String, usize> = HashMap::new();
+        let mut duplicates = vec![];
+        for (i, function) in functions.iter().enumerate() {
+            match indexes.get(&function.name) {
+                Some(first) => duplicates.push(format!(
+                    "{}: `{}` is already defined at {}",
+                    function.location, function.name, functions[*first].location,
+                )),
+                None => {
+                    indexes.insert(function.name.clone(), i);
+                }
+            }
+        }
+
+        Self {
+            functions,
+            indexes,
+            entry: String::from(entry),
+            duplicates,
+        }
+    }
+
+    // 関数ごとに呼び出しと作業用スタックの深さを集める
+    fn scan(file: &VmFile) -> Vec<FunctionInfo> {
+        let mut functions: Vec<FunctionInfo> = vec![];
+        // ジャンプ元での作業用スタックの深さ
+        let mut label_depths: HashMap<String, usize> = HashMap::new();
+        let mut depth: usize = 0;
+
+        for (command, line) in file.commands.iter().zip(file.lines.iter()) {
+            let location = format!("{}.vm:{}", file.name, line);
+            if let Command::Function(function) = command {
+                functions.push(FunctionInfo {
+                    name: function.name.clone(),
+                    location,
+                    num_local_variables: function.num_local_variables,
+                    num_arguments_used: 0,
+                    max_depth: 0,
+                    calls: vec![],
+                });
+                label_depths.clear();
+                depth = 0;
+                continue;
+            }
+            // 関数の外側のコマンドは解析の対象外
+            let function = match functions.last_mut() {
+                Some(function) => function,
+                None => continue,
+            };
+
+            match command {
+                Command::Push(memory_access) => {
+                    if memory_access.segment == MemorySegment::Argument {
+                        function.num_arguments_used = function.num_arguments_used.max(memory_access.index + 1);
+                    }
+                    depth += 1;
+                }
+                Command::Pop(memory_access) => {
+                    if memory_access.segment == MemorySegment::Argument {
+                        function.num_arguments_used = function.num_arguments_used.max(memory_access.index + 1);
+                    }
+                    depth = depth.saturating_sub(1);
+                }
+                Command::Arithmetic(Operator::Neg) | Command::Arithmetic(Operator::Not) => {}
+                Command::Arithmetic(_) => depth = depth.saturating_sub(1),
+                Command::Label(label) => {
+                    if let Some(d) = label_depths.get(label) {
+                        depth = depth.max(*d);
+                    }
+                }
+                Command::Goto(label) => {
+                    label_depths.insert(label.clone(), depth);
+                }
+                Command::IfGoto(label) => {
+                    depth = depth.saturating_sub(1);
+                    label_depths.insert(label.clone(), depth);
+                }
+                Command::Call(call) | Command::TailCall(call) => {
+                    function.calls.push(CallSite {
+                        callee: call.function_name.clone(),
+                        num_arguments: call.num_arguments,
+                        location,
+                        depth,
+                    });
+                    // 引数が戻り値に置き換わる
+                    depth = depth.saturating_sub(call.num_arguments as usize) + 1;
+                }
+                Command::Return => {}
+                Command::Function(_) => unreachable!(),
+            }
+            function.max_depth = function.max_depth.max(depth);
+        }
+
+        functions
+    }
+
+    fn function(&self, name: &str) -> Option<&FunctionInfo> {
+        self.indexes.get(name).map(|i| &self.functions[*i])
+    }
+
+    // 定義されていない関数の呼び出し
+    // OSの関数は引数の数だけを確かめる
+    pub fn undefined_calls(&self) -> Vec<String> {
+        let mut errors = vec![];
+        for function in self.functions.iter() {
+            for call in function.calls.iter() {
+                if self.function(&call.callee).is_some() {
+                    continue;
+                }
+                match os_function(&call.callee) {
+                    Some(num_arguments) if num_arguments != call.num_arguments => errors.push(format!(
+                        "{}: OS function `{}` takes {} arguments, but is called with {}",
+                        call.location, call.callee, num_arguments, call.num_arguments,
+                    )),
+                    Some(_) => {}
+                    None => errors.push(format!(
+                        "{}: call to undefined function `{}` in `{}`",
+                        call.location, call.callee, function.name,
+                    )),
+                }
+            }
+        }
+        errors
+    }
+
+    // 同じ名前で二重に定義された関数
+    pub fn duplicate_functions(&self) -> &[String] {
+        &self.duplicates
+    }
+
+    // 呼び出し箇所ごとに引数の数が異なる、または関数が参照する引数より少ない
+    pub fn argument_mismatches(&self) -> Vec<String> {
+        let mut errors = vec![];
+        let mut call_sites: Vec<(&str, Vec<&CallSite>)> = vec![];
+        for call in self.functions.iter().flat_map(|f| f.calls.iter()) {
+            match call_sites.iter_mut().find(|(name, _)| *name == call.callee) {
+                Some((_, calls)) => calls.push(call),
+                None => call_sites.push((&call.callee, vec![call])),
+            }
+        }
+
+        for (name, calls) in call_sites.iter() {
+            let first = calls[0];
+            if let Some(call) = calls.iter().find(|call| call.num_arguments != first.num_arguments) {
+                errors.push(format!(
+                    "`{}` is called with {} arguments at {}, but with {} arguments at {}",
+                    name, first.num_arguments, first.location, call.num_arguments, call.location,
+                ));
+            }
+
+            if let Some(function) = self.function(name) {
+                for call in calls.iter().filter(|call| call.num_arguments < function.num_arguments_used) {
+                    errors.push(format!(
+                        "{}: `{}` is called with {} arguments, but it uses argument {} ({})",
+                        call.location, name, call.num_arguments, function.num_arguments_used - 1, function.location,
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
+    // エントリーポイントから到達できる関数
+    fn reachable_functions(&self) -> HashSet<&str> {
+        let mut reachable = HashSet::new();
+        let mut stack = vec![self.entry.as_str()];
+        while let Some(name) = stack.pop() {
+            if !reachable.insert(name) {
+                continue;
+            }
+            if let Some(function) = self.function(name) {
+                stack.extend(function.calls.iter().map(|call| call.callee.as_str()));
+            }
+        }
+        reachable
+    }
+
+    // エントリーポイントから呼び出されない関数
+    // エントリーポイントが定義されていない場合は判断できないので空を返す
+    pub fn unreachable_functions(&self) -> Vec<&str> {
+        if self.function(&self.entry).is_none() {
+            return vec![];
+        }
+        let reachable = self.reachable_functions();
+        self.functions.iter()
+            .map(|f| f.name.as_str())
+            .filter(|name| !reachable.contains(name))
+            .collect()
+    }
+
+    // 再帰している関数の組 (強連結成分)
+    pub fn recursions(&self) -> Vec<Vec<&str>> {
+        let mut tarjan = Tarjan {
+            analysis: self,
+            index: 0,
+            indexes: HashMap::new(),
+            low_links: HashMap::new(),
+            stack: vec![],
+            on_stack: HashSet::new(),
+            components: vec![],
+        };
+        for function in self.functions.iter() {
+            if !tarjan.indexes.contains_key(function.name.as_str()) {
+                tarjan.visit(&function.name);
+            }
+        }
+
+        tarjan.components.into_iter()
+            .filter(|component| {
+                component.len() > 1 || self.function(component[0]).unwrap()
+                    .calls.iter().any(|call| call.callee == component[0])
+            })
+            .map(|mut component| {
+                component.sort_unstable();
+                component
+            })
+            .collect()
+    }
+
+    // エントリーポイントから呼び出したときの最悪のスタックの深さ(ワード数)と、その呼び出しの連鎖
+    // 到達できる範囲に再帰がある場合はNone、エントリーポイントが定義されていない場合は Err
+    pub fn max_stack_depth(&self) -> Result<Depth, String> {
+        if self.function(&self.entry).is_none() {
+            return Err(format!("entry function `{}` is not defined", self.entry));
+        }
+        let recursive: HashSet<&str> = self.recursions().into_iter().flatten().collect();
+        let mut memo = HashMap::new();
+        Ok(self.frame_depth(&self.entry, &recursive, &mut memo))
+    }
+
+    // 関数のローカル変数 + 作業用スタック + 呼び出した関数のフレーム
+    fn frame_depth<'a>(&'a self, name: &'a str, recursive: &HashSet<&str>, memo: &mut HashMap<&'a str, Depth>) -> Depth {
+        if recursive.contains(name) {
+            return None;
+        }
+        if let Some(depth) = memo.get(name) {
+            return depth.clone();
+        }
+        // 未定義の関数 (OSなど) はスタックを使わないものとみなす
+        let function = match self.function(name) {
+            Some(function) => function,
+            None => return Some((0, vec![String::from(name)])),
+        };
+
+        let mut deepest = (function.max_depth, vec![]);
+        for call in function.calls.iter() {
+            let (callee_depth, chain) = self.frame_depth(&call.callee, recursive, memo)?;
+            let depth = call.depth + SAVED_FRAME_SIZE + callee_depth;
+            if depth > deepest.0 {
+                deepest = (depth, chain);
+            }
+        }
+        let mut chain = vec![function.name.clone()];
+        chain.extend(deepest.1);
+        let depth = Some((function.num_local_variables as usize + deepest.0, chain));
+
+        memo.insert(name, depth.clone());
+        depth
+    }
+
+    // Graphviz の DOT 形式の呼び出しグラフ
+    // 未定義の関数 (OSなど) は破線、到達しない関数は灰色で表す
+    pub fn to_dot(&self) -> String {
+        let reachable = self.reachable_functions();
+        let entry_defined = self.function(&self.entry).is_some();
+
+        let mut dot = String::from("digraph calls {\n");
+        dot.push_str("  node [shape=box];\n");
+        for function in self.functions.iter() {
+            let mut attributes = vec![];
+            if function.name == self.entry {
+                attributes.push("style=bold");
+            } else if entry_defined && !reachable.contains(function.name.as_str()) {
+                attributes.push("color=gray");
+                attributes.push("fontcolor=gray");
+            }
+            dot.push_str(&format!("  \"{}\"{};\n", function.name, dot_attributes(&attributes)));
+        }
+
+        let mut undefined: Vec<&str> = vec![];
+        let mut edges: Vec<(&str, &str, u16)> = vec![];
+        for function in self.functions.iter() {
+            for call in function.calls.iter() {
+                if self.function(&call.callee).is_none() && !undefined.contains(&call.callee.as_str()) {
+                    undefined.push(&call.callee);
+                }
+                let edge = (function.name.as_str(), call.callee.as_str(), call.num_arguments);
+                if !edges.contains(&edge) {
+                    edges.push(edge);
+                }
+            }
+        }
+        for name in undefined.iter() {
+            dot.push_str(&format!("  \"{}\"{};\n", name, dot_attributes(&["style=dashed"])));
+        }
+        for (caller, callee, num_arguments) in edges.iter() {
+            dot.push_str(&format!("  \"{}\" -> \"{}\" [label=\"{}\"];\n", caller, callee, num_arguments));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // 解析結果をまとめる
+    // 実行前に直すべき問題(リンクエラー、スタックの溢れ)は Err で返す
+    pub fn report(&self) -> (Vec<String>, Result<(), Vec<String>>) {
+        let mut notes = vec![];
+        let mut errors = self.duplicates.clone();
+        errors.extend(self.undefined_calls());
+        errors.extend(self.argument_mismatches());
+
+        for name in self.unreachable_functions() {
+            notes.push(format!("`{}` is unreachable from `{}`", name, self.entry));
+        }
+        for component in self.recursions() {
+            notes.push(format!("recursion: {}", component.join(", ")));
+        }
+
+        match self.max_stack_depth() {
+            Ok(Some((depth, chain))) => {
+                notes.push(format!(
+                    "worst-case stack depth from `{}`: {} words ({})",
+                    self.entry, depth, chain.join(" -> "),
+                ));
+                if depth > STACK_SIZE {
+                    errors.push(format!(
+                        "stack overflow: `{}` needs {} words, but the stack has only {}",
+                        self.entry, depth, STACK_SIZE,
+                    ));
+                }
+            }
+            Ok(None) => notes.push(format!(
+                "worst-case stack depth from `{}` is unbounded because of recursion",
+                self.entry,
+            )),
+            Err(note) => notes.push(note),
+        }
+
+        if errors.is_empty() {
+            (notes, Ok(()))
+        } else {
+            (notes, Err(errors))
+        }
+    }
+}
+
+fn dot_attributes(attributes: &[&str]) -> String {
+    if attributes.is_empty() {
+        String::new()
+    } else {
+        format!(" [{}]", attributes.join(", "))
+    }
+}
+
+// 強連結成分の分解 (Tarjanのアルゴリズム)
+struct Tarjan<'a> {
+    analysis: &'a Analysis,
+    index: usize,
+    indexes: HashMap<&'a str, usize>,
+    low_links: HashMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+    on_stack: HashSet<&'a str>,
+    components: Vec<Vec<&'a str>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn visit(&mut self, name: &'a str) {
+        self.indexes.insert(name, self.index);
+        self.low_links.insert(name, self.index);
+        self.index += 1;
+        self.stack.push(name);
+        self.on_stack.insert(name);
+
+        if let Some(function) = self.analysis.function(name) {
+            for call in function.calls.iter() {
+                let callee = call.callee.as_str();
+                // 未定義の関数は呼び出しを持たないので、再帰にはならない
+                if self.analysis.function(callee).is_none() {
+                    continue;
+                }
+                if !self.indexes.contains_key(callee) {
+                    self.visit(callee);
+                    let low_link = self.low_links[name].min(self.low_links[callee]);
+                    self.low_links.insert(name, low_link);
+                } else if self.on_stack.contains(callee) {
+                    let low_link = self.low_links[name].min(self.indexes[callee]);
+                    self.low_links.insert(name, low_link);
+                }
+            }
+        }
+
+        if self.low_links[name] == self.indexes[name] {
+            let mut component = vec![];
+            while let Some(member) = self.stack.pop() {
+                self.on_stack.remove(member);
+                component.push(member);
+                if member == name {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm_file(name: &str, commands: &[&str]) -> VmFile {
+        VmFile {
+            name: name.into(),
+            commands: commands.iter().map(|command| Command::parse(command)).collect(),
+            lines: (1..=commands.len()).collect(),
+        }
+    }
+
+    fn analyze(commands: &[&str]) -> Analysis {
+        Analysis::new(&[vm_file("Main", commands)], "Main.main")
+    }
+
+    #[test]
+    fn os_functions_are_not_undefined() {
+        let analysis = analyze(&[
+            "function Main.main 0",
+            "push constant 3",
+            "call String.new 1",
+            "push constant 72",
+            "call String.appendChar 2",
+            "call Output.printString 1",
+            "pop temp 0",
+            "call Main.missing 0",
+            "return",
+        ]);
+        assert_eq!(analysis.undefined_calls(), vec!["Main.vm:8: call to undefined function `Main.missing` in `Main.main`"]);
+    }
+
+    #[test]
+    fn os_functions_with_wrong_arguments() {
+        let analysis = analyze(&["function Main.main 0", "push constant 1", "push constant 2", "call Math.abs 2", "return"]);
+        assert_eq!(
+            analysis.undefined_calls(),
+            vec!["Main.vm:4: OS function `Math.abs` takes 1 arguments, but is called with 2"],
+        );
+    }
+
+    #[test]
+    fn missing_entry_has_no_stack_depth() {
+        let analysis = Analysis::new(&[vm_file("Main", &["function Main.main 0", "return"])], "Sys.init");
+        assert_eq!(analysis.max_stack_depth(), Err("entry function `Sys.init` is not defined".into()));
+
+        let (notes, result) = analysis.report();
+        assert!(result.is_ok());
+        assert!(notes.contains(&"entry function `Sys.init` is not defined".into()));
+        assert!(!notes.iter().any(|note| note.starts_with("worst-case stack depth")));
+    }
+
+    #[test]
+    fn stack_depth_from_entry() {
+        let analysis = analyze(&[
+            "function Main.main 1",
+            "push constant 1",
+            "push constant 2",
+            "call Main.f 1",
+            "return",
+            "function Main.f 2",
+            "push argument 0",
+            "return",
+        ]);
+        // 1 (local) + 2 (引数の直前の作業用スタック) + 5 (フレーム) + 2 (local) + 1
+        assert_eq!(analysis.max_stack_depth(), Ok(Some((11, vec!["Main.main".into(), "Main.f".into()]))));
+    }
+
+    #[test]
+    fn recursion_has_unbounded_stack_depth() {
+        let analysis = analyze(&["function Main.main 0", "call Main.main 0", "return"]);
+        assert_eq!(analysis.recursions(), vec![vec!["Main.main"]]);
+        assert_eq!(analysis.max_stack_depth(), Ok(None));
+    }
+
+    #[test]
+    fn duplicate_functions() {
+        let files = [
+            vm_file("Main", &["function Main.main 0", "push constant 0", "return"]),
+            vm_file("Other", &["function Main.main 0", "push constant 1", "return"]),
+        ];
+        let analysis = Analysis::new(&files, "Main.main");
+        assert_eq!(analysis.duplicate_functions(), ["Other.vm:1: `Main.main` is already defined at Main.vm:1"]);
+
+        let (_, result) = analysis.report();
+        assert_eq!(result, Err(vec!["Other.vm:1: `Main.main` is already defined at Main.vm:1".into()]));
+    }
+}
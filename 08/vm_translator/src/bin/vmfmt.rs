@@ -0,0 +1,116 @@
+use std::io::Read;
+use std::path::Path;
+use vm_translator::formatter::Formatter;
+use vm_translator::parser::vm_files;
+
+// 入力のパスに指定すると標準入力から読み込んで標準出力に書き出す
+const STDIO: &str = "-";
+
+#[derive(Debug)]
+struct Options {
+    path: String,
+    strip_comments: bool,
+    // 整形結果を元のファイルに書き戻す
+    write: bool,
+    // 整形されていないファイルがあれば失敗する (書き換えない)
+    check: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut path = None;
+        let mut strip_comments = false;
+        let mut write = false;
+        let mut check = false;
+
+        for arg in args.iter() {
+            match arg.as_str() {
+                "--strip-comments" => strip_comments = true,
+                "--write" => write = true,
+                "--check" => check = true,
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown option: {}", other));
+                }
+                other => {
+                    if path.is_some() {
+                        return Err(format!("unexpected argument: {}", other));
+                    }
+                    path = Some(String::from(other));
+                }
+            }
+        }
+
+        if write && check {
+            return Err("--write and --check cannot be used together".into());
+        }
+
+        Ok(Self {
+            path: path.ok_or("A path to .vm file or directory contains .vm file is required")?,
+            strip_comments,
+            write,
+            check,
+        })
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let options = Options::parse(&args[1..]).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        eprintln!("usage: vmfmt [--strip-comments] [--write | --check] <path | ->");
+        std::process::exit(2);
+    });
+    let formatter = Formatter::new(options.strip_comments);
+
+    if options.path == STDIO {
+        let mut source = String::new();
+        std::io::stdin().read_to_string(&mut source).expect("failed to read stdin");
+        let formatted = format(&formatter, &source, "<stdin>");
+        if options.check {
+            if formatted != source {
+                eprintln!("<stdin> is not formatted");
+                std::process::exit(1);
+            }
+        } else {
+            print!("{}", formatted);
+        }
+        return;
+    }
+
+    let vm_files = vm_files(Path::new(&options.path)).expect("failed to open files");
+    if vm_files.is_empty() {
+        panic!("{}: should have vm files", &options.path)
+    }
+
+    let mut unformatted = vec![];
+    for path in vm_files.iter() {
+        let source = std::fs::read_to_string(path).expect("failed to read the file");
+        let formatted = format(&formatter, &source, &path.display().to_string());
+        if options.check {
+            if formatted != source {
+                unformatted.push(path.display().to_string());
+            }
+        } else if options.write {
+            if formatted != source {
+                std::fs::write(path, formatted).expect("failed to write the file");
+            }
+        } else {
+            print!("{}", formatted);
+        }
+    }
+
+    if !unformatted.is_empty() {
+        for path in unformatted.iter() {
+            eprintln!("{} is not formatted", path);
+        }
+        std::process::exit(1);
+    }
+}
+
+fn format(formatter: &Formatter, source: &str, name: &str) -> String {
+    formatter.format(source).unwrap_or_else(|e| {
+        eprintln!("error: {}: {}", name, e);
+        std::process::exit(1);
+    })
+}
@@ -0,0 +1,452 @@
+// スタックの先頭をDレジスタに保持したままにするコード生成
+// 直線的なコマンド列の間は結果をメモリに書き戻さず、ラベル・関数呼び出し・リターンの前でだけ
+// スタックに書き戻す (書き戻した後のスタックの状態は CodeWriter の出力と同じになる)
+// さらに次の工夫で命令数を減らす
+// - push した値は、使われるまでアドレスの計算だけを遅らせる (`push constant 1` + `add` -> `@1 D=D+A`)
+// - 比較の結果は、直後の `not` や `if-goto` と合わせて1つのジャンプにする
+// - call と return は共通のルーチン ($$call, $$return) に飛ぶだけにする
+use crate::code_writer::{CodeGenerator, CodeWriter};
+use crate::extended_ops::Expansion;
+use crate::parser::{Call, Command, MemoryAccess, MemorySegment, Operator};
+
+// push したまま、まだ読み込んでいない値
+struct Operand {
+    // 値 (register) をAかMで参照できるようにするコード (Dレジスタは使わない)
+    address: Vec<String>,
+    // "A" なら定数、"M" ならメモリ上の値
+    register: &'static str,
+}
+
+impl Operand {
+    fn new(memory_access: &MemoryAccess, code_writer: &CodeWriter) -> Self {
+        let (address, register) = match memory_access.segment {
+            MemorySegment::Constant => (vec![format!("@{}", memory_access.index)], "A"),
+            _ => (CachingCodeWriter::address(memory_access, code_writer), "M"),
+        };
+        Self {
+            address,
+            register,
+        }
+    }
+
+    fn is_constant(&self, value: u16) -> bool {
+        self.register == "A" && self.address == [format!("@{}", value)]
+    }
+
+    // Dレジスタに読み込む
+    fn load(self) -> Vec<String> {
+        let mut a = self.address;
+        a.push(format!("D={}", self.register));
+        a
+    }
+}
+
+pub struct CachingCodeWriter {
+    // ラベルのスコープ化や call/return などは CodeWriter に任せる
+    inner: CodeWriter,
+    // スタックの先頭の値がDレジスタにあり、まだメモリに書き戻していない
+    // (operand があれば、その1つ下の値)
+    d_live: bool,
+    // スタックの先頭に push した値
+    operand: Option<Operand>,
+    // Dレジスタに x - y があり、値は jump が成り立てば true になる比較の結果
+    comparison: Option<&'static str>,
+}
+
+impl CachingCodeWriter {
+    pub fn new(prefix: String) -> Self {
+        Self {
+            inner: CodeWriter::new(prefix),
+            d_live: false,
+            operand: None,
+            comparison: None,
+        }
+    }
+
+    pub fn set_extended_ops(&mut self, expansion: Expansion) {
+        self.inner.set_extended_ops(expansion);
+    }
+
+    // CodeWriter::bootstrap_code と同じく SP を初期化してエントリーポイントを呼び出す
+    pub fn bootstrap_code(entry: &str) -> Vec<String> {
+        let mut a = vec![
+            "@256".into(),
+            "D=A".into(),
+            "@SP".into(),
+            "M=D".into(),
+        ];
+        let mut cw = Self::new("bootstrap".into());
+        a.extend(cw.code(Command::Call(Call::new(entry.into(), 0))));
+        a
+    }
+
+    // call の共通ルーチン
+    // CodeWriter::end_code の後に1度だけ出力する
+    pub fn call_routine() -> Vec<String> {
+        let mut a = vec![
+            // D = 戻りアドレス, R13 = 引数の数, R14 = 呼び出す関数
+            "($$call)".into(),
+        ];
+        a.extend(Self::push_d());
+        for symbol in ["LCL", "ARG", "THIS", "THAT"].iter() {
+            a.extend(vec![format!("@{}", symbol), "D=M".into()]);
+            a.extend(Self::push_d());
+        }
+        a.extend(vec![
+            // ARG = SP-n-5
+            "@SP".into(),
+            "D=M".into(),
+            "@5".into(),
+            "D=D-A".into(),
+            "@R13".into(),
+            "D=D-M".into(),
+            "@ARG".into(),
+            "M=D".into(),
+            // LCL = SP
+            "@SP".into(),
+            "D=M".into(),
+            "@LCL".into(),
+            "M=D".into(),
+            "@R14".into(),
+            "A=M".into(),
+            "0;JMP".into(),
+        ]);
+        a
+    }
+
+    // return の共通ルーチン
+    pub fn return_routine() -> Vec<String> {
+        let mut a = vec![
+            // D = 戻り値
+            "($$return)".into(),
+            "@R13".into(),
+            "M=D".into(),
+            // RET = *(FRAME-5) (引数が無いと *ARG と同じ場所なので先に読む)
+            "@LCL".into(),
+            "D=M".into(),
+            "@5".into(),
+            "A=D-A".into(),
+            "D=M".into(),
+            "@R14".into(),
+            "M=D".into(),
+            // *ARG = 戻り値
+            "@R13".into(),
+            "D=M".into(),
+            "@ARG".into(),
+            "A=M".into(),
+            "M=D".into(),
+            // SP = ARG+1
+            "@ARG".into(),
+            "D=M+1".into(),
+            "@SP".into(),
+            "M=D".into(),
+        ];
+        // THAT, THIS, ARG, LCL = *(FRAME-1), ..., *(FRAME-4)
+        // LCL を FRAME として1つずつ下げながら読む (LCL は最後に書き換える)
+        for symbol in ["THAT", "THIS", "ARG", "LCL"].iter() {
+            a.extend(vec![
+                "@LCL".into(),
+                "AM=M-1".into(),
+                "D=M".into(),
+                format!("@{}", symbol),
+                "M=D".into(),
+            ]);
+        }
+        a.extend(vec![
+            "@R14".into(),
+            "A=M".into(),
+            "0;JMP".into(),
+        ]);
+        a
+    }
+
+    // local, argument などのアドレスをAレジスタに入れる
+    fn address(memory_access: &MemoryAccess, code_writer: &CodeWriter) -> Vec<String> {
+        match memory_access.segment {
+            MemorySegment::Constant => unreachable!(),
+            MemorySegment::Local => CodeWriter::set_memory_address_to_a("LCL", memory_access.index),
+            MemorySegment::Argument => CodeWriter::set_memory_address_to_a("ARG", memory_access.index),
+            MemorySegment::This => CodeWriter::set_memory_address_to_a("THIS", memory_access.index),
+            MemorySegment::That => CodeWriter::set_memory_address_to_a("THAT", memory_access.index),
+            MemorySegment::Pointer | MemorySegment::Temp => vec![format!("@{}", memory_access.get_static_address())],
+            MemorySegment::Static => vec![code_writer.static_symbol(memory_access.index)],
+        }
+    }
+
+    // Dレジスタの値をスタックに積む
+    fn push_d() -> Vec<String> {
+        vec![
+            "@SP".into(),
+            "M=M+1".into(),
+            "A=M-1".into(),
+            "M=D".into(),
+        ]
+    }
+
+    // 比較の結果を true(-1) か false(0) にする
+    fn settle_comparison(&mut self) -> Vec<String> {
+        match self.comparison.take() {
+            Some(jump) => {
+                let label_true = self.inner.next_label();
+                let label_end = self.inner.next_label();
+                vec![
+                    format!("@{}", label_true),
+                    format!("D;{}", jump),
+                    "D=0".into(),
+                    format!("@{}", label_end),
+                    "0;JMP".into(),
+                    format!("({})", label_true),
+                    "D=-1".into(),
+                    format!("({})", label_end),
+                ]
+            }
+            None => vec![],
+        }
+    }
+
+    // Dレジスタの値をスタックに書き戻す (operand はそのまま)
+    fn flush_d(&mut self) -> Vec<String> {
+        let mut a = self.settle_comparison();
+        if self.d_live {
+            self.d_live = false;
+            a.extend(Self::push_d());
+        }
+        a
+    }
+
+    // スタックの先頭をDレジスタに置く (Dレジスタに無い値はメモリ上にある)
+    fn settle_operand(&mut self) -> Vec<String> {
+        match self.operand.take() {
+            Some(operand) => {
+                let mut a = self.flush_d();
+                a.extend(operand.load());
+                self.d_live = true;
+                a
+            }
+            None => self.settle_comparison(),
+        }
+    }
+
+    // すべての値をスタックに書き戻す
+    fn flush(&mut self) -> Vec<String> {
+        let mut a = self.settle_operand();
+        a.extend(self.flush_d());
+        a
+    }
+
+    // スタックの先頭をDレジスタに取り出す
+    fn pop_to_d(&mut self) -> Vec<String> {
+        let mut a = self.settle_operand();
+        if self.d_live {
+            self.d_live = false;
+        } else {
+            a.extend(vec![
+                "@SP".into(),
+                "AM=M-1".into(),
+                "D=M".into(),
+            ]);
+        }
+        a
+    }
+
+    fn push(&mut self, memory_access: MemoryAccess) -> Vec<String> {
+        let a = self.settle_operand();
+        self.operand = Some(Operand::new(&memory_access, &self.inner));
+        a
+    }
+
+    fn pop(&mut self, memory_access: MemoryAccess) -> Vec<String> {
+        // 0 と 1 は、Dレジスタを使わずに書き込める
+        if let Some(operand) = &self.operand {
+            for value in 0..=1 {
+                if operand.is_constant(value) {
+                    self.operand = None;
+                    let mut a = Self::address(&memory_access, &self.inner);
+                    a.push(format!("M={}", value));
+                    return a;
+                }
+            }
+        }
+
+        let mut a = self.pop_to_d();
+        // アドレスの計算はAレジスタだけで行うので、Dレジスタの値は壊れない
+        a.extend(Self::address(&memory_access, &self.inner));
+        a.push("M=D".into());
+        a
+    }
+
+    fn arithmetic(&mut self, operator: Operator) -> Vec<String> {
+        // ループに展開する命令は x, y がスタック上にある前提なので CodeWriter に任せる
+        if let Operator::Mul | Operator::Div | Operator::Mod | Operator::Shl | Operator::Shr = operator {
+            let mut a = self.flush();
+            a.extend(self.inner.code(Command::Arithmetic(operator)));
+            return a;
+        }
+
+        let jump = match operator {
+            Operator::Eq => Some("JEQ"),
+            Operator::Gt => Some("JGT"),
+            Operator::Lt => Some("JLT"),
+            Operator::Lte => Some("JLE"),
+            Operator::Gte => Some("JGE"),
+            Operator::Neq => Some("JNE"),
+            _ => None,
+        };
+
+        match operator {
+            Operator::Not if self.operand.is_none() && self.comparison.is_some() => {
+                // 比較の結果の否定は、逆の比較
+                self.comparison = self.comparison.map(Self::invert);
+                vec![]
+            }
+            Operator::Neg | Operator::Not => {
+                let mut a = self.pop_to_d();
+                a.push(if operator == Operator::Neg { "D=-D" } else { "D=!D" }.into());
+                self.d_live = true;
+                a
+            }
+            _ => {
+                let mut a = vec![];
+                match self.operand.take() {
+                    // y を直接参照する
+                    Some(operand) => {
+                        // x をDレジスタに取り出す
+                        a.extend(self.settle_comparison());
+                        if !self.d_live {
+                            a.extend(vec![
+                                "@SP".into(),
+                                "AM=M-1".into(),
+                                "D=M".into(),
+                            ]);
+                        }
+                        let y = operand.register;
+                        a.extend(operand.address);
+                        a.push(match operator {
+                            Operator::Add => format!("D=D+{}", y),
+                            Operator::And => format!("D=D&{}", y),
+                            Operator::Or => format!("D=D|{}", y),
+                            _ => format!("D=D-{}", y),
+                        });
+                    }
+                    // y をDレジスタに、x をMに取り出す
+                    None => {
+                        a.extend(self.pop_to_d());
+                        a.extend(vec![
+                            "@SP".into(),
+                            "AM=M-1".into(),
+                        ]);
+                        a.push(match operator {
+                            Operator::Add => "D=D+M",
+                            Operator::And => "D=D&M",
+                            Operator::Or => "D=D|M",
+                            _ => "D=M-D",
+                        }.into());
+                    }
+                }
+                self.d_live = true;
+                self.comparison = jump;
+                a
+            }
+        }
+    }
+
+    fn invert(jump: &'static str) -> &'static str {
+        match jump {
+            "JEQ" => "JNE",
+            "JNE" => "JEQ",
+            "JGT" => "JLE",
+            "JLE" => "JGT",
+            "JLT" => "JGE",
+            "JGE" => "JLT",
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl CodeGenerator for CachingCodeWriter {
+    fn code(&mut self, command: Command) -> Vec<String> {
+        match command {
+            Command::Push(memory_access) => self.push(memory_access),
+            Command::Pop(memory_access) => self.pop(memory_access),
+            Command::Arithmetic(operator) => self.arithmetic(operator),
+            Command::IfGoto(label) => {
+                // 条件の値はDレジスタで判定するので、書き戻す必要はない
+                let jump = match self.operand {
+                    None => self.comparison.take(),
+                    Some(_) => None,
+                };
+                let mut a = match jump {
+                    Some(_) => {
+                        self.d_live = false;
+                        vec![]
+                    }
+                    None => self.pop_to_d(),
+                };
+                a.extend(vec![
+                    format!("@{}", self.inner.jump_target(label)),
+                    format!("D;{}", jump.unwrap_or("JNE")),
+                ]);
+                a
+            }
+            Command::Function(function) => {
+                let mut a = self.flush();
+                self.inner.close_label_scope(Some(function.name.clone()));
+                a.push(format!("({})", function.name));
+                // ローカル変数を0で初期化する
+                if function.num_local_variables > 0 {
+                    a.extend(vec!["@SP".into(), "A=M".into()]);
+                    for _ in 1..function.num_local_variables {
+                        a.extend(vec!["M=0".into(), "A=A+1".into()]);
+                    }
+                    a.extend(vec![
+                        "M=0".into(),
+                        "D=A+1".into(),
+                        "@SP".into(),
+                        "M=D".into(),
+                    ]);
+                }
+                a
+            }
+            Command::Call(call) => {
+                let mut a = self.flush();
+                let return_label = self.inner.next_label();
+                a.extend(vec![
+                    format!("@{}", call.num_arguments),
+                    "D=A".into(),
+                    "@R13".into(),
+                    "M=D".into(),
+                    format!("@{}", call.function_name),
+                    "D=A".into(),
+                    "@R14".into(),
+                    "M=D".into(),
+                    format!("@{}", return_label),
+                    "D=A".into(),
+                    "@$$call".into(),
+                    "0;JMP".into(),
+                    format!("({})", return_label),
+                ]);
+                a
+            }
+            Command::Return => {
+                // 戻り値はDレジスタで渡す
+                let mut a = self.pop_to_d();
+                a.extend(vec![
+                    "@$$return".into(),
+                    "0;JMP".into(),
+                ]);
+                a
+            }
+            Command::Label(_) | Command::Goto(_) | Command::TailCall(_) => {
+                let mut a = self.flush();
+                a.extend(self.inner.code(command));
+                a
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<Vec<String>, Vec<String>> {
+        let a = self.flush();
+        self.inner.finish()?;
+        Ok(a)
+    }
+}
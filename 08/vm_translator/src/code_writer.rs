@@ -1,6 +1,8 @@
 use crate::parser::{Command, Call};
 use crate::parser::MemorySegment;
 use crate::parser::Operator;
+use crate::extended_ops::{self, Expansion};
+use std::collections::HashSet;
 
 struct LabelGenerator {
     n: u16,
@@ -22,10 +24,88 @@ impl LabelGenerator {
     }
 }
 
+// ラベルのスコープ
+// label, goto, if-goto のラベルは、それが記述された関数内でのみ有効
+struct LabelScope {
+    // 関数名(関数の外側の場合はNone)
+    function_name: Option<String>,
+    // 定義済みのラベル
+    labels: HashSet<String>,
+    // goto, if-goto の飛び先として参照されたラベル
+    targets: Vec<String>,
+}
+
+impl LabelScope {
+    fn new(function_name: Option<String>) -> Self {
+        Self {
+            function_name,
+            labels: HashSet::new(),
+            targets: vec![],
+        }
+    }
+
+    // 存在しないラベルへのgotoを検出する
+    fn undefined_targets(&self) -> Vec<String> {
+        let mut undefined = vec![];
+        for target in self.targets.iter() {
+            if !self.labels.contains(target) && !undefined.contains(target) {
+                undefined.push(target.clone());
+            }
+        }
+        undefined
+    }
+}
+
+// VMコマンドからアセンブリを生成する
+pub trait CodeGenerator {
+    fn code(&mut self, command: Command) -> Vec<String>;
+    // ファイルの終わりで呼び出し、残りのアセンブリかエラーを返す
+    fn finish(&mut self) -> Result<Vec<String>, Vec<String>>;
+}
+
+impl CodeGenerator for CodeWriter {
+    fn code(&mut self, command: Command) -> Vec<String> {
+        CodeWriter::code(self, command)
+    }
+
+    fn finish(&mut self) -> Result<Vec<String>, Vec<String>> {
+        CodeWriter::finish(self)
+    }
+}
+
+// 実行時の安全性検査 (デバッグ用)
+// 検査に失敗すると `$$trap` に飛び、エラーコードを RAM[TRAP_CODE_ADDRESS] に書き込んで停止する
+#[derive(Debug, Clone)]
+pub struct SafetyChecks {
+    // push した後のSPがこの値を超えたらスタックオーバーフロー
+    pub stack_limit: u16,
+}
+
+impl Default for SafetyChecks {
+    fn default() -> Self {
+        // スタックは RAM[256..2047]
+        Self { stack_limit: 2048 }
+    }
+}
+
+pub const TRAP_CODE_ADDRESS: u16 = 15;
+pub const TRAP_STACK_OVERFLOW: u16 = 1;
+pub const TRAP_BAD_POINTER: u16 = 2;
+pub const TRAP_BAD_RETURN: u16 = 3;
+pub const TRAP_DIVIDE_BY_ZERO: u16 = 4;
+
+// this, that で参照してよいアドレスの範囲 (ヒープ, スクリーン, キーボード)
+const POINTER_MIN: u16 = 2048;
+const POINTER_MAX: u16 = 24576;
+
 // 表7-2 CodeWriterモジュール
 pub struct CodeWriter {
     label_generator: LabelGenerator,
     variable_symbol_prefix: String,
+    label_scope: LabelScope,
+    errors: Vec<String>,
+    safety_checks: Option<SafetyChecks>,
+    extended_ops: Expansion,
 }
 
 impl CodeWriter {
@@ -33,10 +113,173 @@ impl CodeWriter {
         Self {
             label_generator: LabelGenerator::new(prefix.clone()),
             variable_symbol_prefix: prefix,
+            label_scope: LabelScope::new(None),
+            errors: vec![],
+            safety_checks: None,
+            extended_ops: Expansion::Subroutine,
+        }
+    }
+
+    pub fn enable_safety_checks(&mut self, safety_checks: SafetyChecks) {
+        self.safety_checks = Some(safety_checks);
+    }
+
+    // mul, div, mod, shl, shr の展開の方法
+    pub fn set_extended_ops(&mut self, expansion: Expansion) {
+        self.extended_ops = expansion;
+    }
+
+    // プログラムの終端
+    // 後ろに置くサブルーチンやトラップに落ちないようにここで止める
+    // (戻りアドレスの検査でプログラムの終端として使う)
+    pub fn end_code() -> Vec<String> {
+        vec![
+            "($$end)".into(),
+            "@$$end".into(),
+            "0;JMP".into(),
+        ]
+    }
+
+    // 安全性検査の失敗時に飛ぶルーチン
+    // end_code の後に1度だけ出力する
+    pub fn trap_code() -> Vec<String> {
+        let mut a = vec![];
+        for (label, code) in [
+            ("$$trap.stack_overflow", TRAP_STACK_OVERFLOW),
+            ("$$trap.bad_pointer", TRAP_BAD_POINTER),
+            ("$$trap.bad_return", TRAP_BAD_RETURN),
+            (extended_ops::DIVIDE_BY_ZERO_TRAP, TRAP_DIVIDE_BY_ZERO),
+        ].iter() {
+            a.extend(vec![
+                format!("({})", label),
+                format!("@{}", code),
+                "D=A".into(),
+                "@$$trap".into(),
+                "0;JMP".into(),
+            ]);
+        }
+        a.extend(vec![
+            // エラーコード(Dの値)を書き込んで停止する
+            "($$trap)".into(),
+            format!("@{}", TRAP_CODE_ADDRESS),
+            "M=D".into(),
+            "($$halt)".into(),
+            "@$$halt".into(),
+            "0;JMP".into(),
+        ]);
+        a
+    }
+
+    // SPがスタックの上限を超えていないか
+    // Dレジスタの値は変えない
+    fn stack_guard(&self) -> Vec<String> {
+        match &self.safety_checks {
+            Some(safety_checks) => vec![
+                "@SP".into(),
+                "D=M".into(),
+                format!("@{}", safety_checks.stack_limit),
+                "D=D-A".into(),
+                "@$$trap.stack_overflow".into(),
+                "D;JGT".into(),
+                // push した値をDに戻す (続けて同じ値をpushするコードがあるため)
+                "@SP".into(),
+                "A=M-1".into(),
+                "D=M".into(),
+            ],
+            None => vec![],
+        }
+    }
+
+    // base_address + index がヒープかスクリーンの範囲に収まっているか
+    fn pointer_guard(&self, base_address: &str, index: u16) -> Vec<String> {
+        if self.safety_checks.is_none() {
+            return vec![];
+        }
+        vec![
+            format!("@{}", base_address),
+            "D=M".into(),
+            format!("@{}", POINTER_MIN),
+            "D=D-A".into(),
+            "@$$trap.bad_pointer".into(),
+            "D;JLT".into(),
+            format!("@{}", base_address),
+            "D=M".into(),
+            format!("@{}", POINTER_MAX.saturating_sub(index)),
+            "D=D-A".into(),
+            "@$$trap.bad_pointer".into(),
+            "D;JGT".into(),
+        ]
+    }
+
+    // 戻りアドレス(R14)がプログラムの範囲内か
+    fn return_guard(&self) -> Vec<String> {
+        if self.safety_checks.is_none() {
+            return vec![];
+        }
+        vec![
+            "@R14".into(),
+            "D=M".into(),
+            "@$$trap.bad_return".into(),
+            "D;JLE".into(),
+            "@$$end".into(),
+            "D=D-A".into(),
+            "@$$trap.bad_return".into(),
+            "D;JGE".into(),
+        ]
+    }
+
+    // 最後の関数のラベルを検査して、それまでに見つかったエラーを返す
+    pub fn finish(&mut self) -> Result<Vec<String>, Vec<String>> {
+        self.close_label_scope(None);
+
+        if self.errors.is_empty() {
+            Ok(vec![])
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    // `functionName$label` の形式でラベルをスコープ化する
+    // 関数の外側のラベルはファイル名(プレフィックス)でスコープ化する
+    fn scoped_label(&self, label: &str) -> String {
+        match &self.label_scope.function_name {
+            Some(function_name) => format!("{}${}", function_name, label),
+            None => format!("{}${}", self.variable_symbol_prefix, label),
         }
     }
 
-    pub fn bootstrap_code() -> Vec<String> {
+    // スタティック変数のシンボル `@Xxx.i`
+    pub(crate) fn static_symbol(&self, index: u16) -> String {
+        format!("@{}.{}", self.variable_symbol_prefix, index)
+    }
+
+    // goto, if-goto の飛び先として記録して、スコープ化したラベルを返す
+    pub(crate) fn jump_target(&mut self, label: String) -> String {
+        let scoped_label = self.scoped_label(&label);
+        self.label_scope.targets.push(label);
+        scoped_label
+    }
+
+    pub(crate) fn next_label(&mut self) -> String {
+        self.label_generator.gen()
+    }
+
+    pub(crate) fn close_label_scope(&mut self, next_function_name: Option<String>) {
+        let scope = std::mem::replace(&mut self.label_scope, LabelScope::new(next_function_name));
+        for target in scope.undefined_targets() {
+            self.errors.push(format!(
+                "{}: goto target `{}` is not defined in {}",
+                self.variable_symbol_prefix,
+                target,
+                match &scope.function_name {
+                    Some(function_name) => format!("function `{}`", function_name),
+                    None => "the top level".into(),
+                },
+            ));
+        }
+    }
+
+    pub fn bootstrap_code(entry: &str) -> Vec<String> {
         let mut a = vec![
             "@256".into(),
             "D=A".into(),
@@ -44,7 +287,7 @@ impl CodeWriter {
             "M=D".into(),
         ];
         let mut cw = Self::new("bootstrap".into());
-        a.extend(cw.code(Command::Call(Call::new("Sys.init".into(), 0))));
+        a.extend(cw.code(Command::Call(Call::new(entry.into(), 0))));
         a
     }
 
@@ -62,14 +305,22 @@ impl CodeWriter {
                     }
                     MemorySegment::Local => self.push_address_value("LCL", memory_access.index),
                     MemorySegment::Argument => self.push_address_value("ARG", memory_access.index),
-                    MemorySegment::This => self.push_address_value("THIS", memory_access.index),
-                    MemorySegment::That => self.push_address_value("THAT", memory_access.index),
+                    MemorySegment::This => {
+                        let mut a = self.pointer_guard("THIS", memory_access.index);
+                        a.append(&mut self.push_address_value("THIS", memory_access.index));
+                        a
+                    }
+                    MemorySegment::That => {
+                        let mut a = self.pointer_guard("THAT", memory_access.index);
+                        a.append(&mut self.push_address_value("THAT", memory_access.index));
+                        a
+                    }
                     MemorySegment::Pointer | MemorySegment::Temp => {
                         self.push_static_address_value(memory_access.get_static_address())
                     }
                     MemorySegment::Static => {
                         let mut a = vec![
-                            format!("@{}.{}", self.variable_symbol_prefix, memory_access.index),
+                            self.static_symbol(memory_access.index),
                             "D=M".into(),
                         ];
                         a.append(&mut self.push_d_value());
@@ -82,8 +333,16 @@ impl CodeWriter {
                     MemorySegment::Constant => unreachable!(),
                     MemorySegment::Local => self.pop_to_address_value("LCL", memory_access.index),
                     MemorySegment::Argument => self.pop_to_address_value("ARG", memory_access.index),
-                    MemorySegment::This => self.pop_to_address_value("THIS", memory_access.index),
-                    MemorySegment::That => self.pop_to_address_value("THAT", memory_access.index),
+                    MemorySegment::This => {
+                        let mut a = self.pointer_guard("THIS", memory_access.index);
+                        a.append(&mut self.pop_to_address_value("THIS", memory_access.index));
+                        a
+                    }
+                    MemorySegment::That => {
+                        let mut a = self.pointer_guard("THAT", memory_access.index);
+                        a.append(&mut self.pop_to_address_value("THAT", memory_access.index));
+                        a
+                    }
                     MemorySegment::Pointer | MemorySegment::Temp => {
                         self.pop_to_static_address_value(memory_access.get_static_address())
                     }
@@ -92,7 +351,7 @@ impl CodeWriter {
                             "@SP".into(),
                             "AM=M-1".into(),
                             "D=M".into(),
-                            format!("@{}.{}", self.variable_symbol_prefix, memory_access.index),
+                            self.static_symbol(memory_access.index),
                             "M=D".into(),
                         ]
                     }
@@ -125,6 +384,12 @@ impl CodeWriter {
                     Operator::Eq => self.comparison_operation("JEQ"),
                     Operator::Gt => self.comparison_operation("JGT"),
                     Operator::Lt => self.comparison_operation("JLT"),
+                    Operator::Lte => self.comparison_operation("JLE"),
+                    Operator::Gte => self.comparison_operation("JGE"),
+                    Operator::Neq => self.comparison_operation("JNE"),
+                    Operator::Mul | Operator::Div | Operator::Mod | Operator::Shl | Operator::Shr => {
+                        self.extended_operation(operator)
+                    }
                     Operator::And => {
                         let mut a = Self::pop_for_binary_operator();
                         a.append(&mut vec!["D=D&M".into()]);
@@ -152,26 +417,32 @@ impl CodeWriter {
                 }
             }
             Command::Label(label) => {
+                let scoped_label = self.scoped_label(&label);
+                self.label_scope.labels.insert(label);
                 vec![
-                    format!("({})", label),
+                    format!("({})", scoped_label),
                 ]
             }
             Command::IfGoto(label) => {
+                let scoped_label = self.jump_target(label);
                 vec![
                     "@SP".into(),
                     "AM=M-1".into(),
                     "D=M".into(),
-                    format!("@{}", label),
+                    format!("@{}", scoped_label),
                     "D;JNE".into(),
                 ]
             }
             Command::Goto(label) => {
+                let scoped_label = self.jump_target(label);
                 vec![
-                    format!("@{}", label),
+                    format!("@{}", scoped_label),
                     "0;JMP".into(),
                 ]
             }
             Command::Function(function) => {
+                // 前の関数のラベルを検査してから、新しいスコープに切り替える
+                self.close_label_scope(Some(function.name.clone()));
                 let mut a = vec![
                     format!("({})", function.name),
                     "D=0".into(),
@@ -191,13 +462,13 @@ impl CodeWriter {
                 ]);
                 a.extend(self.push_d_value());
                 // push LCL
-                a.append(&mut self.push_address_value("LCL", 0));
+                a.append(&mut self.push_symbol_value("LCL"));
                 // push ARG
-                a.append(&mut self.push_address_value("ARG", 0));
+                a.append(&mut self.push_symbol_value("ARG"));
                 // push THIS
-                a.append(&mut self.push_address_value("THIS", 0));
+                a.append(&mut self.push_symbol_value("THIS"));
                 // push THAT
-                a.append(&mut self.push_address_value("THAT", 0));
+                a.append(&mut self.push_symbol_value("THAT"));
                 // ARG = SP-n-5
                 a.append(&mut vec![
                     "@SP".into(),
@@ -244,6 +515,7 @@ impl CodeWriter {
                     "@R14".into(),
                     "M=D".into(),
                 ];
+                a.append(&mut self.return_guard());
                 // *ARG = pop()
                 a.append(&mut self.pop_to_address_value("ARG", 0));
                 // SP = ARG+1
@@ -299,9 +571,82 @@ impl CodeWriter {
                 ]);
                 a
             }
+            Command::TailCall(call) => {
+                let mut a = vec![];
+                // 呼び出し元のフレーム (return-address, LCL, ARG, THIS, THAT) を引数の上に積み直す
+                for offset in (1..=5).rev() {
+                    a.append(&mut vec![
+                        "@LCL".into(),
+                        "D=M".into(),
+                        format!("@{}", offset),
+                        "A=D-A".into(),
+                        "D=M".into(),
+                    ]);
+                    a.append(&mut self.push_d_value());
+                }
+                // 引数とフレームを ARG の位置に移す
+                // 移動先の方がアドレスが小さいので、重なっていても先頭から順にコピーすればよい
+                a.append(&mut vec![
+                    "@SP".into(),
+                    "D=M".into(),
+                    format!("@{}", call.num_arguments + 5),
+                    "D=D-A".into(),
+                    "@R13".into(),
+                    "M=D".into(),
+                    "@ARG".into(),
+                    "D=M".into(),
+                    "@R14".into(),
+                    "M=D".into(),
+                ]);
+                for _ in 0..call.num_arguments + 5 {
+                    a.append(&mut vec![
+                        "@R13".into(),
+                        "A=M".into(),
+                        "D=M".into(),
+                        "@R13".into(),
+                        "M=M+1".into(),
+                        "@R14".into(),
+                        "A=M".into(),
+                        "M=D".into(),
+                        "@R14".into(),
+                        "M=M+1".into(),
+                    ]);
+                }
+                // SP = LCL = コピーの末尾 (ARG はそのまま)
+                a.append(&mut vec![
+                    "@R14".into(),
+                    "D=M".into(),
+                    "@SP".into(),
+                    "M=D".into(),
+                    "@LCL".into(),
+                    "M=D".into(),
+                ]);
+                // goto f
+                a.append(&mut vec![
+                    format!("@{}", call.function_name),
+                    "0;JMP".into(),
+                ]);
+                a
+            }
         }
     }
 
+    // 拡張命令のループをその場に展開するか、サブルーチンを呼び出す
+    fn extended_operation(&mut self, operator: Operator) -> Vec<String> {
+        let mut a = match self.extended_ops {
+            Expansion::Inline => {
+                let base = self.label_generator.gen();
+                extended_ops::body(operator, &base)
+            }
+            Expansion::Subroutine => {
+                let return_label = self.label_generator.gen();
+                extended_ops::call_subroutine(operator, &return_label)
+            }
+        };
+        a.append(&mut extended_ops::replace_operands_with_d());
+        a
+    }
+
     fn comparison_operation(&mut self, jump: &str) -> Vec<String> {
         let label_true = self.label_generator.gen();
         let label_false = self.label_generator.gen();
@@ -339,15 +684,17 @@ impl CodeWriter {
         a
     }
 
-    fn push_d_value(&mut self) -> Vec<String> {
-        vec![
+    pub(crate) fn push_d_value(&mut self) -> Vec<String> {
+        let mut a = vec![
             // 結果(Dの値)をスタックに戻す
             "@SP".into(),
             "A=M".into(),
             "M=D".into(),
             "@SP".into(),
             "M=M+1".into(),
-        ]
+        ];
+        a.append(&mut self.stack_guard());
+        a
     }
 
     fn pop_for_binary_operator() -> Vec<String> {
@@ -362,7 +709,7 @@ impl CodeWriter {
         ]
     }
 
-    fn set_memory_address_to_a(base_address: &str, index: u16) -> Vec<String> {
+    pub(crate) fn set_memory_address_to_a(base_address: &str, index: u16) -> Vec<String> {
         let mut a = vec![
             format!("@{}", base_address),
             "A=M".into(),
@@ -382,6 +729,16 @@ impl CodeWriter {
         a
     }
 
+    // ポインタが指す先ではなく、ポインタ(LCLなど)そのものの値をpushする
+    fn push_symbol_value(&mut self, symbol: &str) -> Vec<String> {
+        let mut a = vec![
+            format!("@{}", symbol),
+            "D=M".into(),
+        ];
+        a.append(&mut self.push_d_value());
+        a
+    }
+
     fn push_static_address_value(&mut self, static_address: u16) -> Vec<String> {
         let mut a = vec![
             format!("@{}", static_address),
@@ -417,3 +774,431 @@ impl CodeWriter {
         a
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::hack_cpu::Cpu;
+
+    // テストスクリプトと同じように、セグメントのベースアドレスを設定してから実行する
+    const INITIAL_RAM: [(usize, i16); 5] = [(0, 256), (1, 300), (2, 400), (3, 3000), (4, 3010)];
+
+    fn assemble(codes: &[String]) -> Cpu {
+        let binary_code = assembler::assemble(Cursor::new(codes.join("\n")));
+        let mut cpu = Cpu::new(Cpu::from_bits(&binary_code));
+        for (address, value) in INITIAL_RAM.iter() {
+            cpu.ram[*address] = *value;
+        }
+        cpu
+    }
+
+    fn translate(code_writer: &mut CodeWriter, commands: &[&str]) -> Vec<String> {
+        let mut codes = vec![];
+        for command in commands.iter() {
+            codes.extend(code_writer.code(Command::parse(command)));
+        }
+        codes.extend(code_writer.finish().expect("should be translated without errors"));
+        codes
+    }
+
+    fn run_with(mut code_writer: CodeWriter, commands: &[&str], ram: &[(usize, i16)]) -> Cpu {
+        let mut codes = translate(&mut code_writer, commands);
+        codes.extend(CodeWriter::end_code());
+        for operator in extended_ops::SUBROUTINE_OPERATORS.iter() {
+            codes.extend(extended_ops::subroutine(*operator));
+        }
+        codes.extend(CodeWriter::trap_code());
+
+        let mut cpu = assemble(&codes);
+        for (address, value) in ram.iter() {
+            cpu.ram[*address] = *value;
+        }
+        cpu.run(100_000);
+        cpu
+    }
+
+    fn run(commands: &[&str], ram: &[(usize, i16)]) -> Cpu {
+        run_with(CodeWriter::new("Test".into()), commands, ram)
+    }
+
+    // スタックの先頭の値
+    fn top(cpu: &Cpu) -> i16 {
+        cpu.ram[cpu.ram[0] as usize - 1]
+    }
+
+    fn binary(operator: &str, x: i16, y: i16) -> i16 {
+        let mut commands = vec![];
+        for value in [x, y].iter() {
+            if *value >= 0 {
+                commands.push(format!("push constant {}", value));
+            } else {
+                commands.push(format!("push constant {}", !value));
+                commands.push("not".into());
+            }
+        }
+        commands.push(operator.into());
+        let commands: Vec<&str> = commands.iter().map(|c| c.as_str()).collect();
+
+        let cpu = run(&commands, &[]);
+        assert_eq!(cpu.ram[0], 257, "{} {} {}: SP", x, y, operator);
+        top(&cpu)
+    }
+
+    #[test]
+    fn push_constant() {
+        let cpu = run(&["push constant 7", "push constant 32767"], &[]);
+        assert_eq!(cpu.ram[0], 258);
+        assert_eq!(cpu.ram[256], 7);
+        assert_eq!(cpu.ram[257], 32767);
+    }
+
+    #[test]
+    fn push_segments() {
+        let cpu = run(
+            &[
+                "push local 2",
+                "push argument 1",
+                "push this 3",
+                "push that 0",
+                "push pointer 0",
+                "push pointer 1",
+                "push temp 7",
+            ],
+            &[(302, 11), (401, 22), (3003, 33), (3010, 44), (12, 55)],
+        );
+        assert_eq!(cpu.ram[0], 263);
+        assert_eq!(&cpu.ram[256..263], &[11, 22, 33, 44, 3000, 3010, 55]);
+    }
+
+    #[test]
+    fn push_and_pop_static() {
+        let cpu = run(&["push constant 5", "pop static 3", "push constant 6", "pop static 0", "push static 3"], &[]);
+        assert_eq!(cpu.ram[0], 257);
+        assert_eq!(cpu.ram[256], 5);
+        // 変数は最初に現れた順に RAM[16] から割り当てられる
+        assert_eq!(cpu.ram[16], 5);
+        assert_eq!(cpu.ram[17], 6);
+    }
+
+    #[test]
+    fn pop_segments() {
+        let cpu = run(
+            &[
+                "push constant 1",
+                "pop local 2",
+                "push constant 2",
+                "pop argument 1",
+                "push constant 3",
+                "pop this 3",
+                "push constant 4",
+                "pop that 0",
+                "push constant 5",
+                "pop temp 7",
+                "push constant 4000",
+                "pop pointer 0",
+                "push constant 5000",
+                "pop pointer 1",
+            ],
+            &[],
+        );
+        assert_eq!(cpu.ram[0], 256);
+        assert_eq!(cpu.ram[302], 1);
+        assert_eq!(cpu.ram[401], 2);
+        assert_eq!(cpu.ram[3003], 3);
+        assert_eq!(cpu.ram[3010], 4);
+        assert_eq!(cpu.ram[12], 5);
+        assert_eq!(cpu.ram[3], 4000);
+        assert_eq!(cpu.ram[4], 5000);
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(binary("add", 7, 8), 15);
+        assert_eq!(binary("add", 32767, 1), -32768);
+        assert_eq!(binary("sub", 7, 8), -1);
+        assert_eq!(binary("and", 0b1100, 0b1010), 0b1000);
+        assert_eq!(binary("or", 0b1100, 0b1010), 0b1110);
+
+        let cpu = run(&["push constant 5", "neg"], &[]);
+        assert_eq!((cpu.ram[0], top(&cpu)), (257, -5));
+        let cpu = run(&["push constant 5", "not"], &[]);
+        assert_eq!((cpu.ram[0], top(&cpu)), (257, !5));
+    }
+
+    #[test]
+    fn comparison() {
+        for (x, y) in [(1, 2), (2, 2), (3, 2), (-5, 4), (4, -5)].iter() {
+            let expected = |b: bool| if b { -1 } else { 0 };
+            assert_eq!(binary("eq", *x, *y), expected(x == y), "{} eq {}", x, y);
+            assert_eq!(binary("gt", *x, *y), expected(x > y), "{} gt {}", x, y);
+            assert_eq!(binary("lt", *x, *y), expected(x < y), "{} lt {}", x, y);
+            assert_eq!(binary("lte", *x, *y), expected(x <= y), "{} lte {}", x, y);
+            assert_eq!(binary("gte", *x, *y), expected(x >= y), "{} gte {}", x, y);
+            assert_eq!(binary("neq", *x, *y), expected(x != y), "{} neq {}", x, y);
+        }
+    }
+
+    #[test]
+    fn extended_operators() {
+        for expansion in [Expansion::Inline, Expansion::Subroutine].iter() {
+            for (x, y) in [(7, 3), (-7, 3), (7, -3), (-7, -3), (0, 5), (-32768, 1), (-32768, -1), (300, 300), (12345, 7)].iter() {
+                let mut code_writer = CodeWriter::new("Test".into());
+                code_writer.set_extended_ops(*expansion);
+                let cpu = run_with(
+                    code_writer,
+                    &["push argument 0", "push argument 1", "mul", "push argument 0", "push argument 1", "div",
+                      "push argument 0", "push argument 1", "mod", "push argument 0", "push argument 1", "shl",
+                      "push argument 0", "push argument 1", "shr"],
+                    &[(400, *x), (401, *y)],
+                );
+                assert_eq!(cpu.ram[0], 261, "{:?} {} {}", expansion, x, y);
+                assert_eq!(
+                    &cpu.ram[256..261],
+                    &[
+                        x.wrapping_mul(*y),
+                        x.wrapping_div(*y),
+                        x.wrapping_rem(*y),
+                        extended_ops::shift_left(*x, *y),
+                        extended_ops::shift_right(*x, *y),
+                    ],
+                    "{:?} {} {}", expansion, x, y,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        // 安全性検査の有無や展開の方法にかかわらずトラップで停止する
+        for operator in ["div", "mod"].iter() {
+            for expansion in [Expansion::Inline, Expansion::Subroutine].iter() {
+                for safety_checks in [false, true].iter() {
+                    let mut code_writer = CodeWriter::new("Test".into());
+                    code_writer.set_extended_ops(*expansion);
+                    if *safety_checks {
+                        code_writer.enable_safety_checks(SafetyChecks::default());
+                    }
+                    let cpu = run_with(code_writer, &["push constant 7", "push constant 0", operator], &[]);
+                    assert_eq!(
+                        cpu.ram[TRAP_CODE_ADDRESS as usize], TRAP_DIVIDE_BY_ZERO as i16,
+                        "{} {:?} {}", operator, expansion, safety_checks,
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn subroutines_keep_trap_code_address() {
+        // サブルーチンの戻りアドレスでエラーコードの書き込み先を上書きしない
+        let mut code_writer = CodeWriter::new("Test".into());
+        code_writer.enable_safety_checks(SafetyChecks::default());
+        let cpu = run_with(
+            code_writer,
+            &["push constant 6", "push constant 7", "mul", "push constant 3", "div", "push constant 1", "shl"],
+            &[],
+        );
+        assert_eq!(top(&cpu), 28);
+        assert_eq!(cpu.ram[TRAP_CODE_ADDRESS as usize], 0);
+    }
+
+    #[test]
+    fn label_and_goto() {
+        // local 0 = 1 + 2 + 3
+        let cpu = run(
+            &[
+                "push constant 3",
+                "pop argument 0",
+                "label LOOP",
+                "push local 0",
+                "push argument 0",
+                "add",
+                "pop local 0",
+                "push argument 0",
+                "push constant 1",
+                "sub",
+                "pop argument 0",
+                "push argument 0",
+                "if-goto LOOP",
+                "goto END",
+                "push constant 99",
+                "pop local 0",
+                "label END",
+            ],
+            &[],
+        );
+        assert_eq!(cpu.ram[0], 256);
+        assert_eq!(cpu.ram[300], 6);
+    }
+
+    #[test]
+    fn labels_are_scoped_by_function() {
+        let mut code_writer = CodeWriter::new("Test".into());
+        let codes = translate(&mut code_writer, &["function A.f 0", "label L", "function B.g 0", "label L"]);
+        assert!(codes.contains(&"(A.f$L)".to_string()));
+        assert!(codes.contains(&"(B.g$L)".to_string()));
+    }
+
+    #[test]
+    fn undefined_goto_target() {
+        let mut code_writer = CodeWriter::new("Test".into());
+        for command in ["function A.f 0", "goto L", "function B.g 0", "label L"].iter() {
+            code_writer.code(Command::parse(command));
+        }
+        assert_eq!(
+            code_writer.finish(),
+            Err(vec!["Test: goto target `L` is not defined in function `A.f`".to_string()]),
+        );
+    }
+
+    #[test]
+    fn function_initializes_locals() {
+        let cpu = run(&["function Test.f 3"], &[(0, 300), (1, 300), (300, -1), (301, -1), (302, -1)]);
+        assert_eq!(cpu.ram[0], 303);
+        assert_eq!(&cpu.ram[300..303], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn call_and_return() {
+        // Test.main から Test.add(3, 4) を呼び出し、戻り値を temp 0 に入れる
+        let mut codes = CodeWriter::bootstrap_code("Test.main");
+        let mut code_writer = CodeWriter::new("Test".into());
+        codes.extend(translate(&mut code_writer, &[
+            "function Test.main 1",
+            "push constant 4000",
+            "pop pointer 0",
+            "push constant 5000",
+            "pop pointer 1",
+            "push constant 3",
+            "push constant 4",
+            "call Test.add 2",
+            "pop temp 0",
+            "label END",
+            "goto END",
+            "function Test.add 1",
+            "push constant 4001",
+            "pop pointer 0",
+            "push argument 0",
+            "push argument 1",
+            "add",
+            "return",
+        ]));
+        let mut cpu = assemble(&codes);
+        cpu.run(10_000);
+
+        assert_eq!(cpu.ram[5], 7);
+        // 呼び出し元の状態が復元されている
+        assert_eq!(cpu.ram[0], 262);
+        assert_eq!(cpu.ram[1], 261);
+        assert_eq!(cpu.ram[2], 256);
+        assert_eq!(cpu.ram[3], 4000);
+        assert_eq!(cpu.ram[4], 5000);
+    }
+
+    #[test]
+    fn tail_call_reuses_frame() {
+        // Test.main から Test.sum(n, acc) を呼び出す。Test.sum は末尾呼び出しで自分自身を呼び出す
+        let mut codes = CodeWriter::bootstrap_code("Test.main");
+        let mut code_writer = CodeWriter::new("Test".into());
+        for command in [
+            "function Test.main 0",
+            "push constant 4000",
+            "pop pointer 0",
+            "push constant 20",
+            "push constant 0",
+            "call Test.sum 2",
+            "pop temp 0",
+            "label END",
+            "goto END",
+            "function Test.sum 1",
+            "push argument 0",
+            "if-goto RECURSE",
+            "push argument 1",
+            "return",
+            "label RECURSE",
+            "push constant 4001",
+            "pop pointer 0",
+            "push argument 0",
+            "push constant 1",
+            "sub",
+            "push argument 1",
+            "push argument 0",
+            "add",
+        ].iter() {
+            codes.extend(code_writer.code(Command::parse(command)));
+        }
+        codes.extend(code_writer.code(Command::TailCall(Call::new("Test.sum".into(), 2))));
+        codes.extend(code_writer.finish().expect("should be translated without errors"));
+
+        let mut cpu = assemble(&codes);
+        let mut max_sp = 0;
+        for _ in 0..20_000 {
+            cpu.step();
+            max_sp = max_sp.max(cpu.ram[0]);
+        }
+
+        assert_eq!(cpu.ram[5], 210);
+        // 呼び出し元の状態が復元されている
+        assert_eq!(cpu.ram[0], 261);
+        assert_eq!(cpu.ram[1], 261);
+        assert_eq!(cpu.ram[2], 256);
+        assert_eq!(cpu.ram[3], 4000);
+        // 再帰の深さに関わらず、Test.sum のフレームは1つ分しか使わない
+        assert!(max_sp < 280, "SP reached {}", max_sp);
+    }
+
+    #[test]
+    fn stack_overflow_trap() {
+        let mut code_writer = CodeWriter::new("Test".into());
+        code_writer.enable_safety_checks(SafetyChecks { stack_limit: 258 });
+        let cpu = run_with(code_writer, &["push constant 1", "push constant 2", "push constant 3"], &[]);
+        assert_eq!(cpu.ram[TRAP_CODE_ADDRESS as usize], TRAP_STACK_OVERFLOW as i16);
+        assert_eq!(cpu.ram[0], 259);
+    }
+
+    #[test]
+    fn safety_checks_keep_pushed_values() {
+        // 検査のコードを挟んでも、ローカル変数の初期値と呼び出し元のフレームは変わらない
+        let mut code_writer = CodeWriter::new("Test".into());
+        code_writer.enable_safety_checks(SafetyChecks::default());
+        let cpu = run_with(code_writer, &[
+            "push constant 3",
+            "call Test.f 1",
+            "pop temp 0",
+            "label END",
+            "goto END",
+            "function Test.f 2",
+            "push local 0",
+            "push local 1",
+            "add",
+            "push argument 0",
+            "add",
+            "return",
+        ], &[(262, -1), (263, -1)]);
+
+        assert_eq!(cpu.ram[TRAP_CODE_ADDRESS as usize], 0);
+        assert_eq!(cpu.ram[5], 3);
+        // 戻った後もスタックの上に残っているフレームとローカル変数
+        assert_eq!(&cpu.ram[258..262], &[300, 400, 3000, 3010]);
+        assert_eq!(&cpu.ram[262..264], &[0, 0]);
+        assert_eq!(&cpu.ram[0..5], &[256, 300, 400, 3000, 3010]);
+    }
+
+    #[test]
+    fn bad_pointer_trap() {
+        let mut code_writer = CodeWriter::new("Test".into());
+        code_writer.enable_safety_checks(SafetyChecks::default());
+        let cpu = run_with(code_writer, &["push constant 100", "pop pointer 1", "push that 0"], &[]);
+        assert_eq!(cpu.ram[TRAP_CODE_ADDRESS as usize], TRAP_BAD_POINTER as i16);
+    }
+
+    #[test]
+    fn bad_return_trap() {
+        let mut code_writer = CodeWriter::new("Test".into());
+        code_writer.enable_safety_checks(SafetyChecks::default());
+        // 戻りアドレス (LCL-5) が 0
+        let cpu = run_with(code_writer, &["function Test.f 0", "push constant 1", "return"], &[(0, 310), (1, 310), (305, 0)]);
+        assert_eq!(cpu.ram[TRAP_CODE_ADDRESS as usize], TRAP_BAD_RETURN as i16);
+    }
+}
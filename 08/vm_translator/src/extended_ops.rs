@@ -0,0 +1,327 @@
+// 拡張命令 (mul, div, mod, shl, shr) のHackアセンブリへの展開
+// 本のVM仕様には無い命令で、Jack の `*` や `/` を Math.multiply などの呼び出しにせずに済む
+//
+// どの命令も x が RAM[SP-2], y が RAM[SP-1] にある状態で始まり、結果をDレジスタに入れて終わる
+// 作業用に R13, R14 とスタックの先頭より上(RAM[SP]以降)を使う
+// サブルーチンとして展開する場合はDレジスタに戻りアドレスを入れて呼び出し、サブルーチンが RAM[SP] に保存する
+// (R15 は安全性検査のエラーコードの書き込み先なので使わない)
+// 0除算は安全性検査の有無にかかわらずトラップで停止する (VMエミュレータの ERR3 と同じく実行を続けない)
+use crate::parser::Operator;
+
+// mul, div, mod, shl, shr の展開の方法
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expansion {
+    // 呼び出し箇所ごとにループを展開する (速いがコードが大きくなる)
+    Inline,
+    // プログラムに1つだけ置いたルーチンを呼び出す
+    Subroutine,
+}
+
+// サブルーチンとして展開する命令
+pub const SUBROUTINE_OPERATORS: [Operator; 5] = [
+    Operator::Mul,
+    Operator::Div,
+    Operator::Mod,
+    Operator::Shl,
+    Operator::Shr,
+];
+
+// 0除算のトラップ
+pub const DIVIDE_BY_ZERO_TRAP: &str = "$$trap.divide_by_zero";
+
+// シフト量は 0..16 に丸める (負のシフト量は 0 とみなす)
+pub fn shift_left(x: i16, y: i16) -> i16 {
+    if y <= 0 {
+        x
+    } else if y >= 16 {
+        0
+    } else {
+        ((x as u16) << y) as i16
+    }
+}
+
+// 算術右シフト
+pub fn shift_right(x: i16, y: i16) -> i16 {
+    if y <= 0 {
+        x
+    } else {
+        x >> y.min(15)
+    }
+}
+
+// サブルーチンの入口のラベル
+pub fn subroutine_label(operator: Operator) -> String {
+    format!("$$op.{}", operator)
+}
+
+// サブルーチンの呼び出し
+pub fn call_subroutine(operator: Operator, return_label: &str) -> Vec<String> {
+    vec![
+        format!("@{}", return_label),
+        "D=A".into(),
+        format!("@{}", subroutine_label(operator)),
+        "0;JMP".into(),
+        format!("({})", return_label),
+    ]
+}
+
+// プログラムの最後に1度だけ出力するサブルーチン
+pub fn subroutine(operator: Operator) -> Vec<String> {
+    let label = subroutine_label(operator);
+    let mut a = vec![format!("({})", label)];
+    // 戻りアドレスを保存する
+    a.append(&mut code(vec![scratch(0), s(&["M=D"])]));
+    a.append(&mut body(operator, &label));
+    a.append(&mut code(vec![scratch(0), s(&["A=M", "0;JMP"])]));
+    a
+}
+
+// 結果(Dの値)で x を置き換え、y を取り除く
+pub fn replace_operands_with_d() -> Vec<String> {
+    vec![
+        "@SP".into(),
+        "AM=M-1".into(),
+        "A=A-1".into(),
+        "M=D".into(),
+    ]
+}
+
+// 命令の本体
+// base はこの展開の中だけで使うラベルの接頭辞
+pub fn body(operator: Operator, base: &str) -> Vec<String> {
+    match operator {
+        Operator::Mul => multiply(base),
+        Operator::Div => divide(base, false),
+        Operator::Mod => divide(base, true),
+        Operator::Shl => shift_left_code(base),
+        Operator::Shr => shift_right_code(base),
+        _ => panic!("{} is not expanded to a loop", operator),
+    }
+}
+
+// Aレジスタに x (RAM[SP-2]) のアドレスをセットする
+fn x() -> Vec<String> {
+    vec!["@SP".into(), "A=M-1".into(), "A=A-1".into()]
+}
+
+// Aレジスタに y (RAM[SP-1]) のアドレスをセットする
+fn y() -> Vec<String> {
+    vec!["@SP".into(), "A=M-1".into()]
+}
+
+// Aレジスタに RAM[SP+n] のアドレスをセットする
+fn scratch(n: usize) -> Vec<String> {
+    let mut a = vec!["@SP".into(), "A=M".into()];
+    for _ in 0..n {
+        a.push("A=A+1".into());
+    }
+    a
+}
+
+fn code(parts: Vec<Vec<String>>) -> Vec<String> {
+    parts.into_iter().flatten().collect()
+}
+
+fn s(codes: &[&str]) -> Vec<String> {
+    codes.iter().map(|c| String::from(*c)).collect()
+}
+
+// シフトと加算による乗算
+// R13 = 結果, R14 = y の中で調べているビット
+fn multiply(base: &str) -> Vec<String> {
+    code(vec![
+        s(&["@R13", "M=0", "@R14", "M=1"]),
+        vec![format!("({}.loop)", base)],
+        // y のビットが立っていれば、シフトした x を足す
+        y(),
+        s(&["D=M", "@R14", "D=D&M"]),
+        vec![format!("@{}.skip", base), "D;JEQ".into()],
+        x(),
+        s(&["D=M", "@R13", "M=D+M"]),
+        vec![format!("({}.skip)", base)],
+        // x <<= 1
+        x(),
+        s(&["D=M", "M=D+M"]),
+        // 全てのビットを調べるまで繰り返す
+        s(&["@R14", "D=M", "MD=D+M"]),
+        vec![format!("@{}.loop", base), "D;JNE".into()],
+        s(&["@R13", "D=M"]),
+    ])
+}
+
+// 符号なしの筆算による除算 (商は0方向に丸め、余りの符号は x と同じ)
+// x, y を絶対値に置き換え、元の値を RAM[SP+1], RAM[SP+2] に、ループの残り回数を RAM[SP+3] に置く
+// (RAM[SP] はサブルーチンの戻りアドレス)
+// R13 = 商, R14 = 余り
+fn divide(base: &str, remainder: bool) -> Vec<String> {
+    let mut a = code(vec![
+        y(),
+        s(&["D=M"]),
+        vec![format!("@{}.nonzero", base), "D;JNE".into()],
+        vec![format!("@{}", DIVIDE_BY_ZERO_TRAP), "0;JMP".into()],
+        vec![format!("({}.nonzero)", base)],
+        // 元の値を保存する
+        scratch(2),
+        s(&["M=D"]),
+        x(),
+        s(&["D=M"]),
+        scratch(1),
+        s(&["M=D"]),
+        // 絶対値にする (-32768 は符号なしの 32768 として扱う)
+        vec![format!("@{}.xabs", base), "D;JGE".into()],
+        x(),
+        s(&["M=-M"]),
+        vec![format!("({}.xabs)", base)],
+        y(),
+        s(&["D=M"]),
+        vec![format!("@{}.yabs", base), "D;JGE".into()],
+        y(),
+        s(&["M=-M"]),
+        vec![format!("({}.yabs)", base)],
+        s(&["@R13", "M=0", "@R14", "M=0", "@16", "D=A"]),
+        scratch(3),
+        s(&["M=D"]),
+
+        vec![format!("({}.loop)", base)],
+        // 余り = 余り * 2 + (x の最上位ビット)
+        s(&["@R14", "D=M", "M=D+M"]),
+        x(),
+        s(&["D=M"]),
+        vec![format!("@{}.shift", base), "D;JGE".into()],
+        s(&["@R14", "M=M+1"]),
+        vec![format!("({}.shift)", base)],
+        x(),
+        s(&["D=M", "M=D+M"]),
+        s(&["@R13", "D=M", "M=D+M"]),
+        // 余り >= y (符号なしの比較) なら引く
+        y(),
+        s(&["D=M"]),
+        vec![format!("@{}.ylarge", base), "D;JLT".into()],
+        s(&["@R14", "D=M"]),
+        vec![format!("@{}.subtract", base), "D;JLT".into()],
+        y(),
+        s(&["D=M", "@R14", "D=M-D"]),
+        vec![format!("@{}.next", base), "D;JLT".into()],
+        vec![format!("@{}.subtract", base), "0;JMP".into()],
+        vec![format!("({}.ylarge)", base)],
+        s(&["@R14", "D=M"]),
+        vec![format!("@{}.next", base), "D;JGE".into()],
+        y(),
+        s(&["D=M", "@R14", "D=M-D"]),
+        vec![format!("@{}.next", base), "D;JLT".into()],
+        vec![format!("({}.subtract)", base)],
+        y(),
+        s(&["D=M", "@R14", "M=M-D", "@R13", "M=M+1"]),
+        vec![format!("({}.next)", base)],
+        scratch(3),
+        s(&["MD=M-1"]),
+        vec![format!("@{}.loop", base), "D;JGT".into()],
+    ]);
+
+    if remainder {
+        // 余りの符号は x と同じ
+        a.extend(code(vec![
+            scratch(1),
+            s(&["D=M"]),
+            vec![format!("@{}.done", base), "D;JGE".into()],
+            s(&["@R14", "M=-M"]),
+            vec![format!("({}.done)", base)],
+            s(&["@R14", "D=M"]),
+        ]));
+    } else {
+        // x と y の符号が異なれば商は負
+        a.extend(code(vec![
+            scratch(1),
+            s(&["D=M"]),
+            vec![format!("@{}.xneg", base), "D;JLT".into()],
+            scratch(2),
+            s(&["D=M"]),
+            vec![format!("@{}.negate", base), "D;JLT".into()],
+            vec![format!("@{}.done", base), "0;JMP".into()],
+            vec![format!("({}.xneg)", base)],
+            scratch(2),
+            s(&["D=M"]),
+            vec![format!("@{}.done", base), "D;JLT".into()],
+            vec![format!("({}.negate)", base)],
+            s(&["@R13", "M=-M"]),
+            vec![format!("({}.done)", base)],
+            s(&["@R13", "D=M"]),
+        ]));
+    }
+    a
+}
+
+// y 回 x を2倍する (y は 16 で打ち切る)
+fn shift_left_code(base: &str) -> Vec<String> {
+    code(vec![
+        y(),
+        s(&["D=M", "@16", "D=D-A"]),
+        vec![format!("@{}.loop", base), "D;JLE".into()],
+        s(&["@16", "D=A"]),
+        y(),
+        s(&["M=D"]),
+        vec![format!("({}.loop)", base)],
+        y(),
+        s(&["D=M"]),
+        vec![format!("@{}.done", base), "D;JLE".into()],
+        y(),
+        s(&["M=D-1", "A=A-1", "D=M", "M=D+M"]),
+        vec![format!("@{}.loop", base), "0;JMP".into()],
+        vec![format!("({}.done)", base)],
+        x(),
+        s(&["D=M"]),
+    ])
+}
+
+// x のビット i を結果のビット i-y にコピーする (y は 15 で打ち切る)
+// R13 = x の中で調べているビット, R14 = 結果のビット, 結果は y の位置に作る
+fn shift_right_code(base: &str) -> Vec<String> {
+    code(vec![
+        y(),
+        s(&["D=M"]),
+        vec![format!("@{}.shift", base), "D;JGT".into()],
+        // シフト量が 0 以下なら x のまま
+        x(),
+        s(&["D=M"]),
+        vec![format!("@{}.end", base), "0;JMP".into()],
+        vec![format!("({}.shift)", base)],
+        s(&["@15", "D=D-A"]),
+        vec![format!("@{}.mask", base), "D;JLE".into()],
+        s(&["@15", "D=A"]),
+        y(),
+        s(&["M=D"]),
+        vec![format!("({}.mask)", base)],
+        // R13 = 1 << y
+        s(&["@R13", "M=1"]),
+        vec![format!("({}.maskloop)", base)],
+        s(&["@R13", "D=M", "M=D+M"]),
+        y(),
+        s(&["MD=M-1"]),
+        vec![format!("@{}.maskloop", base), "D;JGT".into()],
+        s(&["@R14", "M=1"]),
+        y(),
+        s(&["M=0"]),
+        vec![format!("({}.loop)", base)],
+        x(),
+        s(&["D=M", "@R13", "D=D&M"]),
+        vec![format!("@{}.skip", base), "D;JEQ".into()],
+        s(&["@R14", "D=M"]),
+        y(),
+        s(&["M=D|M"]),
+        vec![format!("({}.skip)", base)],
+        s(&["@R14", "D=M", "M=D+M"]),
+        s(&["@R13", "D=M", "MD=D+M"]),
+        vec![format!("@{}.loop", base), "D;JNE".into()],
+        // x が負なら空いた上位ビットを 1 で埋める (-R14 は R14 のビットより上が全て 1)
+        x(),
+        s(&["D=M"]),
+        vec![format!("@{}.positive", base), "D;JGE".into()],
+        s(&["@R14", "D=-M"]),
+        y(),
+        s(&["M=D|M"]),
+        vec![format!("({}.positive)", base)],
+        y(),
+        s(&["D=M"]),
+        vec![format!("({}.end)", base)],
+    ])
+}
@@ -0,0 +1,232 @@
+// .vm の整形
+// 空白を正規化し、関数の本体をインデントする。整形前後でパース結果の Command は変わらない
+use crate::parser::{Command, VmFile};
+
+// 関数の本体のインデント
+const BODY_INDENT: &str = "    ";
+// 関数の中のラベルのインデント (本体より浅くして目立たせる)
+const LABEL_INDENT: &str = "  ";
+
+enum Line {
+    Blank,
+    // `//` を含むコメント
+    Comment(String),
+    Code(Command, Option<String>),
+}
+
+pub struct Formatter {
+    // コメントを取り除く
+    strip_comments: bool,
+}
+
+impl Formatter {
+    pub fn new(strip_comments: bool) -> Self {
+        Self {
+            strip_comments,
+        }
+    }
+
+    // 整形したコードが元のコードと同じ Command 列にパースされることを確かめてから返す
+    // パースできない行があればその行番号とともにエラーを返す
+    pub fn format(&self, source: &str) -> Result<String, String> {
+        let formatted = self.format_lines(Self::lines(source)?);
+
+        let original = VmFile::read(String::new(), source.as_bytes()).commands;
+        let reparsed = VmFile::read(String::new(), formatted.as_bytes()).commands;
+        if original != reparsed {
+            return Err("the formatted code does not parse back to the same commands".into());
+        }
+        Ok(formatted)
+    }
+
+    fn lines(source: &str) -> Result<Vec<Line>, String> {
+        source.lines().enumerate().map(|(i, line)| {
+            let (code, comment) = match line.find("//") {
+                Some(pos) => (&line[..pos], Some(String::from(line[pos..].trim_end()))),
+                None => (line, None),
+            };
+            let code = code.trim();
+            Ok(match (code.is_empty(), comment) {
+                (true, None) => Line::Blank,
+                (true, Some(comment)) => Line::Comment(comment),
+                (false, comment) => {
+                    let command = Command::try_parse(code).map_err(|e| format!("line {}: {}", i + 1, e))?;
+                    Line::Code(command, comment)
+                }
+            })
+        }).collect()
+    }
+
+    fn format_lines(&self, lines: Vec<Line>) -> String {
+        let mut output: Vec<String> = vec![];
+        // 次のコマンドまでのコメントと空行 (None)
+        // インデントは次のコマンドを見てから決める
+        let mut pending: Vec<Option<String>> = vec![];
+        let mut blank = false;
+        let mut in_function = false;
+
+        for line in lines {
+            match line {
+                Line::Blank => pending.push(None),
+                Line::Comment(comment) => {
+                    if !self.strip_comments {
+                        pending.push(Some(comment));
+                    }
+                }
+                Line::Code(command, comment) => {
+                    let (indent, detached_indent) = match command {
+                        Command::Function(_) => {
+                            in_function = true;
+                            // 関数の間は1行空ける
+                            blank = true;
+                            ("", "")
+                        }
+                        Command::Label(_) if in_function => (LABEL_INDENT, BODY_INDENT),
+                        _ if in_function => (BODY_INDENT, BODY_INDENT),
+                        _ => ("", ""),
+                    };
+                    Self::flush(&mut output, &mut pending, indent, detached_indent, &mut blank);
+
+                    let mut code = format!("{}{}", indent, command);
+                    if let (Some(comment), false) = (comment, self.strip_comments) {
+                        code.push(' ');
+                        code.push_str(&comment);
+                    }
+                    Self::push(&mut output, code, &mut blank);
+                }
+            }
+        }
+        let indent = if in_function { BODY_INDENT } else { "" };
+        Self::flush(&mut output, &mut pending, indent, indent, &mut blank);
+
+        let mut formatted = output.join("\n");
+        if !formatted.is_empty() {
+            formatted.push('\n');
+        }
+        formatted
+    }
+
+    // 直前のコマンドとの間に空行があれば1行だけ空ける (先頭には空けない)
+    fn push(output: &mut Vec<String>, line: String, blank: &mut bool) {
+        if *blank && !output.is_empty() {
+            output.push(String::new());
+        }
+        *blank = false;
+        output.push(line);
+    }
+
+    // コマンドの直前のコメントはコマンドと同じインデント、
+    // 空行で区切られたコメントは後ろのコマンドに付けずに detached_indent で出力する
+    fn flush(output: &mut Vec<String>, pending: &mut Vec<Option<String>>, indent: &str, detached_indent: &str, blank: &mut bool) {
+        let last_blank = pending.iter().rposition(|line| line.is_none());
+        for (i, line) in pending.drain(..).enumerate() {
+            match line {
+                None => *blank = true,
+                Some(comment) => {
+                    let indent = if matches!(last_blank, Some(last) if i < last) { detached_indent } else { indent };
+                    Self::push(output, format!("{}{}", indent, comment), blank);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn format(source: &str) -> String {
+        Formatter::new(false).format(source).unwrap()
+    }
+
+    #[test]
+    fn indent_function_bodies() {
+        assert_eq!(
+            format("function  Main.main 1\npush constant 1\t// one\nlabel LOOP\n  goto LOOP\nfunction Main.f 0\nreturn\n"),
+            "function Main.main 1\n    push constant 1 // one\n  label LOOP\n    goto LOOP\n\nfunction Main.f 0\n    return\n",
+        );
+    }
+
+    #[test]
+    fn comments_before_commands() {
+        // 直前のコメントはコマンドと同じインデント
+        assert_eq!(
+            format("function Main.main 0\n// loop\nlabel LOOP\n// jump\ngoto LOOP\n"),
+            "function Main.main 0\n  // loop\n  label LOOP\n    // jump\n    goto LOOP\n",
+        );
+        // 空行で区切られたコメントは関数の本体のインデント
+        assert_eq!(
+            format("function Main.main 0\npush constant 0\n\n// note\n\npop temp 0\n"),
+            "function Main.main 0\n    push constant 0\n\n    // note\n\n    pop temp 0\n",
+        );
+    }
+
+    #[test]
+    fn comments_before_functions() {
+        // 前の関数の本体のインデントにしない
+        let source = "// Sys.vm\n\nfunction Sys.init 0\ngoto Sys.init\n\n// Sys.main()\n//\n// returns 0\n\nfunction Sys.main 0\npush constant 0\nreturn\n";
+        assert_eq!(
+            format(source),
+            "// Sys.vm\n\nfunction Sys.init 0\n    goto Sys.init\n\n// Sys.main()\n//\n// returns 0\n\nfunction Sys.main 0\n    push constant 0\n    return\n",
+        );
+        assert_eq!(
+            format("function Sys.init 0\nreturn\n// Sys.main()\nfunction Sys.main 0\nreturn\n"),
+            "function Sys.init 0\n    return\n\n// Sys.main()\nfunction Sys.main 0\n    return\n",
+        );
+    }
+
+    #[test]
+    fn strip_comments() {
+        assert_eq!(
+            Formatter::new(true).format("// header\n\nfunction Main.main 0 // main\n// body\nreturn\n").unwrap(),
+            "function Main.main 0\n    return\n",
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let formatter = Formatter::new(false);
+        assert_eq!(formatter.format("push constant 1\npush foo 2\n"), Err("line 2: unknown segment: foo".into()));
+        assert_eq!(formatter.format("push temp 8\n"), Err("line 1: index 8 is not supported".into()));
+        assert_eq!(formatter.format("call Main.f\n"), Err("line 1: call command requires 2 arguments".into()));
+        assert_eq!(formatter.format("function Main.f x\n"), Err("line 1: invalid number: x".into()));
+        assert_eq!(formatter.format("jump LOOP\n"), Err("line 1: the operator is not supported: jump".into()));
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for command in ["push constant 7", "pop that 2", "if-goto END", "call Main.f 2", "tail-call Main.f 2", "lte"].iter() {
+            let parsed = Command::parse(command);
+            assert_eq!(parsed.to_string(), *command);
+            assert_eq!(Command::parse(&parsed.to_string()), parsed);
+        }
+    }
+
+    #[test]
+    fn idempotent_on_fixtures() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let mut count = 0;
+        for program in std::fs::read_dir(fixtures).unwrap() {
+            for file in std::fs::read_dir(program.unwrap().path()).unwrap() {
+                let path = file.unwrap().path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some("vm") {
+                    continue;
+                }
+                let source = std::fs::read_to_string(&path).unwrap();
+                let formatted = format(&source);
+                assert_eq!(format(&formatted), formatted, "{}", path.display());
+                // 関数の前のコメントはインデントしない
+                let lines: Vec<&str> = formatted.lines().collect();
+                for (i, line) in lines.iter().enumerate() {
+                    let next = lines[i..].iter().find(|line| !line.trim_start().starts_with("//") && !line.is_empty());
+                    if line.trim_start().starts_with("//") && matches!(next, Some(next) if next.starts_with("function")) {
+                        assert!(!line.starts_with(' '), "{}: {}", path.display(), line);
+                    }
+                }
+                count += 1;
+            }
+        }
+        assert_eq!(count, 14);
+    }
+}
@@ -0,0 +1,129 @@
+// Hackコンピュータ (CPU + ROM + RAM) のエミュレータ
+// 変換したコードを実機の代わりに実行して、RAMの内容を確かめるために使う
+// スクリーンとキーボードはRAMの領域として扱うだけで、入出力はしない
+
+pub const RAM_SIZE: usize = 32768;
+
+pub struct Cpu {
+    rom: Vec<u16>,
+    pub ram: Vec<i16>,
+    pub a: i16,
+    pub d: i16,
+    pub pc: usize,
+    // 実行した命令の数
+    pub cycles: u64,
+}
+
+impl Cpu {
+    pub fn new(rom: Vec<u16>) -> Self {
+        Self {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+        }
+    }
+
+    // .hack ファイルの形式 (1行に1命令、'0'と'1'の16文字) を読み込む
+    pub fn parse_hack(hack: &str) -> Result<Vec<u16>, String> {
+        hack.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                if line.len() != 16 {
+                    return Err(format!("line {}: an instruction should be 16 bits: {}", i + 1, line));
+                }
+                u16::from_str_radix(line, 2).map_err(|_| format!("line {}: invalid instruction: {}", i + 1, line))
+            })
+            .collect()
+    }
+
+    // アセンブラの出力 (各命令のビット列) から読み込む
+    pub fn from_bits(binary_code: &[[bool; 16]]) -> Vec<u16> {
+        binary_code.iter()
+            .map(|bits| bits.iter().fold(0, |word, b| (word << 1) | *b as u16))
+            .collect()
+    }
+
+    // pc がROMの外に出たら停止したとみなす
+    pub fn is_halted(&self) -> bool {
+        self.pc >= self.rom.len()
+    }
+
+    // `(END) @END 0;JMP` のような自分自身へ戻るだけのループにいる
+    // Hackのプログラムは停止命令が無いのでこのループで終わる (以降は状態が変わらない)
+    pub fn is_in_end_loop(&self) -> bool {
+        let (address, jump) = match (self.rom.get(self.pc), self.rom.get(self.pc + 1)) {
+            (Some(address), Some(jump)) => (*address, *jump),
+            _ => return false,
+        };
+        // `@pc` の後の、どこにも書き込まない無条件ジャンプ
+        address as usize == self.pc && jump & 0xe000 == 0xe000 && jump & 0x0038 == 0 && jump & 0x0007 == 0x0007
+    }
+
+    // 停止するか max_cycles 命令を実行するまで実行する
+    pub fn run(&mut self, max_cycles: u64) {
+        for _ in 0..max_cycles {
+            if !self.step() {
+                break;
+            }
+        }
+    }
+
+    // 1命令を実行する (停止していれば false)
+    pub fn step(&mut self) -> bool {
+        if self.is_halted() {
+            return false;
+        }
+        let instruction = self.rom[self.pc];
+        self.cycles += 1;
+
+        // A命令
+        if instruction & 0x8000 == 0 {
+            self.a = instruction as i16;
+            self.pc += 1;
+            return true;
+        }
+
+        // C命令 111a cccc ccdd djjj
+        let m = instruction & 0x1000 != 0;
+        let y = if m { self.memory() } else { self.a };
+        let out = Self::alu(self.d, y, (instruction >> 6) & 0x3f);
+
+        let address = self.a;
+        if instruction & 0x0008 != 0 {
+            self.ram[address as u16 as usize % RAM_SIZE] = out;
+        }
+        if instruction & 0x0020 != 0 {
+            self.a = out;
+        }
+        if instruction & 0x0010 != 0 {
+            self.d = out;
+        }
+
+        let jump = (instruction & 0x0004 != 0 && out < 0)
+            || (instruction & 0x0002 != 0 && out == 0)
+            || (instruction & 0x0001 != 0 && out > 0);
+        // ジャンプ先は命令を実行する前のAレジスタ
+        self.pc = if jump { address as u16 as usize } else { self.pc + 1 };
+        true
+    }
+
+    fn memory(&self) -> i16 {
+        self.ram[self.a as u16 as usize % RAM_SIZE]
+    }
+
+    // 図2-6 ALU (zx, nx, zy, ny, f, no)
+    fn alu(x: i16, y: i16, control: u16) -> i16 {
+        let bit = |n: u16| control & (1 << (5 - n)) != 0;
+        let x = if bit(0) { 0 } else { x };
+        let x = if bit(1) { !x } else { x };
+        let y = if bit(2) { 0 } else { y };
+        let y = if bit(3) { !y } else { y };
+        let out = if bit(4) { x.wrapping_add(y) } else { x & y };
+        if bit(5) { !out } else { out }
+    }
+}
@@ -0,0 +1,11 @@
+pub mod analyzer;
+pub mod caching_code_writer;
+pub mod code_writer;
+pub mod extended_ops;
+pub mod formatter;
+pub mod hack_cpu;
+pub mod optimizer;
+pub mod parser;
+pub mod profiler;
+pub mod source_map;
+pub mod static_map;
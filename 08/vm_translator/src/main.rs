@@ -1,100 +1,524 @@
-use crate::parser::Parser;
-use crate::code_writer::CodeWriter;
+use vm_translator::analyzer::Analysis;
+use vm_translator::parser::{vm_files, Command, Operator, VmFile};
+use vm_translator::caching_code_writer::CachingCodeWriter;
+use vm_translator::code_writer::{CodeGenerator, CodeWriter, SafetyChecks};
+use vm_translator::extended_ops::{self, Expansion};
+use vm_translator::hack_cpu::Cpu;
+use vm_translator::optimizer::{Optimizer, Passes};
+use vm_translator::profiler::Profile;
+use vm_translator::source_map::{Location, SourceMap};
+use vm_translator::static_map::StaticMap;
 use std::path::{PathBuf, Path};
-use std::io::{BufWriter, Write, Error};
+use std::io::{BufWriter, Cursor, Write};
 use std::fs::File;
 
-mod code_writer;
-mod parser;
+// 入力, 出力のパスに指定すると標準入力, 標準出力を使う
+const STDIO: &str = "-";
+
+// --profile で実行する命令数の上限 (無限ループで終わるプログラムのため)
+const DEFAULT_PROFILE_CYCLES: u64 = 10_000_000;
+
+// ブートストラップコードを出力するかどうか
+#[derive(Debug, PartialEq)]
+enum Bootstrap {
+    Always,
+    Never,
+    // Sys.vm が含まれている場合のみ出力する
+    Auto,
+}
+
+// コード生成の方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Codegen {
+    // 毎回スタックに書き戻す (CodeWriter)
+    Stack,
+    // スタックの先頭をDレジスタに保持する (CachingCodeWriter)
+    Cached,
+}
+
+// 出力の形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    // Hackアセンブリ (.asm)
+    Asm,
+    // アセンブル済みの機械語 (.hack)
+    Hack,
+}
+
+impl Target {
+    fn extension(&self) -> &'static str {
+        match self {
+            Target::Asm => "asm",
+            Target::Hack => "hack",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Options {
+    // `-` の場合は標準入力から読み込む
+    path: String,
+    // 省略した場合は入力の隣 (標準入力の場合は標準出力)
+    output: Option<String>,
+    target: Target,
+    bootstrap: Bootstrap,
+    // ブートストラップコードから呼び出す関数
+    entry: String,
+    // 有効にする最適化パス
+    passes: Passes,
+    codegen: Codegen,
+    // 各コマンドの前に元の .vm のコマンドをコメントとして出力する
+    annotate: bool,
+    // アセンブリと .vm の対応表を出力する
+    source_map: bool,
+    // スタティック変数のRAM割り当てを出力する
+    static_map: bool,
+    // 実行時の安全性検査を埋め込む
+    safety_checks: Option<SafetyChecks>,
+    // 変換せずに呼び出しグラフを解析する
+    analyze: bool,
+    // mul, div, mod, shl, shr の展開の方法
+    extended_ops: Expansion,
+    // 本のVM仕様に無い拡張命令をエラーにする
+    strict: bool,
+    // 変換したプログラムを実行して関数ごとのサイクル数を集計する (実行する命令数の上限)
+    profile: Option<u64>,
+    // 引数や読み込んだファイルなどのデバッグ出力を標準エラー出力に出す
+    verbose: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut path = None;
+        let mut output = None;
+        let mut target = Target::Asm;
+        let mut bootstrap = Bootstrap::Always;
+        let mut entry = String::from("Sys.init");
+        let mut passes = Passes::none();
+        let mut codegen = Codegen::Stack;
+        let mut annotate = false;
+        let mut source_map = false;
+        let mut static_map = false;
+        let mut safety_checks: Option<SafetyChecks> = None;
+        let mut analyze = false;
+        let mut extended_ops = Expansion::Subroutine;
+        let mut strict = false;
+        let mut profile = None;
+        let mut verbose = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" => {
+                    output = Some(args.next().ok_or("-o requires an output path")?.clone());
+                }
+                other if other.starts_with("--output=") => {
+                    output = Some(String::from(other.trim_start_matches("--output=")));
+                }
+                "--target=asm" => target = Target::Asm,
+                "--target=hack" => target = Target::Hack,
+                "--bootstrap" | "--bootstrap=always" => bootstrap = Bootstrap::Always,
+                "--no-bootstrap" | "--bootstrap=never" => bootstrap = Bootstrap::Never,
+                "--bootstrap=auto" => bootstrap = Bootstrap::Auto,
+                other if other.starts_with("--entry=") => {
+                    entry = String::from(other.trim_start_matches("--entry="));
+                    if entry.is_empty() {
+                        return Err("--entry requires a function name".into());
+                    }
+                }
+                "--optimize" => passes = Passes::all(),
+                other if other.starts_with("--optimize=") => {
+                    // `--optimize=constant-folding,push-pop`
+                    passes = Passes::none();
+                    for name in other.trim_start_matches("--optimize=").split(',') {
+                        passes.set(name, true)?;
+                    }
+                }
+                other if other.starts_with("--disable-pass=") => {
+                    passes.set(other.trim_start_matches("--disable-pass="), false)?;
+                }
+                "--codegen=stack" => codegen = Codegen::Stack,
+                "--codegen=cached" => codegen = Codegen::Cached,
+                "--annotate" => annotate = true,
+                "--source-map" => source_map = true,
+                "--static-map" => static_map = true,
+                "--analyze" => analyze = true,
+                "--extended-ops=inline" => extended_ops = Expansion::Inline,
+                "--extended-ops=subroutine" => extended_ops = Expansion::Subroutine,
+                "--strict" => strict = true,
+                "--verbose" => verbose = true,
+                "--profile" => profile = Some(DEFAULT_PROFILE_CYCLES),
+                other if other.starts_with("--profile=") => {
+                    let cycles = other.trim_start_matches("--profile=");
+                    profile = Some(cycles.parse::<u64>().map_err(|_| format!("invalid --profile: {}", cycles))?);
+                }
+                "--safety-checks" => {
+                    safety_checks.get_or_insert_with(SafetyChecks::default);
+                }
+                other if other.starts_with("--stack-limit=") => {
+                    let limit = other.trim_start_matches("--stack-limit=");
+                    let stack_limit = match limit.parse::<u16>() {
+                        Ok(n) if (257..=2048).contains(&n) => n,
+                        _ => return Err(format!("invalid --stack-limit: {} (should be 257..2048)", limit)),
+                    };
+                    safety_checks.get_or_insert_with(SafetyChecks::default).stack_limit = stack_limit;
+                }
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown option: {}", other));
+                }
+                other => {
+                    // `-` は標準入力
+                    if path.is_some() {
+                        return Err(format!("unexpected argument: {}", other));
+                    }
+                    path = Some(String::from(other));
+                }
+            }
+        }
+
+        if safety_checks.is_some() && codegen == Codegen::Cached {
+            return Err("--safety-checks is not supported with --codegen=cached".into());
+        }
+
+        let path = path.ok_or("A path to .vm file or directory contains .vm file is required")?;
+        // 補助ファイルは出力ファイルの隣に書き出すので、出力先がファイルである必要がある
+        let to_stdout = output.as_deref().unwrap_or(path.as_str()) == STDIO;
+        if to_stdout && (source_map || static_map || profile.is_some()) {
+            return Err("--source-map, --static-map and --profile require an output file (-o PATH)".into());
+        }
+
+        Ok(Self {
+            path,
+            output,
+            target,
+            bootstrap,
+            entry,
+            passes,
+            codegen,
+            annotate,
+            source_map,
+            static_map,
+            safety_checks,
+            analyze,
+            extended_ops,
+            strict,
+            profile,
+            verbose,
+        })
+    }
+}
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    println!("args: {:?}", args);
-
-    assert_eq!(args.len(), 2, "A path to .vm file or directory contains .vm file is required");
 
-    let path = std::path::Path::new(&args[1]);
+    let options = Options::parse(&args[1..]).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        eprintln!("usage: vm_translator [--no-bootstrap | --bootstrap=auto] [--entry=Function.name] [--optimize[=PASS,...]] [--disable-pass=PASS] [--codegen=stack|cached] [--annotate] [--source-map] [--static-map] [--safety-checks] [--stack-limit=N] [--analyze] [--target=asm|hack] [-o PATH] [--extended-ops=inline|subroutine] [--strict] [--profile[=CYCLES]] [--verbose] <path | ->");
+        std::process::exit(2);
+    });
 
-    let vm_files = vm_files(path).expect("failed to open files");
-    if vm_files.len() == 0 {
-        panic!(format!("{}: should have vm files", &args[1]))
+    if options.analyze {
+        // 標準出力は DOT だけにする
+        analyze(&options);
+        return;
+    }
+    // 標準出力に結果を書き出す場合があるので、デバッグ出力は標準エラー出力に出す
+    if options.verbose {
+        eprintln!("args: {:?}", args);
     }
-    println!("{:?}", vm_files);
+
+    let mut files = load_files(&options);
 
     let mut assembly_codes = vec![];
-    assembly_codes.extend(CodeWriter::bootstrap_code());
+    let mut source_map = SourceMap::new();
+    let bootstrap = should_bootstrap(&options.bootstrap, &files);
+    if bootstrap {
+        let codes = match options.codegen {
+            Codegen::Stack => CodeWriter::bootstrap_code(&options.entry),
+            Codegen::Cached => CachingCodeWriter::bootstrap_code(&options.entry),
+        };
+        source_map.append(&codes, None);
+        assembly_codes.extend(codes);
+    }
 
-    for pathbuf in vm_files.iter() {
-        assembly_codes.extend(parse(pathbuf.as_path()));
+    let mut errors = vec![];
+    if options.strict {
+        errors.extend(extended_operators(&files).into_iter().map(|(location, operator)| {
+            format!("{}: `{}` is an extended command and not allowed with --strict", location, operator)
+        }));
+        for file in files.iter() {
+            for (command, line) in file.commands.iter().zip(file.lines.iter()) {
+                if let Command::TailCall(_) = command {
+                    errors.push(format!("{}.vm:{}: `tail-call` is an extended command and not allowed with --strict", file.name, line));
+                }
+            }
+        }
+    }
+
+    if !options.passes.is_empty() {
+        Optimizer::new(options.passes.clone(), options.entry.clone()).optimize(&mut files);
+    }
+
+    // 最適化後に残っている拡張命令のサブルーチンだけを出力する
+    let mut subroutines = vec![];
+    if options.extended_ops == Expansion::Subroutine {
+        for (_, operator) in extended_operators(&files) {
+            if extended_ops::SUBROUTINE_OPERATORS.contains(&operator) && !subroutines.contains(&operator) {
+                subroutines.push(operator);
+            }
+        }
     }
-    println!("assembly_codes: {:?}", assembly_codes);
 
-    let output_path = output_path(path);
-    println!("output_path: {:?}", output_path);
+    // 0除算は安全性検査が無効でもトラップで停止する
+    let trap = options.safety_checks.is_some()
+        || extended_operators(&files).iter().any(|(_, operator)| matches!(operator, Operator::Div | Operator::Mod));
 
-    let mut writer = BufWriter::new(File::create(output_path).expect("failed to create asm file"));
+    // call, return の共通ルーチンを使うか
+    let uses = |f: fn(&Command) -> bool| {
+        options.codegen == Codegen::Cached && files.iter().any(|file| file.commands.iter().any(f))
+    };
+    let call_routine = (bootstrap && options.codegen == Codegen::Cached) || uses(|command| matches!(command, Command::Call(_)));
+    let return_routine = uses(|command| matches!(command, Command::Return));
+
+    let classes: Vec<String> = files.iter().map(|file| file.name.clone()).collect();
+    for file in files {
+        if let Err(e) = translate(file, &options, &mut assembly_codes, &mut source_map) {
+            errors.extend(e);
+        }
+    }
+    if trap || !subroutines.is_empty() || call_routine || return_routine {
+        let mut codes = CodeWriter::end_code();
+        if call_routine {
+            codes.extend(CachingCodeWriter::call_routine());
+        }
+        if return_routine {
+            codes.extend(CachingCodeWriter::return_routine());
+        }
+        for operator in subroutines.iter() {
+            codes.extend(extended_ops::subroutine(*operator));
+        }
+        if trap {
+            codes.extend(CodeWriter::trap_code());
+        }
+        source_map.append(&codes, None);
+        assembly_codes.extend(codes);
+    }
+    let static_map = StaticMap::from_assembly(&assembly_codes, &classes);
+    if let Err(e) = static_map.check() {
+        errors.extend(e);
+    }
+    if !errors.is_empty() {
+        for error in errors.iter() {
+            eprintln!("error: {}", error);
+        }
+        std::process::exit(1);
+    }
+    if options.verbose {
+        eprintln!("assembly_codes: {:?}", assembly_codes);
+    }
+
+    let mut output = String::new();
     for code in assembly_codes.iter() {
-        writer.write_all(code.as_bytes()).expect("failed to write assembly codes");
-        writer.write_all(b"\n").unwrap();
+        output.push_str(code);
+        output.push('\n');
+    }
+    let binary_code = if options.target == Target::Hack || options.profile.is_some() {
+        assembler::assemble(Cursor::new(output.as_str()))
+    } else {
+        vec![]
+    };
+    if options.target == Target::Hack {
+        output = assembler::to_hack(&binary_code);
+    }
+
+    let output_path = match &options.output {
+        Some(output) if output != STDIO => PathBuf::from(output),
+        Some(_) => {
+            std::io::stdout().write_all(output.as_bytes()).expect("failed to write the output");
+            return;
+        }
+        None if options.path == STDIO => {
+            std::io::stdout().write_all(output.as_bytes()).expect("failed to write the output");
+            return;
+        }
+        None => output_path(Path::new(&options.path), options.target),
+    };
+    if options.verbose {
+        eprintln!("output_path: {:?}", output_path);
     }
 
+    let mut writer = BufWriter::new(File::create(&output_path).expect("failed to create the output file"));
+    writer.write_all(output.as_bytes()).expect("failed to write the output");
+
+    if options.source_map {
+        // `/foo/bar.asm` -> `/foo/bar.map.json`
+        let source_map_path = output_path.with_extension("map.json");
+        if options.verbose {
+            eprintln!("source_map_path: {:?}", source_map_path);
+        }
+        std::fs::write(source_map_path, source_map.to_json()).expect("failed to write the source map");
+    }
+
+    if options.static_map {
+        // `/foo/bar.asm` -> `/foo/bar.static_map.txt`
+        let static_map_path = output_path.with_extension("static_map.txt");
+        if options.verbose {
+            eprintln!("static_map_path: {:?}", static_map_path);
+        }
+        std::fs::write(static_map_path, static_map.report()).expect("failed to write the static map");
+    }
+
+    if let Some(max_cycles) = options.profile {
+        let mut cpu = Cpu::new(Cpu::from_bits(&binary_code));
+        let profile = Profile::run(&mut cpu, &source_map, max_cycles);
+        // `/foo/bar.asm` -> `/foo/bar.profile.txt`
+        let profile_path = output_path.with_extension("profile.txt");
+        if options.verbose {
+            eprintln!("profile_path: {:?}", profile_path);
+        }
+        std::fs::write(profile_path, profile.report()).expect("failed to write the profile");
+    }
 }
 
-fn vm_files(path: &Path) -> Result<Vec<PathBuf>, Error> {
-    if path.is_dir() {
-        Ok(
-            std::fs::read_dir(path)?.filter(|f| {
-                if !f.is_ok() {
-                    return false;
+// 呼び出しグラフを DOT で標準出力に、解析結果を標準エラー出力に出す
+fn analyze(options: &Options) {
+    let files = load_files(options);
+
+    let analysis = Analysis::new(&files, &options.entry);
+    print!("{}", analysis.to_dot());
+
+    let (notes, result) = analysis.report();
+    for note in notes.iter() {
+        eprintln!("note: {}", note);
+    }
+    if let Err(errors) = result {
+        for error in errors.iter() {
+            eprintln!("error: {}", error);
+        }
+        std::process::exit(1);
+    }
+}
+
+// `-` の場合は標準入力を1つの .vm ファイルとして読み込む
+fn load_files(options: &Options) -> Vec<VmFile> {
+    if options.path == STDIO {
+        return vec![VmFile::read(stdin_name(options), std::io::stdin().lock())];
+    }
+
+    let vm_files = vm_files(Path::new(&options.path)).expect("failed to open files");
+    if vm_files.is_empty() {
+        panic!("{}: should have vm files", &options.path)
+    }
+    if options.verbose {
+        eprintln!("{:?}", vm_files);
+    }
+
+    vm_files.iter()
+        .map(|path| VmFile::open(path).expect("failed to open the file"))
+        .collect()
+}
+
+// 標準入力から読み込んだコードのスタティック変数の名前 (`-o Foo.asm` なら `Foo`)
+fn stdin_name(options: &Options) -> String {
+    options.output.as_ref()
+        .filter(|output| *output != STDIO)
+        .and_then(|output| Path::new(output).file_stem())
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("Stdin")
+        .to_string()
+}
+
+// 拡張命令とその位置 (`Main.vm:12`)
+fn extended_operators(files: &[VmFile]) -> Vec<(String, Operator)> {
+    let mut operators = vec![];
+    for file in files.iter() {
+        for (command, line) in file.commands.iter().zip(file.lines.iter()) {
+            if let Command::Arithmetic(operator) = command {
+                if operator.is_extended() {
+                    operators.push((format!("{}.vm:{}", file.name, line), *operator));
                 }
-                "vm" == f.as_ref().unwrap().path().extension().expect("should have an extension")
-            }).map(|e| {
-                e.unwrap().path()
-            }).collect()
-        )
-    } else {
-        if "vm" == path.extension().expect("should have an extension") {
-            Ok(vec![path.to_path_buf()])
-        } else {
-            Ok(vec![])
+            }
         }
     }
+    operators
 }
 
-fn parse(path: &Path) -> Vec<String> {
-    let mut parser = Parser::new(
-        std::fs::File::open(path).expect("failed to open the file")
-    );
+fn should_bootstrap(bootstrap: &Bootstrap, files: &[VmFile]) -> bool {
+    match bootstrap {
+        Bootstrap::Always => true,
+        Bootstrap::Never => false,
+        Bootstrap::Auto => files.iter().any(|f| f.name == "Sys"),
+    }
+}
 
-    let mut assembly_codes = vec![];
-    let mut code_writer = CodeWriter::new(String::from(path.file_stem().unwrap().to_str().unwrap()));
+fn translate(
+    file: VmFile,
+    options: &Options,
+    assembly_codes: &mut Vec<String>,
+    source_map: &mut SourceMap,
+) -> Result<(), Vec<String>> {
+    let file_name = format!("{}.vm", file.name);
+    let mut code_writer: Box<dyn CodeGenerator> = match options.codegen {
+        Codegen::Stack => {
+            let mut code_writer = CodeWriter::new(file.name);
+            if let Some(safety_checks) = &options.safety_checks {
+                code_writer.enable_safety_checks(safety_checks.clone());
+            }
+            code_writer.set_extended_ops(options.extended_ops);
+            Box::new(code_writer)
+        }
+        Codegen::Cached => {
+            let mut code_writer = CachingCodeWriter::new(file.name);
+            code_writer.set_extended_ops(options.extended_ops);
+            Box::new(code_writer)
+        }
+    };
+
+    let mut function = None;
+    for (command, line) in file.commands.into_iter().zip(file.lines) {
+        if let Command::Function(f) = &command {
+            function = Some(f.name.clone());
+        }
 
-    loop {
-        if let Some(command) = parser.advance() {
-            assembly_codes.append(&mut code_writer.code(command));
-        } else {
-            break;
+        let mut codes = vec![];
+        if options.annotate {
+            codes.push(format!("// {}:{} {}", file_name, line, command));
         }
+        let kind = command.kind();
+        codes.append(&mut code_writer.code(command));
+
+        source_map.append(&codes, Some(Location {
+            file: file_name.clone(),
+            line,
+            function: function.clone(),
+            command: kind,
+        }));
+        assembly_codes.append(&mut codes);
     }
 
-    assembly_codes
+    let codes = code_writer.finish()?;
+    source_map.append(&codes, None);
+    assembly_codes.extend(codes);
+
+    Ok(())
 }
 
-fn output_path(path: &Path) -> PathBuf {
+fn output_path(path: &Path, target: Target) -> PathBuf {
     if path.is_dir() {
         // `/foo/bar` -> `/foo/bar/bar.asm`
         let mut output_path = PathBuf::from(path);
         output_path.push(
             format!(
-                "{}.asm",
-                output_path.file_name().expect("should have file_name").to_str().unwrap()
+                "{}.{}",
+                output_path.file_name().expect("should have file_name").to_str().unwrap(),
+                target.extension(),
             )
         );
         output_path
     } else {
         // `/foo/bar.vm` -> `/foo/bar.asm`
         let mut output_path = PathBuf::from(path);
-        output_path.set_extension("asm");
+        output_path.set_extension(target.extension());
         output_path
     }
 }
@@ -0,0 +1,795 @@
+// VMコマンド列に対する最適化
+// コード生成の前に、パース済みの Command を書き換える
+use std::collections::{HashMap, HashSet};
+use crate::extended_ops::{shift_left, shift_right};
+use crate::parser::{Call, Command, MemoryAccess, MemorySegment, Operator, VmFile};
+
+// 有効にする最適化パス
+#[derive(Debug, Clone, PartialEq)]
+pub struct Passes {
+    // 定数同士の演算、定数を条件にした if-goto を畳み込む
+    pub constant_folding: bool,
+    // `push x` の直後の `pop x` を取り除く
+    pub push_pop_cancellation: bool,
+    // 比較の結果に対する `not` + `if-goto` の組み合わせなど、分岐を簡約する
+    pub branch_inversion: bool,
+    // goto, return の後の到達しないコードを取り除く
+    pub dead_code_elimination: bool,
+    // エントリーポイントから呼び出されない関数を取り除く
+    pub unused_function_elimination: bool,
+    // 小さな葉関数(他の関数を呼び出さない関数)を呼び出し箇所に展開する
+    pub inline_expansion: bool,
+    // `call f n` + `return` を呼び出し元のフレームを再利用する末尾呼び出しにする
+    pub tail_calls: bool,
+}
+
+impl Passes {
+    pub const NAMES: [&'static str; 7] = [
+        "constant-folding",
+        "push-pop",
+        "branch-inversion",
+        "dead-code",
+        "unused-functions",
+        "inline",
+        "tail-calls",
+    ];
+
+    pub fn all() -> Self {
+        Self {
+            constant_folding: true,
+            push_pop_cancellation: true,
+            branch_inversion: true,
+            dead_code_elimination: true,
+            unused_function_elimination: true,
+            inline_expansion: true,
+            tail_calls: true,
+        }
+    }
+
+    pub fn none() -> Self {
+        Self {
+            constant_folding: false,
+            push_pop_cancellation: false,
+            branch_inversion: false,
+            dead_code_elimination: false,
+            unused_function_elimination: false,
+            inline_expansion: false,
+            tail_calls: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::none()
+    }
+
+    // コマンドライン引数のパス名で有効/無効を切り替える
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        match name {
+            "constant-folding" => self.constant_folding = enabled,
+            "push-pop" => self.push_pop_cancellation = enabled,
+            "branch-inversion" => self.branch_inversion = enabled,
+            "dead-code" => self.dead_code_elimination = enabled,
+            "unused-functions" => self.unused_function_elimination = enabled,
+            "inline" => self.inline_expansion = enabled,
+            "tail-calls" => self.tail_calls = enabled,
+            other => return Err(format!("unknown optimization pass: {} (available: {})", other, Self::NAMES.join(", "))),
+        }
+        Ok(())
+    }
+}
+
+pub struct Optimizer {
+    passes: Passes,
+    // 関数の到達可能性を調べる起点
+    entry: String,
+}
+
+impl Optimizer {
+    // 変化が無くなるまで繰り返す回数の上限
+    const MAX_ITERATIONS: usize = 16;
+
+    pub fn new(passes: Passes, entry: String) -> Self {
+        Self {
+            passes,
+            entry,
+        }
+    }
+
+    pub fn optimize(&self, files: &mut [VmFile]) {
+        // 展開した結果、葉関数になった関数もさらに展開する
+        if self.passes.inline_expansion {
+            for _ in 0..Self::MAX_ITERATIONS {
+                if !inline_functions(files) {
+                    break;
+                }
+            }
+        }
+
+        for file in files.iter_mut() {
+            let commands = take_commands(file);
+            let optimized = split_functions(commands).into_iter()
+                .flat_map(|commands| self.optimize_function(commands))
+                .collect();
+            put_commands(file, optimized);
+        }
+
+        if self.passes.unused_function_elimination {
+            eliminate_unused_functions(files, &self.entry);
+        }
+    }
+
+    // 関数(ラベルのスコープ)単位の最適化
+    fn optimize_function(&self, mut commands: Vec<Line>) -> Vec<Line> {
+        for _ in 0..Self::MAX_ITERATIONS {
+            let before = commands.clone();
+            if self.passes.constant_folding {
+                commands = fold_constants(commands);
+            }
+            if self.passes.push_pop_cancellation {
+                commands = cancel_push_pop(commands);
+            }
+            if self.passes.branch_inversion {
+                commands = invert_branches(commands);
+            }
+            if self.passes.dead_code_elimination {
+                commands = eliminate_dead_code(commands);
+            }
+            if self.passes.tail_calls {
+                commands = convert_tail_calls(commands);
+            }
+            if commands == before {
+                break;
+            }
+        }
+        commands
+    }
+}
+
+// コマンドと、そのコマンドの元になった .vm の行番号
+// 最適化で作り直したコマンドには、置き換え前のコマンドの行番号を引き継ぐ
+type Line = (Command, usize);
+
+fn take_commands(file: &mut VmFile) -> Vec<Line> {
+    let commands = std::mem::take(&mut file.commands);
+    let lines = std::mem::take(&mut file.lines);
+    commands.into_iter().zip(lines).collect()
+}
+
+fn put_commands(file: &mut VmFile, commands: Vec<Line>) {
+    let (commands, lines) = commands.into_iter().unzip();
+    file.commands = commands;
+    file.lines = lines;
+}
+
+// `function` コマンドの位置でコマンド列を分割する
+// 先頭の関数の外側のコマンドは1つ目の要素になる
+fn split_functions(commands: Vec<Line>) -> Vec<Vec<Line>> {
+    let mut functions = vec![vec![]];
+    for command in commands {
+        if let Command::Function(_) = command.0 {
+            functions.push(vec![]);
+        }
+        functions.last_mut().unwrap().push(command);
+    }
+    functions
+}
+
+/////////////////////////////////////////////////////////////
+// 定数畳み込み
+/////////////////////////////////////////////////////////////
+fn push_constant(value: u16) -> Command {
+    Command::Push(MemoryAccess {
+        segment: MemorySegment::Constant,
+        index: value,
+    })
+}
+
+// `push constant` は 0..32767 しか表せないので、負の値は `push constant !v` + `not` にする
+fn materialize(value: i16, line: usize, commands: &mut Vec<Line>) {
+    if value >= 0 {
+        commands.push((push_constant(value as u16), line));
+    } else {
+        commands.push((push_constant(!value as u16), line));
+        commands.push((Command::Arithmetic(Operator::Not), line));
+    }
+}
+
+// コマンド列の末尾が定数をpushしていれば、その値とコマンド数を返す
+fn trailing_constant(commands: &[Line]) -> Option<(i16, usize)> {
+    match commands {
+        [.., (Command::Push(MemoryAccess { segment: MemorySegment::Constant, index }), _), (Command::Arithmetic(Operator::Not), _)] => {
+            Some((!(*index as i16), 2))
+        }
+        [.., (Command::Push(MemoryAccess { segment: MemorySegment::Constant, index }), _)] => {
+            Some((*index as i16, 1))
+        }
+        _ => None,
+    }
+}
+
+fn boolean(b: bool) -> i16 {
+    if b { -1 } else { 0 }
+}
+
+fn fold_constants(commands: Vec<Line>) -> Vec<Line> {
+    let mut folded: Vec<Line> = vec![];
+
+    for (command, line) in commands {
+        match &command {
+            Command::Arithmetic(operator @ (Operator::Neg | Operator::Not)) => {
+                if let Some((x, len)) = trailing_constant(&folded) {
+                    // `push constant n` + `not` はそれ自体が定数の表現なので、そのまま残す
+                    if !(*operator == Operator::Not && x >= 0) {
+                        folded.truncate(folded.len() - len);
+                        let value = if *operator == Operator::Neg { x.wrapping_neg() } else { !x };
+                        materialize(value, line, &mut folded);
+                        continue;
+                    }
+                }
+            }
+            Command::Arithmetic(operator) => {
+                if let Some((y, len_y)) = trailing_constant(&folded) {
+                    if let Some((x, len_x)) = trailing_constant(&folded[..folded.len() - len_y]) {
+                        // 0除算は実行時のエラー(トラップ)として残す
+                        if (*operator == Operator::Div || *operator == Operator::Mod) && y == 0 {
+                            folded.push((command, line));
+                            continue;
+                        }
+                        folded.truncate(folded.len() - len_y - len_x);
+                        let value = match operator {
+                            Operator::Add => x.wrapping_add(y),
+                            Operator::Sub => x.wrapping_sub(y),
+                            Operator::Eq => boolean(x == y),
+                            Operator::Gt => boolean(x > y),
+                            Operator::Lt => boolean(x < y),
+                            Operator::And => x & y,
+                            Operator::Or => x | y,
+                            Operator::Mul => x.wrapping_mul(y),
+                            Operator::Div => x.wrapping_div(y),
+                            Operator::Mod => x.wrapping_rem(y),
+                            Operator::Shl => shift_left(x, y),
+                            Operator::Shr => shift_right(x, y),
+                            Operator::Lte => boolean(x <= y),
+                            Operator::Gte => boolean(x >= y),
+                            Operator::Neq => boolean(x != y),
+                            Operator::Neg | Operator::Not => unreachable!(),
+                        };
+                        materialize(value, line, &mut folded);
+                        continue;
+                    }
+                }
+            }
+            Command::IfGoto(label) => {
+                // 条件が定数なら、無条件のgotoにするか取り除く
+                if let Some((condition, len)) = trailing_constant(&folded) {
+                    folded.truncate(folded.len() - len);
+                    if condition != 0 {
+                        folded.push((Command::Goto(label.clone()), line));
+                    }
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        folded.push((command, line));
+    }
+
+    folded
+}
+
+/////////////////////////////////////////////////////////////
+// push/pop の相殺
+/////////////////////////////////////////////////////////////
+fn cancel_push_pop(commands: Vec<Line>) -> Vec<Line> {
+    let mut cancelled: Vec<Line> = vec![];
+
+    for command in commands {
+        if let Command::Pop(pop) = &command.0 {
+            if let Some((Command::Push(push), _)) = cancelled.last() {
+                if push == pop {
+                    cancelled.pop();
+                    continue;
+                }
+            }
+        }
+        cancelled.push(command);
+    }
+
+    cancelled
+}
+
+/////////////////////////////////////////////////////////////
+// 分岐の簡約
+/////////////////////////////////////////////////////////////
+// 結果が必ず true(-1) か false(0) になる演算
+fn is_comparison(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Arithmetic(Operator::Eq | Operator::Gt | Operator::Lt | Operator::Lte | Operator::Gte | Operator::Neq)
+    )
+}
+
+fn invert_branches(commands: Vec<Line>) -> Vec<Line> {
+    let mut inverted: Vec<Line> = vec![];
+
+    for command in commands {
+        match (&command.0, inverted.as_slice()) {
+            // `not` + `not` は何もしない
+            (Command::Arithmetic(Operator::Not), [.., (Command::Arithmetic(Operator::Not), _)]) => {
+                inverted.pop();
+                continue;
+            }
+            // `not` + `if-goto L1` + `goto L2` + `label L1` -> `if-goto L2` + `label L1`
+            // if-goto は0以外なら分岐するので、`not` を外せるのは値が -1 か 0 のときだけ
+            (
+                Command::Label(label),
+                [.., (comparison, _), (Command::Arithmetic(Operator::Not), _), (Command::IfGoto(if_target), line), (Command::Goto(goto_target), _)],
+            ) if label == if_target && is_comparison(comparison) => {
+                let if_goto = (Command::IfGoto(goto_target.clone()), *line);
+                inverted.truncate(inverted.len() - 3);
+                inverted.push(if_goto);
+            }
+            // 直後のラベルへの goto は何もしない
+            (Command::Label(label), [.., (Command::Goto(target), _)]) if label == target => {
+                inverted.pop();
+            }
+            _ => {}
+        }
+        inverted.push(command);
+    }
+
+    inverted
+}
+
+/////////////////////////////////////////////////////////////
+// 到達しないコードの除去
+/////////////////////////////////////////////////////////////
+fn eliminate_dead_code(commands: Vec<Line>) -> Vec<Line> {
+    let referenced: HashSet<String> = commands.iter().filter_map(|(command, _)| {
+        match command {
+            Command::Goto(label) | Command::IfGoto(label) => Some(label.clone()),
+            _ => None,
+        }
+    }).collect();
+
+    let mut reachable = true;
+    let mut live = vec![];
+    for command in commands {
+        match &command.0 {
+            // 参照されないラベルは取り除く
+            Command::Label(label) if !referenced.contains(label) => continue,
+            Command::Label(_) | Command::Function(_) => reachable = true,
+            _ => {}
+        }
+        if !reachable {
+            continue;
+        }
+        if let Command::Goto(_) | Command::Return | Command::TailCall(_) = &command.0 {
+            reachable = false;
+        }
+        live.push(command);
+    }
+
+    live
+}
+
+/////////////////////////////////////////////////////////////
+// 末尾呼び出し
+/////////////////////////////////////////////////////////////
+fn convert_tail_calls(commands: Vec<Line>) -> Vec<Line> {
+    // 関数の外側には戻る先のフレームが無い
+    if !matches!(commands.first(), Some((Command::Function(_), _))) {
+        return commands;
+    }
+
+    let mut converted: Vec<Line> = vec![];
+    for command in commands {
+        if let Command::Return = command.0 {
+            if let Some((Command::Call(call), line)) = converted.last() {
+                let tail_call = (Command::TailCall(call.clone()), *line);
+                converted.pop();
+                converted.push(tail_call);
+                continue;
+            }
+        }
+        converted.push(command);
+    }
+
+    converted
+}
+
+/////////////////////////////////////////////////////////////
+// 葉関数のインライン展開
+/////////////////////////////////////////////////////////////
+// 展開する関数の本体のコマンド数の上限 (return を除く)
+const MAX_INLINE_COMMANDS: usize = 8;
+// 引数, ローカル変数, 退避した pointer の置き場所に使う temp セグメントの大きさ
+const TEMP_SEGMENT_SIZE: u16 = 8;
+
+struct InlineFunction {
+    // 定義されているファイル (スタティック変数のスコープ)
+    file: String,
+    // return を除いた本体
+    body: Vec<Command>,
+}
+
+impl InlineFunction {
+    // 分岐も呼び出しも無く、末尾の return だけで戻る小さな関数を展開の対象にする
+    fn new(file: &str, function: &[Line]) -> Option<Self> {
+        let num_local_variables = match function {
+            [(Command::Function(f), _), .., (Command::Return, _)] => f.num_local_variables,
+            _ => return None,
+        };
+        let body: Vec<Command> = function[1..function.len() - 1].iter().map(|(command, _)| command.clone()).collect();
+        if body.len() > MAX_INLINE_COMMANDS {
+            return None;
+        }
+
+        // return の時点でスタックに戻り値だけが積まれている
+        let mut depth: usize = 0;
+        for command in body.iter() {
+            if let Command::Push(access) | Command::Pop(access) = command {
+                if access.segment == MemorySegment::Local && access.index >= num_local_variables {
+                    return None;
+                }
+            }
+            let (required, pushed) = match command {
+                Command::Push(_) => (0, 1),
+                Command::Pop(_) => (1, 0),
+                Command::Arithmetic(Operator::Neg | Operator::Not) => (1, 1),
+                Command::Arithmetic(_) => (2, 1),
+                _ => return None,
+            };
+            if depth < required {
+                return None;
+            }
+            depth = depth - required + pushed;
+        }
+        if depth != 1 {
+            return None;
+        }
+
+        Some(Self {
+            file: file.into(),
+            body,
+        })
+    }
+
+    fn indices(&self, segment: MemorySegment, pop_only: bool) -> Vec<u16> {
+        let mut indices: Vec<u16> = self.body.iter().filter_map(|command| match command {
+            Command::Pop(access) if access.segment == segment => Some(access.index),
+            Command::Push(access) if access.segment == segment && !pop_only => Some(access.index),
+            _ => None,
+        }).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+
+    // 呼び出し箇所に展開するコマンド列
+    // 引数とローカル変数は temp に置き換え、書き換えられる pointer は退避して元に戻す
+    fn expand(&self, call: &Call, file: &str, free_temps: &[u16]) -> Option<Vec<Command>> {
+        // 別のファイルのスタティック変数は参照できない
+        if self.file != file && !self.indices(MemorySegment::Static, false).is_empty() {
+            return None;
+        }
+        let arguments = self.indices(MemorySegment::Argument, false);
+        if arguments.iter().any(|i| *i >= call.num_arguments) {
+            return None;
+        }
+        let locals = self.indices(MemorySegment::Local, false);
+        let pointers = self.indices(MemorySegment::Pointer, true);
+        let num_arguments = call.num_arguments as usize;
+        if num_arguments + locals.len() + pointers.len() > free_temps.len() {
+            return None;
+        }
+
+        let temp = |index: u16| MemoryAccess { segment: MemorySegment::Temp, index };
+        let pointer = |index: u16| MemoryAccess { segment: MemorySegment::Pointer, index };
+        let (argument_temps, rest) = free_temps.split_at(num_arguments);
+        let (local_temps, pointer_temps) = rest.split_at(locals.len());
+        let local_temp = |index: u16| local_temps[locals.iter().position(|l| *l == index).unwrap()];
+
+        let mut commands = vec![];
+        for t in argument_temps.iter().rev() {
+            commands.push(Command::Pop(temp(*t)));
+        }
+        for t in local_temps.iter() {
+            commands.push(push_constant(0));
+            commands.push(Command::Pop(temp(*t)));
+        }
+        for (p, t) in pointers.iter().zip(pointer_temps) {
+            commands.push(Command::Push(pointer(*p)));
+            commands.push(Command::Pop(temp(*t)));
+        }
+        for command in self.body.iter() {
+            let remap = |access: &MemoryAccess| match access.segment {
+                MemorySegment::Argument => temp(argument_temps[access.index as usize]),
+                MemorySegment::Local => temp(local_temp(access.index)),
+                _ => access.clone(),
+            };
+            commands.push(match command {
+                Command::Push(access) => Command::Push(remap(access)),
+                Command::Pop(access) => Command::Pop(remap(access)),
+                other => other.clone(),
+            });
+        }
+        for (p, t) in pointers.iter().zip(pointer_temps) {
+            commands.push(Command::Push(temp(*t)));
+            commands.push(Command::Pop(pointer(*p)));
+        }
+        Some(commands)
+    }
+}
+
+// 展開できた呼び出しがあれば true
+fn inline_functions(files: &mut [VmFile]) -> bool {
+    let mut candidates: HashMap<String, Option<InlineFunction>> = HashMap::new();
+    for file in files.iter() {
+        let commands: Vec<Line> = file.commands.iter().cloned().zip(file.lines.iter().cloned()).collect();
+        for function in split_functions(commands) {
+            if let Some((Command::Function(f), _)) = function.first() {
+                // 同じ名前の関数が複数あればどちらも展開しない
+                let candidate = InlineFunction::new(&file.name, &function);
+                let duplicated = candidates.contains_key(&f.name);
+                candidates.insert(f.name.clone(), if duplicated { None } else { candidate });
+            }
+        }
+    }
+    if candidates.values().all(|candidate| candidate.is_none()) {
+        return false;
+    }
+
+    let mut changed = false;
+    for file in files.iter_mut() {
+        let commands = take_commands(file);
+        let mut expanded = vec![];
+        for function in split_functions(commands) {
+            // 呼び出し元と展開する関数のどちらも使っていない temp だけを使う
+            let used_temps = temp_indices(function.iter().map(|(command, _)| command));
+            for (command, line) in function {
+                let inlined = match &command {
+                    Command::Call(call) => candidates.get(&call.function_name)
+                        .and_then(|candidate| candidate.as_ref())
+                        .and_then(|candidate| {
+                            let mut used = used_temps.clone();
+                            used.extend(temp_indices(candidate.body.iter()));
+                            let free: Vec<u16> = (0..TEMP_SEGMENT_SIZE).filter(|t| !used.contains(t)).collect();
+                            candidate.expand(call, &file.name, &free)
+                        }),
+                    _ => None,
+                };
+                match inlined {
+                    Some(commands) => {
+                        expanded.extend(commands.into_iter().map(|command| (command, line)));
+                        changed = true;
+                    }
+                    None => expanded.push((command, line)),
+                }
+            }
+        }
+        put_commands(file, expanded);
+    }
+    changed
+}
+
+fn temp_indices<'a>(commands: impl Iterator<Item = &'a Command>) -> HashSet<u16> {
+    commands.filter_map(|command| match command {
+        Command::Push(MemoryAccess { segment: MemorySegment::Temp, index })
+        | Command::Pop(MemoryAccess { segment: MemorySegment::Temp, index }) => Some(*index),
+        _ => None,
+    }).collect()
+}
+
+/////////////////////////////////////////////////////////////
+// 使われない関数の除去
+/////////////////////////////////////////////////////////////
+fn eliminate_unused_functions(files: &mut [VmFile], entry: &str) {
+    // 関数名 -> 呼び出す関数
+    let mut calls: HashMap<String, Vec<String>> = HashMap::new();
+    for file in files.iter() {
+        let mut current = None;
+        for command in file.commands.iter() {
+            match command {
+                Command::Function(function) => {
+                    current = Some(function.name.clone());
+                    calls.entry(function.name.clone()).or_default();
+                }
+                Command::Call(call) | Command::TailCall(call) => {
+                    if let Some(current) = &current {
+                        calls.get_mut(current).unwrap().push(call.function_name.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // エントリーポイントが定義されていない場合は到達可能性を判断できない
+    if !calls.contains_key(entry) {
+        return;
+    }
+
+    let mut reachable = HashSet::new();
+    let mut stack = vec![entry.to_string()];
+    while let Some(name) = stack.pop() {
+        if !reachable.insert(name.clone()) {
+            continue;
+        }
+        if let Some(callees) = calls.get(&name) {
+            stack.extend(callees.iter().cloned());
+        }
+    }
+
+    for file in files.iter_mut() {
+        let commands = take_commands(file);
+        let used = split_functions(commands).into_iter()
+            .filter(|function| {
+                match function.first() {
+                    Some((Command::Function(f), _)) => reachable.contains(&f.name),
+                    _ => true,
+                }
+            })
+            .flatten()
+            .collect();
+        put_commands(file, used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm_file(name: &str, commands: &[&str]) -> VmFile {
+        VmFile {
+            name: name.into(),
+            commands: commands.iter().map(|command| Command::parse(command)).collect(),
+            lines: (1..=commands.len()).collect(),
+        }
+    }
+
+    fn to_strings(file: &VmFile) -> Vec<String> {
+        file.commands.iter().map(|command| command.to_string()).collect()
+    }
+
+    fn only(name: &str) -> Passes {
+        let mut passes = Passes::none();
+        passes.set(name, true).unwrap();
+        passes
+    }
+
+    fn optimize(passes: Passes, commands: &[&str]) -> Vec<String> {
+        let mut files = vec![vm_file("Test", commands)];
+        Optimizer::new(passes, "Sys.init".into()).optimize(&mut files);
+        to_strings(&files[0])
+    }
+
+    #[test]
+    fn fold_constants() {
+        // 2 + 3 - 10 = -5
+        assert_eq!(
+            optimize(only("constant-folding"), &["push constant 2", "push constant 3", "add", "push constant 10", "sub"]),
+            vec!["push constant 4", "not"],
+        );
+        // -3 < 1
+        assert_eq!(
+            optimize(only("constant-folding"), &["push constant 3", "neg", "push constant 1", "lt"]),
+            vec!["push constant 0", "not"],
+        );
+        // 負の定数の表現はそのまま
+        assert_eq!(optimize(only("constant-folding"), &["push constant 5", "not"]), vec!["push constant 5", "not"]);
+        // 0除算は実行時に残す
+        assert_eq!(
+            optimize(only("constant-folding"), &["push constant 1", "push constant 0", "div"]),
+            vec!["push constant 1", "push constant 0", "div"],
+        );
+    }
+
+    #[test]
+    fn fold_constant_conditions() {
+        assert_eq!(
+            optimize(only("constant-folding"), &["push constant 0", "if-goto A", "push constant 5", "if-goto B", "label A", "label B"]),
+            vec!["goto B", "label A", "label B"],
+        );
+    }
+
+    #[test]
+    fn cancel_push_pop() {
+        assert_eq!(
+            optimize(only("push-pop"), &["push local 0", "pop local 0", "push local 0", "pop local 1"]),
+            vec!["push local 0", "pop local 1"],
+        );
+    }
+
+    #[test]
+    fn invert_branches_on_comparisons() {
+        assert_eq!(
+            optimize(only("branch-inversion"), &[
+                "push local 0", "push constant 0", "eq", "not", "if-goto L1", "goto L2",
+                "label L1", "push constant 1", "pop local 0", "label L2",
+            ]),
+            vec!["push local 0", "push constant 0", "eq", "if-goto L2", "label L1", "push constant 1", "pop local 0", "label L2"],
+        );
+    }
+
+    #[test]
+    fn keep_not_before_if_goto_on_other_values() {
+        // local 0 が 5 なら `not` の結果も0ではないので L1 に分岐する
+        let commands = [
+            "push local 0", "not", "if-goto L1", "goto L2",
+            "label L1", "push constant 1", "pop local 0", "label L2",
+        ];
+        assert_eq!(optimize(only("branch-inversion"), &commands), commands);
+    }
+
+    #[test]
+    fn remove_double_not_and_goto_to_next_label() {
+        assert_eq!(
+            optimize(only("branch-inversion"), &["push local 0", "not", "not", "pop local 1", "goto L", "label L"]),
+            vec!["push local 0", "pop local 1", "label L"],
+        );
+    }
+
+    #[test]
+    fn eliminate_dead_code() {
+        assert_eq!(
+            optimize(only("dead-code"), &[
+                "function Test.f 0", "push argument 0", "if-goto L", "push constant 0", "return",
+                "push constant 9", "label UNUSED", "push constant 8", "label L", "push constant 1", "return",
+            ]),
+            vec!["function Test.f 0", "push argument 0", "if-goto L", "push constant 0", "return", "label L", "push constant 1", "return"],
+        );
+    }
+
+    #[test]
+    fn convert_tail_calls() {
+        assert_eq!(
+            optimize(only("tail-calls"), &["function Test.f 1", "push argument 0", "call Test.g 1", "return"]),
+            vec!["function Test.f 1", "push argument 0", "tail-call Test.g 1"],
+        );
+        // 関数の外側の call + return は変えない
+        assert_eq!(optimize(only("tail-calls"), &["call Test.g 0", "return"]), vec!["call Test.g 0", "return"]);
+    }
+
+    #[test]
+    fn inline_leaf_functions() {
+        let commands = [
+            "function Test.add 0", "push argument 0", "push argument 1", "add", "return",
+            "function Test.main 0", "push constant 1", "push constant 2", "call Test.add 2", "return",
+        ];
+        assert_eq!(
+            optimize(only("inline"), &commands),
+            vec![
+                "function Test.add 0", "push argument 0", "push argument 1", "add", "return",
+                "function Test.main 0", "push constant 1", "push constant 2",
+                "pop temp 1", "pop temp 0", "push temp 0", "push temp 1", "add",
+                "return",
+            ],
+        );
+    }
+
+    #[test]
+    fn do_not_inline_functions_with_calls_or_branches() {
+        let commands = [
+            "function Test.g 0", "call Test.h 0", "return",
+            "function Test.h 0", "push constant 0", "if-goto L", "label L", "push constant 1", "return",
+            "function Test.main 0", "call Test.g 0", "call Test.h 0", "return",
+        ];
+        assert_eq!(optimize(only("inline"), &commands), commands);
+    }
+
+    #[test]
+    fn eliminate_unused_functions() {
+        let mut files = vec![
+            vm_file("Sys", &["function Sys.init 0", "call Main.main 0", "return"]),
+            vm_file("Main", &["function Main.main 0", "push constant 0", "return", "function Main.unused 0", "push constant 1", "return"]),
+        ];
+        Optimizer::new(only("unused-functions"), "Sys.init".into()).optimize(&mut files);
+        assert_eq!(to_strings(&files[1]), vec!["function Main.main 0", "push constant 0", "return"]);
+
+        // エントリーポイントが無ければ何も取り除かない
+        let mut files = vec![vm_file("Main", &["function Main.main 0", "push constant 0", "return"])];
+        Optimizer::new(only("unused-functions"), "Sys.init".into()).optimize(&mut files);
+        assert_eq!(to_strings(&files[0]), vec!["function Main.main 0", "push constant 0", "return"]);
+    }
+}
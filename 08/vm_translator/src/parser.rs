@@ -1,12 +1,17 @@
 use std::io::{BufReader, BufRead};
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::fmt;
 
 // 表7-1 Parserモジュール
-pub struct Parser {
-    reader: BufReader<File>,
+// ファイルの他に、標準入力や文字列(&[u8])など任意の BufRead から読み込める
+pub struct Parser<R: BufRead> {
+    reader: R,
+    // 最後に読み込んだ行の行番号 (1始まり)
+    line_number: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Arithmetic(Operator),
     Push(MemoryAccess),
@@ -17,9 +22,12 @@ pub enum Command {
     Function(Function),
     Call(Call),
     Return,
+    // `call f n` + `return` を呼び出し元のフレームを再利用する呼び出しにしたもの
+    // 最適化で作られる。Display の出力を読み戻せるように `tail-call f n` として .vm にも書ける (拡張命令)
+    TailCall(Call),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Operator {
     Add,
     Sub,
@@ -30,9 +38,26 @@ pub enum Operator {
     And,
     Or,
     Not,
+    // 拡張命令 (本のVM仕様には無い)
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    Lte,
+    Gte,
+    Neq,
 }
 
 impl Operator {
+    pub fn is_extended(&self) -> bool {
+        matches!(
+            self,
+            Operator::Mul | Operator::Div | Operator::Mod | Operator::Shl | Operator::Shr
+                | Operator::Lte | Operator::Gte | Operator::Neq
+        )
+    }
+
     fn from(s: &str) -> Option<Self> {
         match s {
             "add" => Some(Operator::Add),
@@ -44,12 +69,45 @@ impl Operator {
             "and" => Some(Operator::And),
             "or" => Some(Operator::Or),
             "not" => Some(Operator::Not),
+            "mul" => Some(Operator::Mul),
+            "div" => Some(Operator::Div),
+            "mod" => Some(Operator::Mod),
+            "shl" => Some(Operator::Shl),
+            "shr" => Some(Operator::Shr),
+            "lte" => Some(Operator::Lte),
+            "gte" => Some(Operator::Gte),
+            "neq" => Some(Operator::Neq),
             _ => None,
         }
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Operator::Add => "add",
+            Operator::Sub => "sub",
+            Operator::Neg => "neg",
+            Operator::Eq => "eq",
+            Operator::Gt => "gt",
+            Operator::Lt => "lt",
+            Operator::And => "and",
+            Operator::Or => "or",
+            Operator::Not => "not",
+            Operator::Mul => "mul",
+            Operator::Div => "div",
+            Operator::Mod => "mod",
+            Operator::Shl => "shl",
+            Operator::Shr => "shr",
+            Operator::Lte => "lte",
+            Operator::Gte => "gte",
+            Operator::Neq => "neq",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MemorySegment {
     // アーキテクチャ上で物理領域を占有しない、仮想的な存在
     Constant,
@@ -66,47 +124,65 @@ pub enum MemorySegment {
 }
 
 impl MemorySegment {
-    fn from(s: &str) -> Self {
+    fn from(s: &str) -> Result<Self, String> {
         match s {
-            "constant" => MemorySegment::Constant,
-            "local" => MemorySegment::Local,
-            "argument" => MemorySegment::Argument,
-            "this" => MemorySegment::This,
-            "that" => MemorySegment::That,
-            "pointer" => MemorySegment::Pointer,
-            "temp" => MemorySegment::Temp,
-            "static" => MemorySegment::Static,
-            _ => panic!(),
+            "constant" => Ok(MemorySegment::Constant),
+            "local" => Ok(MemorySegment::Local),
+            "argument" => Ok(MemorySegment::Argument),
+            "this" => Ok(MemorySegment::This),
+            "that" => Ok(MemorySegment::That),
+            "pointer" => Ok(MemorySegment::Pointer),
+            "temp" => Ok(MemorySegment::Temp),
+            "static" => Ok(MemorySegment::Static),
+            _ => Err(format!("unknown segment: {}", s)),
         }
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for MemorySegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            MemorySegment::Constant => "constant",
+            MemorySegment::Local => "local",
+            MemorySegment::Argument => "argument",
+            MemorySegment::This => "this",
+            MemorySegment::That => "that",
+            MemorySegment::Pointer => "pointer",
+            MemorySegment::Temp => "temp",
+            MemorySegment::Static => "static",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for MemoryAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.segment, self.index)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MemoryAccess {
     pub segment: MemorySegment,
     pub index: u16,
 }
 
 impl MemoryAccess {
-    fn from(segment: &str, index: u16) -> Self {
-        let segment = MemorySegment::from(segment);
+    fn from(segment: &str, index: u16) -> Result<Self, String> {
+        let segment = MemorySegment::from(segment)?;
         match segment {
-            MemorySegment::Pointer => {
-                if index > 3 {
-                    panic!(format!("index {} is not supported", index));
-                }
+            MemorySegment::Pointer if index > 3 => {
+                return Err(format!("index {} is not supported", index));
             }
-            MemorySegment::Temp => {
-                if index > 7 {
-                    panic!(format!("index {} is not supported", index));
-                }
+            MemorySegment::Temp if index > 7 => {
+                return Err(format!("index {} is not supported", index));
             }
             _ => {}
         }
-        Self {
+        Ok(Self {
             segment,
             index,
-        }
+        })
     }
 
     pub fn get_static_address(&self) -> u16 {
@@ -118,7 +194,7 @@ impl MemoryAccess {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Function {
     pub name: String,
     pub num_local_variables: u16,
@@ -133,7 +209,7 @@ impl Function {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Call {
     pub function_name: String,
     pub num_arguments: u16,
@@ -148,13 +224,95 @@ impl Call {
     }
 }
 
-impl Parser {
-    pub fn new(file: File) -> Self {
+// .vm の構文で出力する
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Arithmetic(operator) => write!(f, "{}", operator),
+            Command::Push(memory_access) => write!(f, "push {}", memory_access),
+            Command::Pop(memory_access) => write!(f, "pop {}", memory_access),
+            Command::Label(label) => write!(f, "label {}", label),
+            Command::IfGoto(label) => write!(f, "if-goto {}", label),
+            Command::Goto(label) => write!(f, "goto {}", label),
+            Command::Function(function) => write!(f, "function {} {}", function.name, function.num_local_variables),
+            Command::Call(call) => write!(f, "call {} {}", call.function_name, call.num_arguments),
+            Command::Return => write!(f, "return"),
+            Command::TailCall(call) => write!(f, "tail-call {} {}", call.function_name, call.num_arguments),
+        }
+    }
+}
+
+// 1つの .vm ファイルをパースした結果
+#[derive(Debug)]
+pub struct VmFile {
+    // スタティック変数のスコープになるファイル名(拡張子なし)
+    pub name: String,
+    pub commands: Vec<Command>,
+    // 各コマンドが書かれていた行番号 (commands と同じ長さ)
+    pub lines: Vec<usize>,
+}
+
+impl VmFile {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        Ok(Self::read(String::from(path.file_stem().unwrap().to_str().unwrap()), file))
+    }
+
+    // name はスタティック変数のシンボル(`name.i`)に使う
+    pub fn read<R: BufRead>(name: String, reader: R) -> Self {
+        let mut parser = Parser::new(reader);
+
+        let mut commands = vec![];
+        let mut lines = vec![];
+        while let Some(command) = parser.advance() {
+            commands.push(command);
+            lines.push(parser.line_number());
+        }
+
+        Self {
+            name,
+            commands,
+            lines,
+        }
+    }
+}
+
+pub fn is_vm_file(path: &Path) -> bool {
+    path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("vm")
+}
+
+// path がディレクトリならその中の .vm ファイル、.vm ファイルならそれ自身を返す
+pub fn vm_files(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if path.is_dir() {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(path)? {
+            let file = entry?.path();
+            if is_vm_file(&file) {
+                files.push(file);
+            }
+        }
+        // read_dir の順序はファイルシステム依存なので、出力を再現可能にするためソートする
+        files.sort();
+        Ok(files)
+    } else if is_vm_file(path) {
+        Ok(vec![path.to_path_buf()])
+    } else {
+        Ok(vec![])
+    }
+}
+
+impl<R: BufRead> Parser<R> {
+    pub fn new(reader: R) -> Self {
         Self {
-            reader: BufReader::new(file),
+            reader,
+            line_number: 0,
         }
     }
 
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
     pub fn advance(&mut self) -> Option<Command> {
         let mut buf = String::new();
 
@@ -164,6 +322,7 @@ impl Parser {
                     // EOF
                     return None;
                 }
+                self.line_number += 1;
 
                 // コメント以降を削除
                 if let Some(pos) = buf.find("//") {
@@ -173,61 +332,102 @@ impl Parser {
                 // スペースを削除
                 buf = String::from(buf.trim());
 
-                if buf.len() == 0 {
+                if buf.is_empty() {
                     continue;
                 }
 
-                return Some(Self::parse(buf.as_str()));
+                return Some(Command::parse(buf.as_str()));
             } else {
                 panic!();
             }
         }
     }
+}
+
+impl Command {
+    // 集計に使うコマンドの種類 (`push constant`, `add`, `call` など)
+    pub fn kind(&self) -> String {
+        match self {
+            Command::Push(memory_access) => format!("push {}", memory_access.segment),
+            Command::Pop(memory_access) => format!("pop {}", memory_access.segment),
+            Command::Arithmetic(operator) => operator.to_string(),
+            Command::Label(_) => "label".into(),
+            Command::IfGoto(_) => "if-goto".into(),
+            Command::Goto(_) => "goto".into(),
+            Command::Function(_) => "function".into(),
+            Command::Call(_) => "call".into(),
+            Command::Return => "return".into(),
+            Command::TailCall(_) => "tail-call".into(),
+        }
+    }
 
-    fn parse(s: &str) -> Command {
-        let elems: Vec<&str> = s.split(" ").collect();
+    // コメントを除いた1行分のコマンドをパースする
+    // 要素の間の空白は何文字でもよい (タブを含む)
+    pub fn parse(s: &str) -> Command {
+        Self::try_parse(s).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        match elems[0] {
+    // parse と同じだが、パースできない場合はエラーを返す
+    pub fn try_parse(s: &str) -> Result<Command, String> {
+        let elems: Vec<&str> = s.split_whitespace().collect();
+        let arguments = |n: usize| {
+            if elems.len() == n + 1 {
+                Ok(())
+            } else if n == 0 {
+                Err(format!("{} command requires no arguments", elems[0]))
+            } else if n == 1 {
+                Err(format!("{} command requires 1 argument", elems[0]))
+            } else {
+                Err(format!("{} command requires {} arguments", elems[0], n))
+            }
+        };
+        let number = |s: &str| s.parse::<u16>().map_err(|_| format!("invalid number: {}", s));
+
+        let command = match *elems.first().ok_or("empty command")? {
             "push" => {
-                assert_eq!(elems.len(), 3, "push command requires 2 arguments");
-                Command::Push(MemoryAccess::from(elems[1], elems[2].parse::<u16>().unwrap()))
+                arguments(2)?;
+                Command::Push(MemoryAccess::from(elems[1], number(elems[2])?)?)
             },
             "pop" => {
-                assert_eq!(elems.len(), 3, "pop command requires 2 arguments");
-                Command::Pop(MemoryAccess::from(elems[1], elems[2].parse::<u16>().unwrap()))
+                arguments(2)?;
+                Command::Pop(MemoryAccess::from(elems[1], number(elems[2])?)?)
             }
             "label" => {
-                assert_eq!(elems.len(), 2, "label command requires 1 argument");
+                arguments(1)?;
                 Command::Label(String::from(elems[1]))
             }
             "if-goto" => {
-                assert_eq!(elems.len(), 2, "if-goto command requires 1 argument");
+                arguments(1)?;
                 Command::IfGoto(String::from(elems[1]))
             }
             "goto" => {
-                assert_eq!(elems.len(), 2, "goto command requires 1 argument");
+                arguments(1)?;
                 Command::Goto(String::from(elems[1]))
             }
             "function" => {
-                assert_eq!(elems.len(), 3, "function command requires 2 arguments");
-                Command::Function(Function::new(String::from(elems[1]), elems[2].parse::<u16>().unwrap()))
+                arguments(2)?;
+                Command::Function(Function::new(String::from(elems[1]), number(elems[2])?))
             }
             "call" => {
-                assert_eq!(elems.len(), 3, "call command requires 2 arguments");
-                Command::Call(Call::new(String::from(elems[1]), elems[2].parse::<u16>().unwrap()))
+                arguments(2)?;
+                Command::Call(Call::new(String::from(elems[1]), number(elems[2])?))
+            }
+            "tail-call" => {
+                arguments(2)?;
+                Command::TailCall(Call::new(String::from(elems[1]), number(elems[2])?))
             }
             "return" => {
-                assert_eq!(elems.len(), 1, "return command requires no arguments");
+                arguments(0)?;
                 Command::Return
             }
-            other => {
-                if let Some(arithmetic_operator) = Operator::from(other) {
-                    assert_eq!(elems.len(), 1, "arithmetic command requires no arguments");
-                    return Command::Arithmetic(arithmetic_operator);
+            other => match Operator::from(other) {
+                Some(arithmetic_operator) => {
+                    arguments(0)?;
+                    Command::Arithmetic(arithmetic_operator)
                 }
-
-                panic!(format!("the operator is not supported: {}", other));
-            }
-        }
+                None => return Err(format!("the operator is not supported: {}", other)),
+            },
+        };
+        Ok(command)
     }
 }
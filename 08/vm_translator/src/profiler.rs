@@ -0,0 +1,105 @@
+// 変換したプログラムをHackコンピュータのエミュレータで実行し、
+// VMの関数ごと、コマンドの種類ごとに実行した命令数(サイクル数)を集計する
+// ROMアドレスと .vm のコマンドの対応はソースマップから引く
+use std::collections::HashMap;
+use crate::hack_cpu::Cpu;
+use crate::source_map::SourceMap;
+
+// .vm のコマンドに対応しないコード (ブートストラップ, 拡張命令や call/return の共通ルーチン, トラップ)
+const RUNTIME: &str = "(runtime)";
+// 関数の外側のコマンド
+const TOP_LEVEL: &str = "(top level)";
+
+#[derive(Debug, Default)]
+struct Counter {
+    cycles: u64,
+    // 関数の呼び出し回数 (コマンドの種類では使わない)
+    calls: u64,
+}
+
+pub struct Profile {
+    functions: HashMap<String, Counter>,
+    commands: HashMap<String, Counter>,
+    cycles: u64,
+    // max_cycles に達する前にプログラムの終端のループに入った (またはプログラムの外に出た)
+    halted: bool,
+}
+
+impl Profile {
+    // 終端のループに入るか max_cycles 命令を実行するまで実行する
+    // (ループ自体の命令は数えない)
+    pub fn run(cpu: &mut Cpu, source_map: &SourceMap, max_cycles: u64) -> Self {
+        let table = source_map.rom_table();
+        let entries: HashMap<usize, &str> = source_map.function_entries().into_iter().collect();
+
+        let mut functions: HashMap<String, Counter> = HashMap::new();
+        let mut commands: HashMap<String, Counter> = HashMap::new();
+        let start = cpu.cycles;
+        // 直前に実行した命令のコマンド (ループで関数の先頭に戻った場合は呼び出しに数えない)
+        let mut previous = RUNTIME;
+        let stopped = |cpu: &Cpu| cpu.is_halted() || cpu.is_in_end_loop();
+        while cpu.cycles - start < max_cycles && !stopped(cpu) {
+            let pc = cpu.pc;
+            let (function, command) = match table.get(pc).copied().flatten() {
+                Some(location) => (location.function.as_deref().unwrap_or(TOP_LEVEL), location.command.as_str()),
+                None => (RUNTIME, RUNTIME),
+            };
+
+            let counter = functions.entry(function.into()).or_default();
+            counter.cycles += 1;
+            if let Some(name) = entries.get(&pc) {
+                if let "call" | "tail-call" | RUNTIME = previous {
+                    functions.entry(String::from(*name)).or_default().calls += 1;
+                }
+            }
+            commands.entry(command.into()).or_default().cycles += 1;
+            previous = command;
+
+            cpu.step();
+        }
+
+        Self {
+            functions,
+            commands,
+            cycles: cpu.cycles - start,
+            halted: stopped(cpu),
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "total: {} cycles ({})\n",
+            self.cycles,
+            if self.halted { "halted" } else { "stopped at the cycle limit" },
+        );
+
+        report.push_str("\ncycles\t%\tcalls\tfunction\n");
+        for (name, counter) in Self::sorted(&self.functions) {
+            report.push_str(&format!(
+                "{}\t{:.1}\t{}\t{}\n",
+                counter.cycles, self.percentage(counter.cycles), counter.calls, name,
+            ));
+        }
+
+        report.push_str("\ncycles\t%\tcommand\n");
+        for (name, counter) in Self::sorted(&self.commands) {
+            report.push_str(&format!("{}\t{:.1}\t{}\n", counter.cycles, self.percentage(counter.cycles), name));
+        }
+        report
+    }
+
+    fn percentage(&self, cycles: u64) -> f64 {
+        if self.cycles == 0 {
+            0.0
+        } else {
+            cycles as f64 * 100.0 / self.cycles as f64
+        }
+    }
+
+    // サイクル数の多い順 (同じなら名前順)
+    fn sorted(counters: &HashMap<String, Counter>) -> Vec<(&String, &Counter)> {
+        let mut sorted: Vec<(&String, &Counter)> = counters.iter().collect();
+        sorted.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+        sorted
+    }
+}
@@ -0,0 +1,136 @@
+// 生成したアセンブリの行と、元になった .vm のコマンドとの対応表
+// エミュレータやデバッガでVMのコマンド単位のステップ実行をするために JSON で出力する
+
+// .vm 上の位置
+#[derive(Debug, Clone)]
+pub struct Location {
+    // `Main.vm`
+    pub file: String,
+    pub line: usize,
+    // 関数の外側のコマンドの場合はNone
+    pub function: Option<String>,
+    // コマンドの種類 (Command::kind)
+    pub command: String,
+}
+
+#[derive(Debug)]
+struct Mapping {
+    // .asm の行 (1始まり、両端を含む)
+    asm_start: usize,
+    asm_end: usize,
+    // ROMアドレス (end は含まない)
+    rom_start: usize,
+    rom_end: usize,
+    location: Location,
+}
+
+pub struct SourceMap {
+    mappings: Vec<Mapping>,
+    next_line: usize,
+    next_rom: usize,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self {
+            mappings: vec![],
+            next_line: 1,
+            next_rom: 0,
+        }
+    }
+
+    // アセンブリを出力した順に呼び出す
+    // ブートストラップコードなど .vm に対応しないコードは location を None にする
+    pub fn append(&mut self, codes: &[String], location: Option<Location>) {
+        let rom_length = codes.iter().filter(|code| Self::is_instruction(code)).count();
+
+        if let Some(location) = location {
+            if !codes.is_empty() {
+                self.mappings.push(Mapping {
+                    asm_start: self.next_line,
+                    asm_end: self.next_line + codes.len() - 1,
+                    rom_start: self.next_rom,
+                    rom_end: self.next_rom + rom_length,
+                    location,
+                });
+            }
+        }
+
+        self.next_line += codes.len();
+        self.next_rom += rom_length;
+    }
+
+    // ラベルとコメント以外はROMに1ワードずつ配置される
+    fn is_instruction(code: &str) -> bool {
+        let code = code.trim();
+        !(code.is_empty() || code.starts_with('(') || code.starts_with("//"))
+    }
+
+    // ROMアドレスから .vm 上の位置を引く表 (ブートストラップコードなどはNone)
+    pub fn rom_table(&self) -> Vec<Option<&Location>> {
+        let mut table = vec![None; self.next_rom];
+        for mapping in self.mappings.iter() {
+            for entry in table[mapping.rom_start..mapping.rom_end].iter_mut() {
+                *entry = Some(&mapping.location);
+            }
+        }
+        table
+    }
+
+    // 各関数の先頭 (`function` コマンド) のROMアドレス
+    pub fn function_entries(&self) -> Vec<(usize, &str)> {
+        self.mappings.iter()
+            .filter(|mapping| mapping.location.command == "function")
+            .filter_map(|mapping| Some((mapping.rom_start, mapping.location.function.as_deref()?)))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        json.push_str("{\n");
+        json.push_str("  \"version\": 1,\n");
+        json.push_str("  \"mappings\": [");
+        for (i, mapping) in self.mappings.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str(&format!(
+                "\n    {{\"asm_start\": {}, \"asm_end\": {}, \"rom_start\": {}, \"rom_end\": {}, \"file\": {}, \"line\": {}, \"function\": {}, \"command\": {}}}",
+                mapping.asm_start,
+                mapping.asm_end,
+                mapping.rom_start,
+                mapping.rom_end,
+                json_string(&mapping.location.file),
+                mapping.location.line,
+                match &mapping.location.function {
+                    Some(function) => json_string(function),
+                    None => "null".into(),
+                },
+                json_string(&mapping.location.command),
+            ));
+        }
+        json.push_str("\n  ]\n");
+        json.push_str("}\n");
+        json
+    }
+}
+
+impl Default for SourceMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
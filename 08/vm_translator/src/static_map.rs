@@ -0,0 +1,132 @@
+// スタティック変数のRAM割り当て
+// スタティック変数は `@Xxx.i` のシンボルとして出力され、アセンブラが最初に現れた順に
+// RAM[16] から割り当てる。RAM[256] からはスタックなので、使えるのは240ワードだけ
+use std::collections::{HashMap, HashSet};
+
+pub const STATIC_BASE: u16 = 16;
+pub const STATIC_END: u16 = 255;
+
+const PREDEFINED_SYMBOLS: [&str; 23] = [
+    "SP", "LCL", "ARG", "THIS", "THAT",
+    "R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7",
+    "R8", "R9", "R10", "R11", "R12", "R13", "R14", "R15",
+    "SCREEN", "KBD",
+];
+
+#[derive(Debug)]
+pub struct Variable {
+    pub symbol: String,
+    // `Xxx.i` の Xxx と i (スタティック変数以外の変数の場合はNone)
+    pub static_variable: Option<(String, u16)>,
+    pub address: u16,
+}
+
+pub struct StaticMap {
+    variables: Vec<Variable>,
+    // ラベルとしても定義されているスタティック変数のシンボル
+    collisions: Vec<String>,
+}
+
+impl StaticMap {
+    // アセンブラと同じ順序で変数にアドレスを割り当てる
+    // classes は変換した .vm のファイル名(拡張子なし)
+    pub fn from_assembly(codes: &[String], classes: &[String]) -> Self {
+        let labels: HashSet<&str> = codes.iter()
+            .filter(|code| code.starts_with('('))
+            .map(|code| code.trim_start_matches('(').trim_end_matches(')'))
+            .collect();
+
+        let mut variables = vec![];
+        let mut addresses: HashMap<&str, u16> = HashMap::new();
+        let mut collisions = vec![];
+        for code in codes.iter() {
+            let symbol = match code.strip_prefix('@') {
+                Some(symbol) => symbol,
+                None => continue,
+            };
+            if symbol.parse::<u16>().is_ok() || PREDEFINED_SYMBOLS.contains(&symbol) || addresses.contains_key(symbol) {
+                continue;
+            }
+
+            let static_variable = Self::static_variable(symbol, classes);
+            if labels.contains(symbol) {
+                if static_variable.is_some() && !collisions.iter().any(|s| s == symbol) {
+                    collisions.push(String::from(symbol));
+                }
+                continue;
+            }
+
+            let address = STATIC_BASE + addresses.len() as u16;
+            addresses.insert(symbol, address);
+            variables.push(Variable {
+                symbol: String::from(symbol),
+                static_variable,
+                address,
+            });
+        }
+
+        Self {
+            variables,
+            collisions,
+        }
+    }
+
+    fn static_variable(symbol: &str, classes: &[String]) -> Option<(String, u16)> {
+        let pos = symbol.rfind('.')?;
+        let (class, index) = (&symbol[..pos], &symbol[pos + 1..]);
+        if !classes.iter().any(|c| c == class) {
+            return None;
+        }
+        Some((String::from(class), index.parse::<u16>().ok()?))
+    }
+
+    // 割り当てがスタックの領域まであふれていないか、ラベルと衝突していないかを検査する
+    pub fn check(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+
+        let available = (STATIC_END - STATIC_BASE + 1) as usize;
+        if self.variables.len() > available {
+            let mut usage: Vec<(String, usize)> = vec![];
+            for variable in self.variables.iter() {
+                let name = match &variable.static_variable {
+                    Some((class, _)) => class.clone(),
+                    None => format!("(variable {})", variable.symbol),
+                };
+                match usage.iter_mut().find(|(n, _)| *n == name) {
+                    Some((_, count)) => *count += 1,
+                    None => usage.push((name, 1)),
+                }
+            }
+            errors.push(format!(
+                "static segment overflow: {} words are used, but only {} are available in RAM[{}..{}] ({})",
+                self.variables.len(),
+                available,
+                STATIC_BASE,
+                STATIC_END,
+                usage.iter().map(|(name, count)| format!("{}: {}", name, count)).collect::<Vec<_>>().join(", "),
+            ));
+        }
+
+        for symbol in self.collisions.iter() {
+            errors.push(format!("static variable `{}` collides with a label of the same name", symbol));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // `address class index` の一覧
+    pub fn report(&self) -> String {
+        let mut report = String::from("address\tclass\tindex\n");
+        for variable in self.variables.iter() {
+            match &variable.static_variable {
+                Some((class, index)) => report.push_str(&format!("{}\t{}\t{}\n", variable.address, class, index)),
+                None => report.push_str(&format!("{}\t{}\t-\n", variable.address, variable.symbol)),
+            }
+        }
+        report
+    }
+}
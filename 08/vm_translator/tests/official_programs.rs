@@ -0,0 +1,268 @@
+// 本の7章, 8章のテストプログラムを変換してHackコンピュータのエミュレータで実行し、
+// .cmp と同じRAMの値になることを確かめる
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use vm_translator::hack_cpu::Cpu;
+
+// 無限ループで終わるプログラムも止まるように、実行する命令数に上限を設ける
+const MAX_CYCLES: u64 = 1_000_000;
+
+// コード生成の方式や最適化を変えても結果は同じになる
+const VARIANTS: [&[&str]; 5] = [
+    &[],
+    &["--codegen=cached"],
+    &["--optimize"],
+    &["--codegen=cached", "--optimize"],
+    &["--extended-ops=inline", "--safety-checks"],
+];
+
+struct TestProgram {
+    name: &'static str,
+    // ブートストラップコードを使わないテストは、テストスクリプトと同じようにRAMを初期化する
+    bootstrap: bool,
+    ram: &'static [(usize, i16)],
+    expected: &'static [(usize, i16)],
+    // プログラムの外に return するテストは安全性検査でトラップになるので除く
+    safety_checks: bool,
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name)
+}
+
+fn translate(program: &TestProgram, options: &[&str]) -> Vec<u16> {
+    let output = std::env::temp_dir().join(format!(
+        "vm_translator_{}_{}_{}.hack",
+        program.name,
+        options.join("").replace(|c: char| !c.is_ascii_alphanumeric(), ""),
+        std::process::id(),
+    ));
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_vm_translator"));
+    command.args(options).arg("--target=hack").arg("-o").arg(&output);
+    if !program.bootstrap {
+        command.arg("--no-bootstrap");
+    }
+    let result = command.arg(fixture(program.name)).output().expect("failed to run vm_translator");
+    assert!(
+        result.status.success(),
+        "{}: vm_translator failed: {}",
+        program.name,
+        String::from_utf8_lossy(&result.stderr),
+    );
+
+    let hack = std::fs::read_to_string(&output).expect("failed to read the output");
+    std::fs::remove_file(&output).unwrap();
+    Cpu::parse_hack(&hack).unwrap()
+}
+
+fn run(program: TestProgram) {
+    for options in VARIANTS.iter() {
+        if !program.safety_checks && options.contains(&"--safety-checks") {
+            continue;
+        }
+        let mut cpu = Cpu::new(translate(&program, options));
+        for (address, value) in program.ram.iter() {
+            cpu.ram[*address] = *value;
+        }
+        cpu.run(MAX_CYCLES);
+
+        for (address, value) in program.expected.iter() {
+            assert_eq!(
+                cpu.ram[*address], *value,
+                "{} {:?}: RAM[{}]", program.name, options, address,
+            );
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////
+// 7章
+/////////////////////////////////////////////////////////////
+#[test]
+fn simple_add() {
+    run(TestProgram {
+        name: "SimpleAdd",
+        bootstrap: false,
+        ram: &[(0, 256)],
+        expected: &[(0, 257), (256, 15)],
+        safety_checks: true,
+    });
+}
+
+#[test]
+fn stack_test() {
+    run(TestProgram {
+        name: "StackTest",
+        bootstrap: false,
+        ram: &[(0, 256)],
+        expected: &[
+            (0, 266),
+            (256, -1), (257, 0), (258, 0), (259, 0), (260, -1),
+            (261, 0), (262, -1), (263, 0), (264, 0), (265, -91),
+        ],
+        safety_checks: true,
+    });
+}
+
+#[test]
+fn basic_test() {
+    run(TestProgram {
+        name: "BasicTest",
+        bootstrap: false,
+        ram: &[(0, 256), (1, 300), (2, 400), (3, 3000), (4, 3010)],
+        expected: &[
+            (256, 472), (300, 10), (401, 21), (402, 22),
+            (3006, 36), (3012, 42), (3015, 45), (11, 510),
+        ],
+        safety_checks: true,
+    });
+}
+
+#[test]
+fn pointer_test() {
+    run(TestProgram {
+        name: "PointerTest",
+        bootstrap: false,
+        ram: &[(0, 256)],
+        expected: &[(256, 6084), (3, 3030), (4, 3040), (3032, 32), (3046, 46)],
+        safety_checks: true,
+    });
+}
+
+#[test]
+fn static_test() {
+    run(TestProgram {
+        name: "StaticTest",
+        bootstrap: false,
+        ram: &[(0, 256)],
+        expected: &[(256, 1110)],
+        safety_checks: true,
+    });
+}
+
+/////////////////////////////////////////////////////////////
+// 8章
+/////////////////////////////////////////////////////////////
+#[test]
+fn basic_loop() {
+    run(TestProgram {
+        name: "BasicLoop",
+        bootstrap: false,
+        ram: &[(0, 256), (1, 300), (2, 400), (400, 3)],
+        expected: &[(0, 257), (256, 6)],
+        safety_checks: true,
+    });
+}
+
+#[test]
+fn fibonacci_series() {
+    run(TestProgram {
+        name: "FibonacciSeries",
+        bootstrap: false,
+        ram: &[(0, 256), (1, 300), (2, 400), (400, 6), (401, 3000)],
+        expected: &[(3000, 0), (3001, 1), (3002, 1), (3003, 2), (3004, 3), (3005, 5)],
+        safety_checks: true,
+    });
+}
+
+#[test]
+fn simple_function() {
+    run(TestProgram {
+        name: "SimpleFunction",
+        bootstrap: false,
+        ram: &[
+            (0, 317), (1, 317), (2, 310), (3, 3000), (4, 4000),
+            (310, 1234), (311, 37), (312, 1000), (313, 305), (314, 300), (315, 3010), (316, 4010),
+        ],
+        expected: &[(0, 311), (1, 305), (2, 300), (3, 3010), (4, 4010), (310, 1196)],
+        safety_checks: false,
+    });
+}
+
+#[test]
+fn nested_call() {
+    run(TestProgram {
+        name: "NestedCall",
+        bootstrap: true,
+        ram: &[],
+        expected: &[(0, 261), (1, 261), (2, 256), (3, 4000), (4, 5000), (5, 135), (6, 246)],
+        safety_checks: true,
+    });
+}
+
+#[test]
+fn fibonacci_element() {
+    run(TestProgram {
+        name: "FibonacciElement",
+        bootstrap: true,
+        ram: &[],
+        expected: &[(0, 262), (261, 3)],
+        safety_checks: true,
+    });
+}
+
+#[test]
+fn statics_test() {
+    run(TestProgram {
+        name: "StaticsTest",
+        bootstrap: true,
+        ram: &[],
+        expected: &[(0, 263), (261, -2), (262, 8)],
+        safety_checks: true,
+    });
+}
+
+/////////////////////////////////////////////////////////////
+// 命令数
+/////////////////////////////////////////////////////////////
+// --codegen=cached はどのプログラムでも命令数を半分以下にする
+#[test]
+fn cached_codegen_halves_instruction_count() {
+    let programs = [
+        ("SimpleAdd", false), ("StackTest", false), ("BasicTest", false), ("PointerTest", false),
+        ("StaticTest", false), ("BasicLoop", false), ("FibonacciSeries", false), ("SimpleFunction", false),
+        ("NestedCall", true), ("FibonacciElement", true), ("StaticsTest", true),
+    ];
+    for (name, bootstrap) in programs.iter() {
+        let program = TestProgram {
+            name,
+            bootstrap: *bootstrap,
+            ram: &[],
+            expected: &[],
+            safety_checks: false,
+        };
+        let stack = translate(&program, &[]).len();
+        let cached = translate(&program, &["--codegen=cached"]).len();
+        assert!(cached * 2 <= stack, "{}: {} instructions with --codegen=cached, {} without", name, cached, stack);
+    }
+}
+
+/////////////////////////////////////////////////////////////
+// プロファイル
+/////////////////////////////////////////////////////////////
+// プログラムの終端のループに入ったところで止める
+#[test]
+fn profile_stops_at_the_end_loop() {
+    for name in ["FibonacciElement", "NestedCall"].iter() {
+        let output = std::env::temp_dir().join(format!("vm_translator_profile_{}_{}.asm", name, std::process::id()));
+        let result = Command::new(env!("CARGO_BIN_EXE_vm_translator"))
+            .arg("--profile")
+            .arg("-o")
+            .arg(&output)
+            .arg(fixture(name))
+            .output()
+            .expect("failed to run vm_translator");
+        assert!(result.status.success(), "{}: {}", name, String::from_utf8_lossy(&result.stderr));
+
+        let profile_path = output.with_extension("profile.txt");
+        let profile = std::fs::read_to_string(&profile_path).expect("failed to read the profile");
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(&profile_path).unwrap();
+
+        let total = profile.lines().next().unwrap();
+        assert!(total.ends_with("cycles (halted)"), "{}: {}", name, total);
+        let cycles: u64 = total.trim_start_matches("total: ").split(' ').next().unwrap().parse().unwrap();
+        assert!(cycles < 10_000, "{}: {}", name, total);
+    }
+}
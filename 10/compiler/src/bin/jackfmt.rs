@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+use compiler::{Diagnostic, Dialect, Source};
+
+// .jack ファイルを決まった形に書き直す
+// --check を付けると書き直さずに、形が違うファイルがあれば 1 で終了する (CI 向け)
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let check = args.iter().any(|arg| arg == "--check");
+    let dialect = args.iter()
+        .find_map(|arg| arg.strip_prefix("--dialect="))
+        .map_or(Dialect::Standard, |name| Dialect::from(name).unwrap_or_else(|| panic!("unknown dialect: {}", name)));
+    let paths = args.iter().skip(1).filter(|arg| !arg.starts_with("--")).collect::<Vec<_>>();
+    assert_eq!(paths.len(), 1, "Path to .jack file or a directory contains .jack file is required.");
+
+    let jack_files = jack_files(Path::new(paths[0]));
+    if jack_files.is_empty() {
+        panic!(".jack file is required.");
+    }
+
+    let mut errors = vec![];
+    let mut unformatted = vec![];
+
+    for jack_file in &jack_files {
+        let text = std::fs::read_to_string(jack_file).unwrap_or_else(|_| panic!("failed to read .jack file: {:?}", jack_file));
+        let source = Source::new(&jack_file.to_string_lossy(), &text).with_dialect(dialect);
+
+        let formatted = match compiler::format(&source) {
+            Ok(formatted) => formatted,
+            Err(mut e) => {
+                errors.append(&mut e);
+                continue;
+            }
+        };
+        if formatted == text {
+            continue;
+        }
+
+        if check {
+            println!("{}", jack_file.display());
+            unformatted.push(jack_file);
+        } else {
+            std::fs::write(jack_file, formatted).expect("failed to write .jack file");
+        }
+    }
+
+    if !errors.is_empty() {
+        exit_with_errors(errors);
+    }
+    if !unformatted.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn jack_files(path: &Path) -> Vec<PathBuf> {
+    if path.is_dir() {
+        let mut files = std::fs::read_dir(path)
+            .unwrap_or_else(|_| panic!("failed to read the path: {:?}", path))
+            .filter_map(|f| f.ok().map(|f| f.path()))
+            .filter(|f| f.extension().is_some_and(|e| e == "jack"))
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    } else if path.extension().is_some_and(|e| e == "jack") {
+        vec![path.to_path_buf()]
+    } else {
+        vec![]
+    }
+}
+
+fn exit_with_errors(diagnostics: Vec<Diagnostic>) -> ! {
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}: {}", diagnostic.severity, diagnostic);
+    }
+    std::process::exit(1);
+}
@@ -0,0 +1,55 @@
+use crate::os;
+use crate::semantic::SubroutineKind;
+use crate::structures::Class;
+use crate::vm_writer::VmWriter;
+
+// 構文木をたどってVMコマンドを書き出す
+// 識別子は Resolver で解決済みであること
+pub trait WriteVm {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String>;
+}
+
+pub struct CodeGenerator {
+    pub class_name: String,
+    // constructor で確保するオブジェクトの大きさ
+    pub num_fields: u16,
+    pub writer: VmWriter,
+    // サブルーチン内で if, while のラベルを一意にする連番
+    label_index: usize,
+}
+
+impl CodeGenerator {
+    pub fn generate(class: &Class) -> Result<String, String> {
+        let mut generator = Self {
+            class_name: class.name().into(),
+            num_fields: class.num_fields(),
+            writer: VmWriter::new(),
+            label_index: 0,
+        };
+        class.write_vm(&mut generator)?;
+        Ok(generator.writer.vm())
+    }
+
+    pub fn start_subroutine(&mut self) {
+        self.label_index = 0;
+    }
+
+    // 演算子や文字列定数のために OS のサブルーチンを呼ぶ
+    // 引数の数は OS の宣言から決める
+    pub fn write_os_call(&mut self, class_name: &str, name: &str) -> Result<(), String> {
+        let signature = os::signature(class_name, name)
+            .ok_or_else(|| format!("OS subroutine `{}.{}` is not declared", class_name, name))?;
+        let num_args = signature.parameters.len() as u16 + match signature.kind {
+            SubroutineKind::Method => 1,
+            SubroutineKind::Constructor | SubroutineKind::Function => 0,
+        };
+        self.writer.write_call(&format!("{}.{}", class_name, name), num_args);
+        Ok(())
+    }
+
+    pub fn next_label_index(&mut self) -> usize {
+        let index = self.label_index;
+        self.label_index += 1;
+        index
+    }
+}
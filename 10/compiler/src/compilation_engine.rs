@@ -1,26 +1,250 @@
-use crate::tokenizer::Token;
+use std::fmt;
+use crate::tokenizer::{Span, Token, TokenKind};
 use crate::structures::Class;
+use crate::Dialect;
+
+const STATEMENT_KEYWORD: [&str; 5] = [
+    "let",
+    "if",
+    "while",
+    "do",
+    "return",
+];
+const DECLARATION_KEYWORD: [&str; 5] = [
+    "static",
+    "field",
+    "constructor",
+    "function",
+    "method",
+];
 
 pub struct CompilationEngine {
 }
 
 impl CompilationEngine {
-    pub fn compile(tokens: Vec<Token>) -> Result<Class, String> {
-        let mut iter = tokens.iter().peekable();
-        Ok(Class::extract(&mut iter)?)
+    // 文や宣言の単位でエラーから復帰して、見つかったエラーをすべて返す
+    // end はソースコードの末尾にある長さ0の範囲 (入力の終わりのエラーの位置)
+    pub fn compile(tokens: Vec<Token>, dialect: Dialect, end: Span) -> Result<Class, Vec<ParseError>> {
+        let mut iter = TokenStream::new(&tokens, dialect, end);
+        let result = Class::extract(&mut iter);
+
+        let mut errors = iter.errors;
+        if let Err(e) = &result {
+            // 閉じていない `}` が入れ子になっていると、入力の終わりで同じエラーが続く
+            if errors.last() == Some(e) {
+                return Err(errors);
+            }
+        }
+        match result {
+            Ok(class) if errors.is_empty() => Ok(class),
+            Ok(_) => Err(errors),
+            Err(e) => {
+                errors.push(e);
+                Err(errors)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    // エラーになったトークンの位置。入力の終わりならソースコードの末尾
+    pub span: Span,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
     }
+}
 
+/////////////////////////////////////////////////////////////
+// 構文解析で読み進めるトークン列
+/////////////////////////////////////////////////////////////
+pub struct TokenStream<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    // 式の読み方を変える
+    dialect: Dialect,
+    // ソースコードの末尾
+    end: Span,
+    // 復帰して読み進めたエラー
+    errors: Vec<ParseError>,
 }
 
-pub fn expect_symbol(expected: &str, token: &Token) -> Result<(), String> {
-    match token {
-        Token::Symbol(symbol) => {
-            if symbol == expected {
-                Ok(())
-            } else {
-                Err(format!("expected `{}`, but {:?} has passed", expected, token))
+impl<'a> TokenStream<'a> {
+    pub fn new(tokens: &'a [Token], dialect: Dialect, end: Span) -> Self {
+        Self {
+            tokens,
+            position: 0,
+            dialect,
+            end,
+            errors: vec![],
+        }
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    pub fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    pub fn peek_kind(&self) -> Option<&'a TokenKind> {
+        self.peek().map(|token| &token.kind)
+    }
+
+    // n 個先のトークンの種類 (lookahead(0) は peek_kind と同じ)
+    pub fn lookahead(&self, n: usize) -> Option<&'a TokenKind> {
+        self.tokens.get(self.position + n).map(|token| &token.kind)
+    }
+
+    // 構文の開始位置として span_from に渡す
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    // start から直前に読んだトークンまでの範囲
+    // 何も読んでいなければ次のトークンの先頭にある長さ0の範囲
+    pub fn span_from(&self, start: usize) -> Span {
+        if start < self.position {
+            self.tokens[start].span.to(&self.tokens[self.position - 1].span)
+        } else {
+            let span = self.current_span();
+            Span { end: span.start, ..span }
+        }
+    }
+
+    // 次のトークンの位置。入力の終わりならソースコードの末尾
+    fn current_span(&self) -> Span {
+        match self.peek() {
+            Some(token) => token.span.clone(),
+            None => self.end.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    pub fn next(&mut self) -> Result<&'a Token, ParseError> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token)
+            }
+            None => Err(self.error("unexpected end of input".into())),
+        }
+    }
+
+    // 先読みで確認済みのトークンを読み飛ばす
+    pub fn skip(&mut self) {
+        if !self.is_empty() {
+            self.position += 1;
+        }
+    }
+
+    pub fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek_kind(), Some(TokenKind::Symbol(s)) if s == symbol)
+    }
+
+    pub fn is_keyword(&self, keywords: &[&str]) -> bool {
+        matches!(self.peek_kind(), Some(TokenKind::Keyword(k)) if keywords.contains(&k.as_str()))
+    }
+
+    pub fn expect_symbol(&mut self, expected: &str) -> Result<&'a Token, ParseError> {
+        if self.is_symbol(expected) {
+            self.next()
+        } else {
+            Err(self.unexpected(&format!("`{}`", expected)))
+        }
+    }
+
+    pub fn expect_keyword(&mut self, expected: &str) -> Result<&'a Token, ParseError> {
+        if self.is_keyword(&[expected]) {
+            self.next()
+        } else {
+            Err(self.unexpected(&format!("`{}`", expected)))
+        }
+    }
+
+    pub fn expect_identifier(&mut self, expected: &str) -> Result<&'a Token, ParseError> {
+        match self.peek() {
+            Some(token) if matches!(token.kind, TokenKind::Identifier(_)) => {
+                self.position += 1;
+                Ok(token)
+            }
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+
+    // 次に読むトークンの位置のエラー
+    pub fn error(&self, message: String) -> ParseError {
+        ParseError {
+            message,
+            span: self.current_span(),
+        }
+    }
+
+    // `expected ..., but found ...` のエラー
+    pub fn unexpected(&self, expected: &str) -> ParseError {
+        let found = match self.peek() {
+            Some(token) => format!("`{}`", token.as_str()),
+            None => "end of input".into(),
+        };
+        self.error(format!("expected {}, but found {}", expected, found))
+    }
+
+    /////////////////////////////////////////////////////////////
+    // エラーからの復帰
+    /////////////////////////////////////////////////////////////
+    // 次の文の先頭まで読み飛ばす
+    // `;` と読み飛ばしたブロックを閉じる `}` は読み飛ばし、文を囲む `}` の手前で止まる
+    pub fn recover_statement(&mut self, error: ParseError) {
+        self.errors.push(error);
+        self.skip_until(|token, depth| match token {
+            TokenKind::Symbol(s) if s == ";" && depth == 0 => Some(true),
+            TokenKind::Symbol(s) if s == "}" && depth == 1 => Some(true),
+            TokenKind::Symbol(s) if s == "}" && depth == 0 => Some(false),
+            TokenKind::Keyword(k) if STATEMENT_KEYWORD.contains(&k.as_str()) && depth == 0 => Some(false),
+            _ => None,
+        });
+    }
+
+    // 次のクラス変数かサブルーチンの宣言まで読み飛ばす
+    // クラスを閉じる `}` の手前で止まる
+    pub fn recover_declaration(&mut self, error: ParseError) {
+        self.errors.push(error);
+        self.skip_until(|token, depth| match token {
+            TokenKind::Symbol(s) if s == "}" && depth == 0 => Some(false),
+            TokenKind::Keyword(k) if DECLARATION_KEYWORD.contains(&k.as_str()) => Some(false),
+            _ => None,
+        });
+    }
+
+    // stop が Some(true) ならそのトークンまで、Some(false) ならその手前まで読み飛ばす
+    // depth は読み飛ばした中の `{` `}` の深さ
+    fn skip_until<F>(&mut self, stop: F) where F: Fn(&TokenKind, usize) -> Option<bool> {
+        let mut depth: usize = 0;
+        while let Some(token) = self.peek_kind() {
+            match stop(token, depth) {
+                Some(consume) => {
+                    if consume {
+                        self.position += 1;
+                    }
+                    return;
+                }
+                None => {
+                    match token {
+                        TokenKind::Symbol(s) if s == "{" => depth += 1,
+                        TokenKind::Symbol(s) if s == "}" => depth -= 1,
+                        _ => {}
+                    }
+                    self.position += 1;
+                }
             }
         }
-        _ => Err(format!("expected `{}`, but {:?} has passed", expected, token))
     }
 }
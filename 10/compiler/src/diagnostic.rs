@@ -0,0 +1,65 @@
+use std::fmt;
+use crate::compilation_engine::ParseError;
+use crate::tokenizer::{LexError, Span};
+
+/////////////////////////////////////////////////////////////
+// 字句解析から意味解析までに見つかった問題
+/////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // 位置が分からなければ None
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn error(message: String, span: Option<Span>) -> Self {
+        Self { severity: Severity::Error, message, span }
+    }
+
+    pub fn warning(message: String, span: Option<Span>) -> Self {
+        Self { severity: Severity::Warning, message, span }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+// 警告だけならコンパイルは続ける
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.span {
+            Some(span) => write!(f, "{}: {}", span, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<LexError> for Diagnostic {
+    fn from(error: LexError) -> Self {
+        Self::error(error.message, Some(error.span))
+    }
+}
+
+impl From<ParseError> for Diagnostic {
+    fn from(error: ParseError) -> Self {
+        Self::error(error.message, Some(error.span))
+    }
+}
@@ -0,0 +1,202 @@
+use crate::structures::Class;
+use crate::tokenizer::{Comment, Span, Spanned, Token};
+
+const INDENT: &str = "    ";
+
+/////////////////////////////////////////////////////////////
+// フォーマッタ
+// 構文木から決まった形の Jack のソースコードを書き出す
+// コメントは構文木に無いので、ソース上の位置を見て元の場所に差し込む
+/////////////////////////////////////////////////////////////
+pub trait Format {
+    fn format(&self, formatter: &mut Formatter);
+}
+
+pub struct Formatter<'a> {
+    source: &'a str,
+    // ソース上の順に並んだコメントと、次に書き出すコメントの位置
+    comments: Vec<Comment>,
+    next_comment: usize,
+    output: String,
+    indent: usize,
+    // 最後に書き出したもののソース上の末尾 (空行やコメントの位置を判定する)
+    last_end: usize,
+    // 行の先頭で、まだ何も書いていない
+    line_start: bool,
+    // `{` の直後なので空行を入れない
+    after_open: bool,
+    // 次の行の前に必ず空行を入れる
+    force_blank: bool,
+    // `//` のコメントを書いたので、続きは次の行に書く
+    needs_newline: bool,
+    // 次のトークンの前に空白を入れない
+    glue_next: bool,
+}
+
+impl<'a> Formatter<'a> {
+    // class は source を構文解析したもの、comments は字句解析で読み飛ばしたもの
+    pub fn run(class: &Class, source: &'a str, comments: Vec<Comment>) -> String {
+        let mut formatter = Self {
+            source,
+            comments,
+            next_comment: 0,
+            output: String::new(),
+            indent: 0,
+            last_end: 0,
+            line_start: true,
+            after_open: false,
+            force_blank: false,
+            needs_newline: false,
+            glue_next: false,
+        };
+        formatter.item(&class.span(), class);
+        // クラスの後ろのコメント
+        formatter.flush_comments(usize::MAX);
+        formatter.output.push('\n');
+        formatter.output
+    }
+
+    // 新しい行に宣言や文を書く
+    pub fn item(&mut self, span: &Span, item: &dyn Format) {
+        self.flush_comments(span.start);
+        self.begin_line(span.start, true);
+        self.last_end = span.start;
+        item.format(self);
+        self.last_end = self.last_end.max(span.end);
+    }
+
+    // 次の item の前に空行を入れる
+    pub fn blank_line(&mut self) {
+        self.force_blank = true;
+    }
+
+    // ソースにあるトークンをそのまま書く
+    pub fn token(&mut self, token: &Token) {
+        self.write_token(token, true);
+    }
+
+    // ソースにある記号を前に空白を入れずに書く (`;` `,` `)` など)
+    pub fn symbol_token(&mut self, token: &Token) {
+        self.write_token(token, false);
+    }
+
+    // `}` や `)` に続けて書くトークン (`else` や `{`)
+    // 間に自分の行のコメントがあれば、続きの字下げにせず新しい行に書く
+    pub fn continue_token(&mut self, token: &Token) {
+        self.flush_comments(token.span.start);
+        if self.needs_newline {
+            self.begin_line(token.span.start, false);
+        }
+        self.token(token);
+    }
+
+    // 次のトークンの前に空白を入れない (`(` `[` `.` や単項演算子の後)
+    pub fn glue(&mut self) {
+        self.glue_next = true;
+    }
+
+    pub fn open_brace(&mut self, open_brace: &Token) {
+        self.continue_token(open_brace);
+        self.indent += 1;
+        self.after_open = true;
+    }
+
+    pub fn close_brace(&mut self, close_brace: &Token) {
+        // ブロックの最後のコメントはブロックの中に書く
+        self.flush_comments(close_brace.span.start);
+        self.indent -= 1;
+        self.begin_line(close_brace.span.start, false);
+        self.write("}", false);
+        self.last_end = self.last_end.max(close_brace.span.end);
+    }
+
+    // トークンの前にあるコメントを書いてからトークンを書く
+    fn write_token(&mut self, token: &Token, space: bool) {
+        self.flush_comments(token.span.start);
+        let text = &self.source[token.span.start..token.span.end];
+        self.write(text, space);
+        self.last_end = self.last_end.max(token.span.end);
+    }
+
+    fn write(&mut self, text: &str, space: bool) {
+        if self.needs_newline {
+            // 行の途中の `//` コメントの後は、1段深くして続ける
+            self.begin_line(self.last_end, false);
+            self.output.push_str(INDENT);
+        } else if space && !self.line_start && !self.glue_next {
+            self.output.push(' ');
+        }
+        self.output.push_str(text);
+        self.line_start = false;
+        self.glue_next = false;
+    }
+
+    fn begin_line(&mut self, start: usize, allow_blank: bool) {
+        if self.line_start && !self.output.is_empty() {
+            return;
+        }
+        // 元のソースで空行があれば1行だけ残す
+        let blank = self.force_blank
+            || (allow_blank && !self.after_open && self.gap(start).matches('\n').count() >= 2);
+        if !self.output.is_empty() {
+            self.output.push('\n');
+            if blank {
+                self.output.push('\n');
+            }
+        }
+        for _ in 0..self.indent {
+            self.output.push_str(INDENT);
+        }
+        self.line_start = true;
+        self.after_open = false;
+        self.force_blank = false;
+        self.needs_newline = false;
+        self.glue_next = false;
+    }
+
+    // 最後に書き出したものから start までのソース
+    fn gap(&self, start: usize) -> &str {
+        self.source.get(self.last_end..start).unwrap_or("")
+    }
+
+    // before より前にあるコメントを書き出す
+    fn flush_comments(&mut self, before: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment).filter(|c| c.span.start < before).cloned() {
+            self.next_comment += 1;
+
+            // ソースでコメントの前に同じ行のトークンが無ければ、自分の行に書く
+            let own_line = self.output.is_empty()
+                || self.source[..comment.span.start].rsplit('\n').next().unwrap_or("").trim().is_empty();
+            if own_line {
+                self.begin_line(comment.span.start, true);
+            } else if !self.line_start {
+                self.output.push(' ');
+            }
+            self.write_comment(&comment.text);
+
+            self.line_start = false;
+            self.glue_next = false;
+            // 自分の行にあるコメントの後ろには何も続けない
+            self.needs_newline = own_line || comment.text.starts_with("//");
+            self.last_end = self.last_end.max(comment.span.end);
+        }
+    }
+
+    // 複数行の `/* */` は、`*` で始まる行だけ今の字下げに揃える
+    fn write_comment(&mut self, text: &str) {
+        for (i, line) in text.lines().enumerate() {
+            if i > 0 {
+                self.output.push('\n');
+                if line.trim_start().starts_with('*') {
+                    for _ in 0..self.indent {
+                        self.output.push_str(INDENT);
+                    }
+                    self.output.push(' ');
+                    self.output.push_str(line.trim_start());
+                    continue;
+                }
+            }
+            self.output.push_str(line);
+        }
+    }
+}
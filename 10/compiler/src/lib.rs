@@ -0,0 +1,223 @@
+use crate::code_generator::CodeGenerator;
+use crate::compilation_engine::CompilationEngine;
+use crate::formatter::Formatter;
+use crate::semantic::Checker;
+use crate::structures::Class;
+use crate::symbol_table::Resolver;
+use crate::tokenizer::{Span, Token, Tokenizer};
+
+mod code_generator;
+mod compilation_engine;
+pub mod diagnostic;
+mod formatter;
+mod os;
+mod semantic;
+pub mod structures;
+mod symbol_table;
+pub mod tokenizer;
+mod vm_writer;
+
+pub use crate::diagnostic::Diagnostic;
+
+// 名前の無いソースコードの Span に入れるファイル名
+const ANONYMOUS_SOURCE: &str = "<input>";
+
+/////////////////////////////////////////////////////////////
+// Jack の文法
+/////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    // 本の通りの文法 (演算子の優先順位は無く、左から順に評価する)
+    Standard,
+    // 演算子の優先順位と、`<=` `%` `&&` などの演算子、16進数と文字の定数を加えた文法
+    Extended,
+}
+
+impl Dialect {
+    // `--dialect=` に指定する名前
+    pub fn from(name: &str) -> Option<Self> {
+        match name {
+            "standard" => Some(Dialect::Standard),
+            "extended" => Some(Dialect::Extended),
+            _ => None,
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////
+// ファイルを使わずにコンパイルするための入出力
+/////////////////////////////////////////////////////////////
+pub struct Source {
+    // エラーの位置に出すファイル名
+    pub name: String,
+    pub text: String,
+    pub dialect: Dialect,
+}
+
+impl Source {
+    pub fn new(name: &str, text: &str) -> Self {
+        Self {
+            name: name.into(),
+            text: text.into(),
+            dialect: Dialect::Standard,
+        }
+    }
+
+    pub fn with_dialect(self, dialect: Dialect) -> Self {
+        Self { dialect, ..self }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmFile {
+    // クラス名 (`<class_name>.vm` に書き出す)
+    pub class_name: String,
+    pub code: String,
+}
+
+// コンパイルできたときの結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compiled {
+    // sources と同じ順
+    pub vm_files: Vec<VmFile>,
+    // 意味解析の警告
+    pub warnings: Vec<Diagnostic>,
+}
+
+pub fn tokenize(text: &str) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    tokenize_source(&Source::new(ANONYMOUS_SOURCE, text))
+}
+
+// 構文解析だけで、識別子は解決しない
+pub fn parse(text: &str) -> Result<Class, Vec<Diagnostic>> {
+    let source = Source::new(ANONYMOUS_SOURCE, text);
+    parse_tokens(tokenize_source(&source)?, &source)
+}
+
+// どれかのソースにエラーがあっても、すべてのソースのエラーを集めて返す
+// 意味解析でエラーがあれば警告も一緒に Err で返し、無ければ警告は Compiled に入れて返す
+pub fn compile(sources: &[Source]) -> Result<Compiled, Vec<Diagnostic>> {
+    let mut classes = vec![];
+    let mut diagnostics = vec![];
+
+    for source in sources {
+        let result = tokenize_source(source)
+            .and_then(|tokens| parse_tokens(tokens, source))
+            .and_then(|mut class| {
+                resolve(&mut class)?;
+                Ok(class)
+            });
+        match result {
+            Ok(class) => classes.push(class),
+            Err(mut errors) => diagnostics.append(&mut errors),
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let diagnostics = check(&classes);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(diagnostics);
+    }
+
+    let mut vm_files = vec![];
+    for class in &classes {
+        vm_files.push(generate(class)?);
+    }
+    Ok(Compiled { vm_files, warnings: diagnostics })
+}
+
+// 構文木から決まった形のソースコードを書き出す。コメントは残す
+pub fn format(source: &Source) -> Result<String, Vec<Diagnostic>> {
+    let mut tokenizer = Tokenizer::new(&source.text, &source.name, source.dialect);
+    let tokens = tokenizer.generate_tokens()
+        .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect::<Vec<_>>())?;
+    let comments = tokenizer.take_comments();
+    let class = parse_tokens(tokens, source)?;
+    Ok(Formatter::run(&class, &source.text, comments))
+}
+
+/////////////////////////////////////////////////////////////
+// コンパイルの各段階
+/////////////////////////////////////////////////////////////
+pub fn tokenize_source(source: &Source) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    Tokenizer::new(&source.text, &source.name, source.dialect)
+        .generate_tokens()
+        .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect())
+}
+
+// tokens は source を字句解析したもの
+pub fn parse_tokens(tokens: Vec<Token>, source: &Source) -> Result<Class, Vec<Diagnostic>> {
+    CompilationEngine::compile(tokens, source.dialect, Span::end_of(&source.name, &source.text))
+        .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect())
+}
+
+// 識別子を解決して、構文木に何を指しているかを書き込む
+pub fn resolve(class: &mut Class) -> Result<(), Vec<Diagnostic>> {
+    Resolver::run(class)
+}
+
+// resolve 済みのすべてのクラスを見て、呼び出しや型を検査する
+pub fn check(classes: &[Class]) -> Vec<Diagnostic> {
+    Checker::run(classes)
+}
+
+// resolve 済みの構文木から VM コードを生成する
+pub fn generate(class: &Class) -> Result<VmFile, Vec<Diagnostic>> {
+    let code = CodeGenerator::generate(class)
+        .map_err(|e| vec![Diagnostic::error(e, None)])?;
+    Ok(VmFile {
+        class_name: class.name().into(),
+        code,
+    })
+}
+
+/////////////////////////////////////////////////////////////
+// XML 出力
+/////////////////////////////////////////////////////////////
+pub trait Xml {
+    fn xml(&self, options: XmlOptions) -> String;
+}
+
+// 既定では本の .xml と同じ出力になる
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XmlOptions {
+    // XML の要素に行と列の属性を付ける
+    pub with_spans: bool,
+    // 識別子の要素に種類や番号の属性を付ける
+    pub with_annotations: bool,
+}
+
+// 拡張の文法の `<=` や `&&` も1文字ずつ置き換える
+fn convert_to_xml_symbol(symbol: &str) -> String {
+    symbol.chars()
+        .map(|c| match c {
+            '<' => "&lt;".to_owned(),
+            '>' => "&gt;".to_owned(),
+            '&' => "&amp;".to_owned(),
+            other => other.to_string(),
+        })
+        .collect()
+}
+
+// 非終端記号の要素。本の .xml と同じく、子の要素を2文字ずつ字下げする
+fn element(name: &str, span: &Span, children: &str, options: XmlOptions) -> String {
+    let mut xml = with_span_attributes(format!("<{}>\n", name), span, options);
+    for line in children.lines() {
+        xml.push_str("  ");
+        xml.push_str(line);
+        xml.push('\n');
+    }
+    xml.push_str(&format!("</{}>\n", name));
+    xml
+}
+
+// options で指定されていれば、最初のタグに行と列の属性を足す
+fn with_span_attributes(xml: String, span: &Span, options: XmlOptions) -> String {
+    if !options.with_spans {
+        return xml;
+    }
+    let attributes = format!(" line=\"{}\" column=\"{}\">", span.line, span.column);
+    xml.replacen('>', &attributes, 1)
+}
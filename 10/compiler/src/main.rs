@@ -1,53 +1,102 @@
 use std::path::{Path, PathBuf};
 use std::io::{Error, BufWriter, Write};
 use std::fs::File;
-use crate::compilation_engine::CompilationEngine;
-use crate::tokenizer::Tokenizer;
-
-mod compilation_engine;
-mod structures;
-mod tokenizer;
+use compiler::{Diagnostic, Dialect, Source, Xml, XmlOptions};
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
     println!("args: {:?}", args);
 
-    assert_eq!(args.len(), 2, "Path to .jack file or a directory contains .jack file is required.");
-
-    let path = Path::new(&args[1]);
-    Analyzer::run(path);
+    // --spans を付けると XML に行と列を、--annotations を付けると識別子の種類と番号を出力する
+    let xml_options = XmlOptions {
+        with_spans: args.iter().any(|arg| arg == "--spans"),
+        with_annotations: args.iter().any(|arg| arg == "--annotations"),
+    };
+    // --dialect=extended で演算子の優先順位などを加えた文法を使う
+    let dialect = args.iter()
+        .find_map(|arg| arg.strip_prefix("--dialect="))
+        .map_or(Dialect::Standard, |name| Dialect::from(name).unwrap_or_else(|| panic!("unknown dialect: {}", name)));
+    let paths = args.iter().skip(1).filter(|arg| !arg.starts_with("--")).collect::<Vec<_>>();
+    assert_eq!(paths.len(), 1, "Path to .jack file or a directory contains .jack file is required.");
+
+    let path = Path::new(paths[0]);
+    Analyzer::run(path, dialect, xml_options);
 }
 
 struct Analyzer {}
 
 impl Analyzer {
-    fn run(source: &Path) {
-        let jack_files = Self::jack_files(source).expect(format!("failed to read the path: {:?}", source).as_str());
+    fn run(source: &Path, dialect: Dialect, xml_options: XmlOptions) {
+        let jack_files = Self::jack_files(source).unwrap_or_else(|_| panic!("failed to read the path: {:?}", source));
 
-        if jack_files.len() == 0 {
+        if jack_files.is_empty() {
             panic!(".jack file is required.");
         }
 
         println!("jack_files: {:?}", jack_files);
 
-        for jack_file in jack_files {
-            let (destination, destination_token) = Self::source_to_destinations(&jack_file);
+        let mut classes = vec![];
+        // classes と同じ順に、コード生成で書き出す先を持つ
+        let mut destinations = vec![];
+        let mut errors = vec![];
+
+        for jack_file in &jack_files {
+            let (destination, destination_token) = Self::source_to_destinations(jack_file);
             println!("destination: {:?}", destination);
             println!("destination_token: {:?}", destination_token);
 
+            let text = std::fs::read_to_string(jack_file).unwrap_or_else(|_| panic!("failed to read .jack file: {:?}", jack_file));
+            let source = Source::new(&jack_file.to_string_lossy(), &text).with_dialect(dialect);
+
             /////////////////////////////////////
             // 字句解析
             /////////////////////////////////////
-            let f = std::fs::File::open(jack_file.clone()).expect(format!("failed to open .jack file: {:?}", jack_file).as_str());
-            let mut tokenizer = Tokenizer::new(f);
-            let tokens = tokenizer.generate_tokens();
-            save(destination_token, &tokens);
+            let tokens = match compiler::tokenize_source(&source) {
+                Ok(tokens) => tokens,
+                Err(mut e) => {
+                    errors.append(&mut e);
+                    continue;
+                }
+            };
+            save(destination_token, &tokens, xml_options);
 
             /////////////////////////////////////
-            // 構文解析
+            // 構文解析と識別子の解決
             /////////////////////////////////////
-            let class = CompilationEngine::compile(tokens).unwrap();
-            save(destination, class);
+            let result = compiler::parse_tokens(tokens, &source).and_then(|mut class| {
+                compiler::resolve(&mut class)?;
+                Ok(class)
+            });
+            match result {
+                Ok(class) => {
+                    classes.push(class);
+                    destinations.push((jack_file, destination));
+                }
+                Err(mut e) => errors.append(&mut e),
+            }
+        }
+        // 他のファイルのエラーもまとめて表示する
+        if !errors.is_empty() {
+            exit_with_errors(errors);
+        }
+
+        /////////////////////////////////////
+        // 意味解析
+        /////////////////////////////////////
+        let diagnostics = compiler::check(&classes);
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            exit_with_errors(diagnostics);
+        }
+        print_diagnostics(&diagnostics);
+
+        for (class, (jack_file, destination)) in classes.into_iter().zip(destinations) {
+            /////////////////////////////////////
+            // コード生成
+            /////////////////////////////////////
+            let vm_file = compiler::generate(&class).unwrap_or_else(|e| exit_with_errors(e));
+            std::fs::write(jack_file.with_extension("vm"), vm_file.code).expect("failed to write .vm file");
+
+            save(destination, class, xml_options);
         }
     }
 
@@ -72,8 +121,8 @@ impl Analyzer {
         }
     }
 
-    fn source_to_destinations(path: &PathBuf) -> (PathBuf, PathBuf) {
-        let mut  dest = path.clone();
+    fn source_to_destinations(path: &Path) -> (PathBuf, PathBuf) {
+        let mut dest = path.to_path_buf();
         dest.set_extension("xml");
 
         let mut dest_token = dest.clone();
@@ -87,22 +136,20 @@ impl Analyzer {
     }
 }
 
-trait Xml {
-    fn xml(&self) -> String;
-}
-
-fn save<T>(destination: PathBuf, tokens: T) where T: Xml {
+fn save<T>(destination: PathBuf, tokens: T, options: XmlOptions) where T: Xml {
     let mut writer = BufWriter::new(
         File::create(destination).expect("failed to create a file")
     );
-    writer.write_all(tokens.xml().as_bytes()).unwrap();
+    writer.write_all(tokens.xml(options).as_bytes()).unwrap();
 }
 
-fn convert_to_xml_symbol(symbol: &str) -> String {
-    match symbol {
-        "<" => "&lt;".to_owned(),
-        ">" => "&gt;".to_owned(),
-        "&" => "&amp;".to_owned(),
-        other => String::from(other),
+fn print_diagnostics(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}: {}", diagnostic.severity, diagnostic);
     }
 }
+
+fn exit_with_errors(diagnostics: Vec<Diagnostic>) -> ! {
+    print_diagnostics(&diagnostics);
+    std::process::exit(1);
+}
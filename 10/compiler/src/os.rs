@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use crate::semantic::{Signature, SubroutineKind};
+
+/////////////////////////////////////////////////////////////
+// Jack OS の宣言
+// OS の .jack が無くても呼び出しを検査できるように、サブルーチンの宣言だけを持つ
+/////////////////////////////////////////////////////////////
+
+// (クラス名, 種類, 戻り値の型, サブルーチン名, 引数の型)
+type Declaration = (&'static str, &'static str, &'static str, &'static str, &'static [&'static str]);
+
+const OS_API: [Declaration; 49] = [
+    ("Math", "function", "void", "init", &[]),
+    ("Math", "function", "int", "abs", &["int"]),
+    ("Math", "function", "int", "multiply", &["int", "int"]),
+    ("Math", "function", "int", "divide", &["int", "int"]),
+    ("Math", "function", "int", "min", &["int", "int"]),
+    ("Math", "function", "int", "max", &["int", "int"]),
+    ("Math", "function", "int", "sqrt", &["int"]),
+
+    ("String", "constructor", "String", "new", &["int"]),
+    ("String", "method", "void", "dispose", &[]),
+    ("String", "method", "int", "length", &[]),
+    ("String", "method", "char", "charAt", &["int"]),
+    ("String", "method", "void", "setCharAt", &["int", "char"]),
+    ("String", "method", "String", "appendChar", &["char"]),
+    ("String", "method", "void", "eraseLastChar", &[]),
+    ("String", "method", "int", "intValue", &[]),
+    ("String", "method", "void", "setInt", &["int"]),
+    ("String", "function", "char", "backSpace", &[]),
+    ("String", "function", "char", "doubleQuote", &[]),
+    ("String", "function", "char", "newLine", &[]),
+
+    ("Array", "function", "Array", "new", &["int"]),
+    ("Array", "method", "void", "dispose", &[]),
+
+    ("Output", "function", "void", "init", &[]),
+    ("Output", "function", "void", "moveCursor", &["int", "int"]),
+    ("Output", "function", "void", "printChar", &["char"]),
+    ("Output", "function", "void", "printString", &["String"]),
+    ("Output", "function", "void", "printInt", &["int"]),
+    ("Output", "function", "void", "println", &[]),
+    ("Output", "function", "void", "backSpace", &[]),
+
+    ("Screen", "function", "void", "init", &[]),
+    ("Screen", "function", "void", "clearScreen", &[]),
+    ("Screen", "function", "void", "setColor", &["boolean"]),
+    ("Screen", "function", "void", "drawPixel", &["int", "int"]),
+    ("Screen", "function", "void", "drawLine", &["int", "int", "int", "int"]),
+    ("Screen", "function", "void", "drawRectangle", &["int", "int", "int", "int"]),
+    ("Screen", "function", "void", "drawCircle", &["int", "int", "int"]),
+
+    ("Keyboard", "function", "void", "init", &[]),
+    ("Keyboard", "function", "char", "keyPressed", &[]),
+    ("Keyboard", "function", "char", "readChar", &[]),
+    ("Keyboard", "function", "String", "readLine", &["String"]),
+    ("Keyboard", "function", "int", "readInt", &["String"]),
+
+    ("Memory", "function", "void", "init", &[]),
+    ("Memory", "function", "int", "peek", &["int"]),
+    ("Memory", "function", "void", "poke", &["int", "int"]),
+    ("Memory", "function", "Array", "alloc", &["int"]),
+    ("Memory", "function", "void", "deAlloc", &["Array"]),
+
+    ("Sys", "function", "void", "init", &[]),
+    ("Sys", "function", "void", "halt", &[]),
+    ("Sys", "function", "void", "error", &["int"]),
+    ("Sys", "function", "void", "wait", &["int"]),
+];
+
+fn to_signature(declaration: &Declaration) -> Signature {
+    let (_, kind, return_type, name, parameters) = declaration;
+    Signature {
+        name: name.to_string(),
+        // 表に書いた種類はどれも正しい
+        kind: SubroutineKind::from(kind).unwrap(),
+        return_type: if *return_type == "void" { None } else { Some(return_type.to_string()) },
+        parameters: parameters.iter().map(|parameter| parameter.to_string()).collect(),
+    }
+}
+
+// OS のクラスごとのサブルーチン
+pub fn signatures() -> HashMap<String, HashMap<String, Signature>> {
+    let mut classes: HashMap<String, HashMap<String, Signature>> = HashMap::new();
+    for declaration in OS_API.iter() {
+        let signature = to_signature(declaration);
+        classes.entry(declaration.0.into())
+            .or_default()
+            .insert(signature.name.clone(), signature);
+    }
+    classes
+}
+
+pub fn signature(class_name: &str, name: &str) -> Option<Signature> {
+    OS_API.iter()
+        .find(|(class, _, _, subroutine, _)| *class == class_name && *subroutine == name)
+        .map(to_signature)
+}
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::diagnostic::Diagnostic;
+use crate::os;
+use crate::structures::Class;
+use crate::tokenizer::Span;
+
+/////////////////////////////////////////////////////////////
+// 意味解析
+// 識別子を解決した後で、プログラム全体のクラスを見て
+// 呼び出し、return、代入の型を検査する
+/////////////////////////////////////////////////////////////
+pub trait Check {
+    fn check(&self, checker: &mut Checker);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubroutineKind {
+    Constructor,
+    Function,
+    Method,
+}
+
+impl SubroutineKind {
+    pub fn from(keyword: &str) -> Option<Self> {
+        match keyword {
+            "constructor" => Some(Self::Constructor),
+            "function" => Some(Self::Function),
+            "method" => Some(Self::Method),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SubroutineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Constructor => "constructor",
+            Self::Function => "function",
+            Self::Method => "method",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// 呼び出しの検査に使うサブルーチンの宣言
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub name: String,
+    pub kind: SubroutineKind,
+    // void なら None
+    pub return_type: Option<String>,
+    // 引数の型
+    pub parameters: Vec<String>,
+}
+
+/////////////////////////////////////////////////////////////
+// 式の型
+/////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueType {
+    Int,
+    Char,
+    Boolean,
+    Null,
+    Class(String),
+    // 配列の要素や、宣言の分からないサブルーチンの戻り値
+    Unknown,
+}
+
+impl ValueType {
+    pub fn from(type_name: &str) -> Self {
+        match type_name {
+            "int" => Self::Int,
+            "char" => Self::Char,
+            "boolean" => Self::Boolean,
+            class_name => Self::Class(class_name.into()),
+        }
+    }
+
+    fn is_primitive(&self) -> bool {
+        matches!(self, Self::Int | Self::Char | Self::Boolean)
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Int => write!(f, "int"),
+            Self::Char => write!(f, "char"),
+            Self::Boolean => write!(f, "boolean"),
+            Self::Null => write!(f, "null"),
+            Self::Class(class_name) => write!(f, "{}", class_name),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+// 呼び出しの引数
+pub struct Argument {
+    pub value_type: ValueType,
+    pub span: Span,
+    // 整数定数だけの式か
+    pub is_integer_constant: bool,
+}
+
+enum Compatibility {
+    Compatible,
+    Warning,
+    Error,
+}
+
+// target の型に value を入れられるか
+fn compatibility(target: &ValueType, value: &ValueType) -> Compatibility {
+    match (target, value) {
+        (ValueType::Unknown, _) | (_, ValueType::Unknown) => Compatibility::Compatible,
+        (target, value) if target == value => Compatibility::Compatible,
+        // Array はどのオブジェクトのアドレスとも読み替えて使われる
+        (ValueType::Class(class_name), _) | (_, ValueType::Class(class_name)) if class_name == "Array" => Compatibility::Compatible,
+        (ValueType::Class(_), ValueType::Null) => Compatibility::Compatible,
+        // int, char, boolean はどれも16ビットの値なので警告にとどめる
+        (target, value) if target.is_primitive() && (value.is_primitive() || *value == ValueType::Null) => Compatibility::Warning,
+        _ => Compatibility::Error,
+    }
+}
+
+// 呼び出しの対象
+pub enum Receiver<'a> {
+    // `foo()` は自分のクラスのメソッド
+    This,
+    // `Foo.bar()` はクラスの function か constructor
+    Class(&'a str),
+    // `x.bar()` は変数の型のクラスのメソッド
+    Variable(&'a str, &'a str),
+}
+
+pub struct Checker<'a> {
+    // プログラム中のクラスごとのサブルーチン
+    classes: &'a HashMap<String, HashMap<String, Signature>>,
+    pub class_name: String,
+    // 検査中のサブルーチン
+    pub subroutine: Option<Signature>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+    // エラーと警告をすべて集めて返す
+    pub fn run(classes: &[Class]) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+        // OS の .jack をコンパイルするときは、そちらの宣言を使う
+        let mut signatures = os::signatures();
+        for class in classes {
+            let mut subroutines = HashMap::new();
+            for (signature, span) in class.signatures() {
+                if subroutines.contains_key(&signature.name) {
+                    diagnostics.push(Diagnostic::error(
+                        format!("{}: subroutine `{}` is already defined", class.name(), signature.name),
+                        Some(span),
+                    ));
+                    continue;
+                }
+                subroutines.insert(signature.name.clone(), signature);
+            }
+            signatures.insert(class.name().to_string(), subroutines);
+        }
+
+        for class in classes {
+            let mut checker = Checker {
+                classes: &signatures,
+                class_name: class.name().into(),
+                subroutine: None,
+                diagnostics: vec![],
+            };
+            class.check(&mut checker);
+            diagnostics.append(&mut checker.diagnostics);
+        }
+
+        diagnostics
+    }
+
+    pub fn start_subroutine(&mut self, signature: Signature) {
+        self.subroutine = Some(signature);
+    }
+
+    // プログラムにも OS にも無いクラスなら None
+    pub fn lookup(&self, class_name: &str, name: &str) -> Option<&Signature> {
+        self.classes.get(class_name).and_then(|subroutines| subroutines.get(name))
+    }
+
+    pub fn in_function(&self) -> bool {
+        matches!(&self.subroutine, Some(Signature { kind: SubroutineKind::Function, .. }))
+    }
+
+    // 呼び出すサブルーチンの戻り値の型 (分からなければ Unknown)
+    pub fn return_type(&self, receiver: &Receiver, name: &str) -> ValueType {
+        let class_name = match receiver {
+            Receiver::This => self.class_name.as_str(),
+            Receiver::Class(class_name) | Receiver::Variable(_, class_name) => class_name,
+        };
+        match self.lookup(class_name, name) {
+            Some(Signature { return_type: Some(r#type), .. }) => ValueType::from(r#type),
+            _ => ValueType::Unknown,
+        }
+    }
+
+    pub fn check_call(&mut self, receiver: &Receiver, name: &str, arguments: &[Argument], span: &Span) {
+        let class_name = match receiver {
+            Receiver::This => self.class_name.clone(),
+            Receiver::Class(class_name) => class_name.to_string(),
+            Receiver::Variable(variable, class_name) => {
+                if ValueType::from(class_name).is_primitive() {
+                    self.error(format!("`{}` is `{}` and has no methods", variable, class_name), span);
+                    return;
+                }
+                class_name.to_string()
+            }
+        };
+
+        // 別のディレクトリのクラスかもしれないので、知らないクラスは検査しない
+        if !self.classes.contains_key(&class_name) {
+            return;
+        }
+        let signature = match self.lookup(&class_name, name) {
+            Some(signature) => signature.clone(),
+            None => {
+                self.error(format!("subroutine `{}.{}` is not defined", class_name, name), span);
+                return;
+            }
+        };
+
+        // クラス名を付けた呼び出しだけが function と constructor を呼べる
+        let called_as_method = !matches!(receiver, Receiver::Class(_));
+        match (signature.kind == SubroutineKind::Method, called_as_method) {
+            (true, false) => {
+                self.error(format!("method `{}.{}` is called as a function", class_name, name), span);
+            }
+            (false, true) => {
+                self.error(format!("{} `{}.{}` is called as a method", signature.kind, class_name, name), span);
+            }
+            (true, true) if matches!(receiver, Receiver::This) && self.in_function() => {
+                self.error(format!("method `{}` cannot be called without an object in a function", name), span);
+            }
+            _ => {}
+        }
+
+        if signature.parameters.len() != arguments.len() {
+            self.error(format!(
+                "`{}.{}` takes {} argument(s), but {} were given",
+                class_name, name, signature.parameters.len(), arguments.len()
+            ), span);
+            return;
+        }
+        for (i, (parameter, argument)) in signature.parameters.iter().zip(arguments).enumerate() {
+            let parameter = ValueType::from(parameter);
+            // 文字コードの整数定数は char の引数に渡せる (`Output.printChar(32)`)
+            if parameter == ValueType::Char && argument.is_integer_constant {
+                continue;
+            }
+            let message = format!(
+                "argument {} of `{}.{}` expects `{}`, found `{}`",
+                i + 1, class_name, name, parameter, argument.value_type
+            );
+            match compatibility(&parameter, &argument.value_type) {
+                Compatibility::Compatible => {}
+                Compatibility::Warning => self.warning(message, &argument.span),
+                Compatibility::Error => self.error(message, &argument.span),
+            }
+        }
+    }
+
+    // 代入や return で target の型に value を入れられるか
+    pub fn check_assignment(&mut self, target: &ValueType, value: &ValueType, span: &Span) {
+        match compatibility(target, value) {
+            Compatibility::Compatible => {}
+            Compatibility::Warning => self.warning(format!("assigning `{}` to `{}`", value, target), span),
+            Compatibility::Error => self.error(format!("cannot assign `{}` to `{}`", value, target), span),
+        }
+    }
+
+    pub fn error(&mut self, message: String, span: &Span) {
+        let message = format!("{}: {}", self.location(), message);
+        self.diagnostics.push(Diagnostic::error(message, Some(span.clone())));
+    }
+
+    pub fn warning(&mut self, message: String, span: &Span) {
+        let message = format!("{}: {}", self.location(), message);
+        self.diagnostics.push(Diagnostic::warning(message, Some(span.clone())));
+    }
+
+    // エラーメッセージに出すサブルーチン名
+    fn location(&self) -> String {
+        match &self.subroutine {
+            Some(signature) => format!("{}.{}", self.class_name, signature.name),
+            None => self.class_name.clone(),
+        }
+    }
+}
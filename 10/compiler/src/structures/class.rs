@@ -1,9 +1,12 @@
-use crate::tokenizer::Token;
-use std::iter::Peekable;
-use std::slice::Iter;
-use crate::compilation_engine::expect_symbol;
+use crate::tokenizer::{Span, Spanned, Token, TokenKind};
+use crate::code_generator::{CodeGenerator, WriteVm};
+use crate::compilation_engine::{ParseError, TokenStream};
+use crate::formatter::{Format, Formatter};
+use crate::semantic::{Check, Checker, Signature, SubroutineKind, ValueType};
 use crate::structures::Statements;
-use crate::Xml;
+use crate::symbol_table::{Annotation, Category, Kind, Resolve, Resolver, Symbol};
+use crate::vm_writer::Segment;
+use crate::{element, with_span_attributes, Xml, XmlOptions};
 
 const CLASS_KEYWORD: &str = "class";
 const CLASS_VAR_DEC_KEYWORD: [&str; 2] = [
@@ -26,72 +29,153 @@ const SUBROUTINE_DEC_KEYWORD: [&str; 3] = [
 // `class` className `{` classVarDec* subroutineDec* `}`
 /////////////////////////////////////////////////////////////
 pub struct Class {
+    class_keyword: Token,
     class_name: ClassName,
+    open_brace: Token,
     class_var_decs: Vec<ClassVarDec>,
     subroutine_decs: Vec<SubroutineDec>,
+    close_brace: Token,
+    span: Span,
 }
 
 impl Class {
-    pub fn extract(mut iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
+    pub fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+
         // `class`
-        match iter.next().expect("should have keyword") {
-            Token::Keyword(keyword) => {
-                if keyword != CLASS_KEYWORD {
-                    return Err("should start with `class` keyword".into());
-                }
-            }
-            _ => return Err("should start with `class` keyword".into())
-        }
+        let class_keyword = iter.expect_keyword(CLASS_KEYWORD)?;
 
         // className
-        let class_name = ClassName::extract(&mut iter)?;
+        let class_name = ClassName::extract(iter)?;
 
         // `{`
-        let _ = expect_symbol("{", iter.next().unwrap())?;
+        let open_brace = iter.expect_symbol("{")?;
 
         // classVarDec*
-        let class_var_decs = ClassVarDec::extract_class_var_decs(iter)?;
-        println!("class_var_decs: {:?}", class_var_decs);
+        let class_var_decs = ClassVarDec::extract_class_var_decs(iter);
 
         // subroutineDec*
-        let subroutine_decs = SubroutineDec::extract_subroutine_decs(&mut iter)?;
-        println!("subroutine_decs: {:?}", subroutine_decs);
+        let subroutine_decs = SubroutineDec::extract_subroutine_decs(iter);
 
         // `}`
-        let _ = expect_symbol("}", iter.next().unwrap())?;
+        let close_brace = iter.expect_symbol("}")?;
+        let span = iter.span_from(start);
 
-        while let Some(token) = iter.next() {
-            println!("!!! Remaining token !!! : {:?}", token);
+        // クラスの後ろにトークンが残っていてはいけない
+        if !iter.is_empty() {
+            return Err(iter.unexpected("end of input after the class"));
         }
 
         Ok(Class{
+            class_keyword: class_keyword.into(),
             class_name,
+            open_brace: open_brace.into(),
             class_var_decs,
             subroutine_decs,
+            close_brace: close_brace.into(),
+            span,
         })
     }
 }
 
+impl Spanned for Class {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Class {
+    pub fn name(&self) -> &str {
+        self.class_name.inner.as_str()
+    }
+
+    // constructor で確保するオブジェクトの大きさ
+    pub fn num_fields(&self) -> u16 {
+        self.class_var_decs.iter()
+            .filter(|decs| decs.dec_keyword.as_str() == "field")
+            .map(|decs| decs.var_names.len() as u16)
+            .sum()
+    }
+
+    // 意味解析で呼び出しを検査するためのサブルーチンの宣言と名前の位置
+    pub fn signatures(&self) -> Vec<(Signature, Span)> {
+        self.subroutine_decs.iter()
+            .map(|decs| (decs.signature(), decs.subroutine_name.span()))
+            .collect()
+    }
+}
+
+impl Resolve for Class {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        self.class_name.annotation = Some(Annotation::class(true));
+
+        for decs in self.class_var_decs.iter_mut() {
+            decs.resolve(resolver);
+        }
+
+        for decs in self.subroutine_decs.iter_mut() {
+            decs.resolve(resolver);
+        }
+    }
+}
+
+impl Check for Class {
+    fn check(&self, checker: &mut Checker) {
+        for decs in &self.subroutine_decs {
+            decs.check(checker);
+        }
+    }
+}
+
+impl Format for Class {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.class_keyword);
+        formatter.token(&self.class_name.inner);
+        formatter.open_brace(&self.open_brace);
+
+        for decs in &self.class_var_decs {
+            formatter.item(&decs.span(), decs);
+        }
+
+        // フィールドとサブルーチン、サブルーチン同士の間は1行空ける
+        for (i, decs) in self.subroutine_decs.iter().enumerate() {
+            if i > 0 || !self.class_var_decs.is_empty() {
+                formatter.blank_line();
+            }
+            formatter.item(&decs.span(), decs);
+        }
+
+        formatter.close_brace(&self.close_brace);
+    }
+}
+
+impl WriteVm for Class {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        for decs in &self.subroutine_decs {
+            decs.write_vm(generator)?;
+        }
+        Ok(())
+    }
+}
+
 impl Xml for Class {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<class>\n");
 
-        xml.push_str(Token::Keyword("class".into()).xml().as_str());
-        xml.push_str(self.class_name.xml().as_str());
-        xml.push_str(Token::Symbol("{".into()).xml().as_str());
+        xml.push_str(self.class_keyword.xml(options).as_str());
+        xml.push_str(self.class_name.xml(options).as_str());
+        xml.push_str(self.open_brace.xml(options).as_str());
 
         for decs in &self.class_var_decs {
-            xml.push_str(decs.xml().as_str());
+            xml.push_str(decs.xml(options).as_str());
         }
 
         for decs in &self.subroutine_decs {
-            xml.push_str(decs.xml().as_str());
+            xml.push_str(decs.xml(options).as_str());
         }
 
-        xml.push_str(Token::Symbol("}".into()).xml().as_str());
-        xml.push_str("</class>\n");
-        xml
+        xml.push_str(self.close_brace.xml(options).as_str());
+        element("class", &self.span(), &xml, options)
     }
 }
 
@@ -104,61 +188,83 @@ struct ClassVarDec {
     dec_keyword: Token,
     r#type: Type,
     var_names: Vec<VarName>,
+    // var_names の間の `,`
+    commas: Vec<Token>,
+    semicolon: Token,
+    span: Span,
 }
 
 impl ClassVarDec {
-    fn extract(mut iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
-        let dec = iter.next().unwrap();
-        let class_var_dec_type = Type::new(iter.next().unwrap())?;
-        let var_names = VarName::extract_var_names(&mut iter)?;
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let dec = iter.next()?;
+        let class_var_dec_type = Type::extract(iter)?;
+        let (var_names, commas) = VarName::extract_var_names(iter)?;
 
         // ClassVarDecの宣言はセミコロンで終わる
-        let _ = expect_symbol(";", iter.next().unwrap())?;
+        let semicolon = iter.expect_symbol(";")?;
 
         Ok(Self {
             dec_keyword: dec.into(),
             r#type: class_var_dec_type,
             var_names,
+            commas,
+            semicolon: semicolon.into(),
+            span: iter.span_from(start),
         })
     }
 
-    fn extract_class_var_decs(iter: &mut Peekable<Iter<Token>>) -> Result<Vec<Self>, String> {
+    // エラーのあった宣言は読み飛ばして次の宣言から続ける
+    fn extract_class_var_decs(iter: &mut TokenStream) -> Vec<Self> {
         let mut class_var_decs = vec![];
 
-        loop {
-            // 先読みしてクラス変数の宣言かどうかを判定する
-            let dec = iter.peek();
-            if dec.is_none() {
-                break;
+        // 先読みしてクラス変数の宣言かどうかを判定する
+        while iter.is_keyword(&CLASS_VAR_DEC_KEYWORD) {
+            match Self::extract(iter) {
+                Ok(dec) => class_var_decs.push(dec),
+                Err(e) => iter.recover_declaration(e),
             }
-            match dec.unwrap() {
-                Token::Keyword(keyword) => {
-                    if !CLASS_VAR_DEC_KEYWORD.contains(&keyword.as_str()) {
-                        break;
-                    }
-                }
-                _ => {
-                    break;
-                }
-            }
-            // 宣言を確認できたのでイテレータから取り出す
-            class_var_decs.push(Self::extract(iter)?);
         }
 
-        Ok(class_var_decs)
+        class_var_decs
+    }
+}
+
+impl Spanned for ClassVarDec {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+// クラス変数をシンボルテーブルに登録する
+impl Resolve for ClassVarDec {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        self.r#type.resolve(resolver);
+        // 構文解析で static か field であることは確認済み
+        let kind = Kind::from(self.dec_keyword.as_str()).unwrap();
+        for var_name in self.var_names.iter_mut() {
+            var_name.define(resolver, self.r#type.name(), kind);
+        }
+    }
+}
+
+impl Format for ClassVarDec {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.dec_keyword);
+        formatter.token(&self.r#type.inner);
+        separated_format(formatter, &self.var_names, &self.commas);
+        formatter.symbol_token(&self.semicolon);
     }
 }
 
 impl Xml for ClassVarDec {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<classVarDec>\n");
-        xml.push_str(self.dec_keyword.xml().as_str());
-        xml.push_str(self.r#type.xml().as_str());
-        xml.push_str(self.var_names.xml().as_str());
-        xml.push_str(Token::Symbol(";".into()).xml().as_str());
-        xml.push_str("</classVarDec>\n");
-        xml
+        xml.push_str(self.dec_keyword.xml(options).as_str());
+        xml.push_str(self.r#type.xml(options).as_str());
+        xml.push_str(separated_xml(&self.var_names, &self.commas, options).as_str());
+        xml.push_str(self.semicolon.xml(options).as_str());
+        element("classVarDec", &self.span(), &xml, options)
     }
 }
 
@@ -169,27 +275,45 @@ impl Xml for ClassVarDec {
 #[derive(Debug)]
 struct Type {
     inner: Token,
+    annotation: Option<Annotation>,
 }
 
 impl Type {
-    fn new(token: &Token) -> Result<Self, String> {
-        match token {
-            Token::Keyword(k) => {
-                if !CLASS_VAR_DEC_TYPE_KEYWORD.contains(&k.as_str()) {
-                    return Err(format!("invalid Type: {}", k).into())
-                }
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        match iter.peek_kind() {
+            Some(TokenKind::Keyword(k)) if CLASS_VAR_DEC_TYPE_KEYWORD.contains(&k.as_str()) => {}
+            Some(TokenKind::Identifier(_)) => {}
+            _ => return Err(iter.unexpected("type")),
+        }
 
-                Ok(Self { inner: token.into() })
-            }
-            Token::Identifier(_) => Ok(Self { inner: token.into() }),
-            other => Err(format!("invalid Type: {:?}", other).into())
+        Ok(Self { inner: iter.next()?.into(), annotation: None })
+    }
+}
+
+impl Type {
+    pub fn name(&self) -> &str {
+        self.inner.as_str()
+    }
+}
+
+impl Spanned for Type {
+    fn span(&self) -> Span {
+        self.inner.span()
+    }
+}
+
+// `int` などのキーワード以外はクラス名
+impl Resolve for Type {
+    fn resolve(&mut self, _resolver: &mut Resolver) {
+        if let TokenKind::Identifier(_) = self.inner.kind {
+            self.annotation = Some(Annotation::class(false));
         }
     }
 }
 
 impl Xml for Type {
-    fn xml(&self) -> String {
-        self.inner.xml()
+    fn xml(&self, options: XmlOptions) -> String {
+        annotated_xml(&self.inner, &self.annotation, options)
     }
 }
 
@@ -199,42 +323,64 @@ impl Xml for Type {
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 struct SubroutineBody {
+    open_brace: Token,
     var_decs: Vec<VarDec>,
     statements: Statements,
+    close_brace: Token,
+    span: Span,
 }
 
 impl SubroutineBody {
-    fn extract(mut iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
-        let _ = expect_symbol("{", iter.next().unwrap());
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let open_brace = iter.expect_symbol("{")?;
 
-        let var_decs = VarDec::extract_var_decs(&mut iter)?;
+        let var_decs = VarDec::extract_var_decs(iter)?;
 
-        let statements = Statements::extract(&mut iter)?;
+        let statements = Statements::extract(iter);
 
-        let _ = expect_symbol("}", iter.next().unwrap());
+        let close_brace = iter.expect_symbol("}")?;
 
         Ok(Self {
+            open_brace: open_brace.into(),
             var_decs,
             statements,
+            close_brace: close_brace.into(),
+            span: iter.span_from(start),
         })
     }
 }
 
+impl Spanned for SubroutineBody {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Format for SubroutineBody {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.open_brace(&self.open_brace);
+        for var_dec in &self.var_decs {
+            formatter.item(&var_dec.span(), var_dec);
+        }
+        self.statements.format(formatter);
+        formatter.close_brace(&self.close_brace);
+    }
+}
+
 impl Xml for SubroutineBody {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<subroutineBody>\n");
-        xml.push_str(Token::Symbol("{".into()).xml().as_str());
+        xml.push_str(self.open_brace.xml(options).as_str());
 
         for var_dec in &self.var_decs {
-            xml.push_str(var_dec.xml().as_str());
+            xml.push_str(var_dec.xml(options).as_str());
         }
 
-        xml.push_str(self.statements.xml().as_str());
+        xml.push_str(self.statements.xml(options).as_str());
 
-        xml.push_str(Token::Symbol("}".into()).xml().as_str());
-        xml.push_str("</subroutineBody>\n");
-        xml
+        xml.push_str(self.close_brace.xml(options).as_str());
+        element("subroutineBody", &self.span(), &xml, options)
     }
 }
 
@@ -244,35 +390,33 @@ impl Xml for SubroutineBody {
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 struct VarDec {
+    var_keyword: Token,
     r#type: Type,
     var_names: Vec<VarName>,
+    // var_names の間の `,`
+    commas: Vec<Token>,
+    semicolon: Token,
+    span: Span,
 }
 
 impl VarDec {
-    fn extract_var_decs(mut iter: &mut Peekable<Iter<Token>>) -> Result<Vec<Self>, String> {
+    fn extract_var_decs(iter: &mut TokenStream) -> Result<Vec<Self>, ParseError> {
         let mut var_decs = vec![];
 
-        loop {
-            let token = iter.peek().unwrap();
-            match token {
-                Token::Keyword(keyword) => {
-                    if keyword != "var" {
-                        break;
-                    }
-                }
-                _ => {
-                    break;
-                }
-            }
-
+        while iter.is_keyword(&["var"]) {
+            let start = iter.position();
             // `var` を取り出す
-            let _ = iter.next().unwrap();
-            let r#type = Type::new(iter.next().unwrap())?;
-            let var_names = VarName::extract_var_names(&mut iter)?;
-            let _ = expect_symbol(";", iter.next().unwrap())?;
+            let var_keyword = iter.next()?;
+            let r#type = Type::extract(iter)?;
+            let (var_names, commas) = VarName::extract_var_names(iter)?;
+            let semicolon = iter.expect_symbol(";")?;
             var_decs.push(VarDec{
+                var_keyword: var_keyword.into(),
                 r#type,
                 var_names,
+                commas,
+                semicolon: semicolon.into(),
+                span: iter.span_from(start),
             });
         }
 
@@ -280,14 +424,39 @@ impl VarDec {
     }
 }
 
+impl Spanned for VarDec {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+// ローカル変数をシンボルテーブルに登録する
+impl Resolve for VarDec {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        self.r#type.resolve(resolver);
+        for var_name in self.var_names.iter_mut() {
+            var_name.define(resolver, self.r#type.name(), Kind::Var);
+        }
+    }
+}
+
+impl Format for VarDec {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.var_keyword);
+        formatter.token(&self.r#type.inner);
+        separated_format(formatter, &self.var_names, &self.commas);
+        formatter.symbol_token(&self.semicolon);
+    }
+}
+
 impl Xml for VarDec {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str(Token::Keyword("var".into()).xml().as_str());
-        xml.push_str(self.r#type.xml().as_str());
-        xml.push_str(self.var_names.xml().as_str());
-        xml.push_str(Token::Symbol(";".into()).xml().as_str());
-        xml
+        xml.push_str(self.var_keyword.xml(options).as_str());
+        xml.push_str(self.r#type.xml(options).as_str());
+        xml.push_str(separated_xml(&self.var_names, &self.commas, options).as_str());
+        xml.push_str(self.semicolon.xml(options).as_str());
+        element("varDec", &self.span(), &xml, options)
     }
 }
 
@@ -298,22 +467,25 @@ impl Xml for VarDec {
 #[derive(Debug)]
 struct ClassName {
     inner: Token,
+    annotation: Option<Annotation>,
 }
 
 impl ClassName {
-    fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
-        let token = iter.next().unwrap();
-        if let Token::Identifier(_) = token {
-            Ok(Self { inner: token.into() })
-        } else {
-            Err("class name has missed".into())
-        }
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let token = iter.expect_identifier("class name")?;
+        Ok(Self { inner: token.into(), annotation: None })
+    }
+}
+
+impl Spanned for ClassName {
+    fn span(&self) -> Span {
+        self.inner.span()
     }
 }
 
 impl Xml for ClassName {
-    fn xml(&self) -> String {
-        self.inner.xml()
+    fn xml(&self, options: XmlOptions) -> String {
+        annotated_xml(&self.inner, &self.annotation, options)
     }
 }
 
@@ -324,20 +496,41 @@ impl Xml for ClassName {
 #[derive(Debug)]
 pub struct SubroutineName {
     inner: Token,
+    annotation: Option<Annotation>,
 }
 
 impl SubroutineName {
-    pub fn new(token: &Token) -> Result<Self, String> {
-        match token {
-            Token::Identifier(_) => Ok(Self { inner: token.into() }),
-            _ => Err("invalid SubroutineName".into())
-        }
+    pub fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let token = iter.expect_identifier("subroutine name")?;
+        Ok(Self { inner: token.into(), annotation: None })
+    }
+}
+
+impl SubroutineName {
+    pub fn name(&self) -> &str {
+        self.inner.as_str()
+    }
+
+    pub fn annotate(&mut self, defined: bool) {
+        self.annotation = Some(Annotation::subroutine(defined));
+    }
+}
+
+impl Spanned for SubroutineName {
+    fn span(&self) -> Span {
+        self.inner.span()
+    }
+}
+
+impl Format for SubroutineName {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.inner);
     }
 }
 
 impl Xml for SubroutineName {
-    fn xml(&self) -> String {
-        self.inner.xml()
+    fn xml(&self, options: XmlOptions) -> String {
+        annotated_xml(&self.inner, &self.annotation, options)
     }
 }
 
@@ -348,60 +541,130 @@ impl Xml for SubroutineName {
 #[derive(Debug)]
 pub struct VarName {
     inner: Token,
+    annotation: Option<Annotation>,
 }
 
 impl VarName {
-    pub fn new(token: &Token) -> Result<Self, String> {
-        match token {
-            Token::Identifier(_) => Ok(Self { inner: token.into() }),
-            _ => Err("invalid VarName".into())
+    pub fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let token = iter.expect_identifier("variable name")?;
+        Ok(Self { inner: token.into(), annotation: None })
+    }
+
+    pub fn name(&self) -> &str {
+        self.inner.as_str()
+    }
+
+    fn define(&mut self, resolver: &mut Resolver, r#type: &str, kind: Kind) {
+        self.annotation = resolver.define(self.name(), r#type, kind, Some(&self.inner.span));
+    }
+
+    // 変数を使っている箇所
+    pub fn resolve_use(&mut self, resolver: &mut Resolver) {
+        self.annotation = resolver.use_variable(self.name(), &self.inner.span);
+    }
+
+    // 変数でなければクラス名として扱う (`Foo.new()` や `Output.println()`)
+    pub fn resolve_receiver(&mut self, resolver: &mut Resolver) {
+        self.annotation = Some(resolver.lookup(self.name()).unwrap_or_else(|| Annotation::class(false)));
+    }
+
+    // 変数を使っている箇所の検査。変数の型を返す (クラス名なら Unknown)
+    pub fn check_use(&self, checker: &mut Checker) -> ValueType {
+        match self.symbol() {
+            Some(symbol) => {
+                // function には this が無いのでフィールドを読み書きできない
+                if symbol.kind == Kind::Field && checker.in_function() {
+                    checker.error(format!("field `{}` cannot be used in a function", self.name()), &self.inner.span);
+                }
+                ValueType::from(&symbol.r#type)
+            }
+            None => ValueType::Unknown,
         }
     }
 
-    fn extract_var_names(iter: &mut Peekable<Iter<Token>>) -> Result<Vec<Self>, String> {
+    // 識別子の解決で見つかった変数 (クラス名なら None)
+    pub fn symbol(&self) -> Option<&Symbol> {
+        match &self.annotation {
+            Some(Annotation { category: Category::Variable(symbol), .. }) => Some(symbol),
+            _ => None,
+        }
+    }
+
+    // 変数を読み書きするセグメントとインデックス
+    pub fn variable(&self) -> Result<(Segment, u16), String> {
+        self.symbol()
+            .map(|symbol| (symbol.kind.segment(), symbol.index))
+            .ok_or_else(|| format!("{}: undeclared identifier `{}`", self.inner.span, self.name()))
+    }
+
+    // VarName の並びと、その間の `,`
+    fn extract_var_names(iter: &mut TokenStream) -> Result<(Vec<Self>, Vec<Token>), ParseError> {
         let mut var_names = vec![];
+        let mut commas = vec![];
 
         // 少なくとも1つVarNameが宣言される
-        var_names.push(VarName::new(iter.next().unwrap()).unwrap());
+        var_names.push(VarName::extract(iter)?);
         // 2つめ以降のVarName宣言を処理する
-        loop {
-            // 先読みしてVarNameを組み立てるべきか判定する
-            if let Token::Symbol(symbol) = iter.peek().unwrap() {
-                if symbol == "," {
-                    let _ = iter.next(); // 先読みして判定していた Token::Symbol(",") を捨てる
-                    var_names.push(VarName::new(iter.next().unwrap()).unwrap());
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
+        // 先読みしてVarNameを組み立てるべきか判定する
+        while iter.is_symbol(",") {
+            commas.push(iter.next()?.into()); // 先読みして判定していた `,`
+            var_names.push(VarName::extract(iter)?);
         }
 
-        Ok(var_names)
+        Ok((var_names, commas))
+    }
+}
+
+impl Spanned for VarName {
+    fn span(&self) -> Span {
+        self.inner.span()
+    }
+}
+
+impl Format for VarName {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.inner);
     }
 }
 
 impl Xml for VarName {
-    fn xml(&self) -> String {
-        self.inner.xml()
+    fn xml(&self, options: XmlOptions) -> String {
+        annotated_xml(&self.inner, &self.annotation, options)
     }
 }
 
-impl Xml for Vec<VarName> {
-    fn xml(&self) -> String {
-        let mut xml = String::new();
-        for (i, var_name) in self.iter().enumerate() {
-            if i > 0 {
-                xml.push_str(Token::Symbol(",".into()).xml().as_str());
-            }
-            xml.push_str(var_name.xml().as_str());
+// 識別子の解決をしていて options で指定されていれば、何を指しているかを属性に付ける
+fn annotated_xml(token: &Token, annotation: &Option<Annotation>, options: XmlOptions) -> String {
+    match annotation {
+        Some(annotation) if options.with_annotations => with_span_attributes(annotation.xml(token.as_str()), &token.span, options),
+        _ => token.xml(options),
+    }
+}
+
+// `,` で区切った並び。commas は items の間の `,` で、items より1つ少ない
+pub fn separated_xml<T: Xml>(items: &[T], commas: &[Token], options: XmlOptions) -> String {
+    let mut xml = String::new();
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            xml.push_str(commas[i - 1].xml(options).as_str());
         }
+        xml.push_str(item.xml(options).as_str());
+    }
 
-        xml
+    xml
+}
+
+// `,` で区切った並びを書く
+pub fn separated_format<T: Format>(formatter: &mut Formatter, items: &[T], commas: &[Token]) {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            formatter.symbol_token(&commas[i - 1]);
+        }
+        item.format(formatter);
     }
 }
 
+
 /////////////////////////////////////////////////////////////
 // subroutineDecの構文
 // (`constructor` | `function` | `method`) (`void` | type) subroutineName `(` parameterList `)` subroutineBody
@@ -411,71 +674,185 @@ struct SubroutineDec {
     dec_keyword: Token,
     return_type: SubroutineReturnType,
     subroutine_name: SubroutineName,
+    open_paren: Token,
     parameter_list: ParameterList,
+    close_paren: Token,
     subroutine_body: SubroutineBody,
+    span: Span,
 }
 
 impl SubroutineDec {
-    fn extract(mut iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
-        let dec = iter.next().unwrap();
-        let return_type = SubroutineReturnType::new(iter.next().unwrap())?;
-        let subroutine_name = SubroutineName::new(iter.next().unwrap())?;
-        let _ = expect_symbol("(", iter.next().unwrap())?;
-        let parameter_list = ParameterList::extract(&mut iter)?;
-        let _ = expect_symbol(")", iter.next().unwrap())?;
-        let subroutine_body = SubroutineBody::extract(&mut iter)?;
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let dec = iter.next()?;
+        let return_type = SubroutineReturnType::extract(iter)?;
+        let subroutine_name = SubroutineName::extract(iter)?;
+        let open_paren = iter.expect_symbol("(")?;
+        let parameter_list = ParameterList::extract(iter)?;
+        let close_paren = iter.expect_symbol(")")?;
+        let subroutine_body = SubroutineBody::extract(iter)?;
 
         Ok(Self {
             dec_keyword: dec.into(),
             return_type,
             subroutine_name,
+            open_paren: open_paren.into(),
             parameter_list,
+            close_paren: close_paren.into(),
             subroutine_body,
+            span: iter.span_from(start),
         })
     }
 
-    fn extract_subroutine_decs(iter: &mut Peekable<Iter<Token>>) -> Result<Vec<Self>, String> {
+    // エラーのあった宣言は読み飛ばして次の宣言から続ける
+    fn extract_subroutine_decs(iter: &mut TokenStream) -> Vec<Self> {
         let mut subroutine_decs = vec![];
 
         loop {
             // 先読みしてサブルーチンの宣言かどうかを判定する
-            let dec = iter.peek();
-            if dec.is_none() {
-                break;
-            }
-            match dec.unwrap() {
-                Token::Keyword(keyword) => {
-                    if !SUBROUTINE_DEC_KEYWORD.contains(&keyword.as_str()) {
-                        break;
-                    }
-                }
-                _ => {
-                    break;
+            if iter.is_keyword(&SUBROUTINE_DEC_KEYWORD) {
+                match Self::extract(iter) {
+                    Ok(dec) => subroutine_decs.push(dec),
+                    Err(e) => iter.recover_declaration(e),
                 }
+            } else if iter.is_symbol("}") || iter.is_empty() {
+                break;
+            } else {
+                // 宣言になれないトークンは1つ取り出してから読み飛ばす
+                let e = iter.unexpected("subroutine declaration");
+                iter.skip();
+                iter.recover_declaration(e);
             }
+        }
+
+        subroutine_decs
+    }
+}
+
+impl SubroutineDec {
+    fn signature(&self) -> Signature {
+        Signature {
+            name: self.subroutine_name.name().into(),
+            // 構文解析で constructor, function, method のどれかであることは確認済み
+            kind: SubroutineKind::from(self.dec_keyword.as_str()).unwrap(),
+            return_type: match &self.return_type {
+                SubroutineReturnType::Void(_) => None,
+                SubroutineReturnType::Type(r#type) => Some(r#type.name().into()),
+            },
+            parameters: self.parameter_list.list.iter()
+                .flatten()
+                .map(|param| param.r#type.name().into())
+                .collect(),
+        }
+    }
+}
+
+impl Spanned for SubroutineDec {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Resolve for SubroutineDec {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        resolver.start_subroutine(self.subroutine_name.name());
+
+        // メソッドは呼び出し元のオブジェクトを argument 0 で受け取る
+        if self.dec_keyword.as_str() == "method" {
+            let class_name = resolver.class_name.clone();
+            resolver.define("this", &class_name, Kind::Argument, None);
+        }
+        if let SubroutineReturnType::Type(r#type) = &mut self.return_type {
+            r#type.resolve(resolver);
+        }
+        self.subroutine_name.annotate(true);
+        self.parameter_list.resolve(resolver);
+        for var_dec in self.subroutine_body.var_decs.iter_mut() {
+            var_dec.resolve(resolver);
+        }
+        self.subroutine_body.statements.resolve(resolver);
+    }
+}
+
+impl Check for SubroutineDec {
+    fn check(&self, checker: &mut Checker) {
+        let signature = self.signature();
+        let returns_value = signature.return_type.is_some();
+        checker.start_subroutine(signature);
+
+        let statements = &self.subroutine_body.statements;
+        statements.check(checker);
 
-            // 宣言を確認できたのでイテレータから取り出す
-            subroutine_decs.push(Self::extract(iter)?);
+        // 最後まで実行すると VM の次の関数に落ちてしまう
+        if !statements.always_returns() {
+            let message = if returns_value {
+                "not all paths return a value"
+            } else {
+                "missing `return` at the end of the subroutine"
+            };
+            checker.error(message.into(), &self.subroutine_name.span());
+        }
+    }
+}
+
+impl WriteVm for SubroutineDec {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        generator.start_subroutine();
+
+        let name = format!("{}.{}", generator.class_name, self.subroutine_name.name());
+        let num_locals = self.subroutine_body.var_decs.iter().map(|var_dec| var_dec.var_names.len() as u16).sum();
+        generator.writer.write_function(&name, num_locals);
+
+        match self.dec_keyword.as_str() {
+            // フィールドの数だけメモリを確保して this にする
+            // Memory.alloc(0) はエラーになるので、フィールドがなくても 1 ワードは確保する
+            "constructor" => {
+                let size = generator.num_fields.max(1);
+                generator.writer.write_push(Segment::Constant, size);
+                generator.write_os_call("Memory", "alloc")?;
+                generator.writer.write_pop(Segment::Pointer, 0);
+            }
+            "method" => {
+                generator.writer.write_push(Segment::Argument, 0);
+                generator.writer.write_pop(Segment::Pointer, 0);
+            }
+            _ => {}
         }
 
-        Ok(subroutine_decs)
+        self.subroutine_body.statements.write_vm(generator)
+    }
+}
+
+impl Format for SubroutineDec {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.dec_keyword);
+        match &self.return_type {
+            SubroutineReturnType::Void(token) => formatter.token(token),
+            SubroutineReturnType::Type(r#type) => formatter.token(&r#type.inner),
+        }
+        self.subroutine_name.format(formatter);
+        formatter.symbol_token(&self.open_paren);
+        formatter.glue();
+        if let Some(list) = &self.parameter_list.list {
+            separated_format(formatter, list, &self.parameter_list.commas);
+        }
+        formatter.symbol_token(&self.close_paren);
+        self.subroutine_body.format(formatter);
     }
 }
 
 impl Xml for SubroutineDec {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<subroutineDec>\n");
-        xml.push_str(self.dec_keyword.xml().as_str());
-        xml.push_str(self.return_type.xml().as_str());
-        xml.push_str(self.subroutine_name.xml().as_str());
-        xml.push_str(Token::Symbol("(".into()).xml().as_str());
-        xml.push_str(self.parameter_list.xml().as_str());
-        xml.push_str(Token::Symbol(")".into()).xml().as_str());
-
-        xml.push_str(self.subroutine_body.xml().as_str());
-        xml.push_str("</subroutineDec>\n");
-        xml
+        xml.push_str(self.dec_keyword.xml(options).as_str());
+        xml.push_str(self.return_type.xml(options).as_str());
+        xml.push_str(self.subroutine_name.xml(options).as_str());
+        xml.push_str(self.open_paren.xml(options).as_str());
+        xml.push_str(self.parameter_list.xml(options).as_str());
+        xml.push_str(self.close_paren.xml(options).as_str());
+
+        xml.push_str(self.subroutine_body.xml(options).as_str());
+        element("subroutineDec", &self.span(), &xml, options)
     }
 }
 
@@ -486,27 +863,30 @@ enum SubroutineReturnType {
 }
 
 impl SubroutineReturnType {
-    fn new(token: &Token) -> Result<Self, String> {
-        match token {
-            Token::Keyword(k) => {
-                if k == "void" {
-                    Ok(SubroutineReturnType::Void(token.into()))
-                } else {
-                    Err("invalid return type".into())
-                }
-            }
-            _ => {
-                Ok(SubroutineReturnType::Type(Type::new(token)?))
-            }
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        if iter.is_keyword(&["void"]) {
+            Ok(SubroutineReturnType::Void(iter.next()?.into()))
+        } else {
+            // `int` などのキーワードは Type で判定する
+            Ok(SubroutineReturnType::Type(Type::extract(iter)?))
+        }
+    }
+}
+
+impl Spanned for SubroutineReturnType {
+    fn span(&self) -> Span {
+        match self {
+            Self::Void(token) => token.span(),
+            Self::Type(r#type) => r#type.span(),
         }
     }
 }
 
 impl Xml for SubroutineReturnType {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         match self {
-            Self::Void(token) => token.xml(),
-            Self::Type(r#type) => r#type.xml(),
+            Self::Void(token) => token.xml(options),
+            Self::Type(r#type) => r#type.xml(options),
         }
     }
 }
@@ -518,40 +898,24 @@ impl Xml for SubroutineReturnType {
 #[derive(Debug)]
 struct ParameterList {
     list: Option<Vec<Parameter>>,
+    // list の間の `,`
+    commas: Vec<Token>,
+    // 引数が無ければ `)` の手前の長さ0の範囲
+    span: Span,
 }
 
 impl ParameterList {
-    fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let mut commas = vec![];
         let list = {
             if Self::should_extract(iter) {
-                let mut list = vec![];
-                list.push(
-                    Parameter::new(
-                        iter.next().unwrap(),
-                        iter.next().unwrap()
-                    )?
-                );
-
-                loop {
-                    // 先読みしてパラメータの宣言が続くかどうかを判定する
-                    match iter.peek().unwrap() {
-                        Token::Symbol(symbol) => {
-                            if symbol != "," {
-                                break;
-                            }
-                        }
-                        _ => {
-                            break;
-                        }
-                    }
-
-                    let _ = expect_symbol(",", iter.next().unwrap());
-                    list.push(
-                        Parameter::new(
-                            iter.next().unwrap(),
-                            iter.next().unwrap()
-                        )?
-                    );
+                let mut list = vec![Parameter::extract(iter)?];
+
+                // 先読みしてパラメータの宣言が続くかどうかを判定する
+                while iter.is_symbol(",") {
+                    commas.push(iter.next()?.into());
+                    list.push(Parameter::extract(iter)?);
                 }
 
                 Some(list)
@@ -560,39 +924,41 @@ impl ParameterList {
             }
         };
 
-        Ok(Self { list })
+        Ok(Self { list, commas, span: iter.span_from(start) })
     }
 
-    fn should_extract(iter: &mut Peekable<Iter<Token>>) -> bool {
-        match iter.peek().unwrap() {
-            Token::Symbol(symbol) => {
-                if symbol == ")" {
-                    false
-                } else {
-                    true
-                }
+    fn should_extract(iter: &TokenStream) -> bool {
+        !iter.is_symbol(")")
+    }
+}
+
+impl Spanned for ParameterList {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+// 引数をシンボルテーブルに登録する
+impl Resolve for ParameterList {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        if let Some(list) = &mut self.list {
+            for param in list.iter_mut() {
+                param.r#type.resolve(resolver);
+                param.var_name.define(resolver, param.r#type.name(), Kind::Argument);
             }
-            _ => true
         }
     }
 }
 
 impl Xml for ParameterList {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<parameterList>\n");
 
         if let Some(list) = &self.list {
-            for (i, param) in list.iter().enumerate() {
-                if i > 0 {
-                    xml.push_str(Token::Symbol(",".into()).xml().as_str());
-                }
-                xml.push_str(param.xml().as_str());
-            }
+            xml.push_str(separated_xml(list, &self.commas, options).as_str());
         }
 
-        xml.push_str("</parameterList>\n");
-        xml
+        element("parameterList", &self.span(), &xml, options)
     }
 }
 
@@ -602,10 +968,17 @@ struct Parameter {
     var_name: VarName,
 }
 
+impl Format for Parameter {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.r#type.inner);
+        self.var_name.format(formatter);
+    }
+}
+
 impl Parameter {
-    fn new(type_token: &Token, var_name_token: &Token) -> Result<Self, String> {
-        let r#type = Type::new(type_token)?;
-        let var_name = VarName::new(var_name_token)?;
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let r#type = Type::extract(iter)?;
+        let var_name = VarName::extract(iter)?;
 
         Ok(Self {
             r#type,
@@ -614,11 +987,17 @@ impl Parameter {
     }
 }
 
+impl Spanned for Parameter {
+    fn span(&self) -> Span {
+        self.r#type.span().to(&self.var_name.span())
+    }
+}
+
 impl Xml for Parameter {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str(self.r#type.xml().as_str());
-        xml.push_str(self.var_name.xml().as_str());
+        xml.push_str(self.r#type.xml(options).as_str());
+        xml.push_str(self.var_name.xml(options).as_str());
         xml
     }
 }
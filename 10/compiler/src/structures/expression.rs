@@ -1,9 +1,13 @@
-use std::iter::Peekable;
-use std::slice::Iter;
-use crate::tokenizer::Token;
+use crate::tokenizer::{integer_value, Span, Spanned, Token, TokenKind};
 use crate::structures::class::{VarName, SubroutineName};
-use crate::compilation_engine::expect_symbol;
-use crate::Xml;
+use crate::code_generator::{CodeGenerator, WriteVm};
+use crate::compilation_engine::{ParseError, TokenStream};
+use crate::formatter::{Format, Formatter};
+use crate::semantic::{Argument, Checker, Receiver, ValueType};
+use crate::symbol_table::{Resolve, Resolver};
+use crate::vm_writer::{ArithmeticCommand, Segment};
+use crate::structures::class::{separated_format, separated_xml};
+use crate::{element, Dialect, Xml, XmlOptions};
 
 const OP: [&str; 9] = [
     "+",
@@ -20,6 +24,26 @@ const UNARY_OP: [&str; 2] = [
     "-",
     "~",
 ];
+// 拡張の文法の二項演算子と優先順位 (大きいほど強く結びつき、どれも左結合)
+const EXTENDED_OP: [(&str, u8); 17] = [
+    ("||", 1),
+    ("&&", 2),
+    ("|", 3),
+    ("&", 4),
+    ("=", 5),
+    ("!=", 5),
+    ("<", 6),
+    (">", 6),
+    ("<=", 6),
+    (">=", 6),
+    ("<<", 7),
+    (">>", 7),
+    ("+", 8),
+    ("-", 8),
+    ("*", 9),
+    ("/", 9),
+    ("%", 9),
+];
 const KEYWORD_CONSTANT: [&str; 4] = [
     "true",
     "false",
@@ -30,6 +54,7 @@ const KEYWORD_CONSTANT: [&str; 4] = [
 /////////////////////////////////////////////////////////////
 // expressionの構文
 // term (op term)*
+// 拡張の文法では優先順位に従って Term::BinaryOp の木にし、op_terms は空になる
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 pub struct Expression {
@@ -38,43 +63,236 @@ pub struct Expression {
 }
 
 impl Expression {
-    pub fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
+    pub fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        if iter.dialect() == Dialect::Extended {
+            let term = Self::extract_binary_op(iter, 1)?;
+            return Ok(Self { term: Box::new(term), op_terms: vec![] });
+        }
+
         let term = Term::extract(iter)?;
         let mut op_terms = vec![];
 
-        loop {
-            match iter.peek().unwrap() {
-                Token::Symbol(symbol) => {
-                    if !OP.contains(&symbol.as_str()) {
-                        break;
-                    }
-                }
-                _ => { break; }
+        while let Some(TokenKind::Symbol(symbol)) = iter.peek_kind() {
+            if !OP.contains(&symbol.as_str()) {
+                break;
             }
 
-            let op = iter.next().unwrap();
+            let op = iter.next()?;
             let term = Term::extract(iter)?;
             op_terms.push((op.into(), term));
         }
 
         Ok(Self { term: Box::new(term), op_terms })
     }
+
+    // 優先順位が min_precedence 以上の演算子だけをまとめる
+    fn extract_binary_op(iter: &mut TokenStream, min_precedence: u8) -> Result<Term, ParseError> {
+        let mut lhs = Term::extract(iter)?;
+
+        while let Some(precedence) = Self::precedence(iter).filter(|p| *p >= min_precedence) {
+            let op = iter.next()?.into();
+            // 右側は1つ強い演算子だけをまとめるので左結合になる
+            let rhs = Self::extract_binary_op(iter, precedence + 1)?;
+            lhs = Term::BinaryOp(Box::new(lhs), op, Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    // 次のトークンが二項演算子ならその優先順位
+    fn precedence(iter: &TokenStream) -> Option<u8> {
+        match iter.peek_kind() {
+            Some(TokenKind::Symbol(symbol)) => EXTENDED_OP.iter()
+                .find(|(op, _)| op == symbol)
+                .map(|(_, precedence)| *precedence),
+            _ => None,
+        }
+    }
+}
+
+impl Spanned for Expression {
+    fn span(&self) -> Span {
+        match self.op_terms.last() {
+            Some((_, term)) => self.term.span().to(&term.span()),
+            None => self.term.span(),
+        }
+    }
+}
+
+impl Resolve for Expression {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        self.term.resolve(resolver);
+        for (_, term) in self.op_terms.iter_mut() {
+            term.resolve(resolver);
+        }
+    }
+}
+
+impl Expression {
+    // 式を検査して、その型を返す
+    pub fn check_type(&self, checker: &mut Checker) -> ValueType {
+        let mut value_type = self.term.check_type(checker);
+        for (op, term) in &self.op_terms {
+            term.check_type(checker);
+            value_type = operator_type(op, &value_type);
+        }
+        value_type
+    }
+
+    // `this` だけの式か (constructor の戻り値)
+    pub fn is_this(&self) -> bool {
+        match (self.term.as_ref(), self.op_terms.is_empty()) {
+            (Term::KeywordConstant(token), true) => token.as_str() == "this",
+            (Term::Expression(_, expression, _), true) => expression.is_this(),
+            _ => false,
+        }
+    }
+
+    // 整数定数だけの式か (`Output.printChar(32)` の引数)
+    pub fn is_integer_constant(&self) -> bool {
+        match (self.term.as_ref(), self.op_terms.is_empty()) {
+            (Term::IntegerConstant(_), true) => true,
+            (Term::Expression(_, expression, _), true) => expression.is_integer_constant(),
+            _ => false,
+        }
+    }
+}
+
+impl WriteVm for Expression {
+    // 左から順に評価する (演算子の優先順位は無い)
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        self.term.write_vm(generator)?;
+
+        for (token, term) in &self.op_terms {
+            term.write_vm(generator)?;
+            write_operator(token, generator)?;
+        }
+        Ok(())
+    }
+}
+
+// 演算子の結果の型
+fn operator_type(op: &Token, lhs: &ValueType) -> ValueType {
+    match op.as_str() {
+        "<" | ">" | "=" | "<=" | ">=" | "!=" | "&&" | "||" => ValueType::Boolean,
+        // ビット演算なので boolean 同士なら boolean
+        "&" | "|" if *lhs == ValueType::Boolean => ValueType::Boolean,
+        _ => ValueType::Int,
+    }
+}
+
+// スタックに積んだ2つの値に演算子を適用する (`&&` と `||` は Term::BinaryOp で書き出す)
+fn write_operator(op: &Token, generator: &mut CodeGenerator) -> Result<(), String> {
+    match op.as_str() {
+        "+" => generator.writer.write_arithmetic(ArithmeticCommand::Add),
+        "-" => generator.writer.write_arithmetic(ArithmeticCommand::Sub),
+        "*" => generator.write_os_call("Math", "multiply")?,
+        "/" => generator.write_os_call("Math", "divide")?,
+        "&" => generator.writer.write_arithmetic(ArithmeticCommand::And),
+        "|" => generator.writer.write_arithmetic(ArithmeticCommand::Or),
+        "<" => generator.writer.write_arithmetic(ArithmeticCommand::Lt),
+        ">" => generator.writer.write_arithmetic(ArithmeticCommand::Gt),
+        "=" => generator.writer.write_arithmetic(ArithmeticCommand::Eq),
+        "<=" => {
+            generator.writer.write_arithmetic(ArithmeticCommand::Gt);
+            generator.writer.write_arithmetic(ArithmeticCommand::Not);
+        }
+        ">=" => {
+            generator.writer.write_arithmetic(ArithmeticCommand::Lt);
+            generator.writer.write_arithmetic(ArithmeticCommand::Not);
+        }
+        "!=" => {
+            generator.writer.write_arithmetic(ArithmeticCommand::Eq);
+            generator.writer.write_arithmetic(ArithmeticCommand::Not);
+        }
+        // a - (a / b) * b
+        "%" => {
+            write_save_operands(generator);
+            generator.writer.write_push(Segment::Temp, 1);
+            generator.writer.write_push(Segment::Temp, 1);
+            generator.writer.write_push(Segment::Temp, 2);
+            generator.write_os_call("Math", "divide")?;
+            generator.writer.write_push(Segment::Temp, 2);
+            generator.write_os_call("Math", "multiply")?;
+            generator.writer.write_arithmetic(ArithmeticCommand::Sub);
+        }
+        // VM にシフト命令は無いので、1ビットずつずらすループにする
+        "<<" => write_shift(generator, |generator| {
+            generator.writer.write_push(Segment::Temp, 1);
+            generator.writer.write_push(Segment::Temp, 1);
+            generator.writer.write_arithmetic(ArithmeticCommand::Add);
+            Ok(())
+        })?,
+        // 算術シフト。最下位ビットを落としてから2で割ると負の数も切り捨てになる
+        ">>" => write_shift(generator, |generator| {
+            generator.writer.write_push(Segment::Temp, 1);
+            generator.writer.write_push(Segment::Temp, 1);
+            generator.writer.write_push(Segment::Constant, 1);
+            generator.writer.write_arithmetic(ArithmeticCommand::And);
+            generator.writer.write_arithmetic(ArithmeticCommand::Sub);
+            generator.writer.write_push(Segment::Constant, 2);
+            generator.write_os_call("Math", "divide")
+        })?,
+        other => return Err(format!("invalid operator: {}", other)),
+    }
+    Ok(())
+}
+
+// 左の値を temp 1、右の値を temp 2 に退避する
+// temp 0 は do 文と配列への代入で使うので避ける
+fn write_save_operands(generator: &mut CodeGenerator) {
+    generator.writer.write_pop(Segment::Temp, 2);
+    generator.writer.write_pop(Segment::Temp, 1);
+}
+
+// temp 2 が 0 になるまで、shift_once で求めた値を temp 1 に入れ直す
+fn write_shift<F>(generator: &mut CodeGenerator, shift_once: F) -> Result<(), String>
+    where F: Fn(&mut CodeGenerator) -> Result<(), String> {
+    let index = generator.next_label_index();
+    let shift_loop = format!("SHIFT_LOOP{}", index);
+    let shift_end = format!("SHIFT_END{}", index);
+
+    write_save_operands(generator);
+    generator.writer.write_label(&shift_loop);
+    generator.writer.write_push(Segment::Temp, 2);
+    generator.writer.write_push(Segment::Constant, 0);
+    generator.writer.write_arithmetic(ArithmeticCommand::Gt);
+    generator.writer.write_arithmetic(ArithmeticCommand::Not);
+    generator.writer.write_if(&shift_end);
+    shift_once(generator)?;
+    generator.writer.write_pop(Segment::Temp, 1);
+    generator.writer.write_push(Segment::Temp, 2);
+    generator.writer.write_push(Segment::Constant, 1);
+    generator.writer.write_arithmetic(ArithmeticCommand::Sub);
+    generator.writer.write_pop(Segment::Temp, 2);
+    generator.writer.write_goto(&shift_loop);
+    generator.writer.write_label(&shift_end);
+    generator.writer.write_push(Segment::Temp, 1);
+    Ok(())
+}
+
+impl Format for Expression {
+    fn format(&self, formatter: &mut Formatter) {
+        self.term.format(formatter);
+        for (op, term) in &self.op_terms {
+            formatter.token(op);
+            term.format(formatter);
+        }
+    }
 }
 
 impl Xml for Expression {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<expression>\n");
 
-        xml.push_str(self.term.xml().as_str());
+        xml.push_str(self.term.xml(options).as_str());
 
         for (token, term) in &self.op_terms {
-            xml.push_str(token.xml().as_str());
-            xml.push_str(term.xml().as_str());
+            xml.push_str(token.xml(options).as_str());
+            xml.push_str(term.xml(options).as_str());
         }
 
-        xml.push_str("</expression>\n");
-        xml
+        element("expression", &self.span(), &xml, options)
     }
 }
 
@@ -89,94 +307,299 @@ impl Xml for Expression {
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 enum Term {
-    IntegerConstant(String),
-    StringConstant(String),
+    IntegerConstant(Token),
+    StringConstant(Token),
     KeywordConstant(Token),
     VarName(VarName),
-    VarNameWithExpression(VarName, Expression),
-    SubroutineCall(SubroutineCall),
-    Expression(Expression),
+    // varName `[` expression `]`
+    VarNameWithExpression(VarName, Token, Expression, Token),
+    SubroutineCall(Box<SubroutineCall>), // 大きいのでBoxで包む
+    // `(` expression `)`
+    Expression(Token, Expression, Token),
     UnaryOp(Token, Box<Term>), // 再帰構造なのでBoxで包む
+    // 拡張の文法の二項演算
+    BinaryOp(Box<Term>, Token, Box<Term>),
+}
+
+impl Term {
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let token = match iter.peek() {
+            Some(token) => token,
+            None => return Err(iter.unexpected("term")),
+        };
+        Ok(match &token.kind {
+            TokenKind::IntegerConst(_) => {
+                iter.next()?;
+                Term::IntegerConstant(token.into())
+            }
+            TokenKind::StringConst(_) => {
+                iter.next()?;
+                Term::StringConstant(token.into())
+            }
+            TokenKind::Keyword(keyword) if KEYWORD_CONSTANT.contains(&keyword.as_str()) => {
+                iter.next()?;
+                Term::KeywordConstant(token.into())
+            }
+            // 2つ先のトークンで配列、サブルーチン呼び出し、変数を見分ける
+            TokenKind::Identifier(_) => {
+                match iter.lookahead(1) {
+                    Some(TokenKind::Symbol(symbol)) if symbol == "[" => {
+                        let var_name = VarName::extract(iter)?;
+                        let open_bracket = iter.expect_symbol("[")?;
+                        let expression = Expression::extract(iter)?;
+                        let close_bracket = iter.expect_symbol("]")?;
+                        Term::VarNameWithExpression(var_name, open_bracket.into(), expression, close_bracket.into())
+                    }
+                    Some(TokenKind::Symbol(symbol)) if symbol == "(" || symbol == "." => {
+                        Term::SubroutineCall(Box::new(SubroutineCall::extract(iter)?))
+                    }
+                    _ => Term::VarName(VarName::extract(iter)?),
+                }
+            }
+            TokenKind::Symbol(symbol) if symbol == "(" => {
+                iter.next()?;
+                let expression = Expression::extract(iter)?;
+                let close_paren = iter.expect_symbol(")")?;
+                Term::Expression(token.into(), expression, close_paren.into())
+            }
+            TokenKind::Symbol(symbol) if UNARY_OP.contains(&symbol.as_str()) => {
+                iter.next()?;
+                Term::UnaryOp(token.into(), Box::new(Term::extract(iter)?))
+            }
+            _ => return Err(iter.unexpected("term")),
+        })
+    }
+}
+
+impl Spanned for Term {
+    fn span(&self) -> Span {
+        match self {
+            Self::IntegerConstant(token) | Self::StringConstant(token) | Self::KeywordConstant(token) => token.span(),
+            Self::VarName(var_name) => var_name.span(),
+            Self::VarNameWithExpression(var_name, _, _, close_bracket) => var_name.span().to(&close_bracket.span),
+            Self::Expression(open_paren, _, close_paren) => open_paren.span().to(&close_paren.span),
+            Self::SubroutineCall(subroutine_call) => subroutine_call.span(),
+            Self::UnaryOp(token, term) => token.span().to(&term.span()),
+            Self::BinaryOp(lhs, _, rhs) => lhs.span().to(&rhs.span()),
+        }
+    }
+}
+
+impl Resolve for Term {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        match self {
+            Self::VarName(var_name) => var_name.resolve_use(resolver),
+            Self::VarNameWithExpression(var_name, _, expression, _) => {
+                var_name.resolve_use(resolver);
+                expression.resolve(resolver);
+            }
+            Self::SubroutineCall(subroutine_call) => subroutine_call.resolve(resolver),
+            Self::Expression(_, expression, _) => expression.resolve(resolver),
+            Self::UnaryOp(_, term) => term.resolve(resolver),
+            Self::BinaryOp(lhs, _, rhs) => {
+                lhs.resolve(resolver);
+                rhs.resolve(resolver);
+            }
+            Self::IntegerConstant(_) | Self::StringConstant(_) | Self::KeywordConstant(_) => {}
+        }
+    }
 }
 
 impl Term {
-    fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
-        let token = iter.next().unwrap();
-        Ok(match token {
-            Token::IntegerConst(integer) => Term::IntegerConstant(integer.into()),
-            Token::StringConst(string) => Term::StringConstant(string.into()),
-            Token::Keyword(keyword) => {
-                if KEYWORD_CONSTANT.contains(&keyword.as_str()) {
-                    Term::KeywordConstant(token.into())
+    fn check_type(&self, checker: &mut Checker) -> ValueType {
+        match self {
+            // 拡張の文法の `'a'` は char
+            Self::IntegerConstant(token) if token.as_str().starts_with('\'') => ValueType::Char,
+            Self::IntegerConstant(_) => ValueType::Int,
+            Self::StringConstant(_) => ValueType::Class("String".into()),
+            Self::KeywordConstant(token) => match token.as_str() {
+                "true" | "false" => ValueType::Boolean,
+                "null" => ValueType::Null,
+                _ => {
+                    if checker.in_function() {
+                        checker.error("`this` cannot be used in a function".into(), &token.span);
+                    }
+                    ValueType::Class(checker.class_name.clone())
+                }
+            },
+            Self::VarName(var_name) => var_name.check_use(checker),
+            Self::VarNameWithExpression(var_name, _, expression, _) => {
+                var_name.check_use(checker);
+                expression.check_type(checker);
+                ValueType::Unknown
+            }
+            Self::SubroutineCall(subroutine_call) => subroutine_call.check_type(checker),
+            Self::Expression(_, expression, _) => expression.check_type(checker),
+            Self::UnaryOp(token, term) => {
+                let value_type = term.check_type(checker);
+                match token.as_str() {
+                    "~" if value_type == ValueType::Boolean => ValueType::Boolean,
+                    _ => ValueType::Int,
+                }
+            }
+            Self::BinaryOp(lhs, op, rhs) => {
+                let lhs_type = lhs.check_type(checker);
+                rhs.check_type(checker);
+                operator_type(op, &lhs_type)
+            }
+        }
+    }
+}
+
+impl WriteVm for Term {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        match self {
+            Self::IntegerConstant(integer) => {
+                let value = integer_value(integer.as_str())
+                    .ok_or_else(|| format!("{}: integer constant should be 0..32767: {}", integer.span, integer.as_str()))?;
+                if value <= 32767 {
+                    generator.writer.write_push(Segment::Constant, value);
                 } else {
-                    return Err("invalid keyword".into())
+                    // 拡張の文法の16進数は push constant に入らないので、ビットを反転させて作る
+                    generator.writer.write_push(Segment::Constant, !value);
+                    generator.writer.write_arithmetic(ArithmeticCommand::Not);
+                }
+            }
+            // String.new で確保して1文字ずつ追加する
+            Self::StringConstant(string) => {
+                let string = string.as_str();
+                generator.writer.write_push(Segment::Constant, string.chars().count() as u16);
+                generator.write_os_call("String", "new")?;
+                for c in string.chars() {
+                    generator.writer.write_push(Segment::Constant, c as u16);
+                    generator.write_os_call("String", "appendChar")?;
+                }
+            }
+            Self::KeywordConstant(token) => match token.as_str() {
+                // true は -1
+                "true" => {
+                    generator.writer.write_push(Segment::Constant, 0);
+                    generator.writer.write_arithmetic(ArithmeticCommand::Not);
                 }
+                "false" | "null" => generator.writer.write_push(Segment::Constant, 0),
+                "this" => generator.writer.write_push(Segment::Pointer, 0),
+                other => return Err(format!("invalid keyword constant: {}", other)),
             },
-            Token::Identifier(_) => {
-                let next = iter.peek().unwrap();
-                match next {
-                    Token::Symbol(symbol) => {
-                        if symbol == "[" {
-                            let var_name = VarName::new(token)?;
-                            let _ = expect_symbol("[", iter.next().unwrap())?;
-                            let expression = Expression::extract(iter)?;
-                            let _ = expect_symbol("]", iter.next().unwrap())?;
-                            Term::VarNameWithExpression(var_name, expression)
-                        } else if symbol == "(" || symbol == "." {
-                            Term::SubroutineCall(SubroutineCall::extract_with_first_token(
-                                token,
-                                iter
-                            )?)
-                        } else {
-                            Term::VarName(VarName::new(token)?)
-                        }
-                    }
-                    _ => Term::VarName(VarName::new(token)?),
+            Self::VarName(var_name) => {
+                let (segment, index) = var_name.variable()?;
+                generator.writer.write_push(segment, index);
+            }
+            Self::VarNameWithExpression(var_name, _, expression, _) => {
+                let (segment, index) = var_name.variable()?;
+                generator.writer.write_push(segment, index);
+                expression.write_vm(generator)?;
+                generator.writer.write_arithmetic(ArithmeticCommand::Add);
+                generator.writer.write_pop(Segment::Pointer, 1);
+                generator.writer.write_push(Segment::That, 0);
+            }
+            Self::SubroutineCall(subroutine_call) => subroutine_call.write_vm(generator)?,
+            Self::Expression(_, expression, _) => expression.write_vm(generator)?,
+            Self::UnaryOp(token, term) => {
+                term.write_vm(generator)?;
+                match token.as_str() {
+                    "-" => generator.writer.write_arithmetic(ArithmeticCommand::Neg),
+                    "~" => generator.writer.write_arithmetic(ArithmeticCommand::Not),
+                    other => return Err(format!("invalid unary operator: {}", other)),
                 }
             }
-            Token::Symbol(symbol) => {
-                if symbol == "(" {
-                    let expression = Expression::extract(iter)?;
-                    let _ = expect_symbol(")", iter.next().unwrap())?;
-                    Term::Expression(expression)
-                } else if UNARY_OP.contains(&symbol.as_str()) {
-                    Term::UnaryOp(token.into(), Box::new(Term::extract(iter)?))
+            // 左が決まれば右は評価しない
+            Self::BinaryOp(lhs, op, rhs) if op.as_str() == "&&" || op.as_str() == "||" => {
+                let index = generator.next_label_index();
+                let (short_circuit, end) = if op.as_str() == "&&" {
+                    (format!("AND_FALSE{}", index), format!("AND_END{}", index))
                 } else {
-                    return Err(format!("invalid symbol as term: {}", symbol).into());
+                    (format!("OR_TRUE{}", index), format!("OR_END{}", index))
+                };
+
+                lhs.write_vm(generator)?;
+                if op.as_str() == "&&" {
+                    generator.writer.write_arithmetic(ArithmeticCommand::Not);
                 }
+                generator.writer.write_if(&short_circuit);
+                rhs.write_vm(generator)?;
+                generator.writer.write_goto(&end);
+                generator.writer.write_label(&short_circuit);
+                // false は 0、true は -1
+                generator.writer.write_push(Segment::Constant, 0);
+                if op.as_str() == "||" {
+                    generator.writer.write_arithmetic(ArithmeticCommand::Not);
+                }
+                generator.writer.write_label(&end);
             }
-        })
+            Self::BinaryOp(lhs, op, rhs) => {
+                lhs.write_vm(generator)?;
+                rhs.write_vm(generator)?;
+                write_operator(op, generator)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Format for Term {
+    fn format(&self, formatter: &mut Formatter) {
+        match self {
+            Self::IntegerConstant(token) | Self::StringConstant(token) | Self::KeywordConstant(token) => formatter.token(token),
+            Self::VarName(var_name) => var_name.format(formatter),
+            Self::VarNameWithExpression(var_name, open_bracket, expression, close_bracket) => {
+                var_name.format(formatter);
+                formatter.symbol_token(open_bracket);
+                formatter.glue();
+                expression.format(formatter);
+                formatter.symbol_token(close_bracket);
+            }
+            Self::SubroutineCall(subroutine_call) => subroutine_call.format(formatter),
+            Self::Expression(open_paren, expression, close_paren) => {
+                formatter.token(open_paren);
+                formatter.glue();
+                expression.format(formatter);
+                formatter.symbol_token(close_paren);
+            }
+            Self::UnaryOp(token, term) => {
+                formatter.token(token);
+                formatter.glue();
+                term.format(formatter);
+            }
+            Self::BinaryOp(lhs, op, rhs) => {
+                lhs.format(formatter);
+                formatter.token(op);
+                rhs.format(formatter);
+            }
+        }
     }
 }
 
 impl Xml for Term {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<term>\n");
 
         match self {
-            Self::IntegerConstant(integer) => xml.push_str(format!("<integerConstant>{}</integerConstant>\n", integer).as_str()),
-            Self::StringConstant(string) => xml.push_str(format!("<stringConstant>{}</stringConstant>\n", string).as_str()),
-            Self::KeywordConstant(token) => xml.push_str(token.xml().as_str()),
-            Self::VarName(var_name) => xml.push_str(var_name.xml().as_str()),
-            Self::VarNameWithExpression(var_name, expression) => {
-                xml.push_str(var_name.xml().as_str());
-                xml.push_str(expression.xml().as_str());
-            }
-            Self::SubroutineCall(subroutine_call) => xml.push_str(subroutine_call.xml().as_str()),
-            Self::Expression(expression) => {
-                xml.push_str(Token::Symbol("(".into()).xml().as_str());
-                xml.push_str(expression.xml().as_str());
-                xml.push_str(Token::Symbol(")".into()).xml().as_str());
+            Self::IntegerConstant(token) | Self::StringConstant(token) | Self::KeywordConstant(token) => xml.push_str(token.xml(options).as_str()),
+            Self::VarName(var_name) => xml.push_str(var_name.xml(options).as_str()),
+            Self::VarNameWithExpression(var_name, open_bracket, expression, close_bracket) => {
+                xml.push_str(var_name.xml(options).as_str());
+                xml.push_str(open_bracket.xml(options).as_str());
+                xml.push_str(expression.xml(options).as_str());
+                xml.push_str(close_bracket.xml(options).as_str());
+            }
+            Self::SubroutineCall(subroutine_call) => xml.push_str(subroutine_call.xml(options).as_str()),
+            Self::Expression(open_paren, expression, close_paren) => {
+                xml.push_str(open_paren.xml(options).as_str());
+                xml.push_str(expression.xml(options).as_str());
+                xml.push_str(close_paren.xml(options).as_str());
             },
             Self::UnaryOp(token, term) => {
-                xml.push_str(token.xml().as_str());
-                xml.push_str(term.xml().as_str());
+                xml.push_str(token.xml(options).as_str());
+                xml.push_str(term.xml(options).as_str());
+            }
+            Self::BinaryOp(lhs, op, rhs) => {
+                xml.push_str(lhs.xml(options).as_str());
+                xml.push_str(op.xml(options).as_str());
+                xml.push_str(rhs.xml(options).as_str());
             }
         }
 
-        xml.push_str("</term>\n");
-        xml
+        element("term", &self.span(), &xml, options)
     }
 }
 
@@ -186,64 +609,143 @@ impl Xml for Term {
 // | (className | varName) `.` subroutineName `(` expressionList `)`
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
-pub enum SubroutineCall {
-    Subroutine(Token, ExpressionList),
-    Method(Token, SubroutineName, ExpressionList),
+pub struct SubroutineCall {
+    // 変数名かクラス名と `.` (自分のクラスのメソッドなら None)
+    receiver: Option<(VarName, Token)>,
+    subroutine_name: SubroutineName,
+    open_paren: Token,
+    expression_list: ExpressionList,
+    close_paren: Token,
 }
 
 impl SubroutineCall {
-    pub fn extract_with_first_token(token: &Token, iter: &mut Peekable<Iter<Token>>) -> Result<SubroutineCall, String> {
-        let next = iter.peek().unwrap();
-        match next {
-            Token::Symbol(symbol) => {
-                if symbol == "(" {
-                    expect_symbol("(", iter.next().unwrap())?;
-                    let expression_list = ExpressionList::extract(iter)?;
-                    expect_symbol(")", iter.next().unwrap())?;
-                    Ok(SubroutineCall::Subroutine(
-                        token.into(),
-                        expression_list
-                    ))
-                } else if symbol == "." {
-                    expect_symbol(".", iter.next().unwrap())?;
-                    let subroutine_name = SubroutineName::new(iter.next().unwrap())?;
-                    expect_symbol("(", iter.next().unwrap())?;
-                    let expression_list = ExpressionList::extract(iter)?;
-                    expect_symbol(")", iter.next().unwrap())?;
-                    Ok(SubroutineCall::Method(
-                        token.into(),
-                        subroutine_name,
-                        expression_list
-                    ))
-                } else {
-                    Err("invalid keyword".into())
+    pub fn extract(iter: &mut TokenStream) -> Result<SubroutineCall, ParseError> {
+        // 2つ先のトークンが `.` ならクラス名か変数名から始まる
+        let receiver = match iter.lookahead(1) {
+            Some(TokenKind::Symbol(symbol)) if symbol == "." => {
+                let var_name = VarName::extract(iter)?;
+                let dot = iter.expect_symbol(".")?;
+                Some((var_name, dot.into()))
+            }
+            _ => None,
+        };
+        let subroutine_name = SubroutineName::extract(iter)?;
+        let open_paren = iter.expect_symbol("(")?;
+        let expression_list = ExpressionList::extract(iter)?;
+        let close_paren = iter.expect_symbol(")")?;
+
+        Ok(SubroutineCall {
+            receiver,
+            subroutine_name,
+            open_paren: open_paren.into(),
+            expression_list,
+            close_paren: close_paren.into(),
+        })
+    }
+}
+
+impl Spanned for SubroutineCall {
+    fn span(&self) -> Span {
+        let start = match &self.receiver {
+            Some((receiver, _)) => receiver.span(),
+            None => self.subroutine_name.span(),
+        };
+        start.to(&self.close_paren.span)
+    }
+}
+
+impl Resolve for SubroutineCall {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        if let Some((receiver, _)) = &mut self.receiver {
+            receiver.resolve_receiver(resolver);
+        }
+        self.subroutine_name.annotate(false);
+        self.expression_list.resolve(resolver);
+    }
+}
+
+impl SubroutineCall {
+    // 呼び出しを検査して、戻り値の型を返す
+    pub fn check_type(&self, checker: &mut Checker) -> ValueType {
+        let arguments = self.expression_list.check_types(checker);
+
+        let receiver = match &self.receiver {
+            None => Receiver::This,
+            Some((receiver, _)) => match receiver.symbol() {
+                Some(symbol) => {
+                    receiver.check_use(checker);
+                    Receiver::Variable(receiver.name(), &symbol.r#type)
+                }
+                None => Receiver::Class(receiver.name()),
+            },
+        };
+
+        let name = self.subroutine_name.name();
+        checker.check_call(&receiver, name, &arguments, &self.span());
+        checker.return_type(&receiver, name)
+    }
+}
+
+impl WriteVm for SubroutineCall {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        let expression_list = &self.expression_list;
+        match &self.receiver {
+            // 自分のクラスのメソッドを this に対して呼び出す
+            None => {
+                generator.writer.write_push(Segment::Pointer, 0);
+                expression_list.write_vm(generator)?;
+                let name = format!("{}.{}", generator.class_name, self.subroutine_name.name());
+                generator.writer.write_call(&name, expression_list.len() + 1);
+            }
+            Some((receiver, _)) => {
+                match receiver.symbol() {
+                    // 変数ならそのオブジェクトのメソッド呼び出し
+                    Some(symbol) => {
+                        generator.writer.write_push(symbol.kind.segment(), symbol.index);
+                        expression_list.write_vm(generator)?;
+                        let name = format!("{}.{}", symbol.r#type, self.subroutine_name.name());
+                        generator.writer.write_call(&name, expression_list.len() + 1);
+                    }
+                    // クラス名なら function か constructor の呼び出し
+                    None => {
+                        expression_list.write_vm(generator)?;
+                        let name = format!("{}.{}", receiver.name(), self.subroutine_name.name());
+                        generator.writer.write_call(&name, expression_list.len());
+                    }
                 }
             }
-            _ => Err(format!("invalid subroutine call: {:?}", next).into())
         }
+        Ok(())
+    }
+}
+
+impl Format for SubroutineCall {
+    fn format(&self, formatter: &mut Formatter) {
+        if let Some((receiver, dot)) = &self.receiver {
+            receiver.format(formatter);
+            formatter.symbol_token(dot);
+            formatter.glue();
+        }
+        self.subroutine_name.format(formatter);
+        formatter.symbol_token(&self.open_paren);
+        formatter.glue();
+        self.expression_list.format(formatter);
+        formatter.symbol_token(&self.close_paren);
     }
 }
 
 impl Xml for SubroutineCall {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
 
-        match self {
-            Self::Subroutine(token, expression_list) => {
-                xml.push_str(token.xml().as_str());
-                xml.push_str(Token::Symbol("(".into()).xml().as_str());
-                xml.push_str(expression_list.xml().as_str());
-                xml.push_str(Token::Symbol(")".into()).xml().as_str());
-            }
-            Self::Method(token, subroutine_name, expression_list) => {
-                xml.push_str(token.xml().as_str());
-                xml.push_str(Token::Symbol(".".into()).xml().as_str());
-                xml.push_str(subroutine_name.xml().as_str());
-                xml.push_str(Token::Symbol("(".into()).xml().as_str());
-                xml.push_str(expression_list.xml().as_str());
-                xml.push_str(Token::Symbol(")".into()).xml().as_str());
-            }
+        if let Some((receiver, dot)) = &self.receiver {
+            xml.push_str(receiver.xml(options).as_str());
+            xml.push_str(dot.xml(options).as_str());
         }
+        xml.push_str(self.subroutine_name.xml(options).as_str());
+        xml.push_str(self.open_paren.xml(options).as_str());
+        xml.push_str(self.expression_list.xml(options).as_str());
+        xml.push_str(self.close_paren.xml(options).as_str());
 
         xml
     }
@@ -256,26 +758,23 @@ impl Xml for SubroutineCall {
 #[derive(Debug)]
 pub struct ExpressionList {
     expressions: Option<Vec<Expression>>,
+    // expressions の間の `,`
+    commas: Vec<Token>,
+    // 式が無ければ `)` の手前の長さ0の範囲
+    span: Span,
 }
 
 impl ExpressionList {
-    fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let mut commas = vec![];
         let expressions = {
             if Self::should_extract_expression(iter) {
                 let mut expressions = vec![];
                 expressions.push(Expression::extract(iter)?);
 
-                loop {
-                    match iter.peek().unwrap() {
-                        Token::Symbol(symbol) => {
-                            if symbol != "," {
-                                break;
-                            }
-                        }
-                        _ => { break; }
-                    }
-
-                    let _ = iter.next().unwrap();
+                while iter.is_symbol(",") {
+                    commas.push(iter.next()?.into());
                     expressions.push(Expression::extract(iter)?);
                 }
 
@@ -285,39 +784,78 @@ impl ExpressionList {
             }
         };
 
-        Ok(Self { expressions })
+        Ok(Self { expressions, commas, span: iter.span_from(start) })
     }
 
-    fn should_extract_expression(iter: &mut Peekable<Iter<Token>>) -> bool {
-        match iter.peek().unwrap() {
-            Token::Symbol(symbol) => {
-                if symbol == ")" {
-                    // expression無し
-                    false
-                } else {
-                    true
-                }
+    fn should_extract_expression(iter: &TokenStream) -> bool {
+        // `)` なら expression 無し
+        !iter.is_symbol(")")
+    }
+}
+
+impl ExpressionList {
+    pub fn len(&self) -> u16 {
+        self.expressions.as_ref().map_or(0, |expressions| expressions.len() as u16)
+    }
+}
+
+impl Spanned for ExpressionList {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Resolve for ExpressionList {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        if let Some(expressions) = &mut self.expressions {
+            for expression in expressions.iter_mut() {
+                expression.resolve(resolver);
             }
-            _ => true,
+        }
+    }
+}
+
+impl ExpressionList {
+    // 式を検査して、それぞれの型と位置を返す
+    fn check_types(&self, checker: &mut Checker) -> Vec<Argument> {
+        self.expressions.iter()
+            .flatten()
+            .map(|expression| Argument {
+                value_type: expression.check_type(checker),
+                span: expression.span(),
+                is_integer_constant: expression.is_integer_constant(),
+            })
+            .collect()
+    }
+}
+
+impl WriteVm for ExpressionList {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        if let Some(expressions) = &self.expressions {
+            for expression in expressions.iter() {
+                expression.write_vm(generator)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Format for ExpressionList {
+    fn format(&self, formatter: &mut Formatter) {
+        if let Some(expressions) = &self.expressions {
+            separated_format(formatter, expressions, &self.commas);
         }
     }
 }
 
 impl Xml for ExpressionList {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<expressionList>\n");
 
         if let Some(expressions) = &self.expressions {
-            for (i, expression) in expressions.iter().enumerate() {
-                if i > 0 {
-                    xml.push_str(Token::Symbol(",".into()).xml().as_str());
-                }
-                xml.push_str(expression.xml().as_str());
-            }
+            xml.push_str(separated_xml(expressions, &self.commas, options).as_str());
         }
 
-        xml.push_str("</expressionList>\n");
-        xml
+        element("expressionList", &self.span(), &xml, options)
     }
 }
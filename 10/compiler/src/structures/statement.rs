@@ -1,10 +1,13 @@
-use std::iter::Peekable;
-use std::slice::Iter;
-use crate::tokenizer::Token;
+use crate::tokenizer::{Span, Spanned, Token, TokenKind};
 use crate::structures::class::VarName;
-use crate::compilation_engine::expect_symbol;
+use crate::code_generator::{CodeGenerator, WriteVm};
+use crate::compilation_engine::{ParseError, TokenStream};
+use crate::formatter::{Format, Formatter};
+use crate::semantic::{Check, Checker, SubroutineKind, ValueType};
 use crate::structures::expression::{Expression, SubroutineCall};
-use crate::Xml;
+use crate::symbol_table::{Resolve, Resolver};
+use crate::vm_writer::{ArithmeticCommand, Segment};
+use crate::{element, Xml, XmlOptions};
 
 const STATEMENT_DEC: [&str; 5] = [
     "let",
@@ -20,53 +23,162 @@ const STATEMENT_DEC: [&str; 5] = [
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 pub struct Statements {
-    statements: Vec<Statement>
+    statements: Vec<Statement>,
+    // 文が無ければ `}` の手前の長さ0の範囲
+    span: Span,
 }
 
 impl Statements {
-    pub fn extract(mut iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
+    // statements は必ず `}` で閉じられるので、そこまで読む
+    // エラーのあった文は読み飛ばして次の文から続ける
+    pub fn extract(iter: &mut TokenStream) -> Self {
+        let start = iter.position();
         let mut statements = vec![];
 
         loop {
-            match iter.peek().unwrap() {
-                Token::Keyword(keyword) => {
-                    if !STATEMENT_DEC.contains(&keyword.as_str()) {
-                        break;
-                    }
-
-                    // 先読みして確認済みのキーワードをイテレータから取り出す
-                    let _ = iter.next().unwrap();
-                    let statement = match keyword.as_str() {
-                        "let" => Statement::Let(LetStatement::extract(&mut iter)?),
-                        "if" => Statement::If(IfStatement::extract(iter)?),
-                        "while" => Statement::While(WhileStatement::extract(iter)?),
-                        "do" => Statement::Do(DoStatement::extract(iter)?),
-                        "return" => Statement::Return(ReturnStatement::extract(iter)?),
-                        _ => { break; }
-                    };
-
-                    statements.push(statement);
+            let keyword = match iter.peek_kind() {
+                Some(TokenKind::Keyword(keyword)) if STATEMENT_DEC.contains(&keyword.as_str()) => keyword,
+                Some(TokenKind::Symbol(symbol)) if symbol == "}" => break,
+                None => break,
+                Some(_) => {
+                    // 文になれないトークンは1つ取り出してから読み飛ばす
+                    let e = iter.unexpected("statement");
+                    iter.skip();
+                    iter.recover_statement(e);
+                    continue;
                 }
-                _ => { break }
+            };
+
+            // キーワードはそれぞれの文で取り出す
+            let statement = match keyword.as_str() {
+                "let" => LetStatement::extract(iter).map(Statement::Let),
+                "if" => IfStatement::extract(iter).map(Statement::If),
+                "while" => WhileStatement::extract(iter).map(Statement::While),
+                "do" => DoStatement::extract(iter).map(Statement::Do),
+                _ => ReturnStatement::extract(iter).map(Statement::Return),
+            };
+
+            match statement {
+                Ok(statement) => statements.push(statement),
+                Err(e) => iter.recover_statement(e),
             }
         }
 
-        Ok(Self {
+        Self {
             statements,
+            span: iter.span_from(start),
+        }
+    }
+}
+
+impl Spanned for Statements {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Resolve for Statements {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        for s in self.statements.iter_mut() {
+            s.resolve(resolver);
+        }
+    }
+}
+
+impl Statements {
+    // どの経路でも return で終わるか (while の中の return は数えない)
+    pub fn always_returns(&self) -> bool {
+        self.statements.iter().any(|s| match s {
+            Statement::Return(_) => true,
+            Statement::If(s) => s.else_block.as_ref()
+                .is_some_and(|(_, else_block)| s.block.statements.always_returns() && else_block.statements.always_returns()),
+            _ => false,
         })
     }
 }
 
+impl Check for Statements {
+    fn check(&self, checker: &mut Checker) {
+        for s in &self.statements {
+            s.check(checker);
+        }
+    }
+}
+
+impl WriteVm for Statements {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        for s in &self.statements {
+            s.write_vm(generator)?;
+        }
+        Ok(())
+    }
+}
+
+// 1行に1文ずつ書く
+impl Format for Statements {
+    fn format(&self, formatter: &mut Formatter) {
+        for s in &self.statements {
+            formatter.item(&s.span(), s);
+        }
+    }
+}
+
 impl Xml for Statements {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<statements>\n");
 
         for s in &self.statements {
-            xml.push_str(s.xml().as_str());
+            xml.push_str(s.xml(options).as_str());
         }
 
-        xml.push_str("</statements>\n");
+        element("statements", &self.span(), &xml, options)
+    }
+}
+
+/////////////////////////////////////////////////////////////
+// if 文と while 文の `{` statements `}`
+/////////////////////////////////////////////////////////////
+#[derive(Debug)]
+struct Block {
+    open_brace: Token,
+    statements: Statements,
+    close_brace: Token,
+}
+
+impl Block {
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let open_brace = iter.expect_symbol("{")?;
+        let statements = Statements::extract(iter);
+        let close_brace = iter.expect_symbol("}")?;
+
+        Ok(Self {
+            open_brace: open_brace.into(),
+            statements,
+            close_brace: close_brace.into(),
+        })
+    }
+}
+
+impl Spanned for Block {
+    fn span(&self) -> Span {
+        self.open_brace.span().to(&self.close_brace.span())
+    }
+}
+
+impl Format for Block {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.open_brace(&self.open_brace);
+        self.statements.format(formatter);
+        formatter.close_brace(&self.close_brace);
+    }
+}
+
+impl Xml for Block {
+    fn xml(&self, options: XmlOptions) -> String {
+        let mut xml = String::new();
+        xml.push_str(self.open_brace.xml(options).as_str());
+        xml.push_str(self.statements.xml(options).as_str());
+        xml.push_str(self.close_brace.xml(options).as_str());
         xml
     }
 }
@@ -84,14 +196,74 @@ enum Statement {
     Return(ReturnStatement),
 }
 
+impl Spanned for Statement {
+    fn span(&self) -> Span {
+        match self {
+            Self::Let(s) => s.span(),
+            Self::If(s) => s.span(),
+            Self::While(s) => s.span(),
+            Self::Do(s) => s.span(),
+            Self::Return(s) => s.span(),
+        }
+    }
+}
+
+impl Resolve for Statement {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        match self {
+            Self::Let(s) => s.resolve(resolver),
+            Self::If(s) => s.resolve(resolver),
+            Self::While(s) => s.resolve(resolver),
+            Self::Do(s) => s.resolve(resolver),
+            Self::Return(s) => s.resolve(resolver),
+        }
+    }
+}
+
+impl Check for Statement {
+    fn check(&self, checker: &mut Checker) {
+        match self {
+            Self::Let(s) => s.check(checker),
+            Self::If(s) => s.check(checker),
+            Self::While(s) => s.check(checker),
+            Self::Do(s) => s.check(checker),
+            Self::Return(s) => s.check(checker),
+        }
+    }
+}
+
+impl WriteVm for Statement {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        match self {
+            Self::Let(s) => s.write_vm(generator),
+            Self::If(s) => s.write_vm(generator),
+            Self::While(s) => s.write_vm(generator),
+            Self::Do(s) => s.write_vm(generator),
+            Self::Return(s) => s.write_vm(generator),
+        }
+    }
+}
+
+impl Format for Statement {
+    fn format(&self, formatter: &mut Formatter) {
+        match self {
+            Self::Let(s) => s.format(formatter),
+            Self::If(s) => s.format(formatter),
+            Self::While(s) => s.format(formatter),
+            Self::Do(s) => s.format(formatter),
+            Self::Return(s) => s.format(formatter),
+        }
+    }
+}
+
 impl Xml for Statement {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         match self {
-            Self::Let(s) => s.xml(),
-            Self::If(s) => s.xml(),
-            Self::While(s) => s.xml(),
-            Self::Do(s) => s.xml(),
-            Self::Return(s) => s.xml(),
+            Self::Let(s) => s.xml(options),
+            Self::If(s) => s.xml(options),
+            Self::While(s) => s.xml(options),
+            Self::Do(s) => s.xml(options),
+            Self::Return(s) => s.xml(options),
         }
     }
 }
@@ -102,61 +274,137 @@ impl Xml for Statement {
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 struct LetStatement {
+    let_keyword: Token,
     var_name: VarName,
-    expression_for_bracket: Option<Expression>,
+    // `[` expression `]`
+    expression_for_bracket: Option<(Token, Expression, Token)>,
+    equal: Token,
     expression: Expression,
+    semicolon: Token,
+    span: Span,
 }
 
 impl LetStatement {
-    fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
-        let var_name = VarName::new(iter.next().unwrap())?;
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let let_keyword = iter.expect_keyword("let")?;
+        let var_name = VarName::extract(iter)?;
 
         let expression_for_bracket = {
-            match iter.peek().unwrap() {
-                Token::Symbol(symbol) => {
-                    if symbol == "[" {
-                        let _ = expect_symbol("[", iter.next().unwrap())?;
-                        let expression = Expression::extract(iter)?;
-                        let _ = expect_symbol("]", iter.next().unwrap())?;
-                        Some(expression)
-                    } else {
-                        None
-                    }
-                }
-                _ => None,
+            if iter.is_symbol("[") {
+                let open_bracket = iter.next()?;
+                let expression = Expression::extract(iter)?;
+                let close_bracket = iter.expect_symbol("]")?;
+                Some((open_bracket.into(), expression, close_bracket.into()))
+            } else {
+                None
             }
         };
 
-        let _ = expect_symbol("=", iter.next().unwrap())?;
+        let equal = iter.expect_symbol("=")?;
         let expression = Expression::extract(iter)?;
-        let _ = expect_symbol(";", iter.next().unwrap())?;
+        let semicolon = iter.expect_symbol(";")?;
 
         Ok(Self {
+            let_keyword: let_keyword.into(),
             var_name,
             expression_for_bracket,
+            equal: equal.into(),
             expression,
+            semicolon: semicolon.into(),
+            span: iter.span_from(start),
         })
     }
 }
 
+impl Spanned for LetStatement {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Resolve for LetStatement {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        self.var_name.resolve_use(resolver);
+        if let Some((_, expression, _)) = &mut self.expression_for_bracket {
+            expression.resolve(resolver);
+        }
+        self.expression.resolve(resolver);
+    }
+}
+
+impl Check for LetStatement {
+    fn check(&self, checker: &mut Checker) {
+        let variable_type = self.var_name.check_use(checker);
+        // 配列の要素の型は分からない
+        let target = match &self.expression_for_bracket {
+            Some((_, expression, _)) => {
+                expression.check_type(checker);
+                ValueType::Unknown
+            }
+            None => variable_type,
+        };
+        let value = self.expression.check_type(checker);
+        checker.check_assignment(&target, &value, &self.expression.span());
+    }
+}
+
+impl WriteVm for LetStatement {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        let (segment, index) = self.var_name.variable()?;
+        match &self.expression_for_bracket {
+            None => {
+                self.expression.write_vm(generator)?;
+                generator.writer.write_pop(segment, index);
+            }
+            // 右辺で配列を参照すると that が変わるので、右辺を評価してから代入先を pointer 1 に設定する
+            Some((_, expression, _)) => {
+                generator.writer.write_push(segment, index);
+                expression.write_vm(generator)?;
+                generator.writer.write_arithmetic(ArithmeticCommand::Add);
+                self.expression.write_vm(generator)?;
+                generator.writer.write_pop(Segment::Temp, 0);
+                generator.writer.write_pop(Segment::Pointer, 1);
+                generator.writer.write_push(Segment::Temp, 0);
+                generator.writer.write_pop(Segment::That, 0);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Format for LetStatement {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.let_keyword);
+        self.var_name.format(formatter);
+        if let Some((open_bracket, expression, close_bracket)) = &self.expression_for_bracket {
+            formatter.symbol_token(open_bracket);
+            formatter.glue();
+            expression.format(formatter);
+            formatter.symbol_token(close_bracket);
+        }
+        formatter.token(&self.equal);
+        self.expression.format(formatter);
+        formatter.symbol_token(&self.semicolon);
+    }
+}
+
 impl Xml for LetStatement {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<letStatement>\n");
-        xml.push_str(Token::Keyword("let".into()).xml().as_str());
-        xml.push_str(self.var_name.xml().as_str());
-
-        if let Some(expression) = &self.expression_for_bracket {
-            xml.push_str(Token::Symbol("[".into()).xml().as_str());
-            xml.push_str(expression.xml().as_str());
-            xml.push_str(Token::Symbol("]".into()).xml().as_str());
+        xml.push_str(self.let_keyword.xml(options).as_str());
+        xml.push_str(self.var_name.xml(options).as_str());
+
+        if let Some((open_bracket, expression, close_bracket)) = &self.expression_for_bracket {
+            xml.push_str(open_bracket.xml(options).as_str());
+            xml.push_str(expression.xml(options).as_str());
+            xml.push_str(close_bracket.xml(options).as_str());
         }
 
-        xml.push_str(Token::Symbol("=".into()).xml().as_str());
-        xml.push_str(self.expression.xml().as_str());
-        xml.push_str(Token::Symbol(";".into()).xml().as_str());
-        xml.push_str("</letStatement>\n");
-        xml
+        xml.push_str(self.equal.xml(options).as_str());
+        xml.push_str(self.expression.xml(options).as_str());
+        xml.push_str(self.semicolon.xml(options).as_str());
+        element("letStatement", &self.span(), &xml, options)
     }
 }
 
@@ -167,66 +415,124 @@ impl Xml for LetStatement {
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 struct IfStatement {
+    if_keyword: Token,
+    open_paren: Token,
     expression: Expression,
-    statements: Statements,
-    else_statements: Option<Statements>,
+    close_paren: Token,
+    block: Block,
+    // `else` と else のブロック
+    else_block: Option<(Token, Block)>,
+    span: Span,
 }
 
 impl IfStatement {
-    fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
-        let _ = expect_symbol("(", iter.next().unwrap());
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let if_keyword = iter.expect_keyword("if")?;
+        let open_paren = iter.expect_symbol("(")?;
         let expression = Expression::extract(iter)?;
-        let _ = expect_symbol(")", iter.next().unwrap());
-        let _ = expect_symbol("{", iter.next().unwrap());
-        let statements = Statements::extract(iter)?;
-        let _ = expect_symbol("}", iter.next().unwrap());
-        let else_statements = {
-            match iter.peek().unwrap() {
-                Token::Keyword(keyword) => {
-                    if keyword == "else" {
-                        let _ = iter.next().unwrap();
-                        let _ = expect_symbol("{", iter.next().unwrap());
-                        let statements = Statements::extract(iter)?;
-                        let _ = expect_symbol("}", iter.next().unwrap());
-                        Some(statements)
-                    } else {
-                        None
-                    }
-                }
-                _ => None,
+        let close_paren = iter.expect_symbol(")")?;
+        let block = Block::extract(iter)?;
+        let else_block = {
+            if iter.is_keyword(&["else"]) {
+                let else_keyword = iter.next()?;
+                Some((else_keyword.into(), Block::extract(iter)?))
+            } else {
+                None
             }
         };
 
         Ok(Self {
+            if_keyword: if_keyword.into(),
+            open_paren: open_paren.into(),
             expression,
-            statements,
-            else_statements,
+            close_paren: close_paren.into(),
+            block,
+            else_block,
+            span: iter.span_from(start),
         })
     }
 }
 
+impl Spanned for IfStatement {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Resolve for IfStatement {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        self.expression.resolve(resolver);
+        self.block.statements.resolve(resolver);
+        if let Some((_, else_block)) = &mut self.else_block {
+            else_block.statements.resolve(resolver);
+        }
+    }
+}
+
+impl Check for IfStatement {
+    fn check(&self, checker: &mut Checker) {
+        self.expression.check_type(checker);
+        self.block.statements.check(checker);
+        if let Some((_, else_block)) = &self.else_block {
+            else_block.statements.check(checker);
+        }
+    }
+}
+
+impl WriteVm for IfStatement {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        let index = generator.next_label_index();
+        let if_false = format!("IF_FALSE{}", index);
+        let if_end = format!("IF_END{}", index);
+
+        self.expression.write_vm(generator)?;
+        generator.writer.write_arithmetic(ArithmeticCommand::Not);
+        generator.writer.write_if(&if_false);
+        self.block.statements.write_vm(generator)?;
+        match &self.else_block {
+            Some((_, else_block)) => {
+                generator.writer.write_goto(&if_end);
+                generator.writer.write_label(&if_false);
+                else_block.statements.write_vm(generator)?;
+                generator.writer.write_label(&if_end);
+            }
+            None => generator.writer.write_label(&if_false),
+        }
+        Ok(())
+    }
+}
+
+impl Format for IfStatement {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.if_keyword);
+        formatter.token(&self.open_paren);
+        formatter.glue();
+        self.expression.format(formatter);
+        formatter.symbol_token(&self.close_paren);
+        self.block.format(formatter);
+        if let Some((else_keyword, else_block)) = &self.else_block {
+            formatter.continue_token(else_keyword);
+            else_block.format(formatter);
+        }
+    }
+}
+
 impl Xml for IfStatement {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<ifStatement>\n");
-        xml.push_str(Token::Keyword("if".into()).xml().as_str());
-        xml.push_str(Token::Symbol("(".into()).xml().as_str());
-        xml.push_str(self.expression.xml().as_str());
-        xml.push_str(Token::Symbol(")".into()).xml().as_str());
-
-        xml.push_str(Token::Symbol("{".into()).xml().as_str());
-        xml.push_str(self.statements.xml().as_str());
-        xml.push_str(Token::Symbol("}".into()).xml().as_str());
-
-        if let Some(else_statements) = &self.else_statements {
-            xml.push_str(Token::Keyword("else".into()).xml().as_str());
-            xml.push_str(Token::Symbol("{".into()).xml().as_str());
-            xml.push_str(else_statements.xml().as_str());
-            xml.push_str(Token::Symbol("}".into()).xml().as_str());
+        xml.push_str(self.if_keyword.xml(options).as_str());
+        xml.push_str(self.open_paren.xml(options).as_str());
+        xml.push_str(self.expression.xml(options).as_str());
+        xml.push_str(self.close_paren.xml(options).as_str());
+        xml.push_str(self.block.xml(options).as_str());
+
+        if let Some((else_keyword, else_block)) = &self.else_block {
+            xml.push_str(else_keyword.xml(options).as_str());
+            xml.push_str(else_block.xml(options).as_str());
         }
 
-        xml.push_str("</ifStatement>\n");
-        xml
+        element("ifStatement", &self.span(), &xml, options)
     }
 }
 
@@ -236,36 +542,91 @@ impl Xml for IfStatement {
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 struct WhileStatement {
+    while_keyword: Token,
+    open_paren: Token,
     expression: Expression,
-    statements: Statements,
+    close_paren: Token,
+    block: Block,
+    span: Span,
 }
 
 impl WhileStatement {
-    fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
-        let _ = expect_symbol("(", iter.next().unwrap());
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let while_keyword = iter.expect_keyword("while")?;
+        let open_paren = iter.expect_symbol("(")?;
         let expression = Expression::extract(iter)?;
-        let _ = expect_symbol(")", iter.next().unwrap());
-        let _ = expect_symbol("{", iter.next().unwrap());
-        let statements = Statements::extract(iter)?;
-        let _ = expect_symbol("}", iter.next().unwrap());
+        let close_paren = iter.expect_symbol(")")?;
+        let block = Block::extract(iter)?;
+
+        Ok(Self {
+            while_keyword: while_keyword.into(),
+            open_paren: open_paren.into(),
+            expression,
+            close_paren: close_paren.into(),
+            block,
+            span: iter.span_from(start),
+        })
+    }
+}
+
+impl Spanned for WhileStatement {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Resolve for WhileStatement {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        self.expression.resolve(resolver);
+        self.block.statements.resolve(resolver);
+    }
+}
+
+impl Check for WhileStatement {
+    fn check(&self, checker: &mut Checker) {
+        self.expression.check_type(checker);
+        self.block.statements.check(checker);
+    }
+}
+
+impl WriteVm for WhileStatement {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        let index = generator.next_label_index();
+        let while_exp = format!("WHILE_EXP{}", index);
+        let while_end = format!("WHILE_END{}", index);
+
+        generator.writer.write_label(&while_exp);
+        self.expression.write_vm(generator)?;
+        generator.writer.write_arithmetic(ArithmeticCommand::Not);
+        generator.writer.write_if(&while_end);
+        self.block.statements.write_vm(generator)?;
+        generator.writer.write_goto(&while_exp);
+        generator.writer.write_label(&while_end);
+        Ok(())
+    }
+}
 
-        Ok(Self { expression, statements })
+impl Format for WhileStatement {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.while_keyword);
+        formatter.token(&self.open_paren);
+        formatter.glue();
+        self.expression.format(formatter);
+        formatter.symbol_token(&self.close_paren);
+        self.block.format(formatter);
     }
 }
 
 impl Xml for WhileStatement {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<whileStatement>\n");
-        xml.push_str(Token::Keyword("while".into()).xml().as_str());
-        xml.push_str(Token::Symbol("(".into()).xml().as_str());
-        xml.push_str(self.expression.xml().as_str());
-        xml.push_str(Token::Symbol(")".into()).xml().as_str());
-        xml.push_str(Token::Symbol("{".into()).xml().as_str());
-        xml.push_str(self.statements.xml().as_str());
-        xml.push_str(Token::Symbol("}".into()).xml().as_str());
-        xml.push_str("</whileStatement>\n");
-        xml
+        xml.push_str(self.while_keyword.xml(options).as_str());
+        xml.push_str(self.open_paren.xml(options).as_str());
+        xml.push_str(self.expression.xml(options).as_str());
+        xml.push_str(self.close_paren.xml(options).as_str());
+        xml.push_str(self.block.xml(options).as_str());
+        element("whileStatement", &self.span(), &xml, options)
     }
 }
 
@@ -275,27 +636,69 @@ impl Xml for WhileStatement {
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 struct DoStatement {
+    do_keyword: Token,
     subroutine_call: SubroutineCall,
+    semicolon: Token,
+    span: Span,
 }
 
 impl DoStatement {
-    fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
-        let token = iter.next().unwrap();
-        let subroutine_call = SubroutineCall::extract_with_first_token(token, iter)?;
-        let _ = expect_symbol(";", iter.next().unwrap())?;
-        Ok(Self { subroutine_call })
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let do_keyword = iter.expect_keyword("do")?;
+        let subroutine_call = SubroutineCall::extract(iter)?;
+        let semicolon = iter.expect_symbol(";")?;
+        Ok(Self {
+            do_keyword: do_keyword.into(),
+            subroutine_call,
+            semicolon: semicolon.into(),
+            span: iter.span_from(start),
+        })
+    }
+}
+
+impl Spanned for DoStatement {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Resolve for DoStatement {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        self.subroutine_call.resolve(resolver);
+    }
+}
+
+impl Check for DoStatement {
+    fn check(&self, checker: &mut Checker) {
+        self.subroutine_call.check_type(checker);
+    }
+}
+
+impl WriteVm for DoStatement {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        self.subroutine_call.write_vm(generator)?;
+        // 戻り値は使わない
+        generator.writer.write_pop(Segment::Temp, 0);
+        Ok(())
+    }
+}
+
+impl Format for DoStatement {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.do_keyword);
+        self.subroutine_call.format(formatter);
+        formatter.symbol_token(&self.semicolon);
     }
 }
 
 impl Xml for DoStatement {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<doStatement>\n");
-        xml.push_str(Token::Keyword("do".into()).xml().as_str());
-        xml.push_str(self.subroutine_call.xml().as_str());
-        xml.push_str(Token::Symbol(";".into()).xml().as_str());
-        xml.push_str("</doStatement>\n");
-        xml
+        xml.push_str(self.do_keyword.xml(options).as_str());
+        xml.push_str(self.subroutine_call.xml(options).as_str());
+        xml.push_str(self.semicolon.xml(options).as_str());
+        element("doStatement", &self.span(), &xml, options)
     }
 }
 
@@ -305,40 +708,111 @@ impl Xml for DoStatement {
 /////////////////////////////////////////////////////////////
 #[derive(Debug)]
 struct ReturnStatement {
+    return_keyword: Token,
     expression: Option<Expression>,
+    semicolon: Token,
+    span: Span,
 }
 
 impl ReturnStatement {
-    fn extract(iter: &mut Peekable<Iter<Token>>) -> Result<Self, String> {
+    fn extract(iter: &mut TokenStream) -> Result<Self, ParseError> {
+        let start = iter.position();
+        let return_keyword = iter.expect_keyword("return")?;
         let expression = {
-            match iter.peek().unwrap() {
-                Token::Symbol(symbol) => {
-                    if symbol == ";" {
-                        None
-                    } else {
-                        Some(Expression::extract(iter)?)
-                    }
-                }
-                _ => Some(Expression::extract(iter)?)
+            if iter.is_symbol(";") {
+                None
+            } else {
+                Some(Expression::extract(iter)?)
+            }
+        };
+        let semicolon = iter.expect_symbol(";")?;
+        Ok(Self {
+            return_keyword: return_keyword.into(),
+            expression,
+            semicolon: semicolon.into(),
+            span: iter.span_from(start),
+        })
+    }
+}
+
+impl Spanned for ReturnStatement {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Resolve for ReturnStatement {
+    fn resolve(&mut self, resolver: &mut Resolver) {
+        if let Some(expression) = &mut self.expression {
+            expression.resolve(resolver);
+        }
+    }
+}
+
+impl Check for ReturnStatement {
+    fn check(&self, checker: &mut Checker) {
+        let subroutine = match checker.subroutine.clone() {
+            Some(subroutine) => subroutine,
+            None => return,
+        };
+        let (return_type, expression) = match (&subroutine.return_type, &self.expression) {
+            (Some(return_type), Some(expression)) => (return_type, expression),
+            (None, None) => return,
+            (None, Some(expression)) => {
+                expression.check_type(checker);
+                checker.error("void subroutine cannot return a value".into(), &self.span);
+                return;
+            }
+            (Some(_), None) => {
+                checker.error("expected a return value".into(), &self.span);
+                return;
             }
         };
-        let _ = expect_symbol(";", iter.next().unwrap())?;
-        Ok(Self { expression })
+
+        let value = expression.check_type(checker);
+        // constructor は確保したオブジェクトを返す
+        if subroutine.kind == SubroutineKind::Constructor {
+            if !expression.is_this() {
+                checker.error("constructor must return `this`".into(), &expression.span());
+            }
+        } else {
+            checker.check_assignment(&ValueType::from(return_type), &value, &expression.span());
+        }
+    }
+}
+
+impl WriteVm for ReturnStatement {
+    fn write_vm(&self, generator: &mut CodeGenerator) -> Result<(), String> {
+        match &self.expression {
+            Some(expression) => expression.write_vm(generator)?,
+            // void のサブルーチンも何かしらの値を返す
+            None => generator.writer.write_push(Segment::Constant, 0),
+        }
+        generator.writer.write_return();
+        Ok(())
+    }
+}
+
+impl Format for ReturnStatement {
+    fn format(&self, formatter: &mut Formatter) {
+        formatter.token(&self.return_keyword);
+        if let Some(expression) = &self.expression {
+            expression.format(formatter);
+        }
+        formatter.symbol_token(&self.semicolon);
     }
 }
 
 impl Xml for ReturnStatement {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
-        xml.push_str("<returnStatement>\n");
-        xml.push_str(Token::Keyword("return".into()).xml().as_str());
+        xml.push_str(self.return_keyword.xml(options).as_str());
 
         if let Some(expression) = &self.expression {
-            xml.push_str(expression.xml().as_str());
+            xml.push_str(expression.xml(options).as_str());
         }
 
-        xml.push_str(Token::Symbol(";".into()).xml().as_str());
-        xml.push_str("</returnStatement>\n");
-        xml
+        xml.push_str(self.semicolon.xml(options).as_str());
+        element("returnStatement", &self.span(), &xml, options)
     }
 }
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::diagnostic::Diagnostic;
+use crate::structures::Class;
+use crate::tokenizer::Span;
+use crate::vm_writer::Segment;
+
+/////////////////////////////////////////////////////////////
+// SymbolTableモジュール
+// クラスのスコープ (static, field) とサブルーチンのスコープ (argument, var) を持つ
+/////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Static,
+    Field,
+    Argument,
+    Var,
+}
+
+impl Kind {
+    pub fn from(keyword: &str) -> Option<Self> {
+        match keyword {
+            "static" => Some(Kind::Static),
+            "field" => Some(Kind::Field),
+            _ => None,
+        }
+    }
+
+    // 変数を読み書きするセグメント
+    pub fn segment(&self) -> Segment {
+        match self {
+            Kind::Static => Segment::Static,
+            Kind::Field => Segment::This,
+            Kind::Argument => Segment::Argument,
+            Kind::Var => Segment::Local,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Kind::Static => "static",
+            Kind::Field => "field",
+            Kind::Argument => "argument",
+            Kind::Var => "var",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub r#type: String,
+    pub kind: Kind,
+    pub index: u16,
+}
+
+pub struct SymbolTable {
+    class_scope: HashMap<String, Symbol>,
+    subroutine_scope: HashMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self {
+            class_scope: HashMap::new(),
+            subroutine_scope: HashMap::new(),
+        }
+    }
+
+    // 新しいサブルーチンのスコープを開始する
+    pub fn start_subroutine(&mut self) {
+        self.subroutine_scope.clear();
+    }
+
+    pub fn define(&mut self, name: &str, r#type: &str, kind: Kind) -> Result<Symbol, String> {
+        let index = self.var_count(kind);
+        let scope = match kind {
+            Kind::Static | Kind::Field => &mut self.class_scope,
+            Kind::Argument | Kind::Var => &mut self.subroutine_scope,
+        };
+        if scope.contains_key(name) {
+            return Err(format!("`{}` is already defined", name));
+        }
+        let symbol = Symbol { r#type: r#type.into(), kind, index };
+        scope.insert(name.into(), symbol.clone());
+        Ok(symbol)
+    }
+
+    pub fn var_count(&self, kind: Kind) -> u16 {
+        let scope = match kind {
+            Kind::Static | Kind::Field => &self.class_scope,
+            Kind::Argument | Kind::Var => &self.subroutine_scope,
+        };
+        scope.values().filter(|symbol| symbol.kind == kind).count() as u16
+    }
+
+    // サブルーチンのスコープを優先して探す
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.subroutine_scope.get(name).or_else(|| self.class_scope.get(name))
+    }
+}
+
+/////////////////////////////////////////////////////////////
+// 識別子の解決
+// 宣言をシンボルテーブルに登録し、識別子が何を指しているかを構文木に書き込む
+/////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, PartialEq)]
+pub enum Category {
+    Class,
+    Subroutine,
+    Variable(Symbol),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub category: Category,
+    // 宣言している箇所なら true, 使っている箇所なら false
+    pub defined: bool,
+}
+
+impl Annotation {
+    pub fn class(defined: bool) -> Self {
+        Self { category: Category::Class, defined }
+    }
+
+    pub fn subroutine(defined: bool) -> Self {
+        Self { category: Category::Subroutine, defined }
+    }
+
+    // `<identifier category="var" usage="defined" kind="var" index="0"> x </identifier>`
+    pub fn xml(&self, name: &str) -> String {
+        let usage = if self.defined { "defined" } else { "used" };
+        let attributes = match &self.category {
+            Category::Class => format!("category=\"class\" usage=\"{}\"", usage),
+            Category::Subroutine => format!("category=\"subroutine\" usage=\"{}\"", usage),
+            Category::Variable(symbol) => format!(
+                "category=\"{}\" usage=\"{}\" kind=\"{}\" index=\"{}\"",
+                symbol.kind, usage, symbol.kind, symbol.index,
+            ),
+        };
+        format!("<identifier {}> {} </identifier>\n", attributes, name)
+    }
+}
+
+pub trait Resolve {
+    fn resolve(&mut self, resolver: &mut Resolver);
+}
+
+pub struct Resolver {
+    pub class_name: String,
+    symbol_table: SymbolTable,
+    // エラーメッセージに出すサブルーチン名
+    subroutine_name: Option<String>,
+    errors: Vec<Diagnostic>,
+}
+
+impl Resolver {
+    // 未宣言や重複した識別子をすべて集めて返す
+    pub fn run(class: &mut Class) -> Result<(), Vec<Diagnostic>> {
+        let mut resolver = Self {
+            class_name: class.name().into(),
+            symbol_table: SymbolTable::new(),
+            subroutine_name: None,
+            errors: vec![],
+        };
+        class.resolve(&mut resolver);
+
+        if resolver.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(resolver.errors)
+        }
+    }
+
+    pub fn start_subroutine(&mut self, name: &str) {
+        self.symbol_table.start_subroutine();
+        self.subroutine_name = Some(name.into());
+    }
+
+    // 重複していれば None
+    // span は宣言している箇所 (暗黙に宣言する `this` は None)
+    pub fn define(&mut self, name: &str, r#type: &str, kind: Kind, span: Option<&Span>) -> Option<Annotation> {
+        match self.symbol_table.define(name, r#type, kind) {
+            Ok(symbol) => Some(Annotation { category: Category::Variable(symbol), defined: true }),
+            Err(e) => {
+                self.error(e, span);
+                None
+            }
+        }
+    }
+
+    // 変数を使っている箇所。宣言されていなければエラー
+    pub fn use_variable(&mut self, name: &str, span: &Span) -> Option<Annotation> {
+        let annotation = self.lookup(name);
+        if annotation.is_none() {
+            self.error(format!("undeclared identifier `{}`", name), Some(span));
+        }
+        annotation
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Annotation> {
+        self.symbol_table.get(name).map(|symbol| Annotation {
+            category: Category::Variable(symbol.clone()),
+            defined: false,
+        })
+    }
+
+    fn error(&mut self, message: String, span: Option<&Span>) {
+        let location = match &self.subroutine_name {
+            Some(subroutine_name) => format!("{}.{}", self.class_name, subroutine_name),
+            None => self.class_name.clone(),
+        };
+        self.errors.push(Diagnostic::error(format!("{}: {}", location, message), span.cloned()));
+    }
+}
@@ -1,6 +1,7 @@
-use std::io::{BufReader, BufRead};
-use std::fs::File;
-use crate::{Xml, convert_to_xml_symbol};
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use crate::{Dialect, Xml, XmlOptions, convert_to_xml_symbol, with_span_attributes};
 
 const KEYWORD: [&str; 21] = [
     "class",
@@ -26,6 +27,8 @@ const KEYWORD: [&str; 21] = [
     "return",
 ];
 
+const MAX_INTEGER_CONSTANT: u16 = 32767;
+
 const SYMBOL: [&str; 19] = [
     "{",
     "}",
@@ -48,131 +51,427 @@ const SYMBOL: [&str; 19] = [
     "~",
 ];
 
+// 拡張の文法だけで使う記号 (長いものから順に試す)
+const EXTENDED_SYMBOL: [&str; 8] = [
+    "<=",
+    ">=",
+    "!=",
+    "<<",
+    ">>",
+    "&&",
+    "||",
+    "%",
+];
+
+// 1文字ずつ読んでトークンに分ける
 pub struct Tokenizer {
-    reader: JackReader,
+    // 文字とファイル先頭からのバイト位置
+    chars: Vec<(usize, char)>,
+    source_len: usize,
+    // Span に入れるファイル名
+    file: Rc<str>,
+    dialect: Dialect,
+    position: usize,
+    line: usize,
+    column: usize,
+    // 読み飛ばしたコメント (フォーマッタで使う)
+    comments: Vec<Comment>,
+    errors: Vec<LexError>,
 }
 
 impl Tokenizer {
-    pub fn new(file: File) -> Self {
+    pub fn new(source: &str, name: &str, dialect: Dialect) -> Self {
         Self {
-            reader: JackReader { reader: BufReader::new(file) },
-        }
-    }
-
-    pub fn generate_tokens(&mut self) -> Vec<Token> {
-        let mut elements = vec![];
-
-        while let Some(line) = self.reader.read_line() {
-            let words = Self::split_with_space(&line);
-            let tokens = words.iter()
-                .map(|word| {
-                    word.trim()
-                })
-                .filter(|&word| {
-                    word.len() > 0
-                })
-                .map(|word| {
-                    Self::split_with_symbol(word)
-                })
-                .flatten()
-                .map(|word| {
-                    Self::parse(&word)
-                })
-                .collect::<Vec<_>>();
-            elements.push(tokens);
+            chars: source.char_indices().collect(),
+            source_len: source.len(),
+            file: Rc::from(name),
+            dialect,
+            position: 0,
+            line: 1,
+            column: 1,
+            comments: vec![],
+            errors: vec![],
         }
-
-        return elements.into_iter().flatten().collect::<Vec<Token>>();
     }
 
-    fn split_with_space(line: &str) -> Vec<String> {
-        let mut words = vec![];
-        let mut buf = vec![];
-        let mut is_string = false;
+    // エラーがあっても最後まで読んで、見つかったエラーをすべて返す
+    pub fn generate_tokens(&mut self) -> Result<Vec<Token>, Vec<LexError>> {
+        let mut tokens = vec![];
 
-        for c in line.chars() {
+        while let Some(c) = self.peek(0) {
+            let start = self.mark();
+            if let Some(symbol) = self.extended_symbol() {
+                for _ in symbol.chars() {
+                    self.bump();
+                }
+                tokens.push(self.token(TokenKind::Symbol(symbol.into()), start));
+                continue;
+            }
             match c {
-                ' ' => {
-                    if is_string {
-                        buf.push(c.to_string());
-                    } else {
-                        words.push(buf.join(""));
-                        buf.clear();
-                    }
-                },
+                c if c.is_whitespace() => {
+                    self.bump();
+                }
+                '/' if self.peek(1) == Some('/') => self.skip_line_comment(start),
+                // `/** */` のドキュメントコメントもここで読み飛ばす
+                '/' if self.peek(1) == Some('*') => self.skip_block_comment(start),
                 '"' => {
-                    if is_string {
-                        buf.push(c.to_string());
-                        words.push(buf.join(""));
-                        buf.clear();
-                        is_string = false;
-                    } else {
-                        is_string = true;
-                        buf.push(c.to_string());
+                    if let Some(kind) = self.string_constant(start) {
+                        tokens.push(self.token(kind, start));
+                    }
+                }
+                '0' if self.dialect == Dialect::Extended && matches!(self.peek(1), Some('x') | Some('X')) => {
+                    if let Some(kind) = self.hex_constant(start) {
+                        tokens.push(self.token(kind, start));
+                    }
+                }
+                '\'' if self.dialect == Dialect::Extended => {
+                    if let Some(kind) = self.char_constant(start) {
+                        tokens.push(self.token(kind, start));
                     }
-                },
+                }
+                '0'..='9' => {
+                    if let Some(kind) = self.integer_constant(start) {
+                        tokens.push(self.token(kind, start));
+                    }
+                }
+                c if c.is_ascii_alphabetic() || c == '_' => {
+                    let kind = self.identifier_or_keyword();
+                    tokens.push(self.token(kind, start));
+                }
+                c if SYMBOL.contains(&c.to_string().as_str()) => {
+                    self.bump();
+                    tokens.push(self.token(TokenKind::Symbol(c.to_string()), start));
+                }
                 other => {
-                    buf.push(other.to_string());
+                    self.bump();
+                    self.error(format!("illegal character `{}`", other), start);
                 }
             }
         }
 
-        if buf.len() > 0 {
-            words.push(buf.join(""));
+        if self.errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(std::mem::take(&mut self.errors))
         }
+    }
 
-        words
+    // generate_tokens で読み飛ばしたコメントを取り出す
+    pub fn take_comments(&mut self) -> Vec<Comment> {
+        std::mem::take(&mut self.comments)
     }
 
-    fn split_with_symbol(word: &str) -> Vec<String> {
-        let mut i = 0;
-        let mut parts: Vec<String> = vec![];
-        let mut buf: Vec<char> = vec![];
-        while i < word.len() {
-            let c = word.chars().nth(i).unwrap();
-            if SYMBOL.contains(&c.to_string().as_str()) {
-                if buf.len() > 0 {
-                    parts.push(buf.iter().map(|c| c.to_string() ).collect::<Vec<_>>().join(""));
-                    buf.clear();
+    fn skip_line_comment(&mut self, start: Mark) {
+        while let Some(c) = self.peek(0) {
+            if c == '\n' {
+                break;
+            }
+            self.bump();
+        }
+        self.push_comment(start);
+    }
+
+    fn skip_block_comment(&mut self, start: Mark) {
+        // `/*` を読み飛ばす
+        self.bump();
+        self.bump();
+        loop {
+            match self.peek(0) {
+                Some('*') if self.peek(1) == Some('/') => {
+                    self.bump();
+                    self.bump();
+                    self.push_comment(start);
+                    return;
+                }
+                Some(_) => {
+                    self.bump();
+                }
+                None => {
+                    self.error("unterminated comment".into(), start);
+                    return;
                 }
-                parts.push(String::from(c.to_string()))
-            } else {
-                buf.push(c);
             }
-            i += 1;
         }
-        if buf.len() > 0 {
-            parts.push(buf.iter().map(|c| c.to_string() ).collect::<Vec<_>>().join(""));
+    }
+
+    // 文字列定数は改行を含まず、同じ行の `"` で閉じる
+    fn string_constant(&mut self, start: Mark) -> Option<TokenKind> {
+        // 開始の `"` を読み飛ばす
+        self.bump();
+        let mut string = String::new();
+        loop {
+            match self.peek(0) {
+                Some('"') => {
+                    self.bump();
+                    return Some(TokenKind::StringConst(string));
+                }
+                Some('\n') | Some('\r') => {
+                    self.error("newline in string constant".into(), start);
+                    return None;
+                }
+                Some(c) => {
+                    self.bump();
+                    string.push(c);
+                }
+                None => {
+                    self.error("unterminated string constant".into(), start);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn integer_constant(&mut self, start: Mark) -> Option<TokenKind> {
+        let mut digits = String::new();
+        while let Some(c) = self.peek(0).filter(|c| c.is_ascii_digit()) {
+            self.bump();
+            digits.push(c);
         }
 
-        parts
+        match digits.parse::<u16>() {
+            Ok(value) if value <= MAX_INTEGER_CONSTANT => Some(TokenKind::IntegerConst(digits)),
+            _ => {
+                self.error(format!("integer constant should be 0..{}: {}", MAX_INTEGER_CONSTANT, digits), start);
+                None
+            }
+        }
     }
 
-    fn parse(word: &str) -> Token {
-        if KEYWORD.contains(&word) {
-            return Token::Keyword(String::from(word));
+    // `0x7FFF` のような16進数の定数。16ビットの値ならすべて書ける
+    fn hex_constant(&mut self, start: Mark) -> Option<TokenKind> {
+        let mut text = String::new();
+        while let Some(c) = self.peek(0).filter(|c| c.is_ascii_alphanumeric()) {
+            self.bump();
+            text.push(c);
         }
 
-        if SYMBOL.contains(&word) {
-            return Token::Symbol(String::from(word));
+        if integer_value(&text).is_some() {
+            Some(TokenKind::IntegerConst(text))
+        } else {
+            self.error(format!("hex constant should be 0x0..0xFFFF: {}", text), start);
+            None
         }
+    }
 
-        match word.chars().nth(0).expect("should have chars at least 1") {
-            '0'|'1'|'2'|'3'|'4'|'5'|'6'|'7'|'8'|'9' => {
-                return Token::IntegerConst(String::from(word));
-            }
-            '"' => {
-                return Token::StringConst(String::from(word.trim_matches('"')));
+    // `'a'` のような文字の定数。トークンには `'` も含めて入れる
+    fn char_constant(&mut self, start: Mark) -> Option<TokenKind> {
+        self.bump();
+        match (self.peek(0), self.peek(1)) {
+            (Some(c), Some('\'')) if c != '\'' && c != '\n' && c != '\r' => {
+                self.bump();
+                self.bump();
+                Some(TokenKind::IntegerConst(format!("'{}'", c)))
             }
             _ => {
-                return Token::Identifier(String::from(word));
+                self.error("character constant should be one character in `'`".into(), start);
+                None
+            }
+        }
+    }
+
+    // 拡張の文法なら、現在の位置から始まる記号
+    fn extended_symbol(&self) -> Option<&'static str> {
+        if self.dialect != Dialect::Extended {
+            return None;
+        }
+        EXTENDED_SYMBOL.iter()
+            .find(|symbol| symbol.chars().enumerate().all(|(i, c)| self.peek(i) == Some(c)))
+            .copied()
+    }
+
+    fn identifier_or_keyword(&mut self) -> TokenKind {
+        let mut word = String::new();
+        while let Some(c) = self.peek(0).filter(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            self.bump();
+            word.push(c);
+        }
+
+        if KEYWORD.contains(&word.as_str()) {
+            TokenKind::Keyword(word)
+        } else {
+            TokenKind::Identifier(word)
+        }
+    }
+
+    fn peek(&self, n: usize) -> Option<char> {
+        self.chars.get(self.position + n).map(|(_, c)| *c)
+    }
+
+    fn bump(&mut self) {
+        if let Some((_, c)) = self.chars.get(self.position) {
+            if *c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
             }
+            self.position += 1;
+        }
+    }
+
+    // start から現在の位置までをコメントとして残す
+    fn push_comment(&mut self, start: Mark) {
+        let text = self.chars[start.position..self.position].iter().map(|(_, c)| *c).collect::<String>();
+        let span = self.span(start);
+        // `\r\n` の改行なら `//` のコメントに `\r` が残る
+        self.comments.push(Comment { text: text.trim_end().into(), span });
+    }
+
+    // 現在の位置
+    fn mark(&self) -> Mark {
+        Mark { position: self.position, offset: self.offset(), line: self.line, column: self.column }
+    }
+
+    fn offset(&self) -> usize {
+        self.chars.get(self.position).map_or(self.source_len, |(offset, _)| *offset)
+    }
+
+    // start から現在の位置までの範囲
+    fn span(&self, start: Mark) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: start.line,
+            column: start.column,
+            start: start.offset,
+            end: self.offset(),
+        }
+    }
+
+    fn token(&self, kind: TokenKind, start: Mark) -> Token {
+        Token { kind, span: self.span(start) }
+    }
+
+    fn error(&mut self, message: String, start: Mark) {
+        let span = self.span(start);
+        self.errors.push(LexError { message, span });
+    }
+}
+
+// 整数の定数の値。10進数は 0..32767、16進数は 0x0..0xFFFF、文字は文字コード
+pub fn integer_value(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    if let Some(c) = text.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) {
+        let mut chars = c.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => u16::try_from(c as u32).ok(),
+            _ => None,
+        };
+    }
+    text.parse::<u16>().ok().filter(|value| *value <= MAX_INTEGER_CONSTANT)
+}
+
+#[derive(Clone, Copy)]
+struct Mark {
+    // chars の中の位置
+    position: usize,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+// トークンの間にあるコメント (trivia)
+// 構文解析では使わず、フォーマッタが元の位置に書き戻す
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    // `//` や `/* */` を含めたコメントの文字列
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+/////////////////////////////////////////////////////////////
+// ソースコード上の位置
+/////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: Rc<str>,
+    // 開始位置の行と列 (1始まり、列は文字単位)
+    pub line: usize,
+    pub column: usize,
+    // ファイル先頭からのバイト位置 (end は含まない)
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // self の先頭から end の末尾まで
+    pub fn to(&self, end: &Span) -> Span {
+        Span {
+            end: end.end,
+            ..self.clone()
+        }
+    }
+
+    // ソースコード text の末尾にある長さ0の範囲
+    pub fn end_of(file: &str, text: &str) -> Span {
+        let last_line = text.rsplit('\n').next().unwrap_or("");
+        Span {
+            file: Rc::from(file),
+            line: text.matches('\n').count() + 1,
+            column: last_line.chars().count() + 1,
+            start: text.len(),
+            end: text.len(),
         }
     }
 }
 
-#[derive(Debug)]
-pub enum Token {
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+// ソースコード上の位置を持つもの
+pub trait Spanned {
+    fn span(&self) -> Span;
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    // トークンの文字列 (文字列定数は `"` を除いたもの)
+    pub fn as_str(&self) -> &str {
+        self.kind.as_str()
+    }
+}
+
+impl From<&Token> for Token {
+    fn from(token: &Token) -> Self {
+        token.clone()
+    }
+}
+
+impl Spanned for Token {
+    fn span(&self) -> Span {
+        self.span.clone()
+    }
+}
+
+impl Xml for Token {
+    fn xml(&self, options: XmlOptions) -> String {
+        with_span_attributes(self.kind.xml(), &self.span, options)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
     Keyword(String),
     Symbol(String),
     Identifier(String),
@@ -180,86 +479,33 @@ pub enum Token {
     StringConst(String),
 }
 
-impl From<&Token> for Token {
-    fn from(token: &Token) -> Self {
-        match token {
-            Token::Keyword(s) => Token::Keyword(String::from(s)),
-            Token::Symbol(s) => Token::Symbol(String::from(s)),
-            Token::Identifier(s) => Token::Identifier(String::from(s)),
-            Token::IntegerConst(s) => Token::IntegerConst(String::from(s)),
-            Token::StringConst(s) => Token::StringConst(String::from(s)),
+impl TokenKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            TokenKind::Keyword(s) | TokenKind::Symbol(s) | TokenKind::Identifier(s) | TokenKind::IntegerConst(s) | TokenKind::StringConst(s) => s,
         }
     }
-}
 
-impl Xml for Token {
+    // 位置の属性を付けない終端記号の要素
     fn xml(&self) -> String {
         match self {
-            Token::Keyword(keyword) => format!("<keyword>{}</keyword>\n", keyword),
-            Token::Symbol(symbol) => format!("<symbol>{}</symbol>\n", convert_to_xml_symbol(&symbol)),
-            Token::Identifier(identifier) => format!("<identifier>{}</identifier>\n", identifier),
-            Token::IntegerConst(integer_const) => format!("<integerConstant>{}</integerConstant>\n", integer_const),
-            Token::StringConst(string_const) => format!("<stringConstant>{}</stringConstant>\n", string_const),
+            TokenKind::Keyword(keyword) => format!("<keyword> {} </keyword>\n", keyword),
+            TokenKind::Symbol(symbol) => format!("<symbol> {} </symbol>\n", convert_to_xml_symbol(symbol)),
+            TokenKind::Identifier(identifier) => format!("<identifier> {} </identifier>\n", identifier),
+            TokenKind::IntegerConst(integer_const) => format!("<integerConstant> {} </integerConstant>\n", integer_const),
+            TokenKind::StringConst(string_const) => format!("<stringConstant> {} </stringConstant>\n", string_const),
         }
     }
 }
 
 impl<T> Xml for &Vec<T> where T: Xml {
-    fn xml(&self) -> String {
+    fn xml(&self, options: XmlOptions) -> String {
         let mut xml = String::new();
         xml.push_str("<tokens>\n");
         for t in self.iter() {
-            xml.push_str(t.xml().as_str());
+            xml.push_str(t.xml(options).as_str());
         }
         xml.push_str("</tokens>\n");
         xml
     }
 }
-
-struct JackReader {
-    reader: BufReader<File>
-}
-
-impl JackReader {
-    fn read_line(&mut self) -> Option<String> {
-        let mut buf = String::new();
-
-        loop {
-            if let Ok(len) = self.reader.read_line(&mut buf) {
-                if len == 0 {
-                    // EOF
-                    return None;
-                }
-
-                // コメント以降を削除
-                if let Some(pos) = buf.find("//") {
-                    buf.replace_range(pos.., "");
-                }
-
-                if let Some(pos_start) = buf.find("/*") {
-                    let pos_end = {
-                        let s = &buf[pos_start..];
-                        if let Some(pos_end) = s.find("*/") {
-                            Some(pos_start + pos_end + 2) // "*/" の分を +2 している
-                        } else {
-                            None
-                        }
-                    };
-                    if pos_end.is_some() {
-                        buf.replace_range(pos_start..pos_end.unwrap(    ), "");
-                    }
-                }
-
-                let buf = String::from(buf.trim());
-
-                if buf.len() == 0 {
-                    continue;
-                }
-
-                return Some(buf);
-            } else {
-                panic!("failed to read line");
-            }
-        }
-    }
-}
@@ -0,0 +1,117 @@
+use std::fmt;
+
+/////////////////////////////////////////////////////////////
+// VMWriterモジュール
+// VMコマンドを1行ずつ書き出す
+/////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    Constant,
+    Argument,
+    Local,
+    Static,
+    This,
+    That,
+    Pointer,
+    Temp,
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Segment::Constant => "constant",
+            Segment::Argument => "argument",
+            Segment::Local => "local",
+            Segment::Static => "static",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithmeticCommand {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+}
+
+impl fmt::Display for ArithmeticCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ArithmeticCommand::Add => "add",
+            ArithmeticCommand::Sub => "sub",
+            ArithmeticCommand::Neg => "neg",
+            ArithmeticCommand::Eq => "eq",
+            ArithmeticCommand::Gt => "gt",
+            ArithmeticCommand::Lt => "lt",
+            ArithmeticCommand::And => "and",
+            ArithmeticCommand::Or => "or",
+            ArithmeticCommand::Not => "not",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub struct VmWriter {
+    commands: Vec<String>,
+}
+
+impl VmWriter {
+    pub fn new() -> Self {
+        Self {
+            commands: vec![],
+        }
+    }
+
+    pub fn write_push(&mut self, segment: Segment, index: u16) {
+        self.commands.push(format!("push {} {}", segment, index));
+    }
+
+    pub fn write_pop(&mut self, segment: Segment, index: u16) {
+        self.commands.push(format!("pop {} {}", segment, index));
+    }
+
+    pub fn write_arithmetic(&mut self, command: ArithmeticCommand) {
+        self.commands.push(command.to_string());
+    }
+
+    pub fn write_label(&mut self, label: &str) {
+        self.commands.push(format!("label {}", label));
+    }
+
+    pub fn write_goto(&mut self, label: &str) {
+        self.commands.push(format!("goto {}", label));
+    }
+
+    pub fn write_if(&mut self, label: &str) {
+        self.commands.push(format!("if-goto {}", label));
+    }
+
+    pub fn write_call(&mut self, name: &str, num_arguments: u16) {
+        self.commands.push(format!("call {} {}", name, num_arguments));
+    }
+
+    pub fn write_function(&mut self, name: &str, num_locals: u16) {
+        self.commands.push(format!("function {} {}", name, num_locals));
+    }
+
+    pub fn write_return(&mut self) {
+        self.commands.push("return".into());
+    }
+
+    pub fn vm(&self) -> String {
+        let mut vm = self.commands.join("\n");
+        vm.push('\n');
+        vm
+    }
+}
@@ -0,0 +1,228 @@
+// 生成した VM コードを 8章の VM エミュレータで実行して、結果を確かめる
+use std::cell::RefCell;
+use std::io::{empty, Write};
+use std::rc::Rc;
+use compiler::{Dialect, Source};
+use vm_emulator::os::Os;
+use vm_emulator::vm::{State, Vm};
+use vm_translator::parser::VmFile;
+
+const MAX_STEPS: u64 = 1_000_000;
+
+// Output の出力を集める
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// コンパイルして Main.main から実行し、Output に書き出した文字列を返す
+fn run(sources: &[Source]) -> String {
+    let compiled = compiler::compile(sources).unwrap_or_else(|errors| {
+        panic!("{}", errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"))
+    });
+    let files = compiled.vm_files.iter()
+        .map(|file| VmFile::read(file.class_name.clone(), file.code.as_bytes()))
+        .collect();
+
+    let output = Output::default();
+    let mut vm = Vm::load(files, Os::new(Box::new(empty()), Box::new(output.clone()))).unwrap();
+    vm.run(Some(MAX_STEPS)).unwrap();
+    assert_eq!(*vm.state(), State::Halted, "should return from Main.main");
+
+    let text = output.0.borrow().clone();
+    String::from_utf8(text).unwrap()
+}
+
+const POINT: &str = "
+class Point {
+    field int x, y;
+    static int count;
+
+    constructor Point new(int ax, int ay) {
+        let x = ax;
+        let y = ay;
+        let count = count + 1;
+        return this;
+    }
+
+    method int getX() { return x; }
+    method int getY() { return y; }
+
+    // 別のオブジェクトのフィールドはメソッドで読む
+    method Point plus(Point other) {
+        return Point.new(x + other.getX(), y + other.getY());
+    }
+
+    // 自分のメソッドを this を付けずに呼び出す
+    method int manhattan() {
+        return Math.abs(getX()) + Math.abs(getY());
+    }
+
+    function int count() { return count; }
+}
+";
+
+#[test]
+fn constructors_and_methods() {
+    let main = "
+class Main {
+    function void main() {
+        var Point a, b, c;
+        let a = Point.new(1, 2);
+        let b = Point.new(-10, 20);
+        let c = a.plus(b);
+        do Output.printInt(c.getX());
+        do Output.printChar(44);
+        do Output.printInt(c.getY());
+        do Output.printChar(44);
+        do Output.printInt(c.manhattan());
+        do Output.printChar(44);
+        do Output.printInt(Point.count());
+        return;
+    }
+}
+";
+    assert_eq!(run(&[Source::new("Main.jack", main), Source::new("Point.jack", POINT)]), "-9,22,31,3");
+}
+
+#[test]
+fn constructor_without_fields() {
+    let counter = "
+class Counter {
+    static int count;
+
+    constructor Counter new() {
+        let count = count + 1;
+        return this;
+    }
+
+    function int count() { return count; }
+}
+";
+    let main = "
+class Main {
+    function void main() {
+        var Counter a, b;
+        let a = Counter.new();
+        let b = Counter.new();
+        do Output.printInt(Counter.count());
+        return;
+    }
+}
+";
+    assert_eq!(run(&[Source::new("Main.jack", main), Source::new("Counter.jack", counter)]), "2");
+}
+
+#[test]
+fn arrays() {
+    let main = "
+class Main {
+    function void main() {
+        var Array a, b;
+        var int i, sum;
+        let a = Array.new(5);
+        let b = Array.new(5);
+        let i = 0;
+        while (i < 5) {
+            let a[i] = i * i;
+            let b[i] = 4 - i;
+            let i = i + 1;
+        }
+        // 添字にも配列の要素を使う
+        let i = 0;
+        let sum = 0;
+        while (i < 5) {
+            let sum = sum + a[b[i]];
+            let i = i + 1;
+        }
+        let a[a[2]] = 100;
+        do Output.printInt(sum);
+        do Output.printChar(44);
+        do Output.printInt(a[4]);
+        return;
+    }
+}
+";
+    assert_eq!(run(&[Source::new("Main.jack", main)]), "30,100");
+}
+
+#[test]
+fn string_constants() {
+    let main = "
+class Main {
+    function void main() {
+        var String s;
+        let s = \"Hello, \";
+        do Output.printString(s);
+        do Output.printString(\"world\");
+        do Output.printChar(s.charAt(0));
+        do Output.printInt(s.length());
+        do Output.println();
+        do Output.printString(\"\");
+        return;
+    }
+}
+";
+    assert_eq!(run(&[Source::new("Main.jack", main)]), "Hello, worldH7\n");
+}
+
+#[test]
+fn expressions_and_control_flow() {
+    let main = "
+class Main {
+    function void main() {
+        var int i;
+        var boolean b;
+        // 本の文法では左から順に評価する
+        do Output.printInt(2 + 3 * 4);
+        do Output.printChar(44);
+        do Output.printInt(-7 / 2);
+        do Output.printChar(44);
+        let b = ~(1 > 2) & (3 = 3);
+        if (b) {
+            do Output.printString(\"yes\");
+        } else {
+            do Output.printString(\"no\");
+        }
+        let i = 10;
+        while (~(i = 0)) {
+            let i = i - 3;
+            if (i < 0) {
+                let i = 0;
+            }
+        }
+        do Output.printInt(i);
+        return;
+    }
+}
+";
+    assert_eq!(run(&[Source::new("Main.jack", main)]), "20,-3,yes0");
+}
+
+#[test]
+fn extended_dialect() {
+    let main = "
+class Main {
+    function void main() {
+        do Output.printInt(2 + 3 * 4);
+        do Output.printChar(44);
+        do Output.printInt(17 % 5);
+        do Output.printChar(44);
+        if ((1 <= 2) && (3 != 4)) {
+            do Output.printInt(0x10);
+        }
+        return;
+    }
+}
+";
+    assert_eq!(run(&[Source::new("Main.jack", main).with_dialect(Dialect::Extended)]), "14,2,16");
+}
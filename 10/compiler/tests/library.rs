@@ -0,0 +1,441 @@
+// ファイルを使わずにライブラリとしてコンパイルできることを確かめる
+use compiler::tokenizer::TokenKind;
+use compiler::{Dialect, Source, Xml, XmlOptions};
+
+const MAIN: &str = "
+class Main {
+    function void main() {
+        var Counter c;
+        let c = Counter.new(3);
+        do c.increment();
+        return;
+    }
+}
+";
+
+const COUNTER: &str = "
+class Counter {
+    field int count;
+
+    constructor Counter new(int start) {
+        let count = start;
+        return this;
+    }
+
+    method void increment() {
+        let count = count + 1;
+        return;
+    }
+}
+";
+
+#[test]
+fn tokenize_text() {
+    let tokens = compiler::tokenize("let x = \"a // b\"; /** doc */ // comment").unwrap();
+    let kinds = tokens.iter().map(|token| token.kind.clone()).collect::<Vec<_>>();
+    assert_eq!(kinds, vec![
+        TokenKind::Keyword("let".into()),
+        TokenKind::Identifier("x".into()),
+        TokenKind::Symbol("=".into()),
+        TokenKind::StringConst("a // b".into()),
+        TokenKind::Symbol(";".into()),
+    ]);
+}
+
+#[test]
+fn parse_reports_every_syntax_error() {
+    let errors = compiler::parse("class Main {\n  field int x\n  function void f() {\n    let = 1;\n    return;\n  }\n}\n")
+        .err()
+        .unwrap();
+    let messages = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    assert_eq!(messages, vec![
+        "<input>:3:3: expected `;`, but found `function`",
+        "<input>:4:9: expected variable name, but found `=`",
+    ]);
+}
+
+// 入力の終わりのエラーにもファイル名と位置を付ける
+#[test]
+fn parse_errors_at_end_of_input() {
+    let messages = |text: &str| {
+        compiler::compile(&[Source::new("Main.jack", text)])
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(messages(""), vec!["Main.jack:1:1: expected `class`, but found end of input"]);
+    assert_eq!(messages("// comment\n  /* doc */ "), vec!["Main.jack:2:13: expected `class`, but found end of input"]);
+    // 閉じていない `}` が2つあってもエラーは1つ
+    assert_eq!(messages("class Main {\n  function void main() {\n    return;\n"), vec![
+        "Main.jack:4:1: expected `}`, but found end of input",
+    ]);
+    assert_eq!(messages("class Main { function void main() { let x = 1 +"), vec![
+        "Main.jack:1:48: expected term, but found end of input",
+        "Main.jack:1:48: expected `}`, but found end of input",
+    ]);
+}
+
+#[test]
+fn parse_errors_in_declarations_and_expressions() {
+    let errors = compiler::parse("class Main {\n  field x;\n  function f() {}\n  method void g() {\n    do f(1,);\n    let y = (1 + 2;\n    if x { return; }\n    return;\n  }\n}\n")
+        .err()
+        .unwrap();
+    let messages = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    assert_eq!(messages, vec![
+        "<input>:2:10: expected variable name, but found `;`",
+        "<input>:3:13: expected subroutine name, but found `(`",
+        "<input>:5:12: expected term, but found `)`",
+        "<input>:6:19: expected `)`, but found `;`",
+        "<input>:7:8: expected `(`, but found `x`",
+    ]);
+}
+
+// キーワードや記号もソース上の位置を持つ
+#[test]
+fn xml_with_spans() {
+    let class = compiler::parse("class Main {\n  function void f() {\n    if (x) { let a[0] = 1; }\n    else { return; }\n  }\n}\n")
+        .ok()
+        .unwrap();
+    let xml = class.xml(XmlOptions { with_spans: true, ..XmlOptions::default() });
+    for element in [
+        "<keyword line=\"1\" column=\"1\"> class </keyword>",
+        "<symbol line=\"1\" column=\"12\"> { </symbol>",
+        "<symbol line=\"3\" column=\"19\"> [ </symbol>",
+        "<symbol line=\"3\" column=\"28\"> } </symbol>",
+        "<keyword line=\"4\" column=\"5\"> else </keyword>",
+        "<symbol line=\"6\" column=\"1\"> } </symbol>",
+    ] {
+        assert!(xml.contains(element), "{} in\n{}", element, xml);
+    }
+
+    // 指定しなければ位置の属性は付けない
+    let xml = class.xml(XmlOptions::default());
+    assert!(xml.starts_with("<class>\n  <keyword> class </keyword>\n"), "{}", xml);
+    assert!(!xml.contains("line="), "{}", xml);
+}
+
+// 既定の出力は本の ArrayTest の Main.xml と MainT.xml に一致する
+#[test]
+fn xml_matches_the_book() {
+    let text = include_str!("fixtures/ArrayTest/Main.jack");
+    let tokens = compiler::tokenize(text).ok().unwrap();
+    assert_eq!((&tokens).xml(XmlOptions::default()), include_str!("fixtures/ArrayTest/MainT.xml"));
+
+    // 識別子を解決していても、指定しなければ属性は付けない
+    let mut class = compiler::parse(text).ok().unwrap();
+    compiler::resolve(&mut class).ok().unwrap();
+    assert_eq!(class.xml(XmlOptions::default()), include_str!("fixtures/ArrayTest/Main.xml"));
+
+    let xml = class.xml(XmlOptions { with_annotations: true, ..XmlOptions::default() });
+    for element in [
+        "<identifier category=\"class\" usage=\"defined\"> Main </identifier>",
+        "<identifier category=\"var\" usage=\"defined\" kind=\"var\" index=\"3\"> sum </identifier>",
+        "<identifier category=\"var\" usage=\"used\" kind=\"var\" index=\"0\"> a </identifier>",
+    ] {
+        assert!(xml.contains(element), "{} in\n{}", element, xml);
+    }
+}
+
+#[test]
+fn compile_sources() {
+    let compiled = compiler::compile(&[
+        Source::new("Main.jack", MAIN),
+        Source::new("Counter.jack", COUNTER),
+    ]).unwrap();
+    assert!(compiled.warnings.is_empty());
+
+    let vm_files = compiled.vm_files;
+
+    let names = vm_files.iter().map(|file| file.class_name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["Main", "Counter"]);
+    assert!(vm_files[0].code.contains("call Counter.new 1\n"));
+    assert!(vm_files[0].code.contains("call Counter.increment 1\n"));
+    assert!(vm_files[1].code.contains("function Counter.new 0\npush constant 1\ncall Memory.alloc 1\n"));
+}
+
+#[test]
+fn compile_collects_errors_from_every_source() {
+    let errors = compiler::compile(&[
+        Source::new("Main.jack", "class Main { function void main() { let y = 1; return; } }"),
+        Source::new("Counter.jack", "class Counter { field int count; field int count; }"),
+    ]).err().unwrap();
+
+    let messages = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    assert_eq!(messages, vec![
+        "Main.jack:1:41: Main.main: undeclared identifier `y`",
+        "Counter.jack:1:44: Counter: `count` is already defined",
+    ]);
+}
+
+#[test]
+fn check_calls_returns_and_assignments() {
+    let errors = compiler::compile(&[
+        Source::new("Main.jack", "
+class Main {
+    function void main() {
+        var char c;
+        let c = 65;
+        do Counter.increment();
+        do Counter.new();
+        return;
+    }
+}
+"),
+        Source::new("Counter.jack", COUNTER),
+        Source::new("Bad.jack", "
+class Bad {
+    field int x;
+    constructor Bad new() { return 0; }
+    function int get() { if (true) { return x; } }
+}
+"),
+    ]).err().unwrap();
+
+    let messages = errors.iter()
+        .map(|e| format!("{}: {}", e.severity, e))
+        .collect::<Vec<_>>();
+    assert_eq!(messages, vec![
+        "warning: Main.jack:5:17: Main.main: assigning `int` to `char`",
+        "error: Main.jack:6:12: Main.main: method `Counter.increment` is called as a function",
+        "error: Main.jack:7:12: Main.main: `Counter.new` takes 1 argument(s), but 0 were given",
+        "error: Bad.jack:4:36: Bad.new: constructor must return `this`",
+        "error: Bad.jack:5:45: Bad.get: field `x` cannot be used in a function",
+        "error: Bad.jack:5:18: Bad.get: not all paths return a value",
+    ]);
+}
+
+// エラーが無ければ警告は VM コードと一緒に返す
+#[test]
+fn compile_returns_warnings() {
+    let compiled = compiler::compile(&[Source::new("Main.jack", "
+class Main {
+    function void main() {
+        var char c;
+        let c = 65;
+        return;
+    }
+}
+")]).unwrap();
+
+    assert_eq!(compiled.vm_files.len(), 1);
+    let messages = compiled.warnings.iter()
+        .map(|e| format!("{}: {}", e.severity, e))
+        .collect::<Vec<_>>();
+    assert_eq!(messages, vec!["warning: Main.jack:5:17: Main.main: assigning `int` to `char`"]);
+}
+
+#[test]
+fn check_calls_to_the_os() {
+    let errors = compiler::compile(&[
+        Source::new("Main.jack", "
+class Main {
+    function void main() {
+        var String s;
+        let s = Keyboard.readLine(\"name? \");
+        do Output.printInt(s.length());
+        do Output.printString(s.length());
+        do Output.printChar(s.length());
+        do Screen.fill();
+        return;
+    }
+}
+"),
+    ]).err().unwrap();
+
+    let messages = errors.iter()
+        .map(|e| format!("{}: {}", e.severity, e))
+        .collect::<Vec<_>>();
+    assert_eq!(messages, vec![
+        "error: Main.jack:7:31: Main.main: argument 1 of `Output.printString` expects `String`, found `int`",
+        "warning: Main.jack:8:29: Main.main: argument 1 of `Output.printChar` expects `char`, found `int`",
+        "error: Main.jack:9:12: Main.main: subroutine `Screen.fill` is not defined",
+    ]);
+}
+
+// 文字コードの整数定数は char の引数に渡しても警告しない
+#[test]
+fn char_arguments_accept_integer_constants() {
+    let compiled = compiler::compile(&[Source::new("Main.jack", "
+class Main {
+    function void main() {
+        do Output.printChar(32);
+        do Output.printChar((65));
+        return;
+    }
+}
+")]).unwrap();
+    assert!(compiled.warnings.is_empty(), "{:?}", compiled.warnings);
+}
+
+#[test]
+fn extended_dialect_uses_operator_precedence() {
+    let source = "
+class Main {
+    function int main() {
+        return 1 + 2 * 3;
+    }
+}
+";
+    let standard = compiler::compile(&[Source::new("Main.jack", source)]).unwrap();
+    assert!(standard.vm_files[0].code.contains(
+        "push constant 1\npush constant 2\nadd\npush constant 3\ncall Math.multiply 2\nreturn\n"
+    ));
+
+    let extended = compiler::compile(&[Source::new("Main.jack", source).with_dialect(Dialect::Extended)]).unwrap();
+    assert!(extended.vm_files[0].code.contains(
+        "push constant 1\npush constant 2\npush constant 3\ncall Math.multiply 2\nadd\nreturn\n"
+    ));
+}
+
+#[test]
+fn extended_dialect_adds_operators_and_literals() {
+    let source = "
+class Main {
+    function boolean main(int x) {
+        return (x % 0x10 != 'a') && (x <= 0xFFFF);
+    }
+}
+";
+    let errors = compiler::compile(&[Source::new("Main.jack", source)]).err().unwrap();
+    assert_eq!(errors[0].to_string(), "Main.jack:4:19: illegal character `%`");
+
+    let extended = compiler::compile(&[Source::new("Main.jack", source).with_dialect(Dialect::Extended)]).unwrap();
+    let code = &extended.vm_files[0].code;
+    assert!(code.contains("push constant 16\n"));
+    assert!(code.contains("push constant 97\neq\nnot\nnot\nif-goto AND_FALSE0\n"));
+    assert!(code.contains("push constant 0\nnot\ngt\nnot\n"));
+}
+
+#[test]
+fn format_keeps_comments() {
+    let source = Source::new("Messy.jack", "
+/** Messy class. */
+class Messy{
+  field int x,y; // coordinates
+
+
+  constructor Messy new(int ax,int ay){let x=ax;let y=ay;
+     return this;}
+  method int abs(){ var int s ;
+    if(x<0){let s=-x;}else{ // not negative
+      let s=x;
+    }
+    do Output.printString(\"a  b\"); return /* inline */ s;
+  }
+}
+");
+    let expected = "/** Messy class. */
+class Messy {
+    field int x, y; // coordinates
+
+    constructor Messy new(int ax, int ay) {
+        let x = ax;
+        let y = ay;
+        return this;
+    }
+
+    method int abs() {
+        var int s;
+        if (x < 0) {
+            let s = -x;
+        } else { // not negative
+            let s = x;
+        }
+        do Output.printString(\"a  b\");
+        return /* inline */ s;
+    }
+}
+";
+    assert_eq!(compiler::format(&source).unwrap(), expected);
+
+    // 整形済みのものは変わらない
+    let formatted = Source::new("Messy.jack", expected);
+    assert_eq!(compiler::format(&formatted).unwrap(), expected);
+}
+
+// if 文のコメントは then と else のブロック、条件の中でそれぞれ元の位置に書く
+#[test]
+fn format_keeps_comments_in_if_statements() {
+    let source = Source::new("If.jack", "
+class If {
+  function void f(int i) {
+    if /* a */ ( /* b */ i /* c */ ) {
+      // then
+    }
+    // before else
+    else { /* empty */ }
+    if (i) { do f(1); // after then
+    } else { // else
+      do f(2);
+    }
+    if (i) {} // trailing
+    return;
+  }
+}
+");
+    let expected = "class If {
+    function void f(int i) {
+        if /* a */ ( /* b */ i /* c */) {
+            // then
+        }
+        // before else
+        else { /* empty */
+        }
+        if (i) {
+            do f(1); // after then
+        } else { // else
+            do f(2);
+        }
+        if (i) {
+        } // trailing
+        return;
+    }
+}
+";
+    assert_eq!(compiler::format(&source).unwrap(), expected);
+
+    let formatted = Source::new("If.jack", expected);
+    assert_eq!(compiler::format(&formatted).unwrap(), expected);
+}
+
+#[test]
+fn format_keeps_comments_in_statements() {
+    let source = Source::new("While.jack", "
+class While {
+  function void f(int c) {
+    var int a /* count */, b;
+    while (c > 0) // loop
+    {
+      let a[c /* index */] = c;
+      do f(c /* x */);
+      do Output /* os */ .printInt(c, /* y */ 1);
+    }
+    return /* nothing */;
+  }
+}
+");
+    let expected = "class While {
+    function void f(int c) {
+        var int a /* count */, b;
+        while (c > 0) // loop
+        {
+            let a[c /* index */] = c;
+            do f(c /* x */);
+            do Output /* os */.printInt(c, /* y */ 1);
+        }
+        return /* nothing */;
+    }
+}
+";
+    assert_eq!(compiler::format(&source).unwrap(), expected);
+
+    let formatted = Source::new("While.jack", expected);
+    assert_eq!(compiler::format(&formatted).unwrap(), expected);
+}
+
+#[test]
+fn format_reports_syntax_errors() {
+    let errors = compiler::format(&Source::new("Broken.jack", "class Broken { function void f() { let = 1; } }")).unwrap_err();
+    assert!(errors[0].to_string().starts_with("Broken.jack:1:40: "), "{}", errors[0]);
+}
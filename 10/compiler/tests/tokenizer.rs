@@ -0,0 +1,114 @@
+// 字句解析のエラーと位置を確かめる
+use compiler::tokenizer::{Token, TokenKind, Tokenizer};
+use compiler::{Dialect, Source};
+
+fn tokenize(text: &str) -> Vec<Token> {
+    compiler::tokenize(text).unwrap_or_else(|errors| panic!("{:?}", errors))
+}
+
+// エラーをすべて `<input>:行:列: メッセージ` の形で返す
+fn errors(text: &str) -> Vec<String> {
+    compiler::tokenize(text).unwrap_err().iter().map(|e| e.to_string()).collect()
+}
+
+// トークンの文字列と行と列
+fn positions(tokens: &[Token]) -> Vec<(&str, usize, usize)> {
+    tokens.iter().map(|token| (token.as_str(), token.span.line, token.span.column)).collect()
+}
+
+#[test]
+fn integer_constants() {
+    let tokens = tokenize("0 32767 007");
+    let kinds = tokens.iter().map(|token| token.kind.clone()).collect::<Vec<_>>();
+    assert_eq!(kinds, vec![
+        TokenKind::IntegerConst("0".into()),
+        TokenKind::IntegerConst("32767".into()),
+        TokenKind::IntegerConst("007".into()),
+    ]);
+
+    // -32768 は `-` と 32768 なので、32768 は書けない
+    assert_eq!(errors("let x = 32768;"), vec!["<input>:1:9: integer constant should be 0..32767: 32768"]);
+    // u16 に入らない値もエラーにする
+    assert_eq!(errors("65536 99999999999999999999"), vec![
+        "<input>:1:1: integer constant should be 0..32767: 65536",
+        "<input>:1:7: integer constant should be 0..32767: 99999999999999999999",
+    ]);
+}
+
+#[test]
+fn extended_integer_constants() {
+    let source = Source::new("Main.jack", "0xFFFF 0x10000 0xG").with_dialect(Dialect::Extended);
+    let messages = compiler::tokenize_source(&source).unwrap_err().iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    assert_eq!(messages, vec![
+        "Main.jack:1:8: hex constant should be 0x0..0xFFFF: 0x10000",
+        "Main.jack:1:16: hex constant should be 0x0..0xFFFF: 0xG",
+    ]);
+}
+
+#[test]
+fn string_constants() {
+    let tokens = tokenize("\"\" \"a // b /* c */\"");
+    let kinds = tokens.iter().map(|token| token.kind.clone()).collect::<Vec<_>>();
+    assert_eq!(kinds, vec![
+        TokenKind::StringConst("".into()),
+        TokenKind::StringConst("a // b /* c */".into()),
+    ]);
+
+    // 改行を含む文字列はエラーにして、次の行から読み続ける
+    assert_eq!(errors("let s = \"abc\nlet t = \"def\r\nx"), vec![
+        "<input>:1:9: newline in string constant",
+        "<input>:2:9: newline in string constant",
+    ]);
+    assert_eq!(errors("let s = \"abc"), vec!["<input>:1:9: unterminated string constant"]);
+}
+
+#[test]
+fn comments() {
+    let tokens = tokenize("// line\nx /* a */ y /** doc\n * more\n */ z // end");
+    assert_eq!(positions(&tokens), vec![("x", 2, 1), ("y", 2, 11), ("z", 4, 5)]);
+
+    // 閉じていないコメントは開始位置のエラー
+    assert_eq!(errors("x\n  /* abc\n def"), vec!["<input>:2:3: unterminated comment"]);
+    // コメントは入れ子にならない
+    assert_eq!(positions(&tokenize("/* a /* b */ */")), vec![("*", 1, 14), ("/", 1, 15)]);
+}
+
+#[test]
+fn comments_are_kept_for_the_formatter() {
+    let mut tokenizer = Tokenizer::new("x /* a\n b */ y // c\r\n", "Main.jack", Dialect::Standard);
+    let tokens = tokenizer.generate_tokens().unwrap();
+    assert_eq!(positions(&tokens), vec![("x", 1, 1), ("y", 2, 7)]);
+
+    let comments = tokenizer.take_comments();
+    let texts = comments.iter().map(|c| (c.text.as_str(), c.span.line, c.span.column)).collect::<Vec<_>>();
+    // `\r\n` の `\r` はコメントに含めない
+    assert_eq!(texts, vec![("/* a\n b */", 1, 3), ("// c", 2, 9)]);
+}
+
+#[test]
+fn illegal_characters() {
+    // エラーがあっても最後まで読んで、すべて返す
+    assert_eq!(errors("let x = 1 # 2;\nlet y = $;\nlet z = 'a';"), vec![
+        "<input>:1:11: illegal character `#`",
+        "<input>:2:9: illegal character `$`",
+        "<input>:3:9: illegal character `'`",
+        "<input>:3:11: illegal character `'`",
+    ]);
+    // 列は文字単位で数える
+    assert_eq!(errors("\"あい\" ?"), vec!["<input>:1:6: illegal character `?`"]);
+    // 拡張の文法の記号は標準の文法では使えない
+    assert_eq!(errors("a % b"), vec!["<input>:1:3: illegal character `%`"]);
+}
+
+#[test]
+fn spans() {
+    let tokens = tokenize("class Main {\n\tfield int x;\n}");
+    assert_eq!(positions(&tokens), vec![
+        ("class", 1, 1), ("Main", 1, 7), ("{", 1, 12),
+        ("field", 2, 2), ("int", 2, 8), ("x", 2, 12), (";", 2, 13),
+        ("}", 3, 1),
+    ]);
+    // バイト位置
+    assert_eq!((tokens[1].span.start, tokens[1].span.end), (6, 10));
+    assert_eq!(&*tokens[1].span.file, "<input>");
+}